@@ -1,4 +1,15 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[allow(dead_code)]
+#[path = "../src/embeddings.rs"]
+mod embeddings;
+#[allow(dead_code)]
+#[path = "../src/text_index.rs"]
+mod text_index;
+#[allow(dead_code)]
+#[path = "../src/vector_index.rs"]
+mod vector_index;
 
 fn bench_vector_distance(c: &mut Criterion) {
     let dim = 384usize;
@@ -20,6 +31,14 @@ fn bench_vector_distance(c: &mut Criterion) {
             black_box(sim)
         });
     });
+    let mut group = c.benchmark_group("cosine_distance_384_impl");
+    group.bench_function("scalar", |bch| {
+        bch.iter(|| black_box(vector_index::cosine_similarity_scalar(&a, &b)))
+    });
+    group.bench_function("simd", |bch| {
+        bch.iter(|| black_box(vector_index::cosine_similarity(&a, &b)))
+    });
+    group.finish();
 }
 
 fn bench_ann_search(c: &mut Criterion) {
@@ -56,5 +75,110 @@ fn bench_ann_search(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_vector_distance, bench_ann_search);
+fn bench_hnsw_vs_brute_force(c: &mut Criterion) {
+    // Compare the HNSW index against the brute-force scan on the same sled store
+    let n: usize = std::env::var("HNSW_BENCH_N")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(50_000);
+    let dim = embeddings::EMBED_DIM;
+    let dir = tempfile::tempdir().unwrap();
+    let db = sled::open(dir.path().join("kv")).unwrap();
+    let mut rng = StdRng::seed_from_u64(42);
+    let emb = db.open_tree("mem_embeddings").unwrap();
+    for i in 0..n {
+        let v: Vec<f32> = (0..dim).map(|_| rng.gen::<f32>() - 0.5).collect();
+        emb.insert(format!("mem-{:06}", i), bytemuck::cast_slice::<f32, u8>(&v))
+            .unwrap();
+    }
+    vector_index::ensure_normalized(&db).unwrap();
+    vector_index::build_mem_neighbor_graph(&db).unwrap();
+    let queries: Vec<Vec<f32>> = (0..50)
+        .map(|_| (0..dim).map(|_| rng.gen::<f32>() - 0.5).collect())
+        .collect();
+    let mut hits = 0usize;
+    for q in &queries {
+        let truth: std::collections::HashSet<String> =
+            vector_index::search_memories_by_vector(&db, q, 10, None)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+        hits += vector_index::ann_search_memories(&db, q, 10, None)
+            .into_iter()
+            .filter(|(id, _)| truth.contains(id))
+            .count();
+    }
+    println!(
+        "hnsw recall@10 (n={}): {:.3}",
+        n,
+        hits as f64 / (queries.len() * 10) as f64
+    );
+    let mut group = c.benchmark_group(format!("vector_search_{}", n));
+    group.sample_size(10);
+    group.bench_function("hnsw_top10", |bch| {
+        let mut i = 0usize;
+        bch.iter(|| {
+            let q = &queries[i % queries.len()];
+            i += 1;
+            black_box(vector_index::ann_search_memories(&db, q, 10, None))
+        });
+    });
+    group.bench_function("brute_force_top10", |bch| {
+        let mut i = 0usize;
+        bch.iter(|| {
+            let q = &queries[i % queries.len()];
+            i += 1;
+            black_box(vector_index::search_memories_by_vector(&db, q, 10, None))
+        });
+    });
+    group.finish();
+}
+
+fn bench_text_index_ingest(c: &mut Criterion) {
+    // 200 memories through the shared writer vs. the old open-writer-commit per item
+    let contents: Vec<String> = (0..200)
+        .map(|i| format!("memory {} about release planning and token{}", i, i))
+        .collect();
+    let mut group = c.benchmark_group("text_index_ingest_200");
+    group.sample_size(10);
+    group.bench_function("shared_writer", |bch| {
+        bch.iter(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let index = text_index::TextIndex::new(dir.path());
+            for (i, content) in contents.iter().enumerate() {
+                index
+                    .add(&format!("mem:{}", i), "memory", content, 0)
+                    .unwrap();
+            }
+            black_box(index.search("token199", 10).unwrap())
+        });
+    });
+    group.bench_function("writer_per_item", |bch| {
+        use tantivy::{directory::MmapDirectory, doc, schema::*, Index};
+        bch.iter(|| {
+            let dir = tempfile::tempdir().unwrap();
+            for (i, content) in contents.iter().enumerate() {
+                let mut builder = Schema::builder();
+                let id_f = builder.add_text_field("id", TEXT | STORED);
+                let content_f = builder.add_text_field("content", TEXT);
+                let directory = MmapDirectory::open(dir.path()).unwrap();
+                let index = Index::open_or_create(directory, builder.build()).unwrap();
+                let mut writer: tantivy::IndexWriter = index.writer(50_000_000).unwrap();
+                writer
+                    .add_document(doc!(id_f => format!("mem:{}", i), content_f => content.as_str()))
+                    .unwrap();
+                writer.commit().unwrap();
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_vector_distance,
+    bench_ann_search,
+    bench_hnsw_vs_brute_force,
+    bench_text_index_ingest
+);
 criterion_main!(benches);
@@ -3,6 +3,41 @@ use std::net::SocketAddr;
 pub struct Config {
     pub bind: SocketAddr,
     pub data_dir: String,
+    /// Bearer tokens accepted by the HTTP server; empty leaves it open
+    pub api_keys: Vec<String>,
+    /// Per-client limit on HTTP `POST`s; `None` leaves writes unthrottled
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Token bucket: refills at `rps` per second up to `burst` tokens.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub rps: f64,
+    pub burst: f64,
+}
+
+/// `RATE_LIMIT_RPS` (required, > 0) and `RATE_LIMIT_BURST` (default twice the rate, at least 1).
+pub fn rate_limit_from_env() -> Option<RateLimit> {
+    let rps: f64 = std::env::var("RATE_LIMIT_RPS").ok()?.parse().ok()?;
+    if !(rps > 0.0 && rps.is_finite()) {
+        return None;
+    }
+    let burst = std::env::var("RATE_LIMIT_BURST")
+        .ok()
+        .and_then(|b| b.parse::<f64>().ok())
+        .filter(|b| *b >= 1.0)
+        .unwrap_or((rps * 2.0).max(1.0));
+    Some(RateLimit { rps, burst })
+}
+
+/// Comma-separated `API_KEYS`, trimmed, blanks dropped.
+pub fn api_keys_from_env() -> Vec<String> {
+    std::env::var("API_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect()
 }
 
 impl Config {
@@ -18,6 +53,11 @@ impl Config {
         };
         let bind: SocketAddr = bind.parse()?;
         let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "./data".to_string());
-        Ok(Self { bind, data_dir })
+        Ok(Self {
+            bind,
+            data_dir,
+            api_keys: api_keys_from_env(),
+            rate_limit: rate_limit_from_env(),
+        })
     }
 }
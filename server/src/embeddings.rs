@@ -1,12 +1,531 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use sha2::{Digest, Sha256};
+
+use tracing::warn;
+
 pub const EMBED_DIM: usize = 384;
 
-#[cfg(not(feature = "fastembed"))]
+/// Maximum number of texts sent to a remote embedding API per request.
+const REMOTE_MAX_BATCH: usize = 100;
+
+pub struct RemoteConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+}
+
+pub enum EmbeddingBackend {
+    Stub,
+    FastEmbed,
+    Remote(RemoteConfig),
+}
+
+impl EmbeddingBackend {
+    /// Resolve the backend from EMBED_PROVIDER (stub | fastembed | openai).
+    pub fn from_env() -> Self {
+        let provider = std::env::var("EMBED_PROVIDER")
+            .unwrap_or_default()
+            .to_lowercase();
+        match provider.as_str() {
+            "openai" | "remote" => match std::env::var("EMBED_API_URL") {
+                Ok(url) if !url.is_empty() => EmbeddingBackend::Remote(RemoteConfig {
+                    url,
+                    api_key: std::env::var("EMBED_API_KEY")
+                        .ok()
+                        .filter(|k| !k.is_empty()),
+                    model: std::env::var("EMBED_MODEL")
+                        .unwrap_or_else(|_| "text-embedding-3-small".to_string()),
+                }),
+                _ => {
+                    warn!(
+                        "EMBED_PROVIDER={} requires EMBED_API_URL; using stub",
+                        provider
+                    );
+                    EmbeddingBackend::Stub
+                }
+            },
+            "stub" => EmbeddingBackend::Stub,
+            "fastembed" if !cfg!(feature = "fastembed") => {
+                warn!(
+                    "EMBED_PROVIDER=fastembed but the fastembed feature is not enabled; using stub"
+                );
+                EmbeddingBackend::Stub
+            }
+            _ if cfg!(feature = "fastembed") => EmbeddingBackend::FastEmbed,
+            _ => EmbeddingBackend::Stub,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingBackend::Stub => "stub",
+            EmbeddingBackend::FastEmbed => "fastembed",
+            EmbeddingBackend::Remote(_) => "remote",
+        }
+    }
+
+    /// Identifies the model producing vectors, so cached embeddings from a
+    /// different model or endpoint are never reused.
+    pub fn model_id(&self) -> String {
+        match self {
+            EmbeddingBackend::Stub => String::new(),
+            EmbeddingBackend::FastEmbed => "BGESmallENV15".to_string(),
+            EmbeddingBackend::Remote(cfg) => format!("{}|{}", cfg.url, cfg.model),
+        }
+    }
+}
+
+static BACKEND: OnceLock<EmbeddingBackend> = OnceLock::new();
+
+/// Resolve the embedding backend once; called at startup and lazily on first use.
+pub fn init_backend() -> &'static EmbeddingBackend {
+    BACKEND.get_or_init(EmbeddingBackend::from_env)
+}
+
 pub fn embed_batch(texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    embed_with(init_backend(), texts)
+}
+
+fn embed_with(backend: &EmbeddingBackend, texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    match backend {
+        EmbeddingBackend::Stub => embed_stub(texts),
+        EmbeddingBackend::FastEmbed => embed_fastembed(texts),
+        EmbeddingBackend::Remote(cfg) => embed_remote(cfg, texts),
+    }
+}
+
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Embedding cache (hits, misses) since process start.
+pub fn cache_stats() -> (u64, u64) {
+    (
+        CACHE_HITS.load(Ordering::Relaxed),
+        CACHE_MISSES.load(Ordering::Relaxed),
+    )
+}
+
+/// Cache key: SHA-256 over the backend name, model id and whitespace-normalized text.
+fn cache_key(backend: &EmbeddingBackend, text: &str) -> Vec<u8> {
+    let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut hasher = Sha256::new();
+    hasher.update(backend.name().as_bytes());
+    hasher.update([0u8]);
+    hasher.update(backend.model_id().as_bytes());
+    hasher.update([0u8]);
+    hasher.update(normalized.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Store `vec` as the cached embedding of `text` for the active backend.
+#[cfg(test)]
+pub fn seed_cache(db: &sled::Db, text: &str, vec: &[f32; EMBED_DIM]) {
+    let key = cache_key(init_backend(), text);
+    db.open_tree("emb_cache")
+        .unwrap()
+        .insert(key, bytemuck::cast_slice::<f32, u8>(vec))
+        .unwrap();
+}
+
+/// Embed texts, serving identical content from the `emb_cache` tree and only
+/// invoking the model for misses.
+pub fn embed_batch_cached(db: &sled::Db, texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    embed_cached_with(db, init_backend(), texts)
+}
+
+fn embed_cached_with(
+    db: &sled::Db,
+    backend: &EmbeddingBackend,
+    texts: &[&str],
+) -> Vec<[f32; EMBED_DIM]> {
+    let cache = match db.open_tree("emb_cache") {
+        Ok(t) => t,
+        Err(_) => return embed_with(backend, texts),
+    };
+    let keys: Vec<Vec<u8>> = texts.iter().map(|t| cache_key(backend, t)).collect();
+    let mut out: Vec<Option<[f32; EMBED_DIM]>> = Vec::with_capacity(texts.len());
+    let mut missing: Vec<usize> = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        match cache.get(key) {
+            Ok(Some(v)) if v.len() == EMBED_DIM * 4 => {
+                let mut arr = [0.0f32; EMBED_DIM];
+                for (dst, b) in arr.iter_mut().zip(v.chunks_exact(4)) {
+                    *dst = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                }
+                out.push(Some(arr));
+            }
+            _ => {
+                out.push(None);
+                missing.push(i);
+            }
+        }
+    }
+    CACHE_HITS.fetch_add((texts.len() - missing.len()) as u64, Ordering::Relaxed);
+    CACHE_MISSES.fetch_add(missing.len() as u64, Ordering::Relaxed);
+    if !missing.is_empty() {
+        let miss_texts: Vec<&str> = missing.iter().map(|&i| texts[i]).collect();
+        let vecs = embed_with(backend, &miss_texts);
+        for (&i, v) in missing.iter().zip(vecs) {
+            // Don't cache stub/fallback zero vectors so a later real embedding isn't masked
+            if v.iter().any(|x| *x != 0.0) {
+                let _ = cache.insert(&keys[i], bytemuck::cast_slice::<f32, u8>(&v));
+            }
+            out[i] = Some(v);
+        }
+    }
+    out.into_iter()
+        .map(|v| v.unwrap_or([0.0; EMBED_DIM]))
+        .collect()
+}
+
+fn embed_stub(texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
     texts.iter().map(|_| [0.0; EMBED_DIM]).collect()
 }
 
+fn to_fixed_dim(v: &[f32]) -> [f32; EMBED_DIM] {
+    let mut out = [0.0f32; EMBED_DIM];
+    let n = v.len().min(EMBED_DIM);
+    out[..n].copy_from_slice(&v[..n]);
+    out
+}
+
+#[cfg(not(feature = "fastembed"))]
+fn embed_fastembed(texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    embed_stub(texts)
+}
+
 #[cfg(feature = "fastembed")]
-pub fn embed_batch(texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
-    // TODO: integrate fastembed actual embeddings here
-    texts.iter().map(|_| [0.0; EMBED_DIM]).collect()
+static FASTEMBED_MODEL: OnceLock<Option<fastembed::TextEmbedding>> = OnceLock::new();
+
+/// Lazily load the BGE small model; the cache dir can be set via FASTEMBED_CACHE_DIR.
+#[cfg(feature = "fastembed")]
+fn fastembed_model() -> Option<&'static fastembed::TextEmbedding> {
+    FASTEMBED_MODEL
+        .get_or_init(|| {
+            let mut opts = fastembed::InitOptions::new(fastembed::EmbeddingModel::BGESmallENV15)
+                .with_show_download_progress(false);
+            if let Ok(dir) = std::env::var("FASTEMBED_CACHE_DIR") {
+                opts = opts.with_cache_dir(std::path::PathBuf::from(dir));
+            }
+            match fastembed::TextEmbedding::try_new(opts) {
+                Ok(model) => Some(model),
+                Err(err) => {
+                    tracing::error!(%err, "failed to load fastembed model");
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+#[cfg(feature = "fastembed")]
+fn embed_fastembed(texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    if texts.is_empty() {
+        return Vec::new();
+    }
+    let model = match fastembed_model() {
+        Some(m) => m,
+        None => return embed_stub(texts),
+    };
+    // fastembed returns embeddings in input order
+    match model.embed(texts.to_vec(), None) {
+        Ok(vecs) => vecs.iter().map(|v| to_fixed_dim(v)).collect(),
+        Err(err) => {
+            tracing::error!(%err, "fastembed embedding failed");
+            embed_stub(texts)
+        }
+    }
+}
+
+/// Run blocking work from a possibly-async caller. On a multi-threaded runtime the
+/// worker is handed off via `block_in_place` so other tasks keep being polled.
+fn run_blocking<R>(f: impl FnOnce() -> R) -> R {
+    match tokio::runtime::Handle::try_current() {
+        Ok(h) if h.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// POST texts to an OpenAI-compatible embeddings API in chunks of REMOTE_MAX_BATCH.
+/// Failed chunks fall back to stub vectors so ingestion never aborts.
+fn embed_remote(cfg: &RemoteConfig, texts: &[&str]) -> Vec<[f32; EMBED_DIM]> {
+    // The blocking client must not run on an async runtime thread, so use a scoped thread
+    let joined = run_blocking(|| {
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let client = reqwest::blocking::Client::builder()
+                    .timeout(std::time::Duration::from_secs(30))
+                    .build();
+                let mut out = Vec::with_capacity(texts.len());
+                for chunk in texts.chunks(REMOTE_MAX_BATCH) {
+                    let res = match client.as_ref() {
+                        Ok(c) => remote_request(c, cfg, chunk),
+                        Err(e) => Err(anyhow::anyhow!("failed to create HTTP client: {}", e)),
+                    };
+                    match res {
+                        Ok(vecs) => out.extend(vecs),
+                        Err(err) => {
+                            warn!(%err, "remote embedding failed; falling back to stub");
+                            out.extend(embed_stub(chunk));
+                        }
+                    }
+                }
+                out
+            })
+            .join()
+        })
+    });
+    joined.unwrap_or_else(|_| {
+        warn!("remote embedding thread panicked; falling back to stub");
+        embed_stub(texts)
+    })
+}
+
+fn remote_request(
+    client: &reqwest::blocking::Client,
+    cfg: &RemoteConfig,
+    chunk: &[&str],
+) -> anyhow::Result<Vec<[f32; EMBED_DIM]>> {
+    let base = cfg.url.trim_end_matches('/');
+    let url = if base.ends_with("/embeddings") {
+        base.to_string()
+    } else {
+        format!("{}/v1/embeddings", base)
+    };
+    let body = serde_json::json!({ "model": cfg.model, "input": chunk, "dimensions": EMBED_DIM });
+    let mut req = client.post(&url).json(&body);
+    if let Some(key) = cfg.api_key.as_ref() {
+        req = req.bearer_auth(key);
+    }
+    let resp = req.send()?.error_for_status()?;
+    let val: serde_json::Value = resp.json()?;
+    let data = val
+        .get("data")
+        .and_then(|d| d.as_array())
+        .ok_or_else(|| anyhow::anyhow!("response missing data array"))?;
+    if data.len() != chunk.len() {
+        anyhow::bail!("expected {} embeddings, got {}", chunk.len(), data.len());
+    }
+    // Restore input order using the per-item index when present
+    let mut items: Vec<(usize, [f32; EMBED_DIM])> = Vec::with_capacity(data.len());
+    for (pos, item) in data.iter().enumerate() {
+        let idx = item
+            .get("index")
+            .and_then(|i| i.as_u64())
+            .map(|i| i as usize)
+            .unwrap_or(pos);
+        let emb: Vec<f32> = item
+            .get("embedding")
+            .and_then(|e| e.as_array())
+            .ok_or_else(|| anyhow::anyhow!("item missing embedding"))?
+            .iter()
+            .map(|x| x.as_f64().unwrap_or(0.0) as f32)
+            .collect();
+        if emb.len() != EMBED_DIM {
+            anyhow::bail!("expected {} dimensions, got {}", EMBED_DIM, emb.len());
+        }
+        items.push((idx, to_fixed_dim(&emb)));
+    }
+    items.sort_by_key(|(idx, _)| *idx);
+    Ok(items.into_iter().map(|(_, v)| v).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serve `n` embedding requests, answering each input with [position, 1, 1, ...]
+    /// in reverse order, and hand back the raw request heads and bodies.
+    fn mock_embeddings_server(
+        n: usize,
+    ) -> (
+        String,
+        std::thread::JoinHandle<Vec<(String, serde_json::Value)>>,
+    ) {
+        mock_embeddings_server_with_dim(n, EMBED_DIM)
+    }
+
+    /// Like `mock_embeddings_server`, with `dim`-long embeddings.
+    fn mock_embeddings_server_with_dim(
+        n: usize,
+        dim: usize,
+    ) -> (
+        String,
+        std::thread::JoinHandle<Vec<(String, serde_json::Value)>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for _ in 0..n {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                let mut tmp = [0u8; 4096];
+                let (head, body) = loop {
+                    let read = stream.read(&mut tmp).unwrap();
+                    buf.extend_from_slice(&tmp[..read]);
+                    let text = String::from_utf8_lossy(&buf).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let len = head
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= len {
+                            break (head.to_string(), body.to_string());
+                        }
+                    }
+                };
+                let req: serde_json::Value = serde_json::from_str(&body).unwrap();
+                let count = req["input"].as_array().unwrap().len();
+                let data: Vec<serde_json::Value> = (0..count)
+                    .rev()
+                    .map(|i| {
+                        let mut emb = vec![1.0f32; dim];
+                        emb[0] = i as f32;
+                        serde_json::json!({ "object": "embedding", "index": i, "embedding": emb })
+                    })
+                    .collect();
+                let resp = serde_json::json!({ "object": "list", "data": data }).to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    resp.len(),
+                    resp
+                );
+                seen.push((head, req));
+            }
+            seen
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_remote_backend_request_shape_and_batching() {
+        let (url, handle) = mock_embeddings_server(2);
+        let backend = EmbeddingBackend::Remote(RemoteConfig {
+            url,
+            api_key: Some("secret".to_string()),
+            model: "test-model".to_string(),
+        });
+        let texts: Vec<String> = (0..REMOTE_MAX_BATCH + 1)
+            .map(|i| format!("text {}", i))
+            .collect();
+        let refs: Vec<&str> = texts.iter().map(|s| s.as_str()).collect();
+        let vecs = embed_with(&backend, &refs);
+        let seen = handle.join().unwrap();
+
+        assert_eq!(seen.len(), 2);
+        let (head, body) = &seen[0];
+        assert!(head.starts_with("POST /v1/embeddings "));
+        assert!(head.to_lowercase().contains("authorization: bearer secret"));
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["dimensions"], EMBED_DIM);
+        assert_eq!(body["input"].as_array().unwrap().len(), REMOTE_MAX_BATCH);
+        assert_eq!(body["input"][0], "text 0");
+        assert_eq!(seen[1].1["input"].as_array().unwrap().len(), 1);
+
+        assert_eq!(vecs.len(), REMOTE_MAX_BATCH + 1);
+        assert_eq!(vecs[0].len(), EMBED_DIM);
+        // Order is restored from the response index despite the reversed payload
+        assert_eq!(vecs[0][0], 0.0);
+        assert_eq!(vecs[5][0], 5.0);
+        assert_eq!(vecs[REMOTE_MAX_BATCH][0], 0.0);
+        assert_eq!(vecs[5][EMBED_DIM - 1], 1.0);
+    }
+
+    #[test]
+    fn test_cached_embeddings_skip_model_on_hit() {
+        let dir = std::env::temp_dir().join(format!("emb-cache-{}", uuid::Uuid::new_v4()));
+        let db = sled::open(&dir).unwrap();
+        // Only one request is served; a second model call would hit a closed port
+        let (url, handle) = mock_embeddings_server(1);
+        let backend = EmbeddingBackend::Remote(RemoteConfig {
+            url,
+            api_key: None,
+            model: "test-model".to_string(),
+        });
+        let first = embed_cached_with(&db, &backend, &["alpha", "beta"]);
+        handle.join().unwrap();
+        let second = embed_cached_with(&db, &backend, &["beta", "  alpha "]);
+        assert_eq!(second[0], first[1]);
+        assert_eq!(second[1], first[0]);
+        assert_eq!(second[0][EMBED_DIM - 1], 1.0);
+
+        // A different model must not be served the cached vectors
+        let (url2, handle2) = mock_embeddings_server(1);
+        let other = EmbeddingBackend::Remote(RemoteConfig {
+            url: url2,
+            api_key: None,
+            model: "other-model".to_string(),
+        });
+        embed_cached_with(&db, &other, &["alpha"]);
+        assert_eq!(handle2.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_remote_backend_falls_back_to_stub_on_failure() {
+        // Bind then drop to get a port with nothing listening
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let backend = EmbeddingBackend::Remote(RemoteConfig {
+            url: format!("http://127.0.0.1:{}", port),
+            api_key: None,
+            model: "test-model".to_string(),
+        });
+        let vecs = embed_with(&backend, &["a", "b"]);
+        assert_eq!(vecs.len(), 2);
+        assert!(vecs.iter().all(|v| v.iter().all(|x| *x == 0.0)));
+    }
+
+    #[test]
+    fn test_remote_backend_rejects_wrong_dimensions() {
+        let (url, handle) = mock_embeddings_server_with_dim(1, 1536);
+        let backend = EmbeddingBackend::Remote(RemoteConfig {
+            url,
+            api_key: None,
+            model: "test-model".to_string(),
+        });
+        let vecs = embed_with(&backend, &["a", "b"]);
+        handle.join().unwrap();
+        assert_eq!(vecs.len(), 2);
+        assert!(vecs.iter().all(|v| v.iter().all(|x| *x == 0.0)));
+    }
+
+    #[cfg(feature = "fastembed")]
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let na: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let nb: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+        dot / (na * nb)
+    }
+
+    #[cfg(feature = "fastembed")]
+    #[test]
+    fn test_similar_sentences_score_higher() {
+        let vecs = embed_with(
+            &EmbeddingBackend::FastEmbed,
+            &[
+                "The cat sat on the mat.",
+                "A cat is sitting on a rug.",
+                "Quarterly revenue grew by ten percent.",
+            ],
+        );
+        assert_eq!(vecs.len(), 3);
+        let similar = cosine(&vecs[0], &vecs[1]);
+        let unrelated = cosine(&vecs[0], &vecs[2]);
+        assert!(similar > unrelated);
+    }
 }
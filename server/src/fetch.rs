@@ -0,0 +1,216 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+
+const MAX_REDIRECTS: usize = 5;
+
+/// A fetched resource; `url` is where it was finally served from after redirects.
+pub struct Fetched {
+    pub url: reqwest::Url,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    /// The host resolves to a private, loopback or otherwise internal address
+    Blocked(String),
+    TooLarge(usize),
+    Upstream(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::InvalidUrl(e) => write!(f, "invalid URL: {}", e),
+            FetchError::Blocked(host) => write!(f, "{} resolves to a non-public address", host),
+            FetchError::TooLarge(max) => write!(f, "response exceeds {} bytes", max),
+            FetchError::Upstream(e) => write!(f, "fetch failed: {}", e),
+        }
+    }
+}
+
+/// `URL_FETCH_TIMEOUT_MS`, the limit for each request (default 15s).
+fn fetch_timeout() -> Duration {
+    let ms = std::env::var("URL_FETCH_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|ms| *ms > 0)
+        .unwrap_or(15_000);
+    Duration::from_millis(ms)
+}
+
+/// `URL_FETCH_MAX_BYTES`, the largest response body accepted (default 16 MiB).
+pub fn fetch_max_bytes() -> usize {
+    std::env::var("URL_FETCH_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(16 * 1024 * 1024)
+}
+
+/// `ALLOW_PRIVATE_URLS=1` lets fetches reach private and loopback addresses.
+fn allow_private() -> bool {
+    std::env::var("ALLOW_PRIVATE_URLS")
+        .map(|v| v == "1" || v == "true")
+        .unwrap_or(false)
+}
+
+/// Whether `ip` is reachable on the public internet (not loopback, private, link-local,
+/// CGNAT, unique-local, multicast or unspecified).
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// GET `url` with a timeout and body-size cap. Every hop, redirects included, must
+/// resolve to public addresses (unless `ALLOW_PRIVATE_URLS=1`), and the connection is
+/// pinned to the addresses that were checked.
+pub async fn fetch(url: &str) -> Result<Fetched, FetchError> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl(format!(
+                "unsupported scheme {}",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| FetchError::InvalidUrl("missing host".to_string()))?
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let bare = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((bare, port))
+            .await
+            .map_err(|e| FetchError::Upstream(format!("cannot resolve {}: {}", host, e)))?
+            .collect();
+        if !allow_private() && addrs.iter().any(|a| !is_public_ip(a.ip())) {
+            return Err(FetchError::Blocked(host));
+        }
+        let mut builder = reqwest::Client::builder()
+            .timeout(fetch_timeout())
+            .redirect(reqwest::redirect::Policy::none());
+        if bare.parse::<IpAddr>().is_err() {
+            builder = builder.resolve_to_addrs(bare, &addrs);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| FetchError::Upstream(e.to_string()))?;
+        let mut resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| FetchError::Upstream(e.to_string()))?;
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| FetchError::Upstream("redirect without Location".to_string()))?;
+            url = url
+                .join(location)
+                .map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+            continue;
+        }
+        if !resp.status().is_success() {
+            return Err(FetchError::Upstream(format!(
+                "{} returned {}",
+                url,
+                resp.status()
+            )));
+        }
+        let max = fetch_max_bytes();
+        if resp.content_length().is_some_and(|n| n as usize > max) {
+            return Err(FetchError::TooLarge(max));
+        }
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let mut body = Vec::new();
+        while let Some(chunk) = resp
+            .chunk()
+            .await
+            .map_err(|e| FetchError::Upstream(e.to_string()))?
+        {
+            if body.len() + chunk.len() > max {
+                return Err(FetchError::TooLarge(max));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        return Ok(Fetched {
+            url,
+            content_type,
+            body,
+        });
+    }
+    Err(FetchError::Upstream(format!(
+        "more than {} redirects",
+        MAX_REDIRECTS
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_non_http_schemes() {
+        assert!(matches!(
+            fetch("file:///etc/passwd").await,
+            Err(FetchError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            fetch("not a url").await,
+            Err(FetchError::InvalidUrl(_))
+        ));
+    }
+}
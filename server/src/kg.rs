@@ -1,33 +1,112 @@
 use anyhow::Result;
 use regex::Regex;
+use std::sync::OnceLock;
+use tracing::warn;
 
-pub fn extract_entities(text: &str) -> Vec<String> {
-    // Very simple heuristic: capture Capitalized words (length>=3)
-    let re = Regex::new(r"\b[A-Z][a-zA-Z]{2,}\b").unwrap();
-    let mut entities = Vec::new();
-    for cap in re.captures_iter(text) {
-        let e = cap.get(0).unwrap().as_str().to_string();
-        entities.push(e);
+/// Default entity pattern: capitalized ASCII words of three or more letters.
+const CAPITALIZED_PATTERN: &str = r"\b[A-Z][a-zA-Z]{2,}\b";
+
+/// How `extract_entities` finds entity candidates in text.
+pub enum EntityExtractor {
+    /// Matches of a regex; its first capture group when it has one, else the whole match
+    Pattern(Regex),
+    /// Every word token (any script) of at least `min_len` characters
+    Tokens { min_len: usize },
+}
+
+impl EntityExtractor {
+    /// Resolve from ENTITY_MODE (capitalized | tokens), ENTITY_REGEX (a custom pattern,
+    /// which wins over the mode) and ENTITY_MIN_LEN (default 4, for `tokens`). Anything
+    /// unusable falls back to the capitalized-word pattern.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let min_len = var("ENTITY_MIN_LEN").and_then(|v| v.trim().parse().ok());
+        match Self::parse(
+            var("ENTITY_MODE").as_deref(),
+            var("ENTITY_REGEX").as_deref(),
+            min_len,
+        ) {
+            Ok(extractor) => extractor,
+            Err(err) => {
+                warn!("{}; using the capitalized-word pattern", err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(
+        mode: Option<&str>,
+        pattern: Option<&str>,
+        min_len: Option<usize>,
+    ) -> Result<Self> {
+        if let Some(p) = pattern {
+            let re = Regex::new(p).map_err(|e| anyhow::anyhow!("invalid ENTITY_REGEX: {}", e))?;
+            return Ok(Self::Pattern(re));
+        }
+        match mode.map(|m| m.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("capitalized") => Ok(Self::default()),
+            Some("tokens") => Ok(Self::Tokens {
+                min_len: min_len.unwrap_or(4).max(1),
+            }),
+            Some(other) => Err(anyhow::anyhow!("unknown ENTITY_MODE {}", other)),
+        }
+    }
+
+    /// Distinct entities in `text`, sorted.
+    pub fn extract(&self, text: &str) -> Vec<String> {
+        let mut entities: Vec<String> = match self {
+            Self::Pattern(re) => re
+                .captures_iter(text)
+                .filter_map(|cap| cap.get(1).or_else(|| cap.get(0)))
+                .map(|m| m.as_str().to_string())
+                .filter(|e| !e.is_empty())
+                .collect(),
+            Self::Tokens { min_len } => text
+                .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+                .map(|t| t.trim_matches('-'))
+                .filter(|t| t.chars().count() >= *min_len)
+                .filter(|t| !t.chars().all(char::is_numeric))
+                .map(str::to_string)
+                .collect(),
+        };
+        entities.sort();
+        entities.dedup();
+        entities
+    }
+}
+
+impl Default for EntityExtractor {
+    fn default() -> Self {
+        Self::Pattern(Regex::new(CAPITALIZED_PATTERN).expect("valid default pattern"))
     }
-    entities.sort();
-    entities.dedup();
-    entities
 }
 
+static EXTRACTOR: OnceLock<EntityExtractor> = OnceLock::new();
+
+/// Entities in `text` under the extractor configured by the environment.
+pub fn extract_entities(text: &str) -> Vec<String> {
+    EXTRACTOR
+        .get_or_init(EntityExtractor::from_env)
+        .extract(text)
+}
+
+/// Link `doc_id` to each entity. An entity's count is the number of distinct documents
+/// mentioning it, so relinking an existing `(doc_id, entity)` pair leaves it unchanged.
 pub fn link_entities(db: &sled::Db, doc_id: &str, entities: &[String]) -> Result<()> {
     let ents = db.open_tree("kg_entities")?;
     let links = db.open_tree("kg_links")?;
     for e in entities {
-        // Increment entity count
+        // Create link doc_id -> entity, counting it only the first time
+        let key = format!("{}::{}", doc_id, e);
+        if links.insert(key.as_bytes(), &[])?.is_some() {
+            continue;
+        }
         let cnt = ents
             .get(e.as_bytes())?
             .map(|v| u64::from_le_bytes(v.as_ref().try_into().unwrap_or([0u8; 8])))
             .unwrap_or(0);
         let newv = (cnt + 1).to_le_bytes();
         ents.insert(e.as_bytes(), &newv)?;
-        // Create link doc_id -> entity
-        let key = format!("{}::{}", doc_id, e);
-        let _ = links.insert(key.as_bytes(), &[]);
     }
     Ok(())
 }
@@ -37,12 +116,63 @@ pub fn ensure_entity_node(db: &sled::Db, name: &str, created_at: i64) -> Result<
     let nodes = db.open_tree("kg_nodes")?;
     let key = format!("Entity::{}", name);
     if nodes.get(key.as_bytes())?.is_none() {
-        let val = serde_json::json!({ "type": "Entity", "label": name, "created_at": created_at });
+        let val = serde_json::json!({
+            "type": "Entity",
+            "label": name,
+            "created_at": created_at,
+            "first_seen": created_at,
+            "last_seen": created_at,
+            "mention_count": 0,
+        });
         nodes.insert(key.as_bytes(), serde_json::to_vec(&val)?)?;
     }
     Ok(())
 }
 
+/// `(first_seen, last_seen, mention_count)` of an entity node; nodes written before
+/// activity tracking fall back to `created_at` and no mentions.
+pub fn entity_activity(node: &serde_json::Value) -> (Option<i64>, Option<i64>, u64) {
+    let created = node.get("created_at").and_then(|v| v.as_i64());
+    let first = node.get("first_seen").and_then(|v| v.as_i64()).or(created);
+    let last = node.get("last_seen").and_then(|v| v.as_i64()).or(first);
+    let count = node
+        .get("mention_count")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    (first, last, count)
+}
+
+/// Fold `mentions` mentions spanning `first..=last` into the entity's node, creating it
+/// if needed: the earliest `first_seen` and latest `last_seen` win and counts add up.
+fn merge_entity_activity(
+    db: &sled::Db,
+    name: &str,
+    first: i64,
+    last: i64,
+    mentions: u64,
+) -> Result<()> {
+    ensure_entity_node(db, name, first)?;
+    let nodes = db.open_tree("kg_nodes")?;
+    let key = format!("Entity::{}", name);
+    let Some(mut node) = nodes
+        .get(key.as_bytes())?
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+    else {
+        return Ok(());
+    };
+    let (cur_first, cur_last, count) = entity_activity(&node);
+    node["first_seen"] = serde_json::json!(cur_first.map_or(first, |f| f.min(first)));
+    node["last_seen"] = serde_json::json!(cur_last.map_or(last, |l| l.max(last)));
+    node["mention_count"] = serde_json::json!(count + mentions);
+    nodes.insert(key.as_bytes(), serde_json::to_vec(&node)?)?;
+    Ok(())
+}
+
+/// Record that a document or memory mentioned `name` at `at`.
+pub fn record_entity_mention(db: &sled::Db, name: &str, at: i64) -> Result<()> {
+    merge_entity_activity(db, name, at, at, 1)
+}
+
 pub fn ensure_document_node(db: &sled::Db, doc_id: &str, created_at: i64) -> Result<()> {
     let nodes = db.open_tree("kg_nodes")?;
     let key = format!("Document::{}", doc_id);
@@ -76,6 +206,206 @@ pub fn ensure_episode_node(
     Ok(())
 }
 
+/// Escape `%`, `:` and `>` in one part of a composite key, so `::` and `->` in a key
+/// only ever come from its layout. UUIDs and plain names are unchanged.
+pub fn escape_key_part(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str("%25"),
+            ':' => out.push_str("%3A"),
+            '>' => out.push_str("%3E"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Inverse of `escape_key_part`; other `%` sequences are kept as they are.
+pub fn unescape_key_part(s: &str) -> String {
+    s.replace("%3A", ":")
+        .replace("%3E", ">")
+        .replace("%25", "%")
+}
+
+/// Key form of a node id (`Type::name`): the type prefix is kept, the name escaped.
+pub fn node_key(node: &str) -> String {
+    match node.split_once("::") {
+        Some((kind, name)) => format!("{}::{}", kind, escape_key_part(name)),
+        None => escape_key_part(node),
+    }
+}
+
+fn node_from_key(key: &str) -> String {
+    match key.split_once("::") {
+        Some((kind, name)) => format!("{}::{}", kind, unescape_key_part(name)),
+        None => unescape_key_part(key),
+    }
+}
+
+/// `kg_edges` key for an edge: `src->dst::relation`, each part escaped.
+pub fn edge_key(src: &str, dst: &str, relation: &str) -> String {
+    format!(
+        "{}->{}::{}",
+        node_key(src),
+        node_key(dst),
+        escape_key_part(relation)
+    )
+}
+
+/// Split an `edge_key` back into its `(src, dst, relation)`.
+pub fn parse_edge_key(key: &str) -> Option<(String, String, String)> {
+    let (src, rest) = key.split_once("->")?;
+    let (dst, relation) = rest.rsplit_once("::")?;
+    Some((
+        node_from_key(src),
+        node_from_key(dst),
+        unescape_key_part(relation),
+    ))
+}
+
+/// Prefix shared by the `kg_edges` keys of edges leaving `node`, and the `kg_edges_rev`
+/// keys of edges entering it.
+pub fn edges_prefix(node: &str) -> String {
+    format!("{}->", node_key(node))
+}
+
+/// Write an edge to `kg_edges` (`src->dst::relation`) and its mirror to `kg_edges_rev`
+/// (`dst->src::relation`, value = forward key) so incoming edges can be found by prefix.
+fn put_edge(
+    db: &sled::Db,
+    src: &str,
+    dst: &str,
+    relation: &str,
+    val: &serde_json::Value,
+) -> Result<()> {
+    let edges = db.open_tree("kg_edges")?;
+    let rev = db.open_tree("kg_edges_rev")?;
+    let key = edge_key(src, dst, relation);
+    let rev_key = edge_key(dst, src, relation);
+    edges.insert(key.as_bytes(), serde_json::to_vec(val)?)?;
+    rev.insert(rev_key.as_bytes(), key.as_bytes())?;
+    Ok(())
+}
+
+fn reverse_key(edge: &serde_json::Value) -> String {
+    let field = |f: &str| edge.get(f).and_then(|x| x.as_str()).unwrap_or("");
+    edge_key(field("dst"), field("src"), field("relation"))
+}
+
+/// Remove a forward edge by key along with its reverse entry.
+pub fn remove_edge_by_key(db: &sled::Db, key: &[u8]) -> Result<bool> {
+    let edges = db.open_tree("kg_edges")?;
+    let rev = db.open_tree("kg_edges_rev")?;
+    let Some(v) = edges.remove(key)? else {
+        return Ok(false);
+    };
+    if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
+        rev.remove(reverse_key(&edge).as_bytes())?;
+    }
+    Ok(true)
+}
+
+/// Edges pointing to `dst`, resolved through `kg_edges_rev`.
+pub fn incoming_edges(db: &sled::Db, dst: &str) -> Result<Vec<(Vec<u8>, serde_json::Value)>> {
+    let edges = db.open_tree("kg_edges")?;
+    let rev = db.open_tree("kg_edges_rev")?;
+    let prefix = edges_prefix(dst);
+    let mut out = Vec::new();
+    for kv in rev.scan_prefix(prefix.as_bytes()) {
+        let (_, fwd) = kv?;
+        if let Some(v) = edges.get(&fwd)? {
+            if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
+                out.push((fwd.to_vec(), edge));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Remove every edge leaving or entering `node` from both edge trees.
+pub fn remove_edges_for_node(db: &sled::Db, node: &str) -> Result<u64> {
+    let edges = db.open_tree("kg_edges")?;
+    let prefix = edges_prefix(node);
+    let mut keys: Vec<Vec<u8>> = edges
+        .scan_prefix(prefix.as_bytes())
+        .filter_map(|kv| kv.ok().map(|(k, _)| k.to_vec()))
+        .collect();
+    keys.extend(incoming_edges(db, node)?.into_iter().map(|(k, _)| k));
+    let mut removed = 0u64;
+    for k in keys {
+        if remove_edge_by_key(db, &k)? {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Move every edge of `from` onto `to`, keeping relation and attributes.
+/// Edges that would become self-loops on `to` are dropped.
+pub fn repoint_edges(db: &sled::Db, from: &str, to: &str) -> Result<u64> {
+    let edges = db.open_tree("kg_edges")?;
+    let prefix = edges_prefix(from);
+    let mut found: Vec<(Vec<u8>, serde_json::Value)> = Vec::new();
+    for kv in edges.scan_prefix(prefix.as_bytes()) {
+        let (k, v) = kv?;
+        if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
+            found.push((k.to_vec(), edge));
+        }
+    }
+    found.extend(incoming_edges(db, from)?);
+    let mut moved = 0u64;
+    for (key, mut edge) in found {
+        if !remove_edge_by_key(db, &key)? {
+            continue;
+        }
+        for end in ["src", "dst"] {
+            if edge.get(end).and_then(|x| x.as_str()) == Some(from) {
+                edge[end] = serde_json::json!(to);
+            }
+        }
+        let field = |f: &str| {
+            edge.get(f)
+                .and_then(|x| x.as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        let (src, dst, relation) = (field("src"), field("dst"), field("relation"));
+        if src == dst {
+            continue;
+        }
+        // Landing on an edge `to` already has: the weights add up
+        let target = edges.get(edge_key(&src, &dst, &relation).as_bytes())?;
+        if let Some(other) =
+            target.and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+        {
+            edge["weight"] = serde_json::json!(edge_weight(&edge) + edge_weight(&other));
+        }
+        put_edge(db, &src, &dst, &relation, &edge)?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Backfill `kg_edges_rev` for graphs written before the reverse index existed.
+pub fn ensure_reverse_index(db: &sled::Db) -> Result<u64> {
+    let edges = db.open_tree("kg_edges")?;
+    let rev = db.open_tree("kg_edges_rev")?;
+    if !rev.is_empty() || edges.is_empty() {
+        return Ok(0);
+    }
+    let mut written = 0u64;
+    for kv in edges.iter() {
+        let (k, v) = kv?;
+        let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        rev.insert(reverse_key(&edge).as_bytes(), k)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
 pub fn add_edge(
     db: &sled::Db,
     entity: &str,
@@ -83,11 +413,10 @@ pub fn add_edge(
     relation: &str,
     created_at: i64,
 ) -> Result<()> {
-    let edges = db.open_tree("kg_edges")?;
-    let key = format!("{}->{}::{}", entity, doc_id, relation);
-    let val = serde_json::json!({ "src": entity, "dst": doc_id, "relation": relation, "created_at": created_at });
-    edges.insert(key.as_bytes(), serde_json::to_vec(&val)?)?;
-    Ok(())
+    let src = format!("Entity::{}", entity);
+    let dst = format!("Document::{}", doc_id);
+    let val = serde_json::json!({ "src": src, "dst": dst, "relation": relation, "created_at": created_at });
+    put_edge(db, &src, &dst, relation, &val)
 }
 
 pub fn ensure_memory_node(db: &sled::Db, mem_id: &str, created_at: i64) -> Result<()> {
@@ -100,6 +429,13 @@ pub fn ensure_memory_node(db: &sled::Db, mem_id: &str, created_at: i64) -> Resul
     Ok(())
 }
 
+/// Stored `weight` of an edge; edges written before weights existed count once.
+pub fn edge_weight(edge: &serde_json::Value) -> u64 {
+    edge.get("weight").and_then(|w| w.as_u64()).unwrap_or(1)
+}
+
+/// Assert `src -relation-> dst`. Asserting an existing edge again bumps its `weight`
+/// and `last_seen`, keeping its other fields.
 pub fn add_edge_generic(
     db: &sled::Db,
     src: &str,
@@ -107,11 +443,26 @@ pub fn add_edge_generic(
     relation: &str,
     created_at: i64,
 ) -> Result<()> {
-    let edges = db.open_tree("kg_edges")?;
-    let key = format!("{}->{}::{}", src, dst, relation);
-    let val = serde_json::json!({ "src": src, "dst": dst, "relation": relation, "created_at": created_at });
-    edges.insert(key.as_bytes(), serde_json::to_vec(&val)?)?;
-    Ok(())
+    let existing = db
+        .open_tree("kg_edges")?
+        .get(edge_key(src, dst, relation).as_bytes())?
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok());
+    let val = match existing {
+        Some(mut edge) => {
+            edge["weight"] = serde_json::json!(edge_weight(&edge) + 1);
+            edge["last_seen"] = serde_json::json!(created_at);
+            edge
+        }
+        None => serde_json::json!({
+            "src": src,
+            "dst": dst,
+            "relation": relation,
+            "created_at": created_at,
+            "weight": 1,
+            "last_seen": created_at,
+        }),
+    };
+    put_edge(db, src, dst, relation, &val)
 }
 
 /// Link two documents as RELATED based on shared entities and Jaccard score.
@@ -128,24 +479,63 @@ pub fn relate_documents_by_entities(
     }
     let a: std::collections::HashSet<String> = a_ents.into_iter().collect();
     let b: std::collections::HashSet<String> = b_ents.into_iter().collect();
-    let inter = a.intersection(&b).count() as f32;
-    let uni = a.union(&b).count() as f32;
-    if uni == 0.0 {
-        return Ok(None);
-    }
-    let jacc = inter / uni;
+    let jacc = jaccard(&a, &b);
     if jacc > 0.0 {
         let src = format!("Document::{}", doc_a);
         let dst = format!("Document::{}", doc_b);
-        let edges = db.open_tree("kg_edges")?;
-        let key = format!("{}->{}::RELATED", src, dst);
         let val = serde_json::json!({ "src": src, "dst": dst, "relation": "RELATED", "score": jacc, "created_at": created_at });
-        edges.insert(key.as_bytes(), serde_json::to_vec(&val)?)?;
+        put_edge(db, &src, &dst, "RELATED", &val)?;
         return Ok(Some(jacc));
     }
     Ok(None)
 }
 
+/// Jaccard similarity of two entity sets; 0 when both are empty.
+pub fn jaccard(
+    a: &std::collections::HashSet<String>,
+    b: &std::collections::HashSet<String>,
+) -> f32 {
+    let uni = a.union(b).count() as f32;
+    if uni == 0.0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / uni
+}
+
+/// Documents sharing at least one of `entities`, scored by the Jaccard similarity of
+/// their entity sets, best first (ties by id).
+pub fn docs_by_entity_overlap(
+    db: &sled::Db,
+    entities: &std::collections::HashSet<String>,
+) -> Result<Vec<(String, f32)>> {
+    if entities.is_empty() {
+        return Ok(Vec::new());
+    }
+    let links = db.open_tree("kg_links")?;
+    let mut doc_ents: std::collections::HashMap<String, std::collections::HashSet<String>> =
+        std::collections::HashMap::new();
+    for kv in links.iter() {
+        let (k, _) = kv?;
+        let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+        if let Some((doc_id, ent)) = key.split_once("::") {
+            doc_ents
+                .entry(doc_id.to_string())
+                .or_default()
+                .insert(ent.to_string());
+        }
+    }
+    let mut scored: Vec<(String, f32)> = doc_ents
+        .into_iter()
+        .filter(|(_, ents)| !ents.is_disjoint(entities))
+        .map(|(doc_id, ents)| {
+            let score = jaccard(entities, &ents);
+            (doc_id, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(scored)
+}
+
 pub fn list_entities(db: &sled::Db, limit: usize) -> Result<Vec<(String, u64)>> {
     let ents = db.open_tree("kg_entities")?;
     let mut pairs: Vec<(String, u64)> = Vec::new();
@@ -166,8 +556,9 @@ pub fn docs_for_entity(db: &sled::Db, entity: &str) -> Result<Vec<String>> {
     for kv in links.iter() {
         let (k, _) = kv?;
         let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-        if key.ends_with(&format!("::{}", entity)) {
-            if let Some((doc_id, _)) = key.split_once("::") {
+        // Doc ids never contain `::`, so everything after the first one is the entity
+        if let Some((doc_id, ent)) = key.split_once("::") {
+            if ent == entity {
                 docs.push(doc_id.to_string());
             }
         }
@@ -199,6 +590,7 @@ pub fn get_entity_details(db: &sled::Db, entity: &str) -> Result<serde_json::Val
         .get(key.as_bytes())?
         .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
         .unwrap_or_else(|| serde_json::json!({"type": "Entity", "label": entity}));
+    let (first_seen, last_seen, mention_count) = entity_activity(&node_data);
 
     // Get documents mentioning this entity
     let docs = docs_for_entity(db, entity).unwrap_or_default();
@@ -206,7 +598,7 @@ pub fn get_entity_details(db: &sled::Db, entity: &str) -> Result<serde_json::Val
     // Get edges from this entity
     let edges = db.open_tree("kg_edges")?;
     let mut relations: Vec<serde_json::Value> = Vec::new();
-    let src_prefix = format!("Entity::{}->", entity);
+    let src_prefix = edges_prefix(&key);
     for kv in edges.scan_prefix(src_prefix.as_bytes()) {
         if let Ok((_, v)) = kv {
             if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
@@ -220,57 +612,111 @@ pub fn get_entity_details(db: &sled::Db, entity: &str) -> Result<serde_json::Val
         "node": node_data,
         "docs": docs,
         "relations": relations,
-        "docCount": docs.len()
+        "docCount": docs.len(),
+        "firstSeen": first_seen,
+        "lastSeen": last_seen,
+        "mentionCount": mention_count,
     }))
 }
 
-/// Search nodes by type and optional label pattern
+/// Edit distance between `a` and `b` in characters, or `None` once it exceeds `max`.
+fn levenshtein_within(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = sub.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        // Every later row is at least this row's minimum
+        if cur.iter().min().is_some_and(|&m| m > max) {
+            return None;
+        }
+        prev = cur;
+    }
+    Some(prev[b.len()]).filter(|&d| d <= max)
+}
+
+/// How well `pattern` (lowercased) matches `text`, best first: exact, prefix, substring,
+/// then within `max_distance` edits when fuzzy matching is on.
+fn match_quality(text: &str, pattern: &str, max_distance: Option<usize>) -> Option<(u8, usize)> {
+    let text = text.to_lowercase();
+    if text == pattern {
+        Some((0, 0))
+    } else if text.starts_with(pattern) {
+        Some((1, 0))
+    } else if text.contains(pattern) {
+        Some((2, 0))
+    } else {
+        max_distance
+            .and_then(|max| levenshtein_within(&text, pattern, max))
+            .map(|d| (3, d))
+    }
+}
+
+/// Search nodes by type and optional pattern, matched case-insensitively against the
+/// node's key, name, label and id. With `max_distance`, names within that many edits
+/// also match. Results are ranked by match quality (then key) before the `limit`.
 pub fn search_nodes(
     db: &sled::Db,
     node_type: Option<&str>,
     pattern: Option<&str>,
+    max_distance: Option<usize>,
     limit: usize,
 ) -> Result<Vec<serde_json::Value>> {
     let nodes = db.open_tree("kg_nodes")?;
-    let mut results: Vec<serde_json::Value> = Vec::new();
+    let pattern = pattern.map(|p| p.to_lowercase());
+    let mut ranked: Vec<((u8, usize), String, serde_json::Value)> = Vec::new();
 
     for kv in nodes.iter() {
-        if results.len() >= limit {
-            break;
-        }
         let (k, v) = kv?;
         let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-        if let Ok(node) = serde_json::from_slice::<serde_json::Value>(&v) {
-            // Filter by type if specified
-            if let Some(nt) = node_type {
-                if node.get("type").and_then(|t| t.as_str()) != Some(nt) {
-                    continue;
-                }
+        let Ok(mut node) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        if let Some(nt) = node_type {
+            if node.get("type").and_then(|t| t.as_str()) != Some(nt) {
+                continue;
             }
-            // Filter by pattern if specified
-            if let Some(pat) = pattern {
-                let matches = key.to_lowercase().contains(&pat.to_lowercase())
-                    || node
-                        .get("label")
-                        .and_then(|l| l.as_str())
-                        .map(|s| s.to_lowercase().contains(&pat.to_lowercase()))
-                        .unwrap_or(false)
-                    || node
-                        .get("id")
-                        .and_then(|l| l.as_str())
-                        .map(|s| s.to_lowercase().contains(&pat.to_lowercase()))
-                        .unwrap_or(false);
-                if !matches {
-                    continue;
+        }
+        let quality = match pattern.as_deref() {
+            Some(pat) => {
+                let name = key.split_once("::").map(|(_, n)| n).unwrap_or(&key);
+                let candidates = [
+                    Some(name),
+                    node.get("label").and_then(|l| l.as_str()),
+                    node.get("id").and_then(|l| l.as_str()),
+                ];
+                let best = candidates
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|c| match_quality(c, pat, max_distance))
+                    .chain(match_quality(&key, pat, None))
+                    .min();
+                match best {
+                    Some(q) => q,
+                    None => continue,
                 }
             }
-            let mut result = node.clone();
-            result["nodeKey"] = serde_json::json!(key);
-            results.push(result);
+            None => (0, 0),
+        };
+        node["nodeKey"] = serde_json::json!(key);
+        if quality.0 == 3 {
+            node["distance"] = serde_json::json!(quality.1);
         }
+        ranked.push((quality, key, node));
     }
-
-    Ok(results)
+    ranked.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    Ok(ranked
+        .into_iter()
+        .take(limit)
+        .map(|(_, _, node)| node)
+        .collect())
 }
 
 /// Add tags to an entity node
@@ -393,10 +839,278 @@ pub fn get_entities_by_tag(db: &sled::Db, tag: &str) -> Result<Vec<String>> {
     Ok(entities)
 }
 
+/// The canonical entity `entity` was merged into, or `entity` itself.
+pub fn resolve_alias(db: &sled::Db, entity: &str) -> String {
+    db.open_tree("kg_aliases")
+        .ok()
+        .and_then(|t| t.get(entity.as_bytes()).ok().flatten())
+        .map(|v| String::from_utf8_lossy(&v).to_string())
+        .unwrap_or_else(|| entity.to_string())
+}
+
+/// Like `resolve_alias`, but matching the alias without regard to ASCII case; `None`
+/// when `name` is no alias.
+pub fn resolve_alias_ignoring_case(db: &sled::Db, name: &str) -> Option<String> {
+    let aliases = db.open_tree("kg_aliases").ok()?;
+    if let Ok(Some(v)) = aliases.get(name.as_bytes()) {
+        return Some(String::from_utf8_lossy(&v).to_string());
+    }
+    aliases.iter().flatten().find_map(|(k, v)| {
+        String::from_utf8_lossy(&k)
+            .eq_ignore_ascii_case(name)
+            .then(|| String::from_utf8_lossy(&v).to_string())
+    })
+}
+
+/// Map extracted entities onto their canonical names, deduped.
+pub fn canonicalize_entities(db: &sled::Db, entities: Vec<String>) -> Vec<String> {
+    let mut out: Vec<String> = entities
+        .into_iter()
+        .map(|e| resolve_alias(db, &e))
+        .collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+pub struct MergeOutcome {
+    /// The canonical entity, after following existing aliases
+    pub into: String,
+    pub links_moved: u64,
+    pub edges_moved: u64,
+    /// Documents mentioning the merged entity
+    pub mentions: u64,
+}
+
+/// Fold entity `from` into `into`: document links, mention counts, edges and tags move
+/// over, `from` is deleted and recorded in `kg_aliases` so later extractions resolve to
+/// `into`. Running it again changes nothing.
+pub fn merge_entity(
+    db: &sled::Db,
+    from: &str,
+    into: &str,
+    created_at: i64,
+) -> Result<MergeOutcome> {
+    let into = resolve_alias(db, into);
+    if into == from {
+        anyhow::bail!("cannot merge an entity into itself");
+    }
+    let aliases = db.open_tree("kg_aliases")?;
+    // Aliases of `from` now point at `into`, so chains never form
+    for kv in aliases.iter() {
+        let (k, v) = kv?;
+        if v.as_ref() == from.as_bytes() {
+            aliases.insert(k, into.as_bytes())?;
+        }
+    }
+    aliases.insert(from.as_bytes(), into.as_bytes())?;
+
+    let links = db.open_tree("kg_links")?;
+    let mut links_moved = 0u64;
+    let mut overlap = 0u64;
+    let from_links: Vec<(sled::IVec, String)> = links
+        .iter()
+        .flatten()
+        .filter_map(|(k, _)| {
+            let key = String::from_utf8_lossy(&k).to_string();
+            let (doc_id, ent) = key.split_once("::")?;
+            (ent == from).then(|| (k.clone(), doc_id.to_string()))
+        })
+        .collect();
+    for (k, doc_id) in from_links {
+        let new_key = format!("{}::{}", doc_id, into);
+        if links.insert(new_key.as_bytes(), &[])?.is_some() {
+            overlap += 1;
+        }
+        links.remove(k)?;
+        links_moved += 1;
+    }
+    // A document mentioning both names is counted once
+    let ents = db.open_tree("kg_entities")?;
+    let count = |e: &str| -> Result<u64> {
+        Ok(ents
+            .get(e.as_bytes())?
+            .map(|v| u64::from_le_bytes(v.as_ref().try_into().unwrap_or([0u8; 8])))
+            .unwrap_or(0))
+    };
+    let mentions = (count(&into)? + count(from)?).saturating_sub(overlap);
+    if links_moved > 0 || ents.contains_key(from.as_bytes())? {
+        ents.insert(into.as_bytes(), &mentions.to_le_bytes())?;
+        ents.remove(from.as_bytes())?;
+    }
+
+    let from_node = format!("Entity::{}", from);
+    let into_node = format!("Entity::{}", into);
+    let nodes = db.open_tree("kg_nodes")?;
+    let from_data = nodes
+        .remove(from_node.as_bytes())?
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok());
+    let tags: Vec<String> = from_data
+        .as_ref()
+        .and_then(|n| n.get("tags").and_then(|t| t.as_array()).cloned())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|t| t.as_str().map(|s| s.to_string()))
+        .collect();
+    ensure_entity_node(db, &into, created_at)?;
+    // The merged entity's activity spans both names
+    if let Some(data) = &from_data {
+        if let (Some(first), Some(last), count) = entity_activity(data) {
+            merge_entity_activity(db, &into, first, last, count)?;
+        }
+    }
+    if !tags.is_empty() {
+        tag_entity(db, &into, &tags)?;
+    }
+    let edges_moved = repoint_edges(db, &from_node, &into_node)?;
+    Ok(MergeOutcome {
+        mentions: count(&into)?,
+        into,
+        links_moved,
+        edges_moved,
+    })
+}
+
+/// Keys of edges whose `src` or `dst` has no `kg_nodes` entry (or whose value is unreadable).
+pub fn dangling_edges(db: &sled::Db) -> Result<Vec<Vec<u8>>> {
+    let nodes = db.open_tree("kg_nodes")?;
+    let edges = db.open_tree("kg_edges")?;
+    let mut out = Vec::new();
+    for kv in edges.iter() {
+        let (k, v) = kv?;
+        let val: serde_json::Value = serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
+        let src = val.get("src").and_then(|c| c.as_str()).unwrap_or("");
+        let dst = val.get("dst").and_then(|c| c.as_str()).unwrap_or("");
+        if !nodes.contains_key(src.as_bytes())? || !nodes.contains_key(dst.as_bytes())? {
+            out.push(k.to_vec());
+        }
+    }
+    Ok(out)
+}
+
+pub struct RepairReport {
+    pub dangling_edges: u64,
+    /// `kg_links` entries for documents that no longer exist
+    pub stale_links: u64,
+    /// `kg_entities` counts that disagreed with the remaining links
+    pub recounted_entities: u64,
+}
+
+/// Find, and with `fix` remove, dangling edges and links to documents `doc_exists`
+/// rejects, then reconcile `kg_entities` counts with the links that remain.
+pub fn repair(db: &sled::Db, fix: bool, doc_exists: impl Fn(&str) -> bool) -> Result<RepairReport> {
+    let dangling = dangling_edges(db)?;
+    if fix {
+        for k in &dangling {
+            remove_edge_by_key(db, k)?;
+        }
+    }
+
+    let links = db.open_tree("kg_links")?;
+    let mut counts: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    let mut stale_links = 0u64;
+    let mut doc_ok: std::collections::HashMap<String, bool> = std::collections::HashMap::new();
+    let entries: Vec<sled::IVec> = links.iter().keys().collect::<sled::Result<_>>()?;
+    for k in entries {
+        let key = String::from_utf8_lossy(&k).to_string();
+        let Some((doc_id, ent)) = key.split_once("::") else {
+            continue;
+        };
+        let exists = *doc_ok
+            .entry(doc_id.to_string())
+            .or_insert_with(|| doc_exists(doc_id));
+        if !exists {
+            stale_links += 1;
+            if fix {
+                links.remove(&k)?;
+            }
+            continue;
+        }
+        *counts.entry(ent.to_string()).or_insert(0) += 1;
+    }
+
+    let ents = db.open_tree("kg_entities")?;
+    let mut recounted = 0u64;
+    let stored: Vec<(sled::IVec, sled::IVec)> = ents.iter().collect::<sled::Result<_>>()?;
+    for (k, v) in &stored {
+        let name = String::from_utf8_lossy(k).to_string();
+        let have = u64::from_le_bytes(v.as_ref().try_into().unwrap_or([0u8; 8]));
+        match counts.remove(&name) {
+            Some(want) if want == have => {}
+            Some(want) => {
+                recounted += 1;
+                if fix {
+                    ents.insert(k, &want.to_le_bytes())?;
+                }
+            }
+            None => {
+                recounted += 1;
+                if fix {
+                    ents.remove(k)?;
+                }
+            }
+        }
+    }
+    // Linked entities missing from the counts tree
+    for (name, want) in counts {
+        recounted += 1;
+        if fix {
+            ents.insert(name.as_bytes(), &want.to_le_bytes())?;
+        }
+    }
+    Ok(RepairReport {
+        dangling_edges: dangling.len() as u64,
+        stale_links,
+        recounted_entities: recounted,
+    })
+}
+
+pub struct GraphStats {
+    /// Node counts by type (`Entity`, `Document`, `Memory`, `Episode`, ...)
+    pub nodes: std::collections::BTreeMap<String, u64>,
+    /// Edge counts by relation
+    pub edges: std::collections::BTreeMap<String, u64>,
+    /// `(node, degree)` of the best-connected nodes, highest degree first
+    pub top_nodes: Vec<(String, u64)>,
+}
+
+/// Node and edge counts by type plus the `top` best-connected nodes, from one pass over
+/// `kg_nodes` and one over `kg_edges`. Degree counts edges in both directions.
+pub fn stats(db: &sled::Db, top: usize) -> Result<GraphStats> {
+    let mut nodes = std::collections::BTreeMap::new();
+    for kv in db.open_tree("kg_nodes")?.iter() {
+        let (k, v) = kv?;
+        let key = String::from_utf8_lossy(&k);
+        let kind = serde_json::from_slice::<serde_json::Value>(&v)
+            .ok()
+            .and_then(|n| n.get("type").and_then(|t| t.as_str()).map(str::to_string))
+            .unwrap_or_else(|| key.split("::").next().unwrap_or_default().to_string());
+        *nodes.entry(kind).or_insert(0) += 1;
+    }
+    let mut edges = std::collections::BTreeMap::new();
+    let mut degree: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    for kv in db.open_tree("kg_edges")?.iter() {
+        let (k, _) = kv?;
+        let Some((src, dst, relation)) = parse_edge_key(&String::from_utf8_lossy(&k)) else {
+            continue;
+        };
+        *edges.entry(relation).or_insert(0) += 1;
+        *degree.entry(src).or_insert(0) += 1;
+        *degree.entry(dst).or_insert(0) += 1;
+    }
+    let mut top_nodes: Vec<(String, u64)> = degree.into_iter().collect();
+    top_nodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    top_nodes.truncate(top);
+    Ok(GraphStats {
+        nodes,
+        edges,
+        top_nodes,
+    })
+}
+
 /// Delete an entity node and its edges
 pub fn delete_entity(db: &sled::Db, entity: &str) -> Result<u64> {
     let nodes = db.open_tree("kg_nodes")?;
-    let edges = db.open_tree("kg_edges")?;
     let ents = db.open_tree("kg_entities")?;
     let links = db.open_tree("kg_links")?;
 
@@ -411,34 +1125,17 @@ pub fn delete_entity(db: &sled::Db, entity: &str) -> Result<u64> {
     // Remove from entities count tree
     let _ = ents.remove(entity.as_bytes());
 
-    // Remove edges involving this entity
-    let src_prefix = format!("Entity::{}->", entity);
-    let to_remove_src: Vec<_> = edges
-        .scan_prefix(src_prefix.as_bytes())
-        .filter_map(|kv| kv.ok().map(|(k, _)| k))
-        .collect();
-    for k in to_remove_src {
-        let _ = edges.remove(k);
-        removed += 1;
-    }
-
-    // Find and remove edges pointing TO this entity
-    for kv in edges.iter() {
-        if let Ok((k, v)) = kv {
-            if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
-                if edge.get("dst").and_then(|d| d.as_str()) == Some(&key) {
-                    let _ = edges.remove(k);
-                    removed += 1;
-                }
-            }
-        }
-    }
+    // Remove edges from and to this entity
+    removed += remove_edges_for_node(db, &key)?;
 
     // Remove links
     for kv in links.iter() {
         if let Ok((k, _)) = kv {
             let key_str = String::from_utf8(k.to_vec()).unwrap_or_default();
-            if key_str.ends_with(&format!("::{}", entity)) {
+            if key_str
+                .split_once("::")
+                .is_some_and(|(_, ent)| ent == entity)
+            {
                 let _ = links.remove(k);
             }
         }
@@ -449,7 +1146,475 @@ pub fn delete_entity(db: &sled::Db, entity: &str) -> Result<u64> {
 
 /// Delete a relation/edge
 pub fn delete_relation(db: &sled::Db, src: &str, dst: &str, relation: &str) -> Result<bool> {
+    remove_edge_by_key(db, edge_key(src, dst, relation).as_bytes())
+}
+
+/// Upper bound for traversal depth regardless of what callers ask for.
+pub const MAX_TRAVERSAL_DEPTH: usize = 6;
+
+/// Edges touching `node` in either direction, paired with the node on the other end.
+fn adjacent(db: &sled::Db, node: &str) -> Result<Vec<(String, serde_json::Value)>> {
     let edges = db.open_tree("kg_edges")?;
-    let key = format!("{}->{}::{}", src, dst, relation);
-    Ok(edges.remove(key.as_bytes())?.is_some())
+    let prefix = edges_prefix(node);
+    let mut out = Vec::new();
+    for kv in edges.scan_prefix(prefix.as_bytes()) {
+        let (_, v) = kv?;
+        if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
+            if let Some(dst) = edge.get("dst").and_then(|d| d.as_str()) {
+                out.push((dst.to_string(), edge.clone()));
+            }
+        }
+    }
+    for (_, edge) in incoming_edges(db, node)? {
+        if let Some(src) = edge.get("src").and_then(|s| s.as_str()) {
+            out.push((src.to_string(), edge.clone()));
+        }
+    }
+    Ok(out)
+}
+
+/// Breadth-first search for the fewest-hop path from `src` to `dst`, following edges
+/// in both directions. Returns the edges along the path, or `None` beyond `max_depth`.
+pub fn shortest_path(
+    db: &sled::Db,
+    src: &str,
+    dst: &str,
+    max_depth: usize,
+) -> Result<Option<Vec<serde_json::Value>>> {
+    if src == dst {
+        return Ok(Some(Vec::new()));
+    }
+    let max_depth = max_depth.min(MAX_TRAVERSAL_DEPTH);
+    // node -> (previous node, edge used to reach it)
+    let mut parent: std::collections::HashMap<String, (String, serde_json::Value)> =
+        std::collections::HashMap::new();
+    let mut frontier = vec![src.to_string()];
+    for _ in 0..max_depth {
+        let mut next = Vec::new();
+        for node in &frontier {
+            for (other, edge) in adjacent(db, node)? {
+                if other == src || parent.contains_key(&other) {
+                    continue;
+                }
+                parent.insert(other.clone(), (node.clone(), edge));
+                if other == dst {
+                    let mut path = Vec::new();
+                    let mut cur = other;
+                    while let Some((prev, edge)) = parent.remove(&cur) {
+                        path.push(edge);
+                        cur = prev;
+                    }
+                    path.reverse();
+                    return Ok(Some(path));
+                }
+                next.push(other);
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+    Ok(None)
+}
+
+/// Reached nodes with their hop distance, and the edges traversed.
+pub type Neighborhood = (Vec<(String, usize)>, Vec<serde_json::Value>);
+
+/// Nodes within `depth` hops of `node` (with their hop distance) and the edges between them.
+pub fn neighborhood(db: &sled::Db, node: &str, depth: usize) -> Result<Neighborhood> {
+    Ok(subgraph(db, node, depth, usize::MAX)?.0)
+}
+
+/// Breadth-first `neighborhood` that stops admitting nodes once `limit` are reached,
+/// keeping only edges between admitted nodes. The flag reports whether the cap cut
+/// anything off.
+pub fn subgraph(
+    db: &sled::Db,
+    node: &str,
+    depth: usize,
+    limit: usize,
+) -> Result<(Neighborhood, bool)> {
+    let depth = depth.min(MAX_TRAVERSAL_DEPTH);
+    let limit = limit.max(1);
+    let mut seen: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    seen.insert(node.to_string(), 0);
+    let mut edge_keys: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut edges_out = Vec::new();
+    let mut truncated = false;
+    let mut frontier = vec![node.to_string()];
+    for hop in 1..=depth {
+        let mut next = Vec::new();
+        for cur in &frontier {
+            for (other, edge) in adjacent(db, cur)? {
+                if !seen.contains_key(&other) {
+                    if seen.len() >= limit {
+                        truncated = true;
+                        continue;
+                    }
+                    seen.insert(other.clone(), hop);
+                    next.push(other);
+                }
+                let key = edge_key(
+                    edge.get("src").and_then(|x| x.as_str()).unwrap_or(""),
+                    edge.get("dst").and_then(|x| x.as_str()).unwrap_or(""),
+                    edge.get("relation").and_then(|x| x.as_str()).unwrap_or(""),
+                );
+                if edge_keys.insert(key) {
+                    edges_out.push(edge);
+                }
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+    let mut nodes: Vec<(String, usize)> = seen.into_iter().collect();
+    nodes.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(((nodes, edges_out), truncated))
+}
+
+/// Recompute `CO_OCCURS` edges between entity pairs that share a document, storing how many
+/// documents each pair shares as `count`. Previous co-occurrence edges are dropped first so
+/// re-runs never double-count. Returns `(a, b, count)` for pairs with `count >= min_count`.
+pub fn build_cooccurrence(
+    db: &sled::Db,
+    min_count: u64,
+    created_at: i64,
+) -> Result<Vec<(String, String, u64)>> {
+    let edges = db.open_tree("kg_edges")?;
+    let stale: Vec<Vec<u8>> = edges
+        .iter()
+        .flatten()
+        .filter(|(k, _)| k.ends_with(b"::CO_OCCURS"))
+        .map(|(k, _)| k.to_vec())
+        .collect();
+    for k in stale {
+        remove_edge_by_key(db, &k)?;
+    }
+    // kg_links keys are `doc_id::entity`, grouped by document in key order
+    let links = db.open_tree("kg_links")?;
+    let mut per_doc: std::collections::BTreeMap<String, Vec<String>> =
+        std::collections::BTreeMap::new();
+    for kv in links.iter() {
+        let (k, _) = kv?;
+        let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+        if let Some((doc_id, ent)) = key.split_once("::") {
+            per_doc
+                .entry(doc_id.to_string())
+                .or_default()
+                .push(ent.to_string());
+        }
+    }
+    let mut counts: std::collections::HashMap<(String, String), u64> =
+        std::collections::HashMap::new();
+    for mut ents in per_doc.into_values() {
+        ents.sort();
+        ents.dedup();
+        for i in 0..ents.len() {
+            for j in i + 1..ents.len() {
+                *counts
+                    .entry((ents[i].clone(), ents[j].clone()))
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+    let mut pairs: Vec<(String, String, u64)> = counts
+        .into_iter()
+        .filter(|(_, c)| *c >= min_count.max(1))
+        .map(|((a, b), c)| (a, b, c))
+        .collect();
+    for (a, b, count) in &pairs {
+        let src = format!("Entity::{}", a);
+        let dst = format!("Entity::{}", b);
+        let val = serde_json::json!({ "src": src, "dst": dst, "relation": "CO_OCCURS", "count": count, "created_at": created_at });
+        put_edge(db, &src, &dst, "CO_OCCURS", &val)?;
+    }
+    pairs.sort_by(|x, y| y.2.cmp(&x.2).then_with(|| (&x.0, &x.1).cmp(&(&y.0, &y.1))));
+    Ok(pairs)
+}
+
+/// Strongest stored `CO_OCCURS` edges, highest count first.
+pub fn top_cooccurrences(db: &sled::Db, limit: usize) -> Result<Vec<serde_json::Value>> {
+    let edges = db.open_tree("kg_edges")?;
+    let mut out: Vec<serde_json::Value> = edges
+        .iter()
+        .flatten()
+        .filter(|(k, _)| k.ends_with(b"::CO_OCCURS"))
+        .filter_map(|(_, v)| serde_json::from_slice::<serde_json::Value>(&v).ok())
+        .collect();
+    out.sort_by_key(|e| std::cmp::Reverse(e.get("count").and_then(|c| c.as_u64()).unwrap_or(0)));
+    out.truncate(limit);
+    Ok(out)
+}
+
+/// PageRank over the whole graph by power iteration. Relations are treated as undirected,
+/// since their stored direction (e.g. `Entity -> Document` for MENTIONS) is not semantic.
+/// Ranks sum to 1; nodes with no edges spread their rank evenly. Highest rank first.
+pub fn pagerank(db: &sled::Db, iterations: usize, damping: f64) -> Result<Vec<(String, f64)>> {
+    use petgraph::graph::{NodeIndex, UnGraph};
+    let damping = damping.clamp(0.0, 1.0);
+    let mut g: UnGraph<String, ()> = UnGraph::new_undirected();
+    let mut index: std::collections::HashMap<String, NodeIndex> = std::collections::HashMap::new();
+    let mut node = |g: &mut UnGraph<String, ()>, key: &str| {
+        *index
+            .entry(key.to_string())
+            .or_insert_with(|| g.add_node(key.to_string()))
+    };
+    for kv in db.open_tree("kg_nodes")?.iter() {
+        let (k, _) = kv?;
+        node(&mut g, &String::from_utf8_lossy(&k));
+    }
+    for kv in db.open_tree("kg_edges")?.iter() {
+        let (_, v) = kv?;
+        let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        let (Some(src), Some(dst)) = (
+            edge.get("src").and_then(|s| s.as_str()),
+            edge.get("dst").and_then(|d| d.as_str()),
+        ) else {
+            continue;
+        };
+        let (a, b) = (node(&mut g, src), node(&mut g, dst));
+        if a != b {
+            g.add_edge(a, b, ());
+        }
+    }
+    let n = g.node_count();
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let base = 1.0 / n as f64;
+    let mut ranks = vec![base; n];
+    for _ in 0..iterations {
+        let dangling: f64 = g
+            .node_indices()
+            .filter(|&i| g.neighbors(i).next().is_none())
+            .map(|i| ranks[i.index()])
+            .sum();
+        let mut next = vec![(1.0 - damping) * base + damping * dangling * base; n];
+        for i in g.node_indices() {
+            let degree = g.neighbors(i).count();
+            if degree == 0 {
+                continue;
+            }
+            let share = damping * ranks[i.index()] / degree as f64;
+            for j in g.neighbors(i) {
+                next[j.index()] += share;
+            }
+        }
+        ranks = next;
+    }
+    let mut out: Vec<(String, f64)> = g
+        .node_indices()
+        .map(|i| (g[i].clone(), ranks[i.index()]))
+        .collect();
+    out.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(out)
+}
+
+pub struct GraphNode {
+    pub kind: &'static str,
+    pub label: String,
+}
+
+pub struct GraphEdge {
+    pub relation: String,
+    pub score: Option<f64>,
+}
+
+/// Inclusive `created_at` bounds for graph queries; an edge without `created_at` falls
+/// outside any bounded window.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeWindow {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl TimeWindow {
+    pub fn is_open(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    pub fn contains(&self, edge: &serde_json::Value) -> bool {
+        if self.is_open() {
+            return true;
+        }
+        let Some(ts) = edge.get("created_at").and_then(|t| t.as_i64()) else {
+            return false;
+        };
+        self.from.is_none_or(|f| ts >= f) && self.to.is_none_or(|t| ts <= t)
+    }
+}
+
+/// Entity -> Document MENTIONS graph for the `limit` most mentioned entities, plus
+/// scored RELATED edges between the documents it reaches, keeping only edges created
+/// inside `window`.
+pub fn build_graph(
+    db: &sled::Db,
+    limit: usize,
+    window: TimeWindow,
+) -> Result<petgraph::graph::Graph<GraphNode, GraphEdge>> {
+    use petgraph::graph::Graph;
+    let ents = list_entities(db, limit)?;
+    let mut g: Graph<GraphNode, GraphEdge> = Graph::new();
+    let mut entity_nodes = std::collections::HashMap::new();
+    let mut doc_nodes = std::collections::HashMap::new();
+    for (e, _) in &ents {
+        let n = g.add_node(GraphNode {
+            kind: "Entity",
+            label: e.clone(),
+        });
+        entity_nodes.insert(e.clone(), n);
+    }
+    let edges = db.open_tree("kg_edges")?;
+    for (e, _) in &ents {
+        for d in docs_for_entity(db, e)? {
+            if !window.is_open() {
+                let key = edge_key(
+                    &format!("Entity::{}", e),
+                    &format!("Document::{}", d),
+                    "MENTIONS",
+                );
+                let in_window = edges
+                    .get(key.as_bytes())?
+                    .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+                    .is_some_and(|edge| window.contains(&edge));
+                if !in_window {
+                    continue;
+                }
+            }
+            let doc_node = *doc_nodes.entry(d.clone()).or_insert_with(|| {
+                g.add_node(GraphNode {
+                    kind: "Document",
+                    label: d.clone(),
+                })
+            });
+            g.add_edge(
+                entity_nodes[e],
+                doc_node,
+                GraphEdge {
+                    relation: "MENTIONS".to_string(),
+                    score: None,
+                },
+            );
+        }
+    }
+    for (src_id, &src_node) in &doc_nodes {
+        let prefix = format!(
+            "{}Document::",
+            edges_prefix(&format!("Document::{}", src_id))
+        );
+        for kv in edges.scan_prefix(prefix.as_bytes()) {
+            let (_, v) = kv?;
+            let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) else {
+                continue;
+            };
+            if edge.get("relation").and_then(|r| r.as_str()) != Some("RELATED")
+                || !window.contains(&edge)
+            {
+                continue;
+            }
+            let dst = edge
+                .get("dst")
+                .and_then(|d| d.as_str())
+                .and_then(|d| d.strip_prefix("Document::"));
+            if let Some(&dst_node) = dst.and_then(|d| doc_nodes.get(d)) {
+                g.add_edge(
+                    src_node,
+                    dst_node,
+                    GraphEdge {
+                        relation: "RELATED".to_string(),
+                        score: edge.get("score").and_then(|s| s.as_f64()),
+                    },
+                );
+            }
+        }
+    }
+    Ok(g)
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Graphviz DOT; node ids are `n<index>` with the label and type as attributes.
+pub fn to_dot(g: &petgraph::graph::Graph<GraphNode, GraphEdge>) -> String {
+    let mut out = String::from("digraph kg {\n");
+    for i in g.node_indices() {
+        let n = &g[i];
+        out.push_str(&format!(
+            "  n{} [label=\"{}\", type=\"{}\"];\n",
+            i.index(),
+            escape_dot(&n.label),
+            n.kind
+        ));
+    }
+    for e in g.edge_indices() {
+        let (s, t) = g.edge_endpoints(e).expect("edge endpoints");
+        let w = &g[e];
+        let score = w
+            .score
+            .map(|sc| format!(", score={}", sc))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "  n{} -> n{} [label=\"{}\"{}];\n",
+            s.index(),
+            t.index(),
+            escape_dot(&w.relation),
+            score
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// GraphML with `type`/`label` node data and `relation`/`score` edge data.
+pub fn to_graphml(g: &petgraph::graph::Graph<GraphNode, GraphEdge>) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        "  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n",
+        "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>\n",
+        "  <key id=\"relation\" for=\"edge\" attr.name=\"relation\" attr.type=\"string\"/>\n",
+        "  <key id=\"score\" for=\"edge\" attr.name=\"score\" attr.type=\"double\"/>\n",
+        "  <graph id=\"kg\" edgedefault=\"directed\">\n",
+    ));
+    for i in g.node_indices() {
+        let n = &g[i];
+        out.push_str(&format!(
+            "    <node id=\"n{}\"><data key=\"type\">{}</data><data key=\"label\">{}</data></node>\n",
+            i.index(),
+            n.kind,
+            escape_xml(&n.label)
+        ));
+    }
+    for e in g.edge_indices() {
+        let (s, t) = g.edge_endpoints(e).expect("edge endpoints");
+        let w = &g[e];
+        let score = w
+            .score
+            .map(|sc| format!("<data key=\"score\">{}</data>", sc))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "    <edge id=\"e{}\" source=\"n{}\" target=\"n{}\"><data key=\"relation\">{}</data>{}</edge>\n",
+            e.index(),
+            s.index(),
+            t.index(),
+            escape_xml(&w.relation),
+            score
+        ));
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
 }
@@ -2,11 +2,11 @@ use std::{
     collections::HashMap,
     collections::HashSet,
     collections::VecDeque,
-    sync::{Arc, Mutex as StdMutex},
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
     time::Instant,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::http::StatusCode;
 use axum::{
     response::{IntoResponse, Response},
@@ -34,7 +34,12 @@ use uuid::Uuid;
 
 mod config;
 mod embeddings;
+mod fetch;
 mod kg;
+mod migrations;
+mod query;
+mod telemetry;
+mod text_index;
 mod vector_index;
 
 #[derive(Parser, Debug)]
@@ -55,15 +60,145 @@ struct Cli {
 
 struct AppState {
     start_time: Instant,
-    db: Db,
+    // Swapped wholesale when a restore reopens the store; read through `db()`
+    live_db: StdRwLock<Db>,
     index_dir: std::path::PathBuf,
+    // BM25 index with its long-lived writer; adds are committed in batches
+    text_index: text_index::TextIndex,
+    // In-memory memory embeddings, loaded at startup with WARM_VECTOR_INDEX=1
+    warm_vectors: vector_index::WarmVectors,
+    // Ids of stored documents waiting for the index worker
+    index_jobs: tokio::sync::mpsc::UnboundedSender<String>,
     // Query cache for hot fusion queries: key -> (ts_ms, results)
     query_cache: AsyncMutex<HashMap<String, (i64, Vec<SearchResult>)>>,
+    // Part of every query cache key; bumped by writes so older entries stop matching
+    query_cache_gen: std::sync::atomic::AtomicU64,
     metrics: AsyncMutex<QueryMetrics>,
     ingest_sema: Arc<Semaphore>,
     // Simple buffer pool to reuse byte buffers on hot paths
     #[allow(dead_code)]
     buf_pool: StdMutex<ByteBufPool>,
+    // Open MCP-over-SSE connections: session id -> outbound JSON-RPC messages
+    sse_sessions: StdMutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<serde_json::Value>>>,
+    // Server-initiated JSON-RPC notifications, forwarded to every connected client
+    notifications: tokio::sync::broadcast::Sender<serde_json::Value>,
+    // Write rate limiting: peer IP -> (tokens left, last refill)
+    rate_buckets: StdMutex<HashMap<std::net::IpAddr, (f64, Instant)>>,
+    // Per-route request metrics keyed by the matched route pattern
+    http_metrics: StdMutex<std::collections::BTreeMap<String, RouteMetrics>>,
+    // Rolling 5xx rate feeding the health signal
+    http_errors: StdMutex<ErrorWindow>,
+}
+
+/// Open the sled store with the server's tuning; shared by startup and restore.
+fn open_db(path: &std::path::Path) -> sled::Result<Db> {
+    // Configure Sled to handle concurrent access and quick restarts
+    sled::Config::new()
+        .path(path)
+        .cache_capacity(64_000_000)
+        .flush_every_ms(Some(1000))
+        .mode(sled::Mode::HighThroughput)
+        .open()
+}
+
+/// Bring a freshly opened store up to the current on-disk format. Versioned schema
+/// migrations and the one-time backfills must succeed; the settings-driven rewrites
+/// below them are best-effort.
+fn run_migrations(db: &Db) -> Result<()> {
+    let from = migrations::schema_version(db)?;
+    let applied = migrations::migrate(db)?;
+    if applied > 0 {
+        info!(
+            "Migrated store schema from v{} to v{}",
+            from,
+            migrations::CURRENT_SCHEMA_VERSION
+        );
+    }
+    // One-time migration: L2-normalize legacy memory embeddings
+    let n = vector_index::ensure_normalized(db).context("failed to normalize memory embeddings")?;
+    if n > 0 {
+        info!("Normalized {} legacy memory embeddings", n);
+    }
+    let n = kg::ensure_reverse_index(db).context("failed to backfill reverse KG edges")?;
+    if n > 0 {
+        info!("Backfilled {} reverse KG edges", n);
+    }
+    match vector_index::ensure_quantization(db) {
+        Ok(n) if n > 0 => info!(
+            "Rewrote {} memory embeddings as {}",
+            n,
+            vector_index::quantization(db).name()
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Failed to apply embedding quantization: {}", e),
+    }
+    match vector_index::ensure_metric(db) {
+        Ok(true) => info!(
+            "Vector metric changed to {}; rebuilt neighbor graph",
+            vector_index::metric(db).name()
+        ),
+        Ok(false) => {}
+        Err(e) => error!("Failed to apply vector metric: {}", e),
+    }
+    Ok(())
+}
+
+impl AppState {
+    /// Handle to the current sled store.
+    fn db(&self) -> Db {
+        self.live_db.read().expect("db lock").clone()
+    }
+
+    /// Make cached fusion results computed before a write unreachable. Call after the
+    /// write lands, so a search racing it can only cache under the old generation.
+    /// Every write that adds, removes or renames a memory or document comes through
+    /// here, so this also tells clients to re-fetch `resources/list`.
+    fn invalidate_query_cache(&self) {
+        self.query_cache_gen
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.notify_resources_changed();
+    }
+
+    fn notify_resources_changed(&self) {
+        // No receivers just means no client is connected
+        let _ = self.notifications.send(resources_list_changed());
+    }
+
+    /// Memory vector hits from the warm copy when loaded; otherwise the HNSW graph when
+    /// `ann`, else the sled scan.
+    fn memory_vector_hits(
+        &self,
+        query: &[f32],
+        top_k: usize,
+        filter: &vector_index::VectorFilter,
+        ann: bool,
+    ) -> Vec<(String, f32)> {
+        let db = self.db();
+        if let Some(hits) = self.warm_vectors.search(&db, query, top_k, Some(filter)) {
+            return hits;
+        }
+        if ann {
+            vector_index::ann_search_memories(&db, query, top_k, Some(filter))
+        } else {
+            vector_index::search_memories_by_vector(&db, query, top_k, Some(filter))
+        }
+    }
+
+    /// Tell connected MCP clients to re-fetch `tools/list`. The registry is static
+    /// today, so nothing calls this until tools can be registered at runtime.
+    #[allow(dead_code)]
+    fn notify_tools_changed(&self) {
+        // No receivers just means no client is connected
+        let _ = self.notifications.send(tools_list_changed());
+    }
+}
+
+fn tools_list_changed() -> serde_json::Value {
+    serde_json::json!({ "jsonrpc": "2.0", "method": "notifications/tools/list_changed" })
+}
+
+fn resources_list_changed() -> serde_json::Value {
+    serde_json::json!({ "jsonrpc": "2.0", "method": "notifications/resources/list_changed" })
 }
 
 #[derive(Default)]
@@ -84,7 +219,26 @@ struct StoreDocRequest {
     path: Option<String>,
     mime: Option<String>,
     content: Option<String>,
+    /// Fetched over HTTP(S) and versioned under the URL when no `path` is given
+    #[serde(default)]
+    url: Option<String>,
     metadata: Option<serde_json::Value>,
+    #[serde(rename = "chunkSize", default)]
+    chunk_size: Option<usize>,
+    #[serde(rename = "chunkOverlap", default)]
+    chunk_overlap: Option<usize>,
+    /// Index before responding instead of queueing the work
+    #[serde(default)]
+    sync: bool,
+    /// Report chunks, entities and hash without storing anything
+    #[serde(rename = "dryRun", default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+struct RollbackDocRequest {
+    path: String,
+    version: u64,
 }
 
 #[derive(Serialize)]
@@ -92,12 +246,21 @@ struct StoreDocResponse {
     id: String,
     hash: String,
     chunks: usize,
+    /// `pending` while the index worker has the document, then `done` (or `failed`)
+    status: String,
+    /// The content hash matched a stored document, whose id this is; nothing was indexed
+    deduped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct ChunkHeader {
     id: String,
     position: Position,
+    /// 1-based source page, for PDFs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    page: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -122,7 +285,7 @@ struct RefInput {
     score: Option<f32>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct AddMemoryRequest {
     #[serde(deserialize_with = "deserialize_content_to_string")]
     content: String,
@@ -132,6 +295,64 @@ struct AddMemoryRequest {
     episode_id: Option<String>,
     #[serde(default)]
     references: Option<Vec<RefInput>>,
+    /// Starting importance (default 1.0); promotion and decay read it from the record
+    #[serde(default)]
+    importance: Option<f64>,
+    /// STM lifetime in ms (default one hour); zero or negative never expires
+    #[serde(rename = "ttlMs", default)]
+    ttl_ms: Option<i64>,
+    /// Pinned memories are exempt from expiry, LRU eviction and decay
+    #[serde(default)]
+    pinned: Option<bool>,
+    /// Also cite stored documents whose entities overlap the content's
+    #[serde(rename = "autoLinkDocs", default)]
+    auto_link_docs: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PinMemoryRequest {
+    id: String,
+    pinned: bool,
+}
+
+#[derive(Deserialize)]
+struct DedupeRequest {
+    #[serde(default)]
+    threshold: Option<f32>,
+    #[serde(rename = "dryRun", default)]
+    dry_run: Option<bool>,
+}
+
+/// The memory layer named by `s`, ignoring case; `None` for anything but STM or LTM.
+fn canonical_layer(s: &str) -> Option<&'static str> {
+    if s.eq_ignore_ascii_case("STM") {
+        Some("STM")
+    } else if s.eq_ignore_ascii_case("LTM") {
+        Some("LTM")
+    } else {
+        None
+    }
+}
+
+/// Field checks shared by `memory_add` and `memory_add_batch`.
+fn validate_add_request(req: &AddMemoryRequest) -> Option<&'static str> {
+    if req.content.trim().is_empty() {
+        return Some("content must not be empty");
+    }
+    if req
+        .layer_hint
+        .as_deref()
+        .is_some_and(|l| canonical_layer(l).is_none())
+    {
+        return Some("layer_hint must be STM or LTM");
+    }
+    if req
+        .importance
+        .is_some_and(|imp| !imp.is_finite() || imp < 0.0)
+    {
+        return Some("importance must be a non-negative number");
+    }
+    None
 }
 
 #[derive(Serialize)]
@@ -159,6 +380,11 @@ struct SearchResult {
     doc_refs: Option<Vec<DocRefOut>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     explain: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+    /// `memory` or `document`, set by the unified `/search`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<&'static str>,
 }
 
 #[derive(Serialize)]
@@ -167,6 +393,246 @@ struct SearchResponse {
     #[serde(rename = "tookMs")]
     #[serde(skip_serializing_if = "Option::is_none")]
     took_ms: Option<u128>,
+    #[serde(rename = "nextCursor")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+}
+
+/// Paging window from `limit`, `offset` (or an opaque `cursor`) and `countTotal` query params.
+struct Page {
+    offset: usize,
+    limit: usize,
+    count_total: bool,
+    /// `boostEffectiveness` can lift any candidate into this page
+    rerank: bool,
+}
+
+impl Page {
+    fn from_params(params: &std::collections::HashMap<String, String>) -> Self {
+        let offset = params
+            .get("cursor")
+            .and_then(|c| decode_cursor(c))
+            .or_else(|| params.get("offset").and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
+        Page {
+            offset,
+            limit: params
+                .get("limit")
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(10),
+            count_total: params
+                .get("countTotal")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            rerank: effectiveness_boost(params) > 0.0,
+        }
+    }
+
+    /// Candidates each source must supply for this page to be exact; the extra one
+    /// tells whether a next page exists. Counting the total, or re-ranking by
+    /// effectiveness, needs every candidate.
+    fn window(&self) -> usize {
+        if self.count_total || self.rerank {
+            usize::MAX
+        } else {
+            self.offset.saturating_add(self.limit).saturating_add(1)
+        }
+    }
+
+    /// Sort stably (score desc, then id) and cut out this page.
+    fn apply(&self, mut results: Vec<SearchResult>, took_ms: Option<u128>) -> SearchResponse {
+        sort_results(&mut results);
+        let total = results.len();
+        let end = self.offset.saturating_add(self.limit);
+        let next_cursor = (end < total).then(|| encode_cursor(end));
+        let page = results
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+        SearchResponse {
+            results: page,
+            took_ms,
+            next_cursor,
+            total: self.count_total.then_some(total),
+        }
+    }
+}
+
+const SNIPPET_MAX_CHARS: usize = 200;
+
+/// Window of at most `SNIPPET_MAX_CHARS` around the first case-insensitive match of the
+/// query (or any of its terms); the leading text when nothing matches. With `highlight`
+/// every match inside the window is wrapped in `<em>`.
+fn make_snippet(text: &str, query: &str, highlight: bool) -> String {
+    let mut terms: Vec<String> = std::iter::once(query.trim().to_string())
+        .chain(
+            query
+                .split(|c: char| !c.is_alphanumeric())
+                .map(|t| t.to_string()),
+        )
+        .filter(|t| !t.is_empty())
+        .collect();
+    terms.sort_by_key(|t| std::cmp::Reverse(t.len()));
+    terms.dedup();
+    let re = if terms.is_empty() {
+        None
+    } else {
+        let alts: Vec<String> = terms.iter().map(|t| regex::escape(t)).collect();
+        regex::Regex::new(&format!("(?i){}", alts.join("|"))).ok()
+    };
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let (match_start, match_len) = re
+        .as_ref()
+        .and_then(|r| r.find(text))
+        .map(|m| {
+            let start = chars.partition_point(|(b, _)| *b < m.start());
+            let end = chars.partition_point(|(b, _)| *b < m.end());
+            (start, end - start)
+        })
+        .unwrap_or((0, 0));
+    let from = match_start
+        .saturating_sub(SNIPPET_MAX_CHARS.saturating_sub(match_len) / 2)
+        .min(chars.len().saturating_sub(SNIPPET_MAX_CHARS));
+    let to = (from + SNIPPET_MAX_CHARS).min(chars.len());
+    let byte = |i: usize| chars.get(i).map(|(b, _)| *b).unwrap_or(text.len());
+    let window = &text[byte(from)..byte(to)];
+    let body = match re.as_ref().filter(|_| highlight) {
+        Some(r) => r.replace_all(window, "<em>$0</em>").into_owned(),
+        None => window.to_string(),
+    };
+    format!(
+        "{}{}{}",
+        if from > 0 { "..." } else { "" },
+        body,
+        if to < chars.len() { "..." } else { "" }
+    )
+}
+
+/// Fill `snippet` from the memory's content or the document chunk text in `text_index`.
+fn attach_snippets(db: &sled::Db, results: &mut [SearchResult], query: &str, highlight: bool) {
+    let mems = db.open_tree("memories").ok();
+    let text_idx = db.open_tree("text_index").ok();
+    for r in results.iter_mut() {
+        let from_memory = mems
+            .as_ref()
+            .and_then(|t| t.get(r.id.as_bytes()).ok().flatten())
+            .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+            .and_then(|rec| {
+                rec.get("content")
+                    .and_then(|c| c.as_str())
+                    .map(|c| c.to_string())
+            });
+        let text = from_memory.or_else(|| {
+            text_idx
+                .as_ref()
+                .and_then(|t| t.get(r.id.as_bytes()).ok().flatten())
+                .map(|v| String::from_utf8_lossy(&v).to_string())
+        });
+        r.snippet = text.map(|t| make_snippet(&t, query, highlight));
+    }
+}
+
+/// Parse the `docRefs` array stored on a memory record.
+fn doc_refs_from_record(rec: &serde_json::Value) -> Option<Vec<DocRefOut>> {
+    rec.get("docRefs").and_then(|r| r.as_array()).map(|arr| {
+        arr.iter()
+            .filter_map(|x| {
+                let doc_id = x.get("docId").and_then(|v| v.as_str())?.to_string();
+                let chunk_id = x
+                    .get("chunkId")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string());
+                let score = x.get("score").and_then(|v| v.as_f64()).map(|f| f as f32);
+                Some(DocRefOut {
+                    doc_id,
+                    chunk_id,
+                    score,
+                })
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Weight of the stored memory `effectiveness` in search ranking (`boostEffectiveness`).
+fn effectiveness_boost(params: &std::collections::HashMap<String, String>) -> f32 {
+    params
+        .get("boostEffectiveness")
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|w| w.is_finite())
+        .unwrap_or(0.0)
+        .max(0.0)
+}
+
+/// Highest `effectiveness` written by the last recompute; 0 if it never ran.
+fn effectiveness_max(db: &sled::Db) -> f32 {
+    db.open_tree("settings")
+        .ok()
+        .and_then(|t| t.get(b"effectiveness_max").ok().flatten())
+        .and_then(|v| serde_json::from_slice::<f64>(&v).ok())
+        .unwrap_or(0.0) as f32
+}
+
+/// Add `weight * effectiveness / best` to each result whose record (from `record`) has a
+/// stored `effectiveness`, then re-sort. `best` is the global maximum from
+/// `effectiveness_max`, so a memory gets the same boost on every page.
+fn boost_by_effectiveness(
+    results: &mut [SearchResult],
+    weight: f32,
+    best: f32,
+    record: impl Fn(&str) -> Option<serde_json::Value>,
+) {
+    if weight <= 0.0 || best <= 0.0 {
+        return;
+    }
+    let stored: Vec<f32> = results
+        .iter()
+        .map(|r| {
+            record(&r.id)
+                .and_then(|rec| rec.get("effectiveness").and_then(|e| e.as_f64()))
+                .unwrap_or(0.0)
+                .max(0.0) as f32
+        })
+        .collect();
+    for (res, eff) in results.iter_mut().zip(stored) {
+        let contribution = weight * (eff / best).min(1.0);
+        res.score += contribution;
+        if let Some(serde_json::Value::Object(explain)) = res.explain.as_mut() {
+            explain.insert("effectiveness".to_string(), serde_json::json!(contribution));
+        }
+    }
+    sort_results(results);
+}
+
+/// Order by score descending, then id, so equal scores come back in the same order on
+/// every call. A NaN score ranks last rather than making the comparison inconsistent.
+fn sort_results(results: &mut [SearchResult]) {
+    results.sort_by(|a, b| cmp_score_desc(a.score, b.score).then_with(|| a.id.cmp(&b.id)));
+}
+
+/// Descending score order that is total: NaN sorts below every number.
+fn cmp_score_desc(a: f32, b: f32) -> std::cmp::Ordering {
+    let rank = |s: f32| if s.is_nan() { f32::NEG_INFINITY } else { s };
+    rank(b).total_cmp(&rank(a))
+}
+
+fn encode_cursor(offset: usize) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("offset:{}", offset))
+}
+
+fn decode_cursor(cursor: &str) -> Option<usize> {
+    use base64::Engine;
+    let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()?;
+    String::from_utf8(raw)
+        .ok()?
+        .strip_prefix("offset:")?
+        .parse()
+        .ok()
 }
 
 #[derive(Deserialize)]
@@ -176,11 +642,84 @@ struct UpdateMemoryRequest {
     metadata: Option<JsonValue>,
 }
 
+#[derive(Deserialize)]
+struct RelateMemoryRequest {
+    src: String,
+    dst: String,
+    relation: String,
+}
+
 #[derive(Deserialize)]
 struct DeleteMemoryRequest {
     id: String,
     #[serde(default)]
     backup: Option<bool>,
+    /// Tombstone instead of removing; undo with `/memory/undelete` until purged
+    #[serde(default)]
+    soft: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeleteMemoryBatchRequest {
+    ids: Vec<String>,
+}
+
+/// Memories matching every given field are hard-deleted.
+#[derive(Deserialize)]
+struct DeleteMemoryWhereRequest {
+    #[serde(default)]
+    session_id: Option<String>,
+    #[serde(default)]
+    episode_id: Option<String>,
+    #[serde(default)]
+    layer: Option<String>,
+    /// `created_at` upper bound (epoch ms, exclusive)
+    #[serde(rename = "olderThan", default)]
+    older_than: Option<i64>,
+    /// Required to run with no filter, which deletes every memory
+    #[serde(rename = "confirmAll", default)]
+    confirm_all: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct UndeleteMemoryRequest {
+    id: String,
+}
+
+/// `meta.<path>=<value>` query params; `path` may be dotted to reach nested keys.
+fn metadata_filter_from_params(params: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut filter: Vec<(String, String)> = params
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix("meta.").map(|p| (p.to_string(), v.clone())))
+        .filter(|(p, _)| !p.is_empty())
+        .collect();
+    filter.sort();
+    filter
+}
+
+/// Whether every filter path resolves in `rec.metadata` to a scalar equal to the given value.
+/// Numbers compare numerically (`1` matches `1.0`); booleans match `true`/`false`.
+fn metadata_matches(rec: &serde_json::Value, filter: &[(String, String)]) -> bool {
+    filter.iter().all(|(path, want)| {
+        let mut cur = rec.get("metadata");
+        for part in path.split('.') {
+            cur = cur.and_then(|c| c.get(part));
+        }
+        match cur {
+            Some(serde_json::Value::String(s)) => s == want,
+            Some(serde_json::Value::Number(n)) => match (n.as_f64(), want.parse::<f64>()) {
+                (Some(a), Ok(b)) => a == b,
+                _ => false,
+            },
+            Some(serde_json::Value::Bool(b)) => want.parse::<bool>().ok() == Some(*b),
+            _ => false,
+        }
+    })
+}
+
+/// Soft-deleted records carry a `deleted_at` tombstone and are hidden from search.
+fn is_tombstoned(rec: &serde_json::Value) -> bool {
+    rec.get("deleted_at").is_some_and(|v| !v.is_null())
 }
 
 fn deserialize_content_to_string<'de, D>(deserializer: D) -> Result<String, D::Error>
@@ -246,6 +785,38 @@ struct QueryMetrics {
     history: VecDeque<(i64, u64)>,
 }
 
+/// Upper bounds (seconds) of the HTTP latency histogram buckets; Prometheus' defaults.
+const HTTP_LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Request counters and latency histogram for one route.
+#[derive(Default, Clone)]
+struct RouteMetrics {
+    by_status: std::collections::BTreeMap<u16, u64>,
+    errors: u64,
+    // Non-cumulative per-bucket counts; the final slot is +Inf
+    buckets: [u64; HTTP_LATENCY_BUCKETS.len() + 1],
+    sum_secs: f64,
+    count: u64,
+}
+
+impl RouteMetrics {
+    fn observe(&mut self, status: u16, secs: f64) {
+        *self.by_status.entry(status).or_default() += 1;
+        if status >= 400 {
+            self.errors += 1;
+        }
+        let slot = HTTP_LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(HTTP_LATENCY_BUCKETS.len());
+        self.buckets[slot] += 1;
+        self.sum_secs += secs;
+        self.count += 1;
+    }
+}
+
 #[derive(Serialize)]
 struct StatusResponse {
     uptime_ms: u128,
@@ -254,9 +825,85 @@ struct StatusResponse {
     metrics: QueryMetrics,
     #[serde(rename = "memory")]
     proc_mem: ProcMem,
+    http_errors: HttpErrorStatus,
+    config: ConfigStatus,
     health: &'static str,
 }
 
+/// Effective env-driven settings operators may want to confirm.
+#[derive(Serialize)]
+struct ConfigStatus {
+    stm_ttl_ms: i64,
+    consolidation: PromotionPolicy,
+}
+
+/// 5xx responses: lifetime total and the rate over the last minute.
+#[derive(Serialize, Default, Clone)]
+struct HttpErrorStatus {
+    total: u64,
+    requests_1m: u64,
+    errors_1m: u64,
+    rate_1m: f64,
+}
+
+/// Per-second routed-request and 5xx counts for the last minute, plus a lifetime 5xx total.
+#[derive(Default)]
+struct ErrorWindow {
+    total_errors: u64,
+    // (unix second, requests, 5xx responses), oldest first
+    seconds: VecDeque<(u64, u64, u64)>,
+}
+
+impl ErrorWindow {
+    const SPAN_SECS: u64 = 60;
+
+    fn record(&mut self, now_s: u64, is_error: bool) {
+        match self.seconds.back_mut() {
+            Some((s, requests, errors)) if *s == now_s => {
+                *requests += 1;
+                *errors += is_error as u64;
+            }
+            _ => self.seconds.push_back((now_s, 1, is_error as u64)),
+        }
+        self.total_errors += is_error as u64;
+        while let Some((s, _, _)) = self.seconds.front() {
+            if s + Self::SPAN_SECS <= now_s {
+                self.seconds.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn status(&self, now_s: u64) -> HttpErrorStatus {
+        let (requests, errors) = self
+            .seconds
+            .iter()
+            .filter(|(s, _, _)| s + Self::SPAN_SECS > now_s)
+            .fold((0, 0), |(r, e), (_, req, err)| (r + req, e + err));
+        HttpErrorStatus {
+            total: self.total_errors,
+            requests_1m: requests,
+            errors_1m: errors,
+            rate_1m: if requests > 0 {
+                errors as f64 / requests as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Below this many requests in the window the error rate is too noisy to judge health by.
+const ERROR_RATE_MIN_REQUESTS: u64 = 10;
+
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 #[derive(Serialize, Default, Clone)]
 struct ProcMem {
     rss_mb: u64,
@@ -269,6 +916,21 @@ struct ToolDescriptor {
     name: &'static str,
     description: &'static str,
 }
+
+impl ToolDescriptor {
+    /// JSON Schema for the tool's arguments, as advertised over MCP and `/tools`.
+    fn input_schema(&self) -> serde_json::Value {
+        tool_input_schema(self.name)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "description": self.description,
+            "inputSchema": self.input_schema(),
+        })
+    }
+}
 #[inline]
 fn json_error(
     status: StatusCode,
@@ -276,16 +938,82 @@ fn json_error(
     message: impl Into<String>,
     details: Option<serde_json::Value>,
 ) -> Response {
+    // Inside a routed request, tag the error so it can be matched with the logs
+    let details = match (REQUEST_ID.try_with(|id| id.clone()).ok(), details) {
+        (Some(id), None) => Some(serde_json::json!({ "requestId": id })),
+        (Some(id), Some(serde_json::Value::Object(mut map))) => {
+            map.insert("requestId".to_string(), serde_json::json!(id));
+            Some(serde_json::Value::Object(map))
+        }
+        (_, details) => details,
+    };
     let body = serde_json::json!({ "error": { "code": code, "message": message.into(), "details": details } });
     (status, Json(body)).into_response()
 }
 
+/// Open several trees at once, for handlers that need them all.
+fn open_trees<const N: usize>(db: &sled::Db, names: [&str; N]) -> sled::Result<[sled::Tree; N]> {
+    let mut trees = Vec::with_capacity(N);
+    for name in names {
+        trees.push(db.open_tree(name)?);
+    }
+    Ok(trees.try_into().unwrap_or_else(|_| unreachable!()))
+}
+
+/// 500 for a storage failure inside a handler, instead of panicking the connection away.
+fn internal_error(err: impl std::fmt::Display) -> Response {
+    json_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "INTERNAL_ERROR",
+        err.to_string(),
+        None,
+    )
+}
+
+tokio::task_local! {
+    /// Id of the request the current task is serving; set by `assign_request_id`.
+    static REQUEST_ID: String;
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Accept caller-supplied ids only if they are short and header/log safe.
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Reuse the caller's `X-Request-Id` or mint a UUID, expose it to the request span and
+/// `json_error`, and echo it on the response.
+async fn assign_request_id(
+    mut req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| valid_request_id(v))
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let value = axum::http::HeaderValue::from_str(&id).expect("validated request id");
+    req.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+    let mut resp = REQUEST_ID.scope(id, next.run(req)).await;
+    resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    resp
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing();
     let env_cfg = config::Config::load().unwrap_or_else(|_| config::Config {
         bind: "127.0.0.1:8080".parse().unwrap(),
         data_dir: "./data".to_string(),
+        api_keys: config::api_keys_from_env(),
+        rate_limit: config::rate_limit_from_env(),
     });
     let cli = Cli::parse();
 
@@ -315,15 +1043,8 @@ async fn main() -> Result<()> {
         pid_file
     );
 
-    // Configure Sled to handle concurrent access and quick restarts
-    let db_config = sled::Config::new()
-        .path(&db_path)
-        .cache_capacity(64_000_000)
-        .flush_every_ms(Some(1000))
-        .mode(sled::Mode::HighThroughput);
-
     // Open database (should work now after cleaning stale instances)
-    let db = match db_config.open() {
+    let db = match open_db(&db_path) {
         Ok(db) => {
             info!("Database opened successfully");
             db
@@ -335,6 +1056,11 @@ async fn main() -> Result<()> {
             return Err(e.into());
         }
     };
+    if let Err(e) = run_migrations(&db) {
+        error!("Failed to migrate database: {:#}", e);
+        let _ = std::fs::remove_file(&pid_file);
+        return Err(e);
+    }
 
     // Initialize persistent settings KV with effective config
     {
@@ -343,23 +1069,43 @@ async fn main() -> Result<()> {
         let _ = settings.insert(b"data_dir", data_dir.as_bytes());
     }
 
+    let (index_jobs, index_job_rx) = tokio::sync::mpsc::unbounded_channel();
     let state = Arc::new(AppState {
         start_time: Instant::now(),
-        db,
+        live_db: StdRwLock::new(db),
+        text_index: text_index::TextIndex::new(&dirs.index),
+        warm_vectors: Default::default(),
+        index_jobs,
         index_dir: dirs.index,
         query_cache: AsyncMutex::new(HashMap::new()),
+        query_cache_gen: Default::default(),
         metrics: AsyncMutex::new(QueryMetrics::default()),
-        ingest_sema: Arc::new(Semaphore::new(
-            std::env::var("MAX_CONCURRENT_INGEST")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(4),
-        )),
+        ingest_sema: Arc::new(Semaphore::new(max_concurrent_ingest() as usize)),
         buf_pool: StdMutex::new(ByteBufPool::default()),
+        sse_sessions: StdMutex::new(HashMap::new()),
+        notifications: tokio::sync::broadcast::channel(16).0,
+        rate_buckets: StdMutex::new(HashMap::new()),
+        http_metrics: StdMutex::new(std::collections::BTreeMap::new()),
+        http_errors: StdMutex::new(ErrorWindow::default()),
     });
+    match ensure_text_index_schema(&state.db(), &state.text_index) {
+        Ok(n) if n > 0 => info!("Rebuilt text index in the current schema ({} docs)", n),
+        Ok(_) => {}
+        Err(e) => error!("Failed to rebuild text index: {}", e),
+    }
+    if vector_index::WarmVectors::enabled_by_env() {
+        match state.warm_vectors.load(&state.db()) {
+            Ok(n) => info!("Warmed {} memory vectors", n),
+            Err(e) => error!("Failed to warm memory vectors: {}", e),
+        }
+    }
 
     let mut tasks = Vec::new();
 
+    // Resolve the embedding backend once so misconfiguration surfaces at startup
+    let backend = embeddings::init_backend();
+    info!("Embedding backend: {}", backend.name());
+
     // Maintenance loop (STM eviction, LTM decay)
     let maint_state = state.clone();
     let maint_task = task::spawn(async move {
@@ -367,15 +1113,42 @@ async fn main() -> Result<()> {
     });
     tasks.push(maint_task);
 
+    // Index worker: documents stored without `sync` are chunked and indexed here
+    let worker_state = state.clone();
+    let worker_task = task::spawn(async move {
+        index_worker(worker_state, index_job_rx).await;
+    });
+    tasks.push(worker_task);
+    let requeued = requeue_index_jobs(&state);
+    if requeued > 0 {
+        info!("Requeued {} pending document index jobs", requeued);
+    }
+
+    // Text index commits: buffered adds become durable within TEXT_INDEX_COMMIT_MS
+    let commit_state = state.clone();
+    let commit_task = task::spawn(async move {
+        text_index_commit_loop(commit_state).await;
+    });
+    tasks.push(commit_task);
+
     // HTTP server (if bind not empty)
     if !cli.bind.is_empty() {
         let http_state = state.clone();
+        let api_keys = env_cfg.api_keys.clone();
+        let rate_limit = env_cfg.rate_limit;
+        if api_keys.is_empty() {
+            info!("API_KEYS not set; HTTP server accepts unauthenticated requests");
+        }
         info!(%bind_addr, "Starting HTTP server");
         let http_task = task::spawn(async move {
-            let app = build_router(http_state);
+            let app = require_api_keys(
+                rate_limit_writes(build_router(http_state.clone()), http_state, rate_limit),
+                api_keys,
+            );
             let listener = tokio::net::TcpListener::bind(bind_addr)
                 .await
                 .expect("bind failed");
+            let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
             axum::serve(listener, app)
                 .with_graceful_shutdown(shutdown_signal())
                 .await
@@ -402,9 +1175,12 @@ async fn main() -> Result<()> {
 
     // Graceful shutdown
     info!("Flushing database...");
-    if let Err(e) = state.db.flush_async().await {
+    if let Err(e) = state.db().flush_async().await {
         error!("Failed to flush database: {}", e);
     }
+    if let Err(e) = state.text_index.commit() {
+        error!("Failed to commit text index: {}", e);
+    }
 
     info!("Stopping tasks...");
     for t in tasks {
@@ -442,10 +1218,15 @@ fn init_tracing() {
         .with_target(false)
         .with_ansi(false)
         .with_writer(std::io::stderr);
+    let endpoint = telemetry::otlp_endpoint();
     tracing_subscriber::registry()
         .with(env_filter)
         .with(fmt_layer)
+        .with(telemetry::OtlpLayer::new(endpoint.clone()))
         .init();
+    if let Some(endpoint) = endpoint {
+        info!("Exporting traces to {}", endpoint);
+    }
 }
 
 fn ensure_data_dirs(root: &str) -> Result<DataDirs> {
@@ -537,15 +1318,23 @@ fn handle_stale_instance(pid_file: &std::path::Path) -> Result<()> {
     Ok(())
 }
 
-fn build_router(state: Arc<AppState>) -> Router {
+/// Every API route, before middleware and state are applied.
+fn api_routes() -> Router<Arc<AppState>> {
     Router::new()
         .route("/health", get(health))
         .route("/status", get(status))
         .route("/metrics", get(metrics_route))
         .route("/tools", get(list_tools_route))
+        .route("/sse", get(mcp_sse))
+        .route("/messages", post(mcp_messages))
         .route("/document/store", post(document_store))
         .route("/document/retrieve", get(document_retrieve))
+        .route("/document/list", get(document_list))
+        .route("/document/versions", get(document_versions))
+        .route("/document/rollback", post(document_rollback))
+        .route("/document/diff", get(document_diff))
         .route("/document/analyze", get(document_analyze))
+        .route("/document/job", get(document_job))
         .route("/document/refs_for_memory", get(document_refs_for_memory))
         .route(
             "/document/refs_for_document",
@@ -561,17 +1350,42 @@ fn build_router(state: Arc<AppState>) -> Router {
         .route("/kg/create_relation", post(kg_create_relation))
         .route("/kg/search_nodes", get(kg_search_nodes))
         .route("/kg/read_graph", get(kg_read_graph))
+        .route("/kg/path", get(kg_path))
+        .route("/kg/neighbors", get(kg_neighbors))
+        .route("/kg/subgraph", get(kg_subgraph))
+        .route("/kg/repair", post(kg_repair))
+        .route("/kg/cooccurrence", post(kg_cooccurrence))
+        .route("/kg/centrality", get(kg_centrality))
+        .route("/kg/stats", get(kg_stats))
+        .route("/kg/export", get(kg_export))
         .route("/kg/tag_entity", post(kg_tag_entity))
         .route("/kg/get_tags", get(kg_get_tags))
         .route("/kg/remove_tag", post(kg_remove_tag))
         .route("/kg/delete_entity", post(kg_delete_entity))
+        .route("/kg/merge_entity", post(kg_merge_entity))
         .route("/kg/delete_relation", post(kg_delete_relation))
         .route("/memory/add", post(memory_add))
+        .route("/memory/add_batch", post(memory_add_batch))
         .route("/memory/search", get(memory_search))
+        .route("/memory/get", get(memory_get))
         .route("/memory/update", post(memory_update))
         .route("/memory/delete", post(memory_delete))
+        .route("/memory/delete_batch", post(memory_delete_batch))
+        .route("/memory/delete_where", post(memory_delete_where))
+        .route("/memory/relate", post(memory_relate))
+        .route("/memory/pin", post(memory_pin))
+        .route("/memory/context", get(memory_context))
+        .route("/memory/episode", get(memory_episode))
+        .route("/memory/dedupe", post(memory_dedupe))
+        .route("/memory/undelete", post(memory_undelete))
+        .route("/memory/related", get(memory_related))
+        .route("/search", get(unified_search))
         .route("/search/fusion", get(search_fusion))
         .route("/advanced/consolidate", post(advanced_consolidate))
+        .route(
+            "/advanced/consolidation_log",
+            get(advanced_consolidation_log),
+        )
         .route("/advanced/reindex", post(advanced_reindex))
         .route(
             "/advanced/analyze_patterns",
@@ -581,173 +1395,593 @@ fn build_router(state: Arc<AppState>) -> Router {
         .route("/advanced/clusters", post(advanced_clusters))
         .route("/advanced/relationships", post(advanced_relationships))
         .route("/advanced/effectiveness", post(advanced_effectiveness))
+        .route(
+            "/advanced/recompute_effectiveness",
+            post(advanced_recompute_effectiveness),
+        )
         .route("/system/cleanup", post(system_cleanup))
         .route("/system/backup", post(system_backup))
         .route("/system/restore", post(system_restore))
+        .route("/system/verify_backup", post(system_verify_backup))
         .route("/system/compact", post(system_compact))
         .route("/system/validate", get(system_validate))
+        .route("/system/run_maintenance", post(system_run_maintenance))
         .route("/data/export", post(data_export))
         .route("/data/import", post(data_import))
-        .layer(TraceLayer::new_for_http())
+}
+
+fn build_router(state: Arc<AppState>) -> Router {
+    api_routes()
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_http_metrics,
+        ))
+        // One limit for every body, replacing axum's per-extractor 2 MB default
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .layer(tower_http::limit::RequestBodyLimitLayer::new(
+            max_body_bytes(),
+        ))
+        .layer(axum::middleware::from_fn(payload_too_large_as_json))
+        // INFO so request spans pass the default filter and parent handler spans in traces
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &axum::extract::Request| {
+                let id = req
+                    .headers()
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                tracing::info_span!(
+                    "request",
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    request_id = %id,
+                )
+            }),
+        )
+        // Outermost, so the id is in place before the request span is made
+        .layer(axum::middleware::from_fn(assign_request_id))
         .with_state(state)
 }
 
-async fn proxy_tool_via_http(
-    tool_name: &str,
-    args: &serde_json::Value,
-) -> Result<serde_json::Value, String> {
-    let bind = std::env::var("HTTP_BIND").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+/// `MAX_BODY_BYTES`, the largest request body accepted (default 16 MiB).
+fn max_body_bytes() -> usize {
+    std::env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(16 * 1024 * 1024)
+}
 
-    // If HTTP_BIND is explicitly empty, HTTP server is disabled
-    if bind.is_empty() {
-        return Err("HTTP server is disabled. Cannot proxy tool calls.".to_string());
+/// The body limit answers 413 with an empty or plain-text body; give clients the usual
+/// error shape and point large documents at path ingestion.
+async fn payload_too_large_as_json(
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let resp = next.run(req).await;
+    if resp.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return resp;
     }
+    json_error(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "PAYLOAD_TOO_LARGE",
+        "Request body exceeds MAX_BODY_BYTES",
+        Some(serde_json::json!({
+            "maxBytes": max_body_bytes(),
+            "hint": "store large documents by path: POST /document/store with { \"path\": \"...\" } instead of inline content"
+        })),
+    )
+}
 
-    let base = format!("http://{}", bind);
-    // Map tool names to method and path (support both dot and underscore notation)
-    let (method, path) = match tool_name {
-        // Memory (dot notation)
-        "memory.add" => ("POST", "/memory/add"),
-        "memory.search" => ("GET", "/memory/search"),
-        "memory.update" => ("POST", "/memory/update"),
-        "memory.delete" => ("POST", "/memory/delete"),
+/// Count each routed request and time it into the route's latency histogram. Runs after
+/// routing so the `path` label is the route pattern, keeping label cardinality bounded.
+async fn record_http_metrics(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    path: axum::extract::MatchedPath,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    let secs = started.elapsed().as_secs_f64();
+    if let Ok(mut routes) = state.http_metrics.lock() {
+        routes
+            .entry(path.as_str().to_string())
+            .or_default()
+            .observe(resp.status().as_u16(), secs);
+    }
+    if let Ok(mut errors) = state.http_errors.lock() {
+        errors.record(unix_secs(), resp.status().is_server_error());
+    }
+    resp
+}
+
+/// Guard the HTTP listener with bearer keys. In-process tool dispatch uses the bare router,
+/// so stdio is unaffected. With no keys the router is returned unchanged.
+fn require_api_keys(router: Router, keys: Vec<String>) -> Router {
+    if keys.is_empty() {
+        return router;
+    }
+    router.layer(axum::middleware::from_fn_with_state(
+        Arc::new(keys),
+        check_api_key,
+    ))
+}
+
+async fn check_api_key(
+    axum::extract::State(keys): axum::extract::State<Arc<Vec<String>>>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if req.uri().path() == "/health" {
+        return next.run(req).await;
+    }
+    let token = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    match token {
+        Some(t)
+            if keys
+                .iter()
+                .any(|k| constant_time_eq(k.as_bytes(), t.as_bytes())) =>
+        {
+            next.run(req).await
+        }
+        Some(_) => json_error(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHORIZED",
+            "Invalid API key",
+            None,
+        ),
+        None => json_error(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHORIZED",
+            "Missing bearer token",
+            None,
+        ),
+    }
+}
+
+/// Throttle `POST`s per peer IP with a token bucket; reads are never limited.
+fn rate_limit_writes(
+    router: Router,
+    state: Arc<AppState>,
+    limit: Option<config::RateLimit>,
+) -> Router {
+    match limit {
+        Some(limit) => router.layer(axum::middleware::from_fn_with_state(
+            (state, limit),
+            check_rate_limit,
+        )),
+        None => router,
+    }
+}
+
+const RATE_BUCKETS_MAX: usize = 10_000;
+
+async fn check_rate_limit(
+    axum::extract::State((state, limit)): axum::extract::State<(Arc<AppState>, config::RateLimit)>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if req.method() != axum::http::Method::POST {
+        return next.run(req).await;
+    }
+    let ip = req
+        .extensions()
+        .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+        .map(|c| c.0.ip())
+        .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
+    let now = Instant::now();
+    let wait_secs = {
+        let mut buckets = state.rate_buckets.lock().expect("rate buckets");
+        if buckets.len() >= RATE_BUCKETS_MAX {
+            // Idle buckets have refilled to full, so dropping them changes nothing
+            let full_after = limit.burst / limit.rps;
+            buckets.retain(|_, (_, last)| now.duration_since(*last).as_secs_f64() < full_after);
+        }
+        let (tokens, last) = buckets.entry(ip).or_insert((limit.burst, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * limit.rps).min(limit.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - *tokens) / limit.rps).ceil().max(1.0) as u64)
+        }
+    };
+    match wait_secs {
+        None => next.run(req).await,
+        Some(secs) => {
+            let mut resp = json_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "RATE_LIMITED",
+                "Too many write requests",
+                Some(serde_json::json!({ "retryAfterSecs": secs })),
+            );
+            resp.headers_mut()
+                .insert(axum::http::header::RETRY_AFTER, secs.into());
+            resp
+        }
+    }
+}
+
+/// Byte comparison whose timing doesn't depend on where the inputs differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// HTTP method and route backing each MCP tool (dot and underscore notation).
+fn tool_route(tool_name: &str) -> Option<(&'static str, &'static str)> {
+    match tool_name {
+        // Memory (dot notation)
+        "memory.add" => Some(("POST", "/memory/add")),
+        "memory.add_batch" => Some(("POST", "/memory/add_batch")),
+        "memory.search" => Some(("GET", "/memory/search")),
+        "memory.update" => Some(("POST", "/memory/update")),
+        "memory.delete" => Some(("POST", "/memory/delete")),
+        "memory.delete_batch" => Some(("POST", "/memory/delete_batch")),
+        "memory.delete_where" => Some(("POST", "/memory/delete_where")),
+        "memory.relate" => Some(("POST", "/memory/relate")),
+        "memory.pin" => Some(("POST", "/memory/pin")),
+        "memory.context" => Some(("GET", "/memory/context")),
+        "memory.episode" => Some(("GET", "/memory/episode")),
+        "memory.dedupe" => Some(("POST", "/memory/dedupe")),
+        "memory.undelete" => Some(("POST", "/memory/undelete")),
+        "memory.related" => Some(("GET", "/memory/related")),
+        "memory.get" => Some(("GET", "/memory/get")),
         // Memory (underscore notation)
-        "memory_add" => ("POST", "/memory/add"),
-        "memory_search" => ("GET", "/memory/search"),
-        "memory_update" => ("POST", "/memory/update"),
-        "memory_delete" => ("POST", "/memory/delete"),
+        "memory_add" => Some(("POST", "/memory/add")),
+        "memory_add_batch" => Some(("POST", "/memory/add_batch")),
+        "memory_search" => Some(("GET", "/memory/search")),
+        "memory_update" => Some(("POST", "/memory/update")),
+        "memory_delete" => Some(("POST", "/memory/delete")),
+        "memory_delete_batch" => Some(("POST", "/memory/delete_batch")),
+        "memory_delete_where" => Some(("POST", "/memory/delete_where")),
+        "memory_relate" => Some(("POST", "/memory/relate")),
+        "memory_pin" => Some(("POST", "/memory/pin")),
+        "memory_context" => Some(("GET", "/memory/context")),
+        "memory_episode" => Some(("GET", "/memory/episode")),
+        "memory_dedupe" => Some(("POST", "/memory/dedupe")),
+        "memory_undelete" => Some(("POST", "/memory/undelete")),
+        "memory_related" => Some(("GET", "/memory/related")),
+        "memory_get" => Some(("GET", "/memory/get")),
         // Document (dot notation)
-        "document.store" => ("POST", "/document/store"),
-        "document.retrieve" => ("GET", "/document/retrieve"),
-        "document.analyze" => ("GET", "/document/analyze"),
-        "document.refs_for_memory" => ("GET", "/document/refs_for_memory"),
-        "document.refs_for_document" => ("GET", "/document/refs_for_document"),
-        "document.validate_refs" => ("POST", "/document/validate_refs"),
+        "document.store" => Some(("POST", "/document/store")),
+        "document.retrieve" => Some(("GET", "/document/retrieve")),
+        "document.list" => Some(("GET", "/document/list")),
+        "document.versions" => Some(("GET", "/document/versions")),
+        "document.rollback" => Some(("POST", "/document/rollback")),
+        "document.diff" => Some(("GET", "/document/diff")),
+        "document.analyze" => Some(("GET", "/document/analyze")),
+        "document.job" => Some(("GET", "/document/job")),
+        "document.refs_for_memory" => Some(("GET", "/document/refs_for_memory")),
+        "document.refs_for_document" => Some(("GET", "/document/refs_for_document")),
+        "document.validate_refs" => Some(("POST", "/document/validate_refs")),
         // Document (underscore notation)
-        "document_store" => ("POST", "/document/store"),
-        "document_retrieve" => ("GET", "/document/retrieve"),
-        "document_analyze" => ("GET", "/document/analyze"),
-        "document_refs_for_memory" => ("GET", "/document/refs_for_memory"),
-        "document_refs_for_document" => ("GET", "/document/refs_for_document"),
-        "document_validate_refs" => ("POST", "/document/validate_refs"),
+        "document_store" => Some(("POST", "/document/store")),
+        "document_retrieve" => Some(("GET", "/document/retrieve")),
+        "document_list" => Some(("GET", "/document/list")),
+        "document_versions" => Some(("GET", "/document/versions")),
+        "document_rollback" => Some(("POST", "/document/rollback")),
+        "document_diff" => Some(("GET", "/document/diff")),
+        "document_analyze" => Some(("GET", "/document/analyze")),
+        "document_job" => Some(("GET", "/document/job")),
+        "document_refs_for_memory" => Some(("GET", "/document/refs_for_memory")),
+        "document_refs_for_document" => Some(("GET", "/document/refs_for_document")),
+        "document_validate_refs" => Some(("POST", "/document/validate_refs")),
         // Knowledge Graph (dot notation)
-        "kg.list_entities" => ("GET", "/kg/list_entities"),
-        "kg.get_entity" => ("GET", "/kg/get_entity"),
-        "kg.create_entity" => ("POST", "/kg/create_entity"),
-        "kg.create_relation" => ("POST", "/kg/create_relation"),
-        "kg.search_nodes" => ("GET", "/kg/search_nodes"),
-        "kg.read_graph" => ("GET", "/kg/read_graph"),
-        "kg.tag_entity" => ("POST", "/kg/tag_entity"),
-        "kg.get_tags" => ("GET", "/kg/get_tags"),
-        "kg.remove_tag" => ("POST", "/kg/remove_tag"),
-        "kg.delete_entity" => ("POST", "/kg/delete_entity"),
-        "kg.delete_relation" => ("POST", "/kg/delete_relation"),
+        "kg.list_entities" => Some(("GET", "/kg/list_entities")),
+        "kg.get_entity" => Some(("GET", "/kg/get_entity")),
+        "kg.create_entity" => Some(("POST", "/kg/create_entity")),
+        "kg.create_relation" => Some(("POST", "/kg/create_relation")),
+        "kg.search_nodes" => Some(("GET", "/kg/search_nodes")),
+        "kg.read_graph" => Some(("GET", "/kg/read_graph")),
+        "kg.path" => Some(("GET", "/kg/path")),
+        "kg.neighbors" => Some(("GET", "/kg/neighbors")),
+        "kg.subgraph" => Some(("GET", "/kg/subgraph")),
+        "kg.repair" => Some(("POST", "/kg/repair")),
+        "kg.cooccurrence" => Some(("POST", "/kg/cooccurrence")),
+        "kg.centrality" => Some(("GET", "/kg/centrality")),
+        "kg.stats" => Some(("GET", "/kg/stats")),
+        "kg.export" => Some(("GET", "/kg/export")),
+        "kg.tag_entity" => Some(("POST", "/kg/tag_entity")),
+        "kg.get_tags" => Some(("GET", "/kg/get_tags")),
+        "kg.remove_tag" => Some(("POST", "/kg/remove_tag")),
+        "kg.delete_entity" => Some(("POST", "/kg/delete_entity")),
+        "kg.merge_entity" => Some(("POST", "/kg/merge_entity")),
+        "kg.delete_relation" => Some(("POST", "/kg/delete_relation")),
         // Knowledge Graph (underscore notation)
-        "kg_list_entities" => ("GET", "/kg/list_entities"),
-        "kg_get_entity" => ("GET", "/kg/get_entity"),
-        "kg_create_entity" => ("POST", "/kg/create_entity"),
-        "kg_create_relation" => ("POST", "/kg/create_relation"),
-        "kg_search_nodes" => ("GET", "/kg/search_nodes"),
-        "kg_read_graph" => ("GET", "/kg/read_graph"),
-        "kg_tag_entity" => ("POST", "/kg/tag_entity"),
-        "kg_get_tags" => ("GET", "/kg/get_tags"),
-        "kg_remove_tag" => ("POST", "/kg/remove_tag"),
-        "kg_delete_entity" => ("POST", "/kg/delete_entity"),
-        "kg_delete_relation" => ("POST", "/kg/delete_relation"),
+        "kg_list_entities" => Some(("GET", "/kg/list_entities")),
+        "kg_get_entity" => Some(("GET", "/kg/get_entity")),
+        "kg_create_entity" => Some(("POST", "/kg/create_entity")),
+        "kg_create_relation" => Some(("POST", "/kg/create_relation")),
+        "kg_search_nodes" => Some(("GET", "/kg/search_nodes")),
+        "kg_read_graph" => Some(("GET", "/kg/read_graph")),
+        "kg_path" => Some(("GET", "/kg/path")),
+        "kg_neighbors" => Some(("GET", "/kg/neighbors")),
+        "kg_subgraph" => Some(("GET", "/kg/subgraph")),
+        "kg_repair" => Some(("POST", "/kg/repair")),
+        "kg_cooccurrence" => Some(("POST", "/kg/cooccurrence")),
+        "kg_centrality" => Some(("GET", "/kg/centrality")),
+        "kg_stats" => Some(("GET", "/kg/stats")),
+        "kg_export" => Some(("GET", "/kg/export")),
+        "kg_tag_entity" => Some(("POST", "/kg/tag_entity")),
+        "kg_get_tags" => Some(("GET", "/kg/get_tags")),
+        "kg_remove_tag" => Some(("POST", "/kg/remove_tag")),
+        "kg_delete_entity" => Some(("POST", "/kg/delete_entity")),
+        "kg_merge_entity" => Some(("POST", "/kg/merge_entity")),
+        "kg_delete_relation" => Some(("POST", "/kg/delete_relation")),
         // System (dot notation)
-        "system.status" => ("GET", "/status"),
-        "system.cleanup" => ("POST", "/system/cleanup"),
-        "system.backup" => ("POST", "/system/backup"),
-        "system.restore" => ("POST", "/system/restore"),
+        "system.status" => Some(("GET", "/status")),
+        "system.cleanup" => Some(("POST", "/system/cleanup")),
+        "system.backup" => Some(("POST", "/system/backup")),
+        "system.restore" => Some(("POST", "/system/restore")),
+        "system.verify_backup" => Some(("POST", "/system/verify_backup")),
+        "system.run_maintenance" => Some(("POST", "/system/run_maintenance")),
         // System (underscore notation)
-        "system_status" => ("GET", "/status"),
-        "system_cleanup" => ("POST", "/system/cleanup"),
-        "system_backup" => ("POST", "/system/backup"),
-        "system_restore" => ("POST", "/system/restore"),
+        "system_status" => Some(("GET", "/status")),
+        "system_cleanup" => Some(("POST", "/system/cleanup")),
+        "system_backup" => Some(("POST", "/system/backup")),
+        "system_restore" => Some(("POST", "/system/restore")),
+        "system_verify_backup" => Some(("POST", "/system/verify_backup")),
+        "system_run_maintenance" => Some(("POST", "/system/run_maintenance")),
         // Advanced (dot notation)
-        "advanced.consolidate" => ("POST", "/advanced/consolidate"),
-        "advanced.analyze_patterns" => ("POST", "/advanced/analyze_patterns"),
-        "advanced.reindex" => ("POST", "/advanced/reindex"),
-        "advanced.trends" => ("POST", "/advanced/trends"),
-        "advanced.clusters" => ("POST", "/advanced/clusters"),
-        "advanced.relationships" => ("POST", "/advanced/relationships"),
-        "advanced.effectiveness" => ("POST", "/advanced/effectiveness"),
+        "advanced.consolidate" => Some(("POST", "/advanced/consolidate")),
+        "advanced.consolidation_log" => Some(("GET", "/advanced/consolidation_log")),
+        "advanced.analyze_patterns" => Some(("POST", "/advanced/analyze_patterns")),
+        "advanced.reindex" => Some(("POST", "/advanced/reindex")),
+        "advanced.trends" => Some(("POST", "/advanced/trends")),
+        "advanced.clusters" => Some(("POST", "/advanced/clusters")),
+        "advanced.relationships" => Some(("POST", "/advanced/relationships")),
+        "advanced.effectiveness" => Some(("POST", "/advanced/effectiveness")),
+        "advanced.recompute_effectiveness" => Some(("POST", "/advanced/recompute_effectiveness")),
         // Advanced (underscore notation)
-        "advanced_consolidate" => ("POST", "/advanced/consolidate"),
-        "advanced_analyze_patterns" => ("POST", "/advanced/analyze_patterns"),
-        "advanced_reindex" => ("POST", "/advanced/reindex"),
-        "advanced_trends" => ("POST", "/advanced/trends"),
-        "advanced_clusters" => ("POST", "/advanced/clusters"),
-        "advanced_relationships" => ("POST", "/advanced/relationships"),
-        "advanced_effectiveness" => ("POST", "/advanced/effectiveness"),
-        _ => return Err(format!("Unknown tool: {}", tool_name)),
-    };
-    let url = format!("{}{}", base, path);
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
-
-    // Retry logic for connection issues (e.g., HTTP server not ready yet)
-    let max_retries = 3;
-    let mut last_error = String::new();
-
-    for attempt in 0..max_retries {
-        if attempt > 0 {
-            // Small delay before retry
-            sleep(Duration::from_millis(100 * (attempt as u64))).await;
-        }
-
-        let resp_result = if method == "GET" {
-            let mut qp: Vec<(String, String)> = Vec::new();
-            if let Some(map) = args.as_object() {
-                for (k, v) in map.iter() {
-                    let s = if v.is_string() || v.is_number() || v.is_boolean() {
-                        v.to_string().trim_matches('"').to_string()
-                    } else {
-                        v.to_string()
-                    };
-                    qp.push((k.clone(), s));
-                }
+        "advanced_consolidate" => Some(("POST", "/advanced/consolidate")),
+        "advanced_consolidation_log" => Some(("GET", "/advanced/consolidation_log")),
+        "advanced_analyze_patterns" => Some(("POST", "/advanced/analyze_patterns")),
+        "advanced_reindex" => Some(("POST", "/advanced/reindex")),
+        "advanced_trends" => Some(("POST", "/advanced/trends")),
+        "advanced_clusters" => Some(("POST", "/advanced/clusters")),
+        "advanced_relationships" => Some(("POST", "/advanced/relationships")),
+        "advanced_effectiveness" => Some(("POST", "/advanced/effectiveness")),
+        "advanced_recompute_effectiveness" => Some(("POST", "/advanced/recompute_effectiveness")),
+        // Unified retrieval
+        "search" => Some(("GET", "/search")),
+        _ => None,
+    }
+}
+
+/// The API routes for in-process tool calls, built once. Tool calls are not HTTP traffic,
+/// so they skip the request metrics, tracing and request ids of `build_router`.
+fn tool_routes() -> &'static Router<Arc<AppState>> {
+    static ROUTES: std::sync::OnceLock<Router<Arc<AppState>>> = std::sync::OnceLock::new();
+    ROUTES.get_or_init(|| {
+        api_routes()
+            .layer(axum::extract::DefaultBodyLimit::disable())
+            .layer(tower_http::limit::RequestBodyLimitLayer::new(
+                max_body_bytes(),
+            ))
+            .layer(axum::middleware::from_fn(payload_too_large_as_json))
+    })
+}
+
+/// Run a tool call in-process through the API routes, so stdio works without a listener.
+/// GET tools take `args` as query params, POST tools as JSON.
+async fn call_tool(
+    state: Arc<AppState>,
+    tool_name: &str,
+    args: &serde_json::Value,
+) -> Result<serde_json::Value, ToolError> {
+    use tower::ServiceExt;
+
+    let (method, path) = tool_route(tool_name).ok_or_else(|| ToolError {
+        code: JSONRPC_METHOD_NOT_FOUND,
+        message: format!("Unknown tool: {}", tool_name),
+        data: None,
+    })?;
+    let request = if method == "GET" {
+        let mut qp: Vec<(String, String)> = Vec::new();
+        if let Some(map) = args.as_object() {
+            for (k, v) in map.iter() {
+                let s = if v.is_string() || v.is_number() || v.is_boolean() {
+                    v.to_string().trim_matches('"').to_string()
+                } else {
+                    v.to_string()
+                };
+                qp.push((k.clone(), s));
             }
-            client.get(&url).query(&qp).send().await
-        } else {
-            client.post(&url).json(args).send().await
+        }
+        let url = reqwest::Url::parse_with_params(&format!("http://localhost{}", path), &qp)
+            .map_err(|e| ToolError::invalid_params(format!("Invalid arguments: {}", e)))?;
+        let uri = match url.query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path.to_string(),
         };
+        axum::http::Request::get(uri).body(axum::body::Body::empty())
+    } else {
+        axum::http::Request::post(path)
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(args.to_string()))
+    }
+    .map_err(|e| ToolError::invalid_params(format!("Failed to build request: {}", e)))?;
 
-        match resp_result {
-            Ok(resp) => {
-                let status = resp.status();
-                let text = resp.text().await.unwrap_or_default();
-                if status.is_success() {
-                    if let Ok(val) = serde_json::from_str::<serde_json::Value>(&text) {
-                        return Ok(val);
-                    } else {
-                        return Ok(serde_json::Value::String(text));
-                    }
-                } else {
-                    return Err(format!("HTTP {}: {}", status.as_u16(), text));
-                }
+    let resp = tool_routes()
+        .clone()
+        .with_state(state)
+        .oneshot(request)
+        .await
+        .map_err(|e| ToolError::internal(format!("Tool call failed: {}", e)))?;
+    let status = resp.status();
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .map_err(|e| ToolError::internal(format!("Failed to read response: {}", e)))?;
+    let text = String::from_utf8_lossy(&bytes).to_string();
+    if !status.is_success() {
+        return Err(ToolError::from_response(status, &text));
+    }
+    Ok(serde_json::from_str::<serde_json::Value>(&text).unwrap_or(serde_json::Value::String(text)))
+}
+
+const JSONRPC_INVALID_PARAMS: i64 = -32602;
+const JSONRPC_METHOD_NOT_FOUND: i64 = -32601;
+const JSONRPC_INTERNAL_ERROR: i64 = -32603;
+/// Server-defined codes for domain errors.
+const JSONRPC_NOT_FOUND: i64 = -32002;
+const JSONRPC_CONFLICT: i64 = -32009;
+const JSONRPC_SERVER_ERROR: i64 = -32000;
+
+/// A failed tool call, shaped as a JSON-RPC `error` object.
+#[derive(Debug)]
+struct ToolError {
+    code: i64,
+    message: String,
+    data: Option<serde_json::Value>,
+}
+
+impl ToolError {
+    fn invalid_params(message: String) -> Self {
+        ToolError {
+            code: JSONRPC_INVALID_PARAMS,
+            message,
+            data: None,
+        }
+    }
+
+    fn internal(message: String) -> Self {
+        ToolError {
+            code: JSONRPC_INTERNAL_ERROR,
+            message,
+            data: None,
+        }
+    }
+
+    /// Map a handler's `json_error` body onto a JSON-RPC code; `data` keeps the
+    /// original error code, HTTP status and details.
+    fn from_response(status: StatusCode, body: &str) -> Self {
+        let err = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|v| v.get("error").cloned());
+        let app_code = err
+            .as_ref()
+            .and_then(|e| e.get("code"))
+            .and_then(|c| c.as_str())
+            .unwrap_or("")
+            .to_string();
+        let code = match app_code.as_str() {
+            "INVALID_INPUT" => JSONRPC_INVALID_PARAMS,
+            "NOT_FOUND" => JSONRPC_NOT_FOUND,
+            "CONFLICT" => JSONRPC_CONFLICT,
+            "INTERNAL_ERROR" => JSONRPC_INTERNAL_ERROR,
+            // Rejected by an extractor before reaching the handler
+            _ if status == StatusCode::BAD_REQUEST
+                || status == StatusCode::UNPROCESSABLE_ENTITY =>
+            {
+                JSONRPC_INVALID_PARAMS
             }
-            Err(e) => {
-                last_error = format!(
-                    "Connection failed (attempt {}/{}): {}",
-                    attempt + 1,
-                    max_retries,
-                    e
-                );
-                if attempt < max_retries - 1 {
-                    info!("Retrying HTTP request: {}", last_error);
-                }
+            _ if status.is_server_error() => JSONRPC_INTERNAL_ERROR,
+            _ => JSONRPC_SERVER_ERROR,
+        };
+        let message = err
+            .as_ref()
+            .and_then(|e| e.get("message"))
+            .and_then(|m| m.as_str())
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| format!("HTTP {}: {}", status.as_u16(), body));
+        let mut data = serde_json::json!({ "status": status.as_u16() });
+        if !app_code.is_empty() {
+            data["code"] = serde_json::json!(app_code);
+        }
+        if let Some(details) = err.and_then(|e| e.get("details").cloned()) {
+            if !details.is_null() {
+                data["details"] = details;
             }
         }
+        ToolError {
+            code,
+            message,
+            data: Some(data),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut err = serde_json::json!({ "code": self.code, "message": self.message });
+        if let Some(data) = &self.data {
+            err["data"] = data.clone();
+        }
+        err
     }
+}
+
+/// Liveness by default; `?deep=true` also probes sled, the tantivy index and the stored
+/// embeddings and answers 503 with the failing checks.
+async fn health(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    if params.get("deep").map(|v| v != "true").unwrap_or(true) {
+        return Json(Health { status: "ok" }).into_response();
+    }
+    let storage = match probe_storage(&state.db()) {
+        Ok(()) => serde_json::json!({ "ok": true }),
+        Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+    };
+    let text_index = match probe_text_index(&state.index_dir) {
+        Ok(docs) => serde_json::json!({ "ok": true, "docs": docs }),
+        Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+    };
+    let (total, invalid) = vector_index::validate_mem_embeddings(&state.db());
+    let embeddings = serde_json::json!({ "ok": invalid == 0, "total": total, "invalid": invalid });
+    let checks = serde_json::json!({
+        "storage": storage,
+        "textIndex": text_index,
+        "embeddings": embeddings,
+    });
+    let healthy = checks
+        .as_object()
+        .map(|c| c.values().all(|v| v["ok"] == true))
+        .unwrap_or(false);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "fail" },
+        "checks": checks,
+    });
+    (status, Json(body)).into_response()
+}
 
-    Err(last_error)
+/// Write, read back and delete a key in the `health_probe` tree.
+fn probe_storage(db: &sled::Db) -> Result<()> {
+    let tree = db.open_tree("health_probe")?;
+    let value = Uuid::new_v4().to_string();
+    tree.insert(b"probe", value.as_bytes())?;
+    tree.flush()?;
+    let read = tree.get(b"probe")?;
+    if read.as_deref() != Some(value.as_bytes()) {
+        anyhow::bail!("probe value did not round-trip");
+    }
+    tree.remove(b"probe")?;
+    Ok(())
 }
 
-async fn health() -> Json<Health> {
-    Json(Health { status: "ok" })
+/// Open the tantivy index and count its docs; a missing index (nothing stored yet) is fine.
+fn probe_text_index(index_dir: &std::path::Path) -> Result<u64> {
+    use tantivy::{directory::MmapDirectory, Index};
+    let dir = index_dir.join("tantivy");
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let index = Index::open(MmapDirectory::open(&dir)?)?;
+    Ok(index.reader()?.searcher().num_docs())
 }
 
 async fn status(
@@ -760,19 +1994,19 @@ async fn build_status(state: Arc<AppState>) -> StatusResponse {
     let uptime_ms = state.start_time.elapsed().as_millis();
     // Indices
     let mut idx = IndicesStatus::default();
-    if let Ok(tree) = state.db.open_tree("mem_embeddings") {
+    if let Ok(tree) = state.db().open_tree("mem_embeddings") {
         idx.vector.items += tree.iter().count() as u64;
     }
-    if let Ok(tree) = state.db.open_tree("embeddings") {
+    if let Ok(tree) = state.db().open_tree("embeddings") {
         idx.vector.items += tree.iter().count() as u64;
     }
-    if let Ok(tree) = state.db.open_tree("text_index") {
+    if let Ok(tree) = state.db().open_tree("text_index") {
         idx.text.docs = tree.iter().count() as u64;
     }
-    if let Ok(tree) = state.db.open_tree("kg_nodes") {
+    if let Ok(tree) = state.db().open_tree("kg_nodes") {
         idx.graph.nodes = tree.iter().count() as u64;
     }
-    if let Ok(tree) = state.db.open_tree("kg_edges") {
+    if let Ok(tree) = state.db().open_tree("kg_edges") {
         idx.graph.edges = tree.iter().count() as u64;
     }
     // Storage
@@ -788,7 +2022,7 @@ async fn build_status(state: Arc<AppState>) -> StatusResponse {
     // Process memory and STM/LTM counts
     let mut pm = ProcMem::default();
     pm.rss_mb = current_process_rss_mb().unwrap_or(0);
-    if let Ok(tree) = state.db.open_tree("memories") {
+    if let Ok(tree) = state.db().open_tree("memories") {
         let mut stm = 0u64;
         let mut ltm = 0u64;
         for kv in tree.iter() {
@@ -820,12 +2054,32 @@ async fn build_status(state: Arc<AppState>) -> StatusResponse {
     if metrics.p95_ms > p95_threshold as f64 || pm.rss_mb > rss_threshold_mb {
         health = "degraded";
     }
+    // Degrade if too many recent requests are failing server-side
+    let http_errors = state
+        .http_errors
+        .lock()
+        .map(|w| w.status(unix_secs()))
+        .unwrap_or_default();
+    let error_rate_threshold = std::env::var("STATUS_ERROR_RATE_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(0.05);
+    if http_errors.requests_1m >= ERROR_RATE_MIN_REQUESTS
+        && http_errors.rate_1m > error_rate_threshold
+    {
+        health = "degraded";
+    }
     StatusResponse {
         uptime_ms,
         indices: idx,
         storage,
         metrics,
         proc_mem: pm,
+        http_errors,
+        config: ConfigStatus {
+            stm_ttl_ms: stm_ttl_ms(),
+            consolidation: PromotionPolicy::load(&state.db()),
+        },
         health,
     }
 }
@@ -855,6 +2109,64 @@ async fn metrics_route(
     out.push_str(&format!("mcp_query_p95_ms {}\n", metrics.p95_ms));
     out.push_str("# TYPE mcp_query_qps_1m gauge\n");
     out.push_str(&format!("mcp_query_qps_1m {}\n", metrics.qps_1m));
+    let (emb_hits, emb_misses) = embeddings::cache_stats();
+    out.push_str("# TYPE mcp_embed_cache_hits_total counter\n");
+    out.push_str(&format!("mcp_embed_cache_hits_total {}\n", emb_hits));
+    out.push_str("# TYPE mcp_embed_cache_misses_total counter\n");
+    out.push_str(&format!("mcp_embed_cache_misses_total {}\n", emb_misses));
+    let errors = state
+        .http_errors
+        .lock()
+        .map(|w| w.status(unix_secs()))
+        .unwrap_or_default();
+    out.push_str("# TYPE mcp_http_errors_total counter\n");
+    out.push_str(&format!("mcp_http_errors_total {}\n", errors.total));
+    out.push_str("# TYPE mcp_http_error_rate_1m gauge\n");
+    out.push_str(&format!("mcp_http_error_rate_1m {}\n", errors.rate_1m));
+    let routes = state
+        .http_metrics
+        .lock()
+        .map(|r| r.clone())
+        .unwrap_or_default();
+    out.push_str("# TYPE mcp_http_requests_total counter\n");
+    for (path, m) in &routes {
+        for (status, n) in &m.by_status {
+            out.push_str(&format!(
+                "mcp_http_requests_total{{path=\"{}\",status=\"{}\"}} {}\n",
+                path, status, n
+            ));
+        }
+    }
+    out.push_str("# TYPE mcp_http_request_errors_total counter\n");
+    for (path, m) in &routes {
+        out.push_str(&format!(
+            "mcp_http_request_errors_total{{path=\"{}\"}} {}\n",
+            path, m.errors
+        ));
+    }
+    out.push_str("# TYPE mcp_http_request_duration_seconds histogram\n");
+    for (path, m) in &routes {
+        let mut cumulative = 0u64;
+        for (le, n) in HTTP_LATENCY_BUCKETS.iter().zip(m.buckets.iter()) {
+            cumulative += n;
+            out.push_str(&format!(
+                "mcp_http_request_duration_seconds_bucket{{path=\"{}\",le=\"{}\"}} {}\n",
+                path, le, cumulative
+            ));
+        }
+        out.push_str(&format!(
+            "mcp_http_request_duration_seconds_bucket{{path=\"{}\",le=\"+Inf\"}} {}\n",
+            path, m.count
+        ));
+        out.push_str(&format!(
+            "mcp_http_request_duration_seconds_sum{{path=\"{}\"}} {}\n",
+            path, m.sum_secs
+        ));
+        out.push_str(&format!(
+            "mcp_http_request_duration_seconds_count{{path=\"{}\"}} {}\n",
+            path, m.count
+        ));
+    }
     (axum::http::StatusCode::OK, out)
 }
 
@@ -896,10 +2208,19 @@ fn current_process_rss_mb() -> Option<u64> {
 
 fn list_tools() -> Vec<ToolDescriptor> {
     vec![
+        ToolDescriptor {
+            name: "search",
+            description:
+                "Memories and document chunks in one ranked list, each tagged with its kind",
+        },
         ToolDescriptor {
             name: "memory.add",
             description: "Add a memory entry",
         },
+        ToolDescriptor {
+            name: "memory.add_batch",
+            description: "Add many memory entries in one call",
+        },
         ToolDescriptor {
             name: "memory.search",
             description: "Hybrid search across indices",
@@ -912,6 +2233,46 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "memory.delete",
             description: "Delete a memory entry",
         },
+        ToolDescriptor {
+            name: "memory.delete_batch",
+            description: "Delete many memories by id",
+        },
+        ToolDescriptor {
+            name: "memory.delete_where",
+            description: "Delete memories matching a session, episode, layer or age filter",
+        },
+        ToolDescriptor {
+            name: "memory.undelete",
+            description: "Restore a soft-deleted memory",
+        },
+        ToolDescriptor {
+            name: "memory.dedupe",
+            description: "Find and merge near-duplicate memories",
+        },
+        ToolDescriptor {
+            name: "memory.episode",
+            description: "List an episode's memories in chronological order",
+        },
+        ToolDescriptor {
+            name: "memory.context",
+            description: "Load a session's most relevant memories within a character budget",
+        },
+        ToolDescriptor {
+            name: "memory.pin",
+            description: "Pin or unpin a memory so maintenance never evicts it",
+        },
+        ToolDescriptor {
+            name: "memory.relate",
+            description: "Relate two memories (e.g. SUPPORTS, CONTRADICTS, FOLLOWS)",
+        },
+        ToolDescriptor {
+            name: "memory.related",
+            description: "List memories related to a memory",
+        },
+        ToolDescriptor {
+            name: "memory.get",
+            description: "Fetch one memory's full record by id",
+        },
         ToolDescriptor {
             name: "document.store",
             description: "Ingest a document",
@@ -920,10 +2281,30 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "document.retrieve",
             description: "Retrieve a document",
         },
+        ToolDescriptor {
+            name: "document.list",
+            description: "List stored documents",
+        },
+        ToolDescriptor {
+            name: "document.versions",
+            description: "List the stored versions of a document path",
+        },
+        ToolDescriptor {
+            name: "document.rollback",
+            description: "Point a document path back at an earlier version",
+        },
+        ToolDescriptor {
+            name: "document.diff",
+            description: "Line-level diff between two versions of a document",
+        },
         ToolDescriptor {
             name: "document.analyze",
             description: "Analyze a document",
         },
+        ToolDescriptor {
+            name: "document.job",
+            description: "Poll the indexing status of a stored document",
+        },
         ToolDescriptor {
             name: "document.refs_for_memory",
             description: "List document references for a memory",
@@ -960,6 +2341,39 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "kg.read_graph",
             description: "Get graph snapshot with configurable limit",
         },
+        ToolDescriptor {
+            name: "kg.path",
+            description: "Shortest relation path between two nodes",
+        },
+        ToolDescriptor {
+            name: "kg.neighbors",
+            description: "N-hop neighborhood of a node",
+        },
+        ToolDescriptor {
+            name: "kg.subgraph",
+            description: "Typed nodes and edges within N hops of a seed node, capped by limit",
+        },
+        ToolDescriptor {
+            name: "kg.repair",
+            description: "Report or fix dangling edges, stale document links and entity counts",
+        },
+        ToolDescriptor {
+            name: "kg.cooccurrence",
+            description: "Rebuild entity co-occurrence edges and list the strongest pairs",
+        },
+        ToolDescriptor {
+            name: "kg.centrality",
+            description: "Rank graph nodes by PageRank centrality",
+        },
+        ToolDescriptor {
+            name: "kg.stats",
+            description:
+                "Node counts by type, edge counts by relation and the most-connected nodes",
+        },
+        ToolDescriptor {
+            name: "kg.export",
+            description: "Export the graph as GraphML or Graphviz DOT",
+        },
         ToolDescriptor {
             name: "kg.tag_entity",
             description: "Add tags to an entity",
@@ -976,6 +2390,10 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "kg.delete_entity",
             description: "Delete an entity and its edges",
         },
+        ToolDescriptor {
+            name: "kg.merge_entity",
+            description: "Merge an alias entity into its canonical entity",
+        },
         ToolDescriptor {
             name: "kg.delete_relation",
             description: "Delete a specific relation",
@@ -996,10 +2414,22 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "system.restore",
             description: "Restore from backup",
         },
+        ToolDescriptor {
+            name: "system.verify_backup",
+            description: "Check a backup against its checksums",
+        },
+        ToolDescriptor {
+            name: "system.run_maintenance",
+            description: "Run STM expiry, promotion, LTM decay and eviction once, reporting counts",
+        },
         ToolDescriptor {
             name: "advanced.consolidate",
             description: "Promote STM to LTM",
         },
+        ToolDescriptor {
+            name: "advanced.consolidation_log",
+            description: "History of STM to LTM promotions",
+        },
         ToolDescriptor {
             name: "advanced.analyze_patterns",
             description: "Analyze memory patterns",
@@ -1014,7 +2444,7 @@ fn list_tools() -> Vec<ToolDescriptor> {
         },
         ToolDescriptor {
             name: "advanced.clusters",
-            description: "Cross-document clusters via RELATED edges",
+            description: "Document clusters via RELATED edges, or memory clusters by embedding",
         },
         ToolDescriptor {
             name: "advanced.relationships",
@@ -1024,11 +2454,296 @@ fn list_tools() -> Vec<ToolDescriptor> {
             name: "advanced.effectiveness",
             description: "Memory effectiveness scoring",
         },
+        ToolDescriptor {
+            name: "advanced.recompute_effectiveness",
+            description: "Store effectiveness scores on memories for boostEffectiveness ranking",
+        },
     ]
 }
 
-async fn list_tools_route() -> Json<Vec<ToolDescriptor>> {
-    Json(list_tools())
+async fn list_tools_route() -> Json<Vec<serde_json::Value>> {
+    Json(list_tools().iter().map(ToolDescriptor::to_json).collect())
+}
+
+/// Object schema with the given properties; `required` names must appear in `props`.
+fn object_schema(props: serde_json::Value, required: &[&str]) -> serde_json::Value {
+    let mut schema = serde_json::json!({ "type": "object", "properties": props });
+    if !required.is_empty() {
+        schema["required"] = serde_json::json!(required);
+    }
+    schema
+}
+
+/// Argument schemas per tool; names without an entry accept any object.
+fn tool_input_schema(name: &str) -> serde_json::Value {
+    use serde_json::json;
+    let string = json!({ "type": "string" });
+    let integer = json!({ "type": "integer", "minimum": 0 });
+    let number = json!({ "type": "number" });
+    let boolean = json!({ "type": "boolean" });
+    let references = json!({
+        "type": "array",
+        "items": object_schema(
+            json!({ "docId": string, "chunkId": string, "score": number }),
+            &["docId"],
+        ),
+    });
+    let memory_item = object_schema(
+        json!({
+            "content": string,
+            "metadata": { "type": "object" },
+            "layer_hint": { "type": "string", "enum": ["STM", "LTM"] },
+            "session_id": string,
+            "episode_id": string,
+            "references": references,
+            "importance": { "type": "number", "minimum": 0 },
+            "ttlMs": { "type": "integer" },
+            "pinned": boolean,
+            "autoLinkDocs": boolean,
+        }),
+        &["content"],
+    );
+    let src_dst_relation = object_schema(
+        json!({ "src": string, "dst": string, "relation": string }),
+        &["src", "dst"],
+    );
+    let entity_tags = object_schema(
+        json!({ "entity": string, "tags": { "type": "array", "items": string } }),
+        &["entity", "tags"],
+    );
+    let id_only = object_schema(json!({ "id": string }), &["id"]);
+    // Underscore aliases (`memory_add`) share the dot-notation schema
+    let base = if name.contains('.') {
+        name.to_string()
+    } else {
+        name.replacen('_', ".", 1)
+    };
+    match base.as_str() {
+        "search" => object_schema(
+            json!({
+                "q": string,
+                "types": { "type": "string", "description": "comma-separated: memory, document" },
+                "limit": integer,
+                "offset": integer,
+                "cursor": string,
+                "countTotal": boolean,
+                "highlight": boolean,
+                "from": { "type": "integer", "description": "created_at lower bound (epoch ms)" },
+                "to": { "type": "integer", "description": "created_at upper bound (epoch ms)" },
+                "fusion": { "type": "string", "enum": ["weighted", "rrf"] },
+                "boostEffectiveness": { "type": "number", "minimum": 0 },
+            }),
+            &["q"],
+        ),
+        "memory.add" => memory_item,
+        "memory.add_batch" => object_schema(
+            json!({ "items": { "type": "array", "items": memory_item } }),
+            &["items"],
+        ),
+        "memory.search" => {
+            let mut schema = object_schema(
+                json!({
+                    "q": string,
+                    "limit": integer,
+                    "offset": integer,
+                    "cursor": string,
+                    "countTotal": boolean,
+                    "highlight": boolean,
+                    "layer": { "type": "string", "enum": ["STM", "LTM"] },
+                    "episode": string,
+                    "from": { "type": "integer", "description": "created_at lower bound (epoch ms)" },
+                    "to": { "type": "integer", "description": "created_at upper bound (epoch ms)" },
+                    "boostEffectiveness": { "type": "number", "minimum": 0 },
+                }),
+                &["q"],
+            );
+            schema["patternProperties"] = json!({ "^meta\\.": string });
+            schema
+        }
+        "memory.update" => object_schema(
+            json!({ "id": string, "content": string, "metadata": { "type": "object" } }),
+            &["id"],
+        ),
+        "memory.delete" => object_schema(
+            json!({ "id": string, "backup": boolean, "soft": boolean }),
+            &["id"],
+        ),
+        "memory.delete_batch" => object_schema(
+            json!({ "ids": { "type": "array", "items": string } }),
+            &["ids"],
+        ),
+        "memory.delete_where" => object_schema(
+            json!({
+                "session_id": string,
+                "episode_id": string,
+                "layer": { "type": "string", "enum": ["STM", "LTM"] },
+                "olderThan": { "type": "integer", "description": "created_at upper bound (epoch ms)" },
+                "confirmAll": boolean,
+            }),
+            &[],
+        ),
+        "memory.undelete"
+        | "document.analyze"
+        | "document.job"
+        | "document.refs_for_memory"
+        | "document.refs_for_document" => id_only,
+        "memory.dedupe" => object_schema(
+            json!({
+                "threshold": { "type": "number", "exclusiveMinimum": 0, "maximum": 1 },
+                "dryRun": boolean,
+            }),
+            &[],
+        ),
+        "memory.episode" => id_only,
+        "memory.context" => object_schema(
+            json!({ "session": string, "maxChars": integer }),
+            &["session"],
+        ),
+        "memory.pin" => object_schema(
+            json!({ "id": string, "pinned": boolean }),
+            &["id", "pinned"],
+        ),
+        "memory.relate" => object_schema(
+            json!({ "src": string, "dst": string, "relation": string }),
+            &["src", "dst", "relation"],
+        ),
+        "memory.related" => object_schema(json!({ "id": string, "relation": string }), &["id"]),
+        "memory.get" => object_schema(json!({ "id": string, "touch": boolean }), &["id"]),
+        "document.store" => object_schema(
+            json!({
+                "path": string,
+                "url": string,
+                "mime": string,
+                "content": string,
+                "metadata": { "type": "object" },
+                "chunkSize": integer,
+                "chunkOverlap": integer,
+                "sync": boolean,
+                "dryRun": boolean,
+            }),
+            &[],
+        ),
+        "document.retrieve" => object_schema(
+            json!({ "id": string, "hash": string, "path": string, "withText": boolean }),
+            &[],
+        ),
+        "document.list" => object_schema(json!({ "limit": integer, "offset": integer }), &[]),
+        "document.versions" => object_schema(json!({ "path": string }), &["path"]),
+        "document.rollback" => object_schema(
+            json!({ "path": string, "version": integer }),
+            &["path", "version"],
+        ),
+        "document.diff" => object_schema(
+            json!({ "path": string, "from": integer, "to": integer }),
+            &["path", "from", "to"],
+        ),
+        "document.validate_refs" | "kg.repair" => object_schema(json!({ "fix": boolean }), &[]),
+        "kg.list_entities" => object_schema(json!({ "limit": integer }), &[]),
+        "kg.read_graph" => object_schema(
+            json!({ "limit": integer, "from": integer, "to": integer }),
+            &[],
+        ),
+        "kg.get_entity" | "kg.create_entity" | "kg.delete_entity" => {
+            object_schema(json!({ "entity": string }), &["entity"])
+        }
+        "kg.create_relation" | "kg.delete_relation" => src_dst_relation,
+        "kg.merge_entity" => {
+            object_schema(json!({ "from": string, "into": string }), &["from", "into"])
+        }
+        "kg.search_nodes" => object_schema(
+            json!({
+                "pattern": string,
+                "type": string,
+                "limit": integer,
+                "fuzzy": boolean,
+                "maxDistance": integer,
+            }),
+            &[],
+        ),
+        "kg.path" => object_schema(
+            json!({ "src": string, "dst": string, "maxDepth": integer }),
+            &["src", "dst"],
+        ),
+        "kg.neighbors" => object_schema(json!({ "node": string, "depth": integer }), &["node"]),
+        "kg.subgraph" => object_schema(
+            json!({ "node": string, "depth": integer, "limit": integer }),
+            &["node"],
+        ),
+        "kg.cooccurrence" => object_schema(json!({ "minCount": integer, "limit": integer }), &[]),
+        "kg.centrality" => object_schema(
+            json!({
+                "limit": integer,
+                "iterations": integer,
+                "damping": { "type": "number", "exclusiveMinimum": 0, "exclusiveMaximum": 1 },
+                "type": string,
+            }),
+            &[],
+        ),
+        "kg.stats" => object_schema(json!({ "top": integer }), &[]),
+        "kg.export" => object_schema(
+            json!({ "format": { "type": "string", "enum": ["graphml", "dot"] }, "limit": integer }),
+            &[],
+        ),
+        "kg.tag_entity" | "kg.remove_tag" => entity_tags,
+        "kg.get_tags" => object_schema(json!({ "tag": string }), &[]),
+        "system.cleanup" => object_schema(json!({ "reindex": boolean, "compact": boolean }), &[]),
+        "system.backup" => object_schema(
+            json!({
+                "destination": string,
+                "includeIndices": boolean,
+                "compress": boolean,
+                "baseSnapshot": string
+            }),
+            &[],
+        ),
+        "system.restore" => object_schema(
+            json!({ "source": string, "includeIndices": boolean, "verify": boolean }),
+            &["source"],
+        ),
+        "system.verify_backup" => object_schema(json!({ "source": string }), &["source"]),
+        "system.run_maintenance" => object_schema(json!({}), &[]),
+        "advanced.consolidate" => {
+            object_schema(json!({ "dryRun": boolean, "limit": integer }), &[])
+        }
+        "advanced.consolidation_log" => object_schema(
+            json!({ "from": integer, "to": integer, "limit": integer }),
+            &[],
+        ),
+        "advanced.analyze_patterns" => object_schema(
+            json!({
+                "window": { "type": "object", "properties": { "from": integer, "to": integer } },
+                "minSupport": integer,
+                "sensitivity": { "type": "number", "minimum": 0 },
+            }),
+            &[],
+        ),
+        "advanced.reindex" => object_schema(
+            json!({ "vector": boolean, "text": boolean, "graph": boolean }),
+            &[],
+        ),
+        "advanced.trends" => object_schema(
+            json!({
+                "from": integer,
+                "to": integer,
+                "buckets": integer,
+                "entities": { "type": "array", "items": string },
+                "topN": integer,
+            }),
+            &[],
+        ),
+        "advanced.clusters" => object_schema(
+            json!({
+                "method": { "type": "string", "enum": ["kg", "embedding"] },
+                "threshold": { "type": "number", "exclusiveMinimum": 0, "maximum": 1 },
+            }),
+            &[],
+        ),
+        "advanced.relationships" => object_schema(json!({ "from": integer, "to": integer }), &[]),
+        "system.status" | "advanced.effectiveness" | "advanced.recompute_effectiveness" => {
+            object_schema(json!({}), &[])
+        }
+        _ => json!({ "type": "object", "properties": {}, "additionalProperties": true }),
+    }
 }
 
 async fn document_store(
@@ -1036,13 +2751,26 @@ async fn document_store(
     Json(req): Json<StoreDocRequest>,
 ) -> Response {
     let _permit = state.ingest_sema.acquire().await.expect("sema");
-    let mime = req.mime.unwrap_or_else(|| "md".to_string());
-    let content = if let Some(c) = req.content {
-        c
+    let (default_size, default_overlap) = default_chunking();
+    let chunk_size = req.chunk_size.unwrap_or(default_size);
+    let chunk_overlap = req.chunk_overlap.unwrap_or(default_overlap);
+    if chunk_size == 0 || chunk_overlap >= chunk_size {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "chunkOverlap must be smaller than chunkSize",
+            Some(serde_json::json!({"chunkSize": chunk_size, "chunkOverlap": chunk_overlap})),
+        );
+    }
+    let kind = detect_doc_kind(req.mime.as_deref(), req.path.as_deref());
+    // Text and markdown files are streamed, once to hash and once to chunk, so large files
+    // never sit in memory whole; HTML and PDF conversion needs the full document
+    let text = if let Some(c) = req.content {
+        DocText::Loaded(if kind == "html" { html_to_text(&c) } else { c })
     } else if let Some(path) = req.path.clone() {
-        if (mime == "pdf") || path.to_lowercase().ends_with(".pdf") {
+        if kind == "pdf" {
             match read_pdf_text(&path) {
-                Ok(t) => t,
+                Ok(pages) => DocText::Pages(pages),
                 Err(_) => {
                     return json_error(
                         StatusCode::NOT_FOUND,
@@ -1052,15 +2780,9 @@ async fn document_store(
                     )
                 }
             }
-        } else {
+        } else if kind == "html" {
             match std::fs::read_to_string(&path) {
-                Ok(raw) => {
-                    if mime == "md" || path.to_lowercase().ends_with(".md") {
-                        markdown_to_text(&raw)
-                    } else {
-                        raw
-                    }
-                }
+                Ok(raw) => DocText::Loaded(html_to_text(&raw)),
                 Err(_) => {
                     return json_error(
                         StatusCode::NOT_FOUND,
@@ -1070,37 +2792,54 @@ async fn document_store(
                     )
                 }
             }
+        } else {
+            DocText::Streamed(path)
+        }
+    } else if let Some(url) = req.url.as_deref() {
+        match fetch_document_text(url, req.mime.as_deref()).await {
+            Ok(text) => text,
+            Err(resp) => return resp,
         }
     } else {
         return json_error(
             StatusCode::BAD_REQUEST,
             "INVALID_INPUT",
-            "Provide either content or path",
+            "Provide content, path or url",
             None,
         );
     };
+    let doc_path = req.path.clone().or_else(|| req.url.clone());
     let mut hasher = Sha256::new();
-    hasher.update(content.as_bytes());
+    if let Err(err) = text.feed(kind, |_, t| hasher.update(t.as_bytes())) {
+        return read_error(&err, req.path.as_deref());
+    }
     let hash = format!("{:x}", hasher.finalize());
+    if req.dry_run {
+        return preview_document(&state.db(), &text, kind, hash, chunk_size, chunk_overlap);
+    }
 
-    // Trees used for documents and versioning
-    let docs = state.db.open_tree("docs").expect("docs tree"); // hash -> id
-    let docs_info = state.db.open_tree("docs_info").expect("docs_info tree"); // id -> {path, hash, version, prev_id, created_at}
-    let path_latest = state
-        .db
-        .open_tree("doc_path_latest")
-        .expect("path latest tree"); // path -> id
-    let versions = state
-        .db
-        .open_tree("doc_versions")
-        .expect("doc versions tree"); // path:version -> id
+    // Trees used for documents and versioning: hash -> id, id -> {path, hash, version,
+    // prev_id, created_at}, path -> id and path:version -> id
+    let [docs, docs_info, path_latest, versions] = match open_trees(
+        &state.db(),
+        ["docs", "docs_info", "doc_path_latest", "doc_versions"],
+    ) {
+        Ok(trees) => trees,
+        Err(e) => return internal_error(e),
+    };
 
     // Dedup: check docs tree by hash
     if let Ok(Some(existing)) = docs.get(hash.as_bytes()) {
         let id =
             String::from_utf8(existing.to_vec()).unwrap_or_else(|_| Uuid::new_v4().to_string());
+        let mut version = docs_info
+            .get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
+            .and_then(|v| v.get("version").and_then(|n| n.as_u64()));
         // If a path is provided, ensure version mappings exist
-        if let Some(ref p) = req.path {
+        if let Some(ref p) = doc_path {
             let prev_id = path_latest
                 .get(p.as_bytes())
                 .ok()
@@ -1120,7 +2859,7 @@ async fn document_store(
             let ver = if prev_id.as_deref() == Some(&id) {
                 prev_version
             } else {
-                prev_version + 1
+                next_doc_version(&versions, p)
             };
             // Upsert docs_info for id if missing
             if docs_info.get(id.as_bytes()).ok().flatten().is_none() {
@@ -1130,105 +2869,434 @@ async fn document_store(
             let _ = path_latest.insert(p.as_bytes(), id.as_bytes());
             let ver_key = format!("{}:{}", p, ver);
             let _ = versions.insert(ver_key.as_bytes(), id.as_bytes());
+            version = Some(ver);
         }
+        let status = get_job_record(&state.db(), &id)
+            .map(|rec| rec.status)
+            .unwrap_or_else(|| "done".to_string());
         return Json(StoreDocResponse {
             id,
             hash,
             chunks: 0,
+            status,
+            deduped: true,
+            version,
         })
         .into_response();
     }
 
     let id = Uuid::new_v4().to_string();
-    docs.insert(hash.as_bytes(), id.as_bytes())
-        .expect("insert doc");
+    if let Err(e) = docs.insert(hash.as_bytes(), id.as_bytes()) {
+        return internal_error(e);
+    }
     // Persist minimal metadata so request.metadata is used and not warned
     if let Some(meta) = req.metadata {
-        let meta_tree = state.db.open_tree("docs_meta").expect("docs_meta tree");
+        let meta_tree = match state.db().open_tree("docs_meta") {
+            Ok(tree) => tree,
+            Err(e) => return internal_error(e),
+        };
         let key = format!("{}:meta", id);
         let val = serde_json::to_vec(&meta).unwrap_or_else(|_| b"{}".to_vec());
         let _ = meta_tree.insert(key.as_bytes(), val);
     }
     // Versioning if path is provided
-    if let Some(ref p) = req.path {
+    let mut version = 1;
+    if let Some(ref p) = doc_path {
         let prev_id = path_latest
             .get(p.as_bytes())
             .ok()
             .flatten()
             .map(|v| String::from_utf8(v.to_vec()).unwrap_or_default());
-        let prev_version = prev_id
-            .as_ref()
-            .and_then(|pid| {
-                docs_info
-                    .get(pid.as_bytes())
-                    .ok()
-                    .flatten()
-                    .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
-                    .and_then(|v| v.get("version").and_then(|n| n.as_u64()))
-            })
-            .unwrap_or(0);
-        let ver = prev_version + 1;
-        let info = serde_json::json!({"path": p, "hash": hash, "version": ver, "prev_id": prev_id, "created_at": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() });
+        // After a rollback the latest id may not be the highest version
+        let ver = next_doc_version(&versions, p);
+        version = ver;
+        let info = serde_json::json!({"path": p, "hash": hash, "version": ver, "prev_id": prev_id, "created_at": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis(), "chunk_size": chunk_size, "chunk_overlap": chunk_overlap });
         let _ = docs_info.insert(id.as_bytes(), serde_json::to_vec(&info).unwrap());
         let _ = path_latest.insert(p.as_bytes(), id.as_bytes());
         let ver_key = format!("{}:{}", p, ver);
         let _ = versions.insert(ver_key.as_bytes(), id.as_bytes());
+    } else {
+        let info = serde_json::json!({"path": null, "hash": hash, "version": 1, "prev_id": null, "created_at": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis(), "chunk_size": chunk_size, "chunk_overlap": chunk_overlap });
+        let _ = docs_info.insert(id.as_bytes(), serde_json::to_vec(&info).unwrap());
     }
-    let chunks = chunk_markdown(&content);
-    let chunks_tree = state.db.open_tree("chunks").expect("chunks tree");
-    for ch in &chunks {
-        let key = format!("{}:{}", id, ch.position.start);
-        let val = serde_json::to_vec(ch).unwrap();
-        chunks_tree
-            .insert(key.as_bytes(), val)
-            .expect("insert chunk");
-    }
-    // batch embed placeholders and persist
-    let emb_tree = state.db.open_tree("embeddings").expect("embeddings tree");
-    let texts: Vec<&str> = chunks.iter().map(|_| "").collect();
-    let vecs = embeddings::embed_batch(&texts);
-    for (idx, ch) in chunks.iter().enumerate() {
-        let key = format!("{}:{}", id, ch.position.start);
-        let bytes: &[u8] = bytemuck::cast_slice(&vecs[idx]);
-        emb_tree.insert(key.as_bytes(), bytes).expect("insert emb");
-    }
-    // update vector index scaffold metadata
-    let starts: Vec<usize> = chunks.iter().map(|c| c.position.start).collect();
-    vector_index::record_vectors(&state.db, &id, &starts, embeddings::EMBED_DIM).expect("vec meta");
-    // extract and link entities (basic heuristic)
-    let entities = kg::extract_entities(&content);
-    kg::link_entities(&state.db, &id, &entities).expect("kg link");
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
+    let job = IndexJob {
+        hash: hash.clone(),
+        kind: kind.to_string(),
+        text,
+        chunk_size,
+        chunk_overlap,
+    };
+    // Without a running worker (e.g. during shutdown) the job runs inline below
+    if !req.sync && !state.index_jobs.is_closed() {
+        put_job_record(&state.db(), &id, &JobRecord::pending(job));
+        // A job that misses the worker stays pending and is queued again at startup
+        let _ = state.index_jobs.send(id.clone());
+        return Json(StoreDocResponse {
+            id,
+            hash,
+            chunks: 0,
+            status: "pending".to_string(),
+            deduped: false,
+            version: Some(version),
+        })
+        .into_response();
+    }
+    match run_index_job(&state, &id, &job) {
+        Ok(chunks) => Json(StoreDocResponse {
+            id,
+            hash,
+            chunks,
+            status: "done".to_string(),
+            deduped: false,
+            version: Some(version),
+        })
+        .into_response(),
+        Err(IndexFailure::Changed) => json_error(
+            StatusCode::CONFLICT,
+            "CONFLICT",
+            "File changed while it was being read",
+            Some(serde_json::json!({"path": req.path})),
+        ),
+        Err(IndexFailure::Failed(err)) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            Some(serde_json::json!({"id": id})),
+        ),
+    }
+}
+
+/// Chunking, embedding, indexing and KG linking for a stored document, deferred to the
+/// index worker unless the store was `sync`.
+#[derive(Serialize, Deserialize)]
+struct IndexJob {
+    hash: String,
+    kind: String,
+    text: DocText,
+    chunk_size: usize,
+    chunk_overlap: usize,
+}
+
+/// Progress of a document's indexing in the `doc_jobs` tree; the job itself is kept only
+/// while pending, so queued work survives a restart.
+#[derive(Serialize, Deserialize)]
+struct JobRecord {
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    job: Option<IndexJob>,
+    #[serde(default)]
+    chunks: Option<usize>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl JobRecord {
+    fn pending(job: IndexJob) -> Self {
+        Self {
+            status: "pending".to_string(),
+            job: Some(job),
+            chunks: None,
+            error: None,
+        }
+    }
+}
+
+fn put_job_record(db: &sled::Db, doc_id: &str, rec: &JobRecord) {
+    if let Ok(tree) = db.open_tree("doc_jobs") {
+        let _ = tree.insert(
+            doc_id.as_bytes(),
+            serde_json::to_vec(rec).unwrap_or_default(),
+        );
+    }
+}
+
+fn get_job_record(db: &sled::Db, doc_id: &str) -> Option<JobRecord> {
+    let raw = db
+        .open_tree("doc_jobs")
+        .ok()?
+        .get(doc_id.as_bytes())
+        .ok()??;
+    serde_json::from_slice(&raw).ok()
+}
+
+/// Response for a document file that could not be read: 400 for invalid UTF-8, 404 when
+/// it is missing, 500 otherwise.
+fn read_error(err: &std::io::Error, path: Option<&str>) -> Response {
+    match err.kind() {
+        std::io::ErrorKind::InvalidData => json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "File is not valid UTF-8",
+            Some(serde_json::json!({"path": path})),
+        ),
+        std::io::ErrorKind::NotFound => json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "File not found",
+            Some(serde_json::json!({"path": path})),
+        ),
+        _ => internal_error(err),
+    }
+}
+
+enum IndexFailure {
+    /// The source file changed between hashing and chunking
+    Changed,
+    Failed(anyhow::Error),
+}
+
+impl std::fmt::Display for IndexFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexFailure::Changed => write!(f, "file changed while it was being read"),
+            IndexFailure::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Run an index job, returning the number of chunks stored. On failure the partial
+/// document is discarded so it is neither listed, searched nor deduplicated against.
+fn run_index_job(
+    state: &AppState,
+    id: &str,
+    job: &IndexJob,
+) -> std::result::Result<usize, IndexFailure> {
+    let db = state.db();
+    let result = index_document(state, &db, id, job);
+    if result.is_err() {
+        if let Err(err) = discard_document(state, &db, id, &job.hash) {
+            error!(doc = %id, %err, "failed to discard partially indexed document");
+        }
+    }
+    result
+}
+
+/// Remove a document whose indexing failed: the chunks written so far (headers, vectors and
+/// both text indexes), its hash and metadata entries, and its version, pointing the path
+/// back at the version it replaced.
+fn discard_document(state: &AppState, db: &sled::Db, id: &str, hash: &str) -> Result<()> {
+    let [docs, docs_info, path_latest, versions, chunks, emb, text_idx] = open_trees(
+        db,
+        [
+            "docs",
+            "docs_info",
+            "doc_path_latest",
+            "doc_versions",
+            "chunks",
+            "embeddings",
+            "text_index",
+        ],
+    )?;
+    let prefix = format!("{}:", id);
+    let keys: Vec<sled::IVec> = chunks
+        .scan_prefix(prefix.as_bytes())
+        .keys()
+        .collect::<std::result::Result<_, _>>()?;
+    for key in keys {
+        chunks.remove(&key)?;
+        emb.remove(&key)?;
+        text_idx.remove(&key)?;
+        state.text_index.delete(&String::from_utf8_lossy(&key))?;
+    }
+    if docs.get(hash.as_bytes())?.as_deref() == Some(id.as_bytes()) {
+        docs.remove(hash.as_bytes())?;
+    }
+    if let Some(raw) = docs_info.remove(id.as_bytes())? {
+        let info: serde_json::Value = serde_json::from_slice(&raw).unwrap_or_default();
+        if let Some(path) = info.get("path").and_then(|p| p.as_str()) {
+            if let Some(ver) = info.get("version").and_then(|v| v.as_u64()) {
+                let ver_key = format!("{}:{}", path, ver);
+                if versions.get(ver_key.as_bytes())?.as_deref() == Some(id.as_bytes()) {
+                    versions.remove(ver_key.as_bytes())?;
+                }
+            }
+            if path_latest.get(path.as_bytes())?.as_deref() == Some(id.as_bytes()) {
+                match info.get("prev_id").and_then(|p| p.as_str()) {
+                    Some(prev) => path_latest.insert(path.as_bytes(), prev.as_bytes())?,
+                    None => path_latest.remove(path.as_bytes())?,
+                };
+            }
+        }
+    }
+    db.open_tree("docs_meta")?
+        .remove(format!("{}:meta", id).as_bytes())?;
+    state.invalidate_query_cache();
+    Ok(())
+}
+
+fn index_document(
+    state: &AppState,
+    db: &sled::Db,
+    id: &str,
+    job: &IndexJob,
+) -> std::result::Result<usize, IndexFailure> {
+    let mut chunker = ChunkStream::new(job.chunk_size, job.chunk_overlap);
+    let mut sink = ChunkSink {
+        db: db.clone(),
+        text_index: &state.text_index,
+        doc_id: id,
+        starts: Vec::new(),
+        entities: Default::default(),
+    };
+    let mut write_err = None;
+    // Hash what is chunked: the file may have changed since the store hashed it
+    let mut hasher = Sha256::new();
+    let fed = job.text.feed(&job.kind, |page, t| {
+        hasher.update(t.as_bytes());
+        if let Some(page) = page {
+            chunker.start_page(page);
+        }
+        chunker.push(t);
+        if chunker.ready.len() >= CHUNK_WRITE_BATCH && write_err.is_none() {
+            write_err = sink.write(chunker.take()).err();
+        }
+    });
+    if let Err(err) = fed {
+        return Err(IndexFailure::Failed(err.into()));
+    }
+    if let Some(err) = write_err {
+        return Err(IndexFailure::Failed(err));
+    }
+    if format!("{:x}", hasher.finalize()) != job.hash {
+        return Err(IndexFailure::Changed);
+    }
+    chunker.finish();
+    sink.write(chunker.take()).map_err(IndexFailure::Failed)?;
+    let ChunkSink {
+        starts, entities, ..
+    } = sink;
+    // update vector index scaffold metadata
+    vector_index::record_vectors(db, id, &starts, embeddings::EMBED_DIM)
+        .map_err(IndexFailure::Failed)?;
+    // extract and link entities (basic heuristic)
+    let entities = kg::canonicalize_entities(db, entities.into_iter().collect());
+    kg::link_entities(db, id, &entities).map_err(IndexFailure::Failed)?;
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
         .as_millis() as i64;
-    kg::ensure_document_node(&state.db, &id, now_ms).ok();
+    kg::ensure_document_node(db, id, now_ms).ok();
     for e in &entities {
-        kg::ensure_entity_node(&state.db, e, now_ms).ok();
-        kg::add_edge(&state.db, e, &id, "MENTIONS", now_ms).ok();
+        kg::record_entity_mention(db, e, now_ms).ok();
+        kg::add_edge(db, e, id, "MENTIONS", now_ms).ok();
     }
     // Relate to existing documents by shared entities (best-effort)
-    if let Ok(existing) = state.db.open_tree("doc_path_latest") {
+    if let Ok(existing) = db.open_tree("doc_path_latest") {
         // iterate latest known docs
         for kv in existing.iter() {
             if let Ok((_, v)) = kv {
                 if let Ok(other_id) = String::from_utf8(v.to_vec()) {
                     if other_id != id {
-                        kg::relate_documents_by_entities(&state.db, &id, &other_id, now_ms).ok();
+                        kg::relate_documents_by_entities(db, id, &other_id, now_ms).ok();
                     }
                 }
             }
         }
     }
-    index_chunks_tantivy(&state.index_dir, &id, &chunks, &content).expect("index tantivy");
-    index_chunks_sled(&state.db, &id, &chunks, &content).expect("index text");
-    state.db.flush().expect("flush");
-    Json(StoreDocResponse {
-        id,
-        hash,
-        chunks: chunks.len(),
-    })
-    .into_response()
+    state.invalidate_query_cache();
+    db.flush().map_err(|e| IndexFailure::Failed(e.into()))?;
+    Ok(starts.len())
+}
+
+/// Consume queued document ids, running each pending job off the async runtime and
+/// recording its outcome in `doc_jobs`.
+async fn index_worker(
+    state: Arc<AppState>,
+    mut jobs: tokio::sync::mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(id) = jobs.recv().await {
+        let Ok(_permit) = state.ingest_sema.acquire().await else {
+            break;
+        };
+        let Some(JobRecord { job: Some(job), .. }) = get_job_record(&state.db(), &id) else {
+            continue;
+        };
+        let worker_state = state.clone();
+        let doc_id = id.clone();
+        let outcome =
+            task::spawn_blocking(move || run_index_job(&worker_state, &doc_id, &job)).await;
+        let rec = match outcome {
+            Ok(Ok(chunks)) => JobRecord {
+                status: "done".to_string(),
+                job: None,
+                chunks: Some(chunks),
+                error: None,
+            },
+            Ok(Err(err)) => JobRecord {
+                status: "failed".to_string(),
+                job: None,
+                chunks: None,
+                error: Some(err.to_string()),
+            },
+            Err(err) => JobRecord {
+                status: "failed".to_string(),
+                job: None,
+                chunks: None,
+                error: Some(err.to_string()),
+            },
+        };
+        if rec.error.is_some() {
+            error!(doc = %id, error = ?rec.error, "document indexing failed");
+        }
+        put_job_record(&state.db(), &id, &rec);
+    }
+}
+
+/// Queue again every job left pending by a previous run. Returns how many were queued.
+fn requeue_index_jobs(state: &AppState) -> usize {
+    let Ok(tree) = state.db().open_tree("doc_jobs") else {
+        return 0;
+    };
+    let mut queued = 0;
+    for (k, v) in tree.iter().flatten() {
+        let pending = serde_json::from_slice::<JobRecord>(&v)
+            .map(|rec| rec.job.is_some())
+            .unwrap_or(false);
+        if pending
+            && state
+                .index_jobs
+                .send(String::from_utf8_lossy(&k).to_string())
+                .is_ok()
+        {
+            queued += 1;
+        }
+    }
+    queued
+}
+
+async fn document_job(
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Response {
+    let Some(id) = params.get("id") else {
+        return json_error(StatusCode::BAD_REQUEST, "INVALID_INPUT", "Specify id", None);
+    };
+    match get_job_record(&state.db(), id) {
+        Some(rec) => Json(serde_json::json!({
+            "id": id,
+            "status": rec.status,
+            "chunks": rec.chunks,
+            "error": rec.error,
+        }))
+        .into_response(),
+        // Documents stored before the queue existed were indexed inline
+        None if docs_info_exists(&state.db(), id) => {
+            Json(serde_json::json!({ "id": id, "status": "done" })).into_response()
+        }
+        None => json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Unknown document",
+            Some(serde_json::json!({ "id": id })),
+        ),
+    }
+}
+
+fn docs_info_exists(db: &sled::Db, id: &str) -> bool {
+    db.open_tree("docs_info")
+        .ok()
+        .and_then(|t| t.contains_key(id.as_bytes()).ok())
+        .unwrap_or(false)
 }
 
 async fn document_retrieve(
@@ -1246,17 +3314,25 @@ async fn document_retrieve(
             None,
         );
     }
-    let docs = state.db.open_tree("docs").expect("docs tree");
+    let [docs, path_latest, chunks_tree, text_idx, meta_tree] = match open_trees(
+        &state.db(),
+        [
+            "docs",
+            "doc_path_latest",
+            "chunks",
+            "text_index",
+            "docs_meta",
+        ],
+    ) {
+        Ok(trees) => trees,
+        Err(e) => return internal_error(e),
+    };
     let resolved_id = if let Some(h) = hash {
         match docs.get(h.as_bytes()) {
             Ok(Some(v)) => String::from_utf8(v.to_vec()).unwrap_or_default(),
             _ => String::new(),
         }
     } else if let Some(p) = path {
-        let path_latest = state
-            .db
-            .open_tree("doc_path_latest")
-            .expect("path latest tree");
         match path_latest.get(p.as_bytes()) {
             Ok(Some(v)) => String::from_utf8(v.to_vec()).unwrap_or_default(),
             _ => String::new(),
@@ -1272,18 +3348,38 @@ async fn document_retrieve(
             None,
         );
     }
-    let chunks_tree = state.db.open_tree("chunks").expect("chunks tree");
+    let with_text = params
+        .get("withText")
+        .map(|v| v != "false" && v != "0")
+        .unwrap_or(true);
     let prefix = format!("{}:", resolved_id);
-    let mut chunks: Vec<ChunkHeader> = Vec::new();
+    let mut headers: Vec<(Vec<u8>, ChunkHeader)> = Vec::new();
     for item in chunks_tree.scan_prefix(prefix.as_bytes()) {
-        if let Ok((_, v)) = item {
+        if let Ok((k, v)) = item {
             if let Ok(ch) = serde_json::from_slice::<ChunkHeader>(&v) {
-                chunks.push(ch)
+                headers.push((k.to_vec(), ch))
             }
         }
     }
+    // Zero-padded keys already scan in offset order; sort anyway for unmigrated data
+    headers.sort_by_key(|(_, ch)| ch.position.start);
+    let chunks: Vec<serde_json::Value> = headers
+        .into_iter()
+        .map(|(key, ch)| {
+            let mut v = serde_json::to_value(&ch).unwrap_or_default();
+            if with_text {
+                let text = text_idx
+                    .get(&key)
+                    .ok()
+                    .flatten()
+                    .map(|t| String::from_utf8_lossy(&t).to_string())
+                    .unwrap_or_default();
+                v["text"] = serde_json::Value::String(text);
+            }
+            v
+        })
+        .collect();
     // Include metadata if present
-    let meta_tree = state.db.open_tree("docs_meta").expect("docs_meta tree");
     let meta_key = format!("{}:meta", resolved_id);
     let metadata = meta_tree
         .get(meta_key.as_bytes())
@@ -1302,2581 +3398,11002 @@ async fn document_retrieve(
         .into_response()
 }
 
-fn chunk_markdown(content: &str) -> Vec<ChunkHeader> {
-    let max_len = 1000usize;
-    let mut chunks = Vec::new();
-    let mut start = 0usize;
-    while start < content.len() {
-        let end = (start + max_len).min(content.len());
-        let id = Uuid::new_v4().to_string();
-        chunks.push(ChunkHeader {
-            id,
-            position: Position { start, end },
-        });
-        start = end;
+/// Key for a chunk in `chunks`, `embeddings` and `text_index`. The offset is zero-padded
+/// so byte order matches numeric order and `scan_prefix` yields chunks by position.
+fn chunk_key(doc_id: &str, start: usize) -> String {
+    format!("{}:{:020}", doc_id, start)
+}
+
+/// Re-encode a `docId:start` key with the padded offset; `None` if it is not a chunk key.
+fn canonical_chunk_key(key: &str) -> Option<String> {
+    let (doc_id, start) = key.rsplit_once(':')?;
+    if start.is_empty() || !start.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
-    chunks
+    Some(chunk_key(doc_id, start.parse().ok()?))
 }
 
-fn read_pdf_text(path: &str) -> Result<String> {
-    let doc = LoDocument::load(path)?;
-    let mut out = String::new();
-    // Limits for large PDFs (best-effort streaming-like behavior)
-    let max_pages: usize = std::env::var("PDF_MAX_PAGES")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
-    let max_bytes: usize = std::env::var("PDF_MAX_BYTES")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
-    let max_time_ms: u128 = std::env::var("PDF_MAX_TIME_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
-    let started = Instant::now();
-    let mut page_count: usize = 0;
-    let mut stop = false;
-    for page_id in doc.get_pages().values() {
-        if stop {
-            break;
-        }
-        if max_pages > 0 && page_count >= max_pages {
-            break;
-        }
-        if max_time_ms > 0 && started.elapsed().as_millis() >= max_time_ms {
-            break;
-        }
-        page_count += 1;
-        let page = LoDocument::get_page_content(&doc, *page_id)?;
-        let content = lopdf::content::Content::decode(&page)?;
-        for operation in content.operations {
-            if stop {
-                break;
+/// Rewrite chunk keys stored with plain decimal offsets; already padded keys are left alone.
+fn migrate_chunk_keys(db: &sled::Db) -> Result<u64> {
+    let mut rewritten = 0u64;
+    for name in ["chunks", "embeddings", "text_index"] {
+        let tree = db.open_tree(name)?;
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let key = String::from_utf8_lossy(&k).to_string();
+            if key.starts_with("mem:") {
+                continue;
             }
-            if operation.operator == "Tj" || operation.operator == "TJ" {
-                for operand in operation.operands {
-                    if let lopdf::Object::String(s, _) = operand {
-                        let bytes: Vec<u8> = s.into();
-                        if let Ok(text) = std::str::from_utf8(&bytes) {
-                            out.push_str(text);
-                            out.push('\n');
-                            if max_bytes > 0 && out.len() >= max_bytes {
-                                stop = true;
-                                break;
-                            }
-                            if max_time_ms > 0 && started.elapsed().as_millis() >= max_time_ms {
-                                stop = true;
-                                break;
-                            }
-                        }
-                    }
+            match canonical_chunk_key(&key) {
+                Some(padded) if padded != key => {
+                    tree.insert(padded.as_bytes(), v)?;
+                    tree.remove(&k)?;
+                    rewritten += 1;
                 }
+                _ => {}
             }
         }
     }
-    Ok(out)
+    if rewritten > 0 {
+        db.flush()?;
+    }
+    Ok(rewritten)
 }
 
-fn markdown_to_text(md: &str) -> String {
+/// Versions recorded for `path` in `doc_versions` (`path:version -> id`), oldest first.
+fn doc_versions_for_path(versions: &sled::Tree, path: &str) -> Vec<(u64, String)> {
+    let prefix = format!("{}:", path);
+    let mut out: Vec<(u64, String)> = versions
+        .scan_prefix(prefix.as_bytes())
+        .flatten()
+        .filter_map(|(k, v)| {
+            // Skip longer paths sharing the prefix (e.g. "a:b:1" when listing "a")
+            let ver = std::str::from_utf8(&k[prefix.len()..]).ok()?.parse().ok()?;
+            Some((ver, String::from_utf8(v.to_vec()).ok()?))
+        })
+        .collect();
+    out.sort_by_key(|(ver, _)| *ver);
+    out
+}
+
+fn next_doc_version(versions: &sled::Tree, path: &str) -> u64 {
+    doc_versions_for_path(versions, path)
+        .last()
+        .map(|(ver, _)| ver + 1)
+        .unwrap_or(1)
+}
+
+/// Rebuild a document's text from its indexed chunks, skipping overlapping prefixes.
+fn document_text(db: &sled::Db, id: &str) -> String {
+    let (Ok(chunks_tree), Ok(text_idx)) = (db.open_tree("chunks"), db.open_tree("text_index"))
+    else {
+        return String::new();
+    };
+    let mut parts: Vec<(usize, String)> = chunks_tree
+        .scan_prefix(format!("{}:", id).as_bytes())
+        .flatten()
+        .filter_map(|(k, v)| {
+            let ch = serde_json::from_slice::<ChunkHeader>(&v).ok()?;
+            let text = text_idx.get(&k).ok().flatten()?;
+            Some((
+                ch.position.start,
+                String::from_utf8_lossy(&text).to_string(),
+            ))
+        })
+        .collect();
+    parts.sort_by_key(|(start, _)| *start);
     let mut out = String::new();
-    let parser = MdParser::new_ext(
-        md,
-        MdOptions::ENABLE_STRIKETHROUGH | MdOptions::ENABLE_TABLES,
-    );
-    for event in parser {
-        match event {
-            MdEvent::Text(t) => {
-                out.push_str(&t);
-            }
-            MdEvent::SoftBreak | MdEvent::HardBreak => out.push('\n'),
-            _ => {}
+    let mut covered = 0usize;
+    for (start, text) in parts {
+        let skip = covered.saturating_sub(start).min(text.len());
+        if let Some(rest) = text.get(skip..) {
+            out.push_str(rest);
         }
+        covered = covered.max(start + text.len());
     }
     out
 }
 
-async fn document_analyze(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    let id = params.get("id").cloned().unwrap_or_default();
-    let entities = kg::entities_for_doc(&state.db, &id).unwrap_or_default();
-    // Derive simple key concepts as top frequent entities
-    let key_concepts = entities.iter().take(5).cloned().collect::<Vec<_>>();
-    // Compose a trivial summary from first chunk
-    let chunks_tree = state.db.open_tree("chunks").expect("chunks tree");
-    let prefix = format!("{}:", id);
-    let mut first_text: Option<String> = None;
-    for item in chunks_tree.scan_prefix(prefix.as_bytes()).take(1) {
-        if let Ok((k, _)) = item {
-            let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-            if let Some((_, _start_str)) = key.split_once(":") {
-                let idx = state.db.open_tree("text_index").expect("text_index");
-                if let Ok(Some(v)) = idx.get(key.as_bytes()) {
-                    first_text = Some(String::from_utf8_lossy(&v).chars().take(300).collect());
-                }
-            }
+/// Largest LCS table (changed lines in `from` x changed lines in `to`) a diff may build.
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+/// Line diff via longest common subsequence; entries are prefixed `"  "`, `"- "` or `"+ "`.
+/// The shared prefix and suffix are skipped; returns None when the rest exceeds DIFF_MAX_CELLS.
+fn diff_lines(from: &str, to: &str) -> Option<Vec<String>> {
+    let a: Vec<&str> = from.lines().collect();
+    let b: Vec<&str> = to.lines().collect();
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (mid_a, mid_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if (mid_a.len() + 1).saturating_mul(mid_b.len() + 1) > DIFF_MAX_CELLS {
+        return None;
+    }
+    let mut lcs = vec![vec![0u32; mid_b.len() + 1]; mid_a.len() + 1];
+    for i in (0..mid_a.len()).rev() {
+        for j in (0..mid_b.len()).rev() {
+            lcs[i][j] = if mid_a[i] == mid_b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
         }
     }
-    let summary = first_text;
-    // Collect related documents from KG
-    let mut related: Vec<serde_json::Value> = Vec::new();
-    if let Ok(edges) = state.db.open_tree("kg_edges") {
-        let src = format!("Document::{}", id);
-        let prefix = format!("{}->", src);
-        for kv in edges.scan_prefix(prefix.as_bytes()) {
-            if let Ok((k, v)) = kv {
-                let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-                if key.ends_with("::RELATED") {
-                    if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&v) {
-                        if let Some(dst) = val.get("dst").and_then(|x| x.as_str()) {
-                            related.push(serde_json::json!({ "docId": dst.strip_prefix("Document::").unwrap_or(dst), "score": val.get("score").and_then(|s| s.as_f64()).unwrap_or(0.0) }));
-                        }
-                    }
-                }
-            }
+    let mut out: Vec<String> = a[..prefix].iter().map(|l| format!("  {}", l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < mid_a.len() && j < mid_b.len() {
+        if mid_a[i] == mid_b[j] {
+            out.push(format!("  {}", mid_a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            out.push(format!("- {}", mid_a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", mid_b[j]));
+            j += 1;
         }
     }
-    Json(
-        serde_json::json!({ "id": id, "keyConcepts": key_concepts, "entities": entities, "summary": summary, "docRefs": related }),
-    )
-}
-
-async fn kg_entities(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-) -> Json<serde_json::Value> {
-    let list = kg::list_entities(&state.db, 50).unwrap_or_default();
-    Json(serde_json::json!({ "entities": list }))
+    out.extend(mid_a[i..].iter().map(|l| format!("- {}", l)));
+    out.extend(mid_b[j..].iter().map(|l| format!("+ {}", l)));
+    out.extend(a[a.len() - suffix..].iter().map(|l| format!("  {}", l)));
+    Some(out)
 }
 
-async fn kg_docs_for_entity(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    let entity = params.get("entity").cloned().unwrap_or_default();
-    let docs = kg::docs_for_entity(&state.db, &entity).unwrap_or_default();
-    Json(serde_json::json!({ "entity": entity, "docs": docs }))
-}
-
-async fn kg_snapshot(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-) -> Json<serde_json::Value> {
-    use petgraph::graph::Graph;
-    let ents = kg::list_entities(&state.db, 100).unwrap_or_default();
-    let mut g: Graph<String, String> = Graph::new();
-    let mut nodes = std::collections::HashMap::new();
-    for (e, _) in &ents {
-        let n = g.add_node(e.clone());
-        nodes.insert(e.clone(), n);
-    }
-    for (e, _) in &ents {
-        let docs = kg::docs_for_entity(&state.db, e).unwrap_or_default();
-        for d in docs {
-            let doc_node = nodes
-                .entry(d.clone())
-                .or_insert_with(|| g.add_node(d.clone()))
-                .to_owned();
-            let e_node = nodes.get(e).cloned().unwrap();
-            let _ = g.add_edge(e_node, doc_node, "MENTIONS".to_string());
-        }
-    }
-    let nodes_out: Vec<String> = g.node_indices().map(|i| g[i].clone()).collect();
-    let edges_out: Vec<(String, String, String)> = g
-        .edge_indices()
-        .map(|eidx| {
-            let (s, t) = g.edge_endpoints(eidx).unwrap();
-            (g[s].clone(), g[t].clone(), g[eidx].clone())
-        })
-        .collect();
-    Json(serde_json::json!({ "nodes": nodes_out, "edges": edges_out }))
-}
-
-async fn kg_list_entities(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    let limit = params
-        .get("limit")
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(50);
-    let list = kg::list_entities(&state.db, limit).unwrap_or_default();
-    Json(serde_json::json!({ "entities": list }))
-}
-
-async fn kg_get_entity(
+/// List every stored version of a path with ids and timestamps.
+async fn document_versions(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Response {
-    let entity = match params.get("entity").cloned() {
-        Some(e) => e,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "entity parameter required",
-                None,
-            )
-        }
-    };
-    match kg::get_entity_details(&state.db, &entity) {
-        Ok(details) => Json(details).into_response(),
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
+    let Some(path) = params.get("path").cloned() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "Specify path",
             None,
-        ),
+        );
+    };
+    let [versions_tree, docs_info, path_latest] = match open_trees(
+        &state.db(),
+        ["doc_versions", "docs_info", "doc_path_latest"],
+    ) {
+        Ok(trees) => trees,
+        Err(e) => return internal_error(e),
+    };
+    let latest = path_latest
+        .get(path.as_bytes())
+        .ok()
+        .flatten()
+        .map(|v| String::from_utf8_lossy(&v).to_string());
+    let versions = doc_versions_for_path(&versions_tree, &path);
+    if versions.is_empty() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Document not found",
+            Some(serde_json::json!({"path": path})),
+        );
     }
+    let items: Vec<serde_json::Value> = versions
+        .into_iter()
+        .map(|(ver, id)| {
+            let info = docs_info
+                .get(id.as_bytes())
+                .ok()
+                .flatten()
+                .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
+                .unwrap_or_default();
+            serde_json::json!({
+                "version": ver,
+                "id": id,
+                "hash": info.get("hash"),
+                "created_at": info.get("created_at"),
+                "latest": latest.as_deref() == Some(id.as_str()),
+            })
+        })
+        .collect();
+    Json(serde_json::json!({ "path": path, "latest": latest, "versions": items })).into_response()
 }
 
-async fn kg_create_entity(
+/// Point `doc_path_latest` back at an earlier version; history is left untouched.
+async fn document_rollback(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
+    Json(req): Json<RollbackDocRequest>,
 ) -> Response {
-    let entity = match body.get("entity").and_then(|e| e.as_str()) {
-        Some(e) => e,
-        None => {
+    let [versions, path_latest] = match open_trees(&state.db(), ["doc_versions", "doc_path_latest"])
+    {
+        Ok(trees) => trees,
+        Err(e) => return internal_error(e),
+    };
+    let ver_key = format!("{}:{}", req.path, req.version);
+    let id = match versions.get(ver_key.as_bytes()) {
+        Ok(Some(v)) => String::from_utf8_lossy(&v).to_string(),
+        _ => {
             return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "entity field required",
-                None,
+                StatusCode::NOT_FOUND,
+                "NOT_FOUND",
+                "Version not found",
+                Some(serde_json::json!({"path": req.path, "version": req.version})),
             )
         }
     };
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    match kg::ensure_entity_node(&state.db, entity, now_ms) {
-        Ok(_) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "entity": entity, "created": true })).into_response()
-        }
-        Err(err) => json_error(
+    if let Err(e) = path_latest.insert(req.path.as_bytes(), id.as_bytes()) {
+        return json_error(
             StatusCode::INTERNAL_SERVER_ERROR,
             "INTERNAL_ERROR",
-            err.to_string(),
+            e.to_string(),
             None,
-        ),
+        );
     }
+    Json(serde_json::json!({ "path": req.path, "version": req.version, "id": id })).into_response()
 }
 
-async fn kg_create_relation(
+/// Line-level diff of two versions' reconstructed text.
+async fn document_diff(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Response {
-    let src = match body.get("src").and_then(|s| s.as_str()) {
-        Some(s) => s,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "src field required",
-                None,
-            )
-        }
+    let path = params.get("path").cloned();
+    let from = params.get("from").and_then(|s| s.parse::<u64>().ok());
+    let to = params.get("to").and_then(|s| s.parse::<u64>().ok());
+    let (Some(path), Some(from), Some(to)) = (path, from, to) else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "Specify path, from and to",
+            None,
+        );
     };
-    let dst = match body.get("dst").and_then(|d| d.as_str()) {
-        Some(d) => d,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "dst field required",
-                None,
-            )
-        }
+    let versions = match state.db().open_tree("doc_versions") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
     };
-    let relation = body
-        .get("relation")
-        .and_then(|r| r.as_str())
-        .unwrap_or("RELATED");
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-
-    match kg::add_edge_generic(&state.db, src, dst, relation, now_ms) {
-        Ok(_) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "src": src, "dst": dst, "relation": relation, "created": true })).into_response()
-        }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
-    }
+    let lookup = |ver: u64| {
+        versions
+            .get(format!("{}:{}", path, ver).as_bytes())
+            .ok()
+            .flatten()
+            .map(|v| String::from_utf8_lossy(&v).to_string())
+    };
+    let (Some(from_id), Some(to_id)) = (lookup(from), lookup(to)) else {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Version not found",
+            Some(serde_json::json!({"path": path, "from": from, "to": to})),
+        );
+    };
+    let Some(lines) = diff_lines(
+        &document_text(&state.db(), &from_id),
+        &document_text(&state.db(), &to_id),
+    ) else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "Versions differ too much to diff",
+            Some(serde_json::json!({"path": path, "from": from, "to": to})),
+        );
+    };
+    let added = lines.iter().filter(|l| l.starts_with("+ ")).count();
+    let removed = lines.iter().filter(|l| l.starts_with("- ")).count();
+    Json(serde_json::json!({
+        "path": path,
+        "from": { "version": from, "id": from_id },
+        "to": { "version": to, "id": to_id },
+        "added": added,
+        "removed": removed,
+        "lines": lines,
+    }))
+    .into_response()
 }
 
-async fn kg_search_nodes(
+/// List the latest version of every stored document, newest first.
+async fn document_list(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    let node_type = params.get("type").map(|s| s.as_str());
-    let pattern = params.get("pattern").map(|s| s.as_str());
+) -> Response {
     let limit = params
         .get("limit")
         .and_then(|s| s.parse::<usize>().ok())
         .unwrap_or(50);
-    let results = kg::search_nodes(&state.db, node_type, pattern, limit).unwrap_or_default();
-    Json(serde_json::json!({ "nodes": results, "count": results.len() }))
-}
-
-async fn kg_read_graph(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    let limit = params
-        .get("limit")
+    let offset = params
+        .get("offset")
         .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(100);
-    // Similar to kg_snapshot but with configurable limit
-    use petgraph::graph::Graph;
-    let ents = kg::list_entities(&state.db, limit).unwrap_or_default();
-    let mut g: Graph<String, String> = Graph::new();
-    let mut nodes = std::collections::HashMap::new();
-    for (e, _) in &ents {
-        let n = g.add_node(e.clone());
-        nodes.insert(e.clone(), n);
-    }
-    for (e, _) in &ents {
-        let docs = kg::docs_for_entity(&state.db, e).unwrap_or_default();
-        for d in docs {
-            let doc_node = nodes
-                .entry(d.clone())
-                .or_insert_with(|| g.add_node(d.clone()))
-                .to_owned();
-            let e_node = nodes.get(e).cloned().unwrap();
-            let _ = g.add_edge(e_node, doc_node, "MENTIONS".to_string());
-        }
-    }
-    let nodes_out: Vec<String> = g.node_indices().map(|i| g[i].clone()).collect();
-    let edges_out: Vec<(String, String, String)> = g
-        .edge_indices()
-        .map(|eidx| {
-            let (s, t) = g.edge_endpoints(eidx).unwrap();
-            (g[s].clone(), g[t].clone(), g[eidx].clone())
+        .unwrap_or(0);
+    let [docs, docs_info, path_latest, chunks_tree] = match open_trees(
+        &state.db(),
+        ["docs", "docs_info", "doc_path_latest", "chunks"],
+    ) {
+        Ok(trees) => trees,
+        Err(e) => return internal_error(e),
+    };
+    let mut items: Vec<serde_json::Value> = Vec::new();
+    // Every stored document is reachable from the hash index; older versions are skipped
+    for (hash_k, id_v) in docs.iter().flatten() {
+        let id = String::from_utf8_lossy(&id_v).to_string();
+        let info = docs_info
+            .get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
+            .unwrap_or_else(|| serde_json::json!({}));
+        let path = info.get("path").and_then(|p| p.as_str());
+        if let Some(p) = path {
+            let latest = path_latest.get(p.as_bytes()).ok().flatten();
+            if latest.as_deref() != Some(id.as_bytes()) {
+                continue;
+            }
+        }
+        let chunk_count = chunks_tree
+            .scan_prefix(format!("{}:", id).as_bytes())
+            .count();
+        items.push(serde_json::json!({
+            "id": id,
+            "path": path,
+            "version": info.get("version").and_then(|v| v.as_u64()),
+            "hash": String::from_utf8_lossy(&hash_k),
+            "created_at": info.get("created_at").and_then(|v| v.as_u64()),
+            "chunks": chunk_count,
+        }));
+    }
+    items.sort_by(|a, b| {
+        let ta = a.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+        let tb = b.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+        tb.cmp(&ta).then_with(|| {
+            a.get("id")
+                .and_then(|v| v.as_str())
+                .cmp(&b.get("id").and_then(|v| v.as_str()))
         })
-        .collect();
-    Json(serde_json::json!({ "nodes": nodes_out, "edges": edges_out }))
+    });
+    let total = items.len();
+    let page: Vec<serde_json::Value> = items.into_iter().skip(offset).take(limit).collect();
+    Json(serde_json::json!({ "documents": page, "total": total, "limit": limit, "offset": offset }))
+        .into_response()
 }
 
-async fn kg_tag_entity(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Response {
-    let entity = match body.get("entity").and_then(|e| e.as_str()) {
-        Some(e) => e,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "entity field required",
-                None,
-            )
+/// Chunk size and overlap from `CHUNK_SIZE` / `CHUNK_OVERLAP`; store requests may override.
+fn default_chunking() -> (usize, usize) {
+    let size = std::env::var("CHUNK_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000usize);
+    let overlap = std::env::var("CHUNK_OVERLAP")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0usize);
+    (size, overlap)
+}
+
+/// A chunk of converted document text; `core_start..end` is the part no other chunk covers.
+struct TextChunk {
+    header: ChunkHeader,
+    core_start: usize,
+    text: String,
+}
+
+impl TextChunk {
+    /// The chunk's text without the overlap borrowed from its predecessor.
+    fn core(&self) -> &str {
+        &self.text[self.core_start - self.header.position.start..]
+    }
+}
+
+/// Markdown-aware chunking over text fed in pieces: blocks (paragraphs, split at blank lines)
+/// are packed into chunks of about `size` bytes, headings always open a new chunk, and blocks
+/// larger than `size` are split at whitespace. Each chunk then reaches back `overlap` bytes
+/// into its predecessor. All positions are char boundaries. Only text from the last chunk's
+/// core onwards is buffered, so memory stays at a few chunks however long the document is.
+struct ChunkStream {
+    size: usize,
+    overlap: usize,
+    /// Text from offset `base` onwards
+    buf: String,
+    base: usize,
+    /// Line being fed: where it starts, how far it was scanned, its first non-space char
+    line_start: usize,
+    scanned: usize,
+    line_first: Option<char>,
+    line_cut_done: bool,
+    prev_blank: bool,
+    cur_start: usize,
+    block_start: usize,
+    prev_core_start: Option<usize>,
+    page: Option<usize>,
+    ready: Vec<TextChunk>,
+}
+
+impl ChunkStream {
+    fn new(size: usize, overlap: usize) -> Self {
+        Self {
+            size: size.max(1),
+            overlap,
+            buf: String::new(),
+            base: 0,
+            line_start: 0,
+            scanned: 0,
+            line_first: None,
+            line_cut_done: false,
+            prev_blank: false,
+            cur_start: 0,
+            block_start: 0,
+            prev_core_start: None,
+            page: None,
+            ready: Vec::new(),
         }
-    };
+    }
 
-    // Accept tags as either array or comma-separated string for flexibility
-    let tags: Vec<String> = match body.get("tags") {
-        Some(val) => {
-            if let Some(arr) = val.as_array() {
-                // Normal case: array of strings
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect()
-            } else if let Some(s) = val.as_str() {
-                // Fallback: comma-separated string
-                s.split(',')
-                    .map(|t| t.trim().to_string())
-                    .filter(|t| !t.is_empty())
-                    .collect()
-            } else {
-                return json_error(
-                    StatusCode::BAD_REQUEST,
-                    "INVALID_INPUT",
-                    format!("tags must be array or string, got: {:?}", val),
-                    None,
-                );
+    fn len(&self) -> usize {
+        self.base + self.buf.len()
+    }
+
+    fn is_char_boundary(&self, at: usize) -> bool {
+        self.buf.is_char_boundary(at - self.base)
+    }
+
+    fn slice(&self, from: usize, to: usize) -> &str {
+        &self.buf[from - self.base..to - self.base]
+    }
+
+    fn push(&mut self, text: &str) {
+        self.buf.push_str(text);
+        loop {
+            let rest = &self.buf[self.scanned - self.base..];
+            let newline = rest.find('\n');
+            let seg = &rest[..newline.map_or(rest.len(), |i| i + 1)];
+            if self.line_first.is_none() {
+                self.line_first = seg.chars().find(|c| !c.is_whitespace());
             }
+            let seg_len = seg.len();
+            // A line is a block boundary after a blank line, or when it is a heading
+            if !self.line_cut_done && (self.line_first.is_some() || newline.is_some()) {
+                self.line_cut_done = true;
+                let is_heading = self.line_first == Some('#');
+                if self.line_start > 0 && (self.prev_blank || is_heading) {
+                    self.cut(self.line_start, is_heading);
+                }
+            }
+            self.scanned += seg_len;
+            if newline.is_none() {
+                break;
+            }
+            self.prev_blank = self.line_first.is_none();
+            self.line_start = self.scanned;
+            self.line_first = None;
+            self.line_cut_done = false;
         }
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "tags field required",
-                None,
-            )
+        // The next boundary lies past everything buffered, so long text can be split now
+        if self.line_cut_done {
+            self.pack(self.len());
         }
-    };
-
-    if tags.is_empty() {
-        return json_error(
-            StatusCode::BAD_REQUEST,
-            "INVALID_INPUT",
-            "tags cannot be empty",
-            None,
-        );
     }
 
-    match kg::tag_entity(&state.db, entity, &tags) {
-        Ok(_) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "entity": entity, "tags": tags, "tagged": true }))
-                .into_response()
+    fn finish(&mut self) {
+        let len = self.len();
+        if self.line_start < len && !self.line_cut_done && self.line_start > 0 && self.prev_blank {
+            self.cut(self.line_start, false);
+        }
+        self.cut(len, false);
+        if self.cur_start < len {
+            self.emit(self.cur_start, len);
+            self.cur_start = len;
         }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
     }
-}
 
-async fn kg_get_tags(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<serde_json::Value> {
-    if let Some(tag) = params.get("tag") {
-        // Get entities by specific tag
-        let entities = kg::get_entities_by_tag(&state.db, tag).unwrap_or_default();
-        Json(serde_json::json!({ "tag": tag, "entities": entities }))
-    } else {
-        // Get all tags
-        let tags = kg::get_all_tags(&state.db).unwrap_or_default();
-        Json(serde_json::json!({ "tags": tags }))
+    /// Close the current chunk, as a heading would; text pushed next is tagged `page`.
+    fn start_page(&mut self, page: usize) {
+        let len = self.len();
+        self.cut(len, true);
+        self.page = Some(page);
     }
-}
 
-async fn kg_remove_tag(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Response {
-    let entity = match body.get("entity").and_then(|e| e.as_str()) {
-        Some(e) => e,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "entity field required",
-                None,
-            )
-        }
-    };
+    /// Chunks completed so far.
+    fn take(&mut self) -> Vec<TextChunk> {
+        std::mem::take(&mut self.ready)
+    }
 
-    // Accept tags as either array or comma-separated string for flexibility
-    let tags: Vec<String> = match body.get("tags") {
-        Some(val) => {
-            if let Some(arr) = val.as_array() {
-                // Normal case: array of strings
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect()
-            } else if let Some(s) = val.as_str() {
-                // Fallback: comma-separated string
-                s.split(',')
-                    .map(|t| t.trim().to_string())
-                    .filter(|t| !t.is_empty())
-                    .collect()
-            } else {
-                return json_error(
-                    StatusCode::BAD_REQUEST,
-                    "INVALID_INPUT",
-                    format!("tags must be array or string, got: {:?}", val),
-                    None,
-                );
-            }
+    fn cut(&mut self, cut: usize, heading: bool) {
+        if cut <= self.block_start {
+            return;
         }
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "tags field required",
-                None,
-            )
+        self.pack(cut);
+        if heading && cut > self.cur_start {
+            self.emit(self.cur_start, cut);
+            self.cur_start = cut;
         }
-    };
+        self.block_start = cut;
+    }
 
-    if tags.is_empty() {
-        return json_error(
-            StatusCode::BAD_REQUEST,
-            "INVALID_INPUT",
-            "tags cannot be empty",
-            None,
-        );
+    /// Close chunks while more than `size` bytes are pending before `limit`.
+    fn pack(&mut self, limit: usize) {
+        if limit - self.cur_start > self.size && self.block_start > self.cur_start {
+            self.emit(self.cur_start, self.block_start);
+            self.cur_start = self.block_start;
+        }
+        // Oversized block: split at whitespace near the target
+        while limit - self.cur_start > self.size {
+            let mut end = self.cur_start + self.size;
+            while !self.is_char_boundary(end) {
+                end -= 1;
+            }
+            if let Some(ws) = self.slice(self.cur_start, end).rfind(char::is_whitespace) {
+                if ws > 0 {
+                    end = self.cur_start + ws + 1;
+                    while !self.is_char_boundary(end) {
+                        end += 1;
+                    }
+                }
+            }
+            if end <= self.cur_start {
+                // A single char wider than `size`
+                end = self.cur_start
+                    + self
+                        .slice(self.cur_start, self.len())
+                        .chars()
+                        .next()
+                        .map_or(1, |c| c.len_utf8());
+            }
+            self.emit(self.cur_start, end);
+            self.cur_start = end;
+        }
     }
 
-    match kg::remove_tags_from_entity(&state.db, entity, &tags) {
-        Ok(_) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "entity": entity, "removed": tags, "success": true }))
-                .into_response()
+    fn emit(&mut self, core_start: usize, end: usize) {
+        let mut start = match self.prev_core_start {
+            Some(p) => core_start.saturating_sub(self.overlap).max(p + 1),
+            None => core_start,
+        };
+        while !self.is_char_boundary(start) {
+            start += 1;
+        }
+        self.prev_core_start = Some(core_start);
+        let text = self.slice(start, end).to_string();
+        self.ready.push(TextChunk {
+            header: ChunkHeader {
+                id: Uuid::new_v4().to_string(),
+                position: Position { start, end },
+                page: self.page,
+            },
+            core_start,
+            text,
+        });
+        // Later chunks reach back no further than just past this core's start; drop the
+        // text before it once that is most of the buffer, keeping the shifting amortized
+        let dead = core_start - self.base;
+        if dead > self.buf.len() / 2 {
+            self.buf.drain(..dead);
+            self.base = core_start;
         }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
     }
 }
 
-async fn kg_delete_entity(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Response {
-    let entity = match body.get("entity").and_then(|e| e.as_str()) {
-        Some(e) => e,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "entity field required",
-                None,
-            )
+/// Read size for streamed documents, and the markdown converted per pass.
+const STREAM_BLOCK_BYTES: usize = 64 * 1024;
+
+/// Feed a text or markdown file to `f` in pieces so it is never held in memory whole.
+/// Markdown is converted in groups of about `STREAM_BLOCK_BYTES`, closed at blank lines
+/// outside code fences; link reference definitions only resolve within their group.
+fn stream_document_text(path: &str, kind: &str, mut f: impl FnMut(&str)) -> std::io::Result<()> {
+    use std::io::{BufRead, Read};
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    if kind == "md" {
+        let mut line = String::new();
+        let mut group = String::new();
+        let mut in_fence = false;
+        while reader.read_line(&mut line)? > 0 {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+            }
+            group.push_str(&line);
+            if !in_fence && group.len() >= STREAM_BLOCK_BYTES && line.trim().is_empty() {
+                f(&markdown_to_text(&group));
+                group.clear();
+            }
+            line.clear();
         }
-    };
+        if !group.is_empty() {
+            f(&markdown_to_text(&group));
+        }
+        return Ok(());
+    }
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "not valid UTF-8");
+    let mut block = vec![0u8; STREAM_BLOCK_BYTES];
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let n = reader.read(&mut block)?;
+        if n == 0 {
+            break;
+        }
+        pending.extend_from_slice(&block[..n]);
+        // A char split across reads waits for the rest of its bytes
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(invalid()),
+        };
+        f(std::str::from_utf8(&pending[..valid]).map_err(|_| invalid())?);
+        pending.drain(..valid);
+    }
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
 
-    match kg::delete_entity(&state.db, entity) {
-        Ok(removed) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "entity": entity, "deleted": true, "removedItems": removed }))
-                .into_response()
+/// Document text either already in memory (whole, or per PDF page) or streamed from its file.
+#[derive(Serialize, Deserialize)]
+enum DocText {
+    Loaded(String),
+    Pages(Vec<(usize, String)>),
+    Streamed(String),
+}
+
+impl DocText {
+    /// Pass the text to `f` in order, with the page number for paged documents.
+    fn feed(&self, kind: &str, mut f: impl FnMut(Option<usize>, &str)) -> std::io::Result<()> {
+        match self {
+            DocText::Loaded(text) => {
+                f(None, text);
+                Ok(())
+            }
+            DocText::Pages(pages) => {
+                for (page, text) in pages {
+                    f(Some(*page), text);
+                }
+                Ok(())
+            }
+            DocText::Streamed(path) => stream_document_text(path, kind, |t| f(None, t)),
         }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
     }
 }
 
-async fn kg_delete_relation(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
+/// `document.store` with `dryRun`: chunk the text and extract its entities as indexing
+/// would, and report the stored document with the same hash, without writing anything.
+fn preview_document(
+    db: &sled::Db,
+    text: &DocText,
+    kind: &str,
+    hash: String,
+    chunk_size: usize,
+    chunk_overlap: usize,
 ) -> Response {
-    let src = match body.get("src").and_then(|s| s.as_str()) {
-        Some(s) => s,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "src field required",
-                None,
-            )
-        }
+    let mut chunker = ChunkStream::new(chunk_size, chunk_overlap);
+    let mut chunks: Vec<serde_json::Value> = Vec::new();
+    let mut entities: std::collections::BTreeSet<String> = Default::default();
+    let mut drain = |batch: Vec<TextChunk>| {
+        let cores: String = batch.iter().map(|ch| ch.core()).collect();
+        entities.extend(kg::extract_entities(&cores));
+        chunks.extend(batch.iter().map(|ch| {
+            let mut c = serde_json::json!({
+                "start": ch.header.position.start,
+                "end": ch.header.position.end,
+            });
+            if let Some(page) = ch.header.page {
+                c["page"] = serde_json::json!(page);
+            }
+            c
+        }));
     };
-    let dst = match body.get("dst").and_then(|d| d.as_str()) {
-        Some(d) => d,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "dst field required",
-                None,
-            )
+    let mut hasher = Sha256::new();
+    let fed = text.feed(kind, |page, t| {
+        hasher.update(t.as_bytes());
+        if let Some(page) = page {
+            chunker.start_page(page);
         }
-    };
-    let relation = body
-        .get("relation")
-        .and_then(|r| r.as_str())
-        .unwrap_or("RELATED");
-
-    match kg::delete_relation(&state.db, src, dst, relation) {
-        Ok(deleted) => {
-            state.db.flush().ok();
-            Json(serde_json::json!({ "src": src, "dst": dst, "relation": relation, "deleted": deleted })).into_response()
+        chunker.push(t);
+        if chunker.ready.len() >= CHUNK_WRITE_BATCH {
+            drain(chunker.take());
         }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
+    });
+    if let Err(err) = fed {
+        return read_error(&err, None);
+    }
+    if format!("{:x}", hasher.finalize()) != hash {
+        return json_error(
+            StatusCode::CONFLICT,
+            "CONFLICT",
+            "File changed while it was being read",
             None,
-        ),
+        );
     }
+    chunker.finish();
+    drain(chunker.take());
+    let duplicate_of = db
+        .open_tree("docs")
+        .ok()
+        .and_then(|docs| docs.get(hash.as_bytes()).ok().flatten())
+        .map(|id| String::from_utf8_lossy(&id).to_string());
+    let entities = kg::canonicalize_entities(db, entities.into_iter().collect());
+    Json(serde_json::json!({
+        "dryRun": true,
+        "hash": hash,
+        "duplicateOf": duplicate_of,
+        "chunkSize": chunk_size,
+        "chunkOverlap": chunk_overlap,
+        "chunkCount": chunks.len(),
+        "chunks": chunks,
+        "entities": entities,
+    }))
+    .into_response()
 }
 
-fn index_chunks_tantivy(
-    index_dir: &std::path::Path,
-    doc_id: &str,
-    chunks: &[ChunkHeader],
-    full_text: &str,
-) -> Result<()> {
-    use tantivy::{directory::MmapDirectory, doc, schema::*, Index};
-    let mut schema_builder = Schema::builder();
-    let id_f = schema_builder.add_text_field("id", TEXT | STORED);
-    let t_f = schema_builder.add_text_field("type", STRING | STORED);
-    let content_f = schema_builder.add_text_field("content", TEXT);
-    let ts_f = schema_builder.add_i64_field("timestamp", INDEXED);
-    let schema = schema_builder.build();
-    let dir = index_dir.join("tantivy");
-    std::fs::create_dir_all(&dir)?;
-    let directory = MmapDirectory::open(&dir)?;
-    let index = Index::open_or_create(directory, schema.clone())?;
-    let mut writer = index.writer(50_000_000)?;
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    for ch in chunks {
-        let start = ch.position.start;
-        let end = ch.position.end.min(full_text.len());
-        let text_slice = &full_text[start..end];
-        let _ = writer.add_document(doc!(id_f=>format!("{}:{}", doc_id, start), t_f=>"chunk", content_f=>text_slice, ts_f=>now));
-    }
-    writer.commit()?;
-    Ok(())
-}
+/// Number of chunks stored per write: one embedding batch and one index commit.
+const CHUNK_WRITE_BATCH: usize = 1024;
 
-fn index_memory_tantivy(index_dir: &std::path::Path, mem_id: &str, content: &str) -> Result<()> {
-    use tantivy::{directory::MmapDirectory, doc, schema::*, Index};
-    let mut schema_builder = Schema::builder();
-    let id_f = schema_builder.add_text_field("id", TEXT | STORED);
-    let t_f = schema_builder.add_text_field("type", STRING | STORED);
-    let content_f = schema_builder.add_text_field("content", TEXT);
-    let ts_f = schema_builder.add_i64_field("timestamp", INDEXED);
-    let schema = schema_builder.build();
-    let dir = index_dir.join("tantivy");
-    std::fs::create_dir_all(&dir)?;
-    let directory = MmapDirectory::open(&dir)?;
-    let index = Index::open_or_create(directory, schema.clone())?;
-    let mut writer = index.writer(50_000_000)?;
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let _ = writer.add_document(
-        doc!(id_f=>format!("mem:{}", mem_id), t_f=>"memory", content_f=>content, ts_f=>now),
-    );
-    writer.commit()?;
-    Ok(())
+/// Persists a document's chunks batch by batch as they are produced: headers, embeddings of
+/// the chunk text and both text indexes. Keeps the starts and entities needed afterwards.
+struct ChunkSink<'a> {
+    db: Db,
+    text_index: &'a text_index::TextIndex,
+    doc_id: &'a str,
+    starts: Vec<usize>,
+    entities: std::collections::BTreeSet<String>,
 }
 
-fn index_memory_sled(db: &sled::Db, mem_id: &str, content: &str) -> Result<()> {
-    let text_idx = db.open_tree("text_index")?;
-    let key = format!("mem:{}", mem_id);
-    text_idx.insert(key.as_bytes(), content.as_bytes())?;
-    Ok(())
+impl ChunkSink<'_> {
+    fn write(&mut self, batch: Vec<TextChunk>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let chunks_tree = self.db.open_tree("chunks")?;
+        let emb_tree = self.db.open_tree("embeddings")?;
+        let texts: Vec<&str> = batch.iter().map(|ch| ch.text.as_str()).collect();
+        let vecs = embeddings::embed_batch_cached(&self.db, &texts);
+        for (ch, vec) in batch.iter().zip(&vecs) {
+            let key = chunk_key(self.doc_id, ch.header.position.start);
+            chunks_tree.insert(key.as_bytes(), serde_json::to_vec(&ch.header)?)?;
+            let bytes: &[u8] = bytemuck::cast_slice(vec);
+            emb_tree.insert(key.as_bytes(), bytes)?;
+            self.starts.push(ch.header.position.start);
+        }
+        // Cores partition the text, so together they read as the original passage
+        let cores: String = batch.iter().map(|ch| ch.core()).collect();
+        self.entities.extend(kg::extract_entities(&cores));
+        index_chunks_tantivy(self.text_index, self.doc_id, &batch)?;
+        index_chunks_sled(&self.db, self.doc_id, &batch)?;
+        Ok(())
+    }
 }
 
-async fn run_stdio(_state: Arc<AppState>) {
-    use tokio::io::stdout;
-    use tokio::time::timeout;
-
-    let stdin = tokio::io::stdin();
-    let mut reader = BufReader::new(stdin).lines();
-    let stdout = Arc::new(AsyncMutex::new(stdout()));
-
-    info!("STDIO MCP handler started");
-
-    // Track active requests to limit concurrency
-    let active_requests = Arc::new(AsyncMutex::new(0_usize));
-    const MAX_CONCURRENT_REQUESTS: usize = 10;
-
-    loop {
-        // Read next line with proper error handling
-        let line_result = reader.next_line().await;
+fn read_pdf_text(path: &str) -> Result<Vec<(usize, String)>> {
+    pdf_page_texts(&LoDocument::load(path)?)
+}
 
-        let line = match line_result {
-            Ok(Some(l)) => l,
-            Ok(None) => {
-                info!("Stdin closed (EOF), shutting down stdio handler");
-                break;
-            }
-            Err(e) => {
-                error!("Error reading from stdin: {}, shutting down", e);
+/// Text of each page as `(page number, text)`, in page order.
+fn pdf_page_texts(doc: &LoDocument) -> Result<Vec<(usize, String)>> {
+    let mut pages: Vec<(usize, String)> = Vec::new();
+    let mut total_bytes = 0usize;
+    // Limits for large PDFs (best-effort streaming-like behavior)
+    let max_pages: usize = std::env::var("PDF_MAX_PAGES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let max_bytes: usize = std::env::var("PDF_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let max_time_ms: u128 = std::env::var("PDF_MAX_TIME_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let started = Instant::now();
+    let mut stop = false;
+    for (read, (page_no, page_id)) in doc.get_pages().into_iter().enumerate() {
+        if stop {
+            break;
+        }
+        if max_pages > 0 && read >= max_pages {
+            break;
+        }
+        if max_time_ms > 0 && started.elapsed().as_millis() >= max_time_ms {
+            break;
+        }
+        let page = LoDocument::get_page_content(doc, page_id)?;
+        let content = lopdf::content::Content::decode(&page)?;
+        let mut out = String::new();
+        for operation in content.operations {
+            if stop {
                 break;
             }
-        };
-
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-
-        // Parse JSON-RPC request
-        let v: serde_json::Value = match serde_json::from_str(&line) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Failed to parse JSON-RPC request: {}", e);
-                // Send error response for malformed JSON
-                let error_response = serde_json::json!({
-                    "jsonrpc": "2.0",
-                    "id": null,
-                    "error": { "code": -32700, "message": format!("Parse error: {}", e) }
-                });
-                if let Err(write_err) = write_response(stdout.clone(), &error_response).await {
-                    error!("Failed to write error response: {}", write_err);
-                    break;
+            if operation.operator == "Tj" || operation.operator == "TJ" {
+                for operand in operation.operands {
+                    if let lopdf::Object::String(s, _) = operand {
+                        let bytes: Vec<u8> = s.into();
+                        if let Ok(text) = std::str::from_utf8(&bytes) {
+                            out.push_str(text);
+                            out.push('\n');
+                            total_bytes += text.len() + 1;
+                            if max_bytes > 0 && total_bytes >= max_bytes {
+                                stop = true;
+                                break;
+                            }
+                            if max_time_ms > 0 && started.elapsed().as_millis() >= max_time_ms {
+                                stop = true;
+                                break;
+                            }
+                        }
+                    }
                 }
-                continue;
             }
-        };
-
-        let id_val_opt = v.get("id").cloned();
-        let method = v.get("method").and_then(|m| m.as_str()).unwrap_or("");
-        let params = v.get("params").cloned().unwrap_or(serde_json::json!({}));
-
-        // Ignore notifications (no id), including notifications/initialized
-        if id_val_opt.is_none() || id_val_opt.as_ref().map(|x| x.is_null()).unwrap_or(true) {
-            info!("Received notification: {}", method);
-            continue;
         }
+        pages.push((page_no as usize, out));
+    }
+    Ok(pages)
+}
 
-        let id_val = id_val_opt.unwrap();
-        info!("Received request: method={}, id={}", method, id_val);
-
-        // Check concurrent request limit
-        {
-            let mut count = active_requests.lock().await;
-            if *count >= MAX_CONCURRENT_REQUESTS {
-                error!("Too many concurrent requests, rejecting: method={}", method);
-                let error_response = serde_json::json!({
-                    "jsonrpc": "2.0",
-                    "id": id_val,
-                    "error": { "code": -32000, "message": "Too many concurrent requests" }
-                });
-                if let Err(e) = write_response(stdout.clone(), &error_response).await {
-                    error!("Failed to write rate limit error: {}", e);
-                    break;
-                }
-                continue;
-            }
-            *count += 1;
+/// Fetch a document for `document_store` and convert it by `mime`, else the response's
+/// content type, else the URL's extension. Failures come back as the error response.
+async fn fetch_document_text(url: &str, mime: Option<&str>) -> Result<DocText, Response> {
+    let fetched = fetch::fetch(url).await.map_err(|e| {
+        let details = Some(serde_json::json!({ "url": url }));
+        match e {
+            fetch::FetchError::InvalidUrl(_) => json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                e.to_string(),
+                details,
+            ),
+            fetch::FetchError::Blocked(_) => json_error(
+                StatusCode::FORBIDDEN,
+                "FORBIDDEN",
+                e.to_string(),
+                Some(serde_json::json!({
+                    "url": url,
+                    "hint": "set ALLOW_PRIVATE_URLS=1 to fetch from internal hosts"
+                })),
+            ),
+            fetch::FetchError::TooLarge(max) => json_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "PAYLOAD_TOO_LARGE",
+                e.to_string(),
+                Some(serde_json::json!({ "url": url, "maxBytes": max })),
+            ),
+            fetch::FetchError::Upstream(_) => json_error(
+                StatusCode::BAD_GATEWAY,
+                "UPSTREAM_ERROR",
+                e.to_string(),
+                details,
+            ),
         }
+    })?;
+    let content_type = fetched
+        .content_type
+        .as_deref()
+        .map(|ct| ct.split(';').next().unwrap_or("").trim());
+    match detect_doc_kind(mime.or(content_type), Some(fetched.url.path())) {
+        "pdf" => LoDocument::load_mem(&fetched.body)
+            .map_err(anyhow::Error::from)
+            .and_then(|doc| pdf_page_texts(&doc))
+            .map(DocText::Pages)
+            .map_err(|_| {
+                json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    "Failed to read PDF from URL",
+                    Some(serde_json::json!({ "url": url })),
+                )
+            }),
+        kind => {
+            let raw = String::from_utf8_lossy(&fetched.body).into_owned();
+            Ok(DocText::Loaded(match kind {
+                "md" => markdown_to_text(&raw),
+                "html" => html_to_text(&raw),
+                _ => raw,
+            }))
+        }
+    }
+}
 
-        // Process request concurrently
-        let stdout_clone = stdout.clone();
-        let active_requests_clone = active_requests.clone();
-        let method_str = method.to_string();
-        let id_val_clone = id_val.clone();
-
-        tokio::spawn(async move {
-            // Set timeout for request processing
-            let request_timeout = Duration::from_secs(60);
-
-            let response_result = timeout(request_timeout, async {
-                process_request(&method_str, &params, &id_val_clone).await
-            })
-            .await;
-
-            let response_json = match response_result {
-                Ok(response) => response,
-                Err(_) => {
-                    error!(
-                        "Request timeout: method={}, id={}",
-                        method_str, id_val_clone
-                    );
-                    serde_json::json!({
-                        "jsonrpc": "2.0",
-                        "id": id_val_clone,
-                        "error": { "code": -32000, "message": "Request timeout" }
-                    })
-                }
-            };
+/// Document format from the explicit `mime` (preferred) or the path extension:
+/// "pdf", "html", "txt" or "md" (the default).
+fn detect_doc_kind(mime: Option<&str>, path: Option<&str>) -> &'static str {
+    let from_name = |s: &str| -> Option<&'static str> {
+        let s = s.trim().to_lowercase();
+        let s = s.rsplit(['/', '.']).next().unwrap_or("").to_string();
+        match s.as_str() {
+            "pdf" => Some("pdf"),
+            "html" | "htm" | "xhtml" => Some("html"),
+            "txt" | "text" | "plain" => Some("txt"),
+            "md" | "markdown" => Some("md"),
+            _ => None,
+        }
+    };
+    mime.and_then(from_name)
+        .or_else(|| path.and_then(from_name))
+        .unwrap_or("md")
+}
 
-            // Write response
-            if let Err(e) = write_response(stdout_clone, &response_json).await {
-                error!("Failed to write response for method={}: {}", method_str, e);
-            } else {
-                info!(
-                    "Response sent for method={}, id={}",
-                    method_str, id_val_clone
-                );
+/// Strip HTML to text: drops tags, comments, `<script>`/`<style>` bodies; block-level tags
+/// become line breaks and common entities are decoded.
+fn html_to_text(html: &str) -> String {
+    const BLOCK: &[&str] = &[
+        "p",
+        "div",
+        "br",
+        "li",
+        "ul",
+        "ol",
+        "tr",
+        "table",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "section",
+        "article",
+        "header",
+        "footer",
+        "blockquote",
+        "pre",
+        "hr",
+    ];
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(lt) = rest.find('<') {
+        out.push_str(&rest[..lt]);
+        rest = &rest[lt..];
+        if let Some(body) = rest.strip_prefix("<!--") {
+            rest = body.find("-->").map(|i| &body[i + 3..]).unwrap_or("");
+            continue;
+        }
+        let gt = match rest.find('>') {
+            Some(i) => i,
+            None => {
+                // Unterminated tag: treat the rest as text
+                out.push_str(rest);
+                rest = "";
+                break;
             }
-
-            // Decrement active request count
-            let mut count = active_requests_clone.lock().await;
-            *count = count.saturating_sub(1);
-        });
+        };
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        if (name == "script" || name == "style") && !tag.starts_with('/') {
+            let close = format!("</{}", name);
+            rest = match rest.to_lowercase().find(&close) {
+                Some(i) => rest[i..]
+                    .find('>')
+                    .map(|j| &rest[i + j + 1..])
+                    .unwrap_or(""),
+                None => "",
+            };
+            continue;
+        }
+        if BLOCK.contains(&name.as_str()) {
+            out.push('\n');
+        }
     }
-
-    // Wait for active requests to complete before exiting
-    let wait_start = std::time::Instant::now();
-    while wait_start.elapsed() < Duration::from_secs(2) {
-        let count = *active_requests.lock().await;
-        if count == 0 {
-            break;
+    out.push_str(rest);
+    let decoded = out
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    // Collapse runs of blank lines left behind by nested blocks
+    let mut text = String::with_capacity(decoded.len());
+    let mut blank = true;
+    for line in decoded.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            if !blank {
+                text.push('\n');
+            }
+            blank = true;
+        } else {
+            text.push_str(&line);
+            text.push('\n');
+            blank = false;
         }
-        info!("Waiting for {} active requests to complete...", count);
-        sleep(Duration::from_millis(100)).await;
     }
-
-    info!(
-        "STDIO handler exiting (active requests: {})",
-        *active_requests.lock().await
-    );
+    text.trim_end().to_string()
 }
 
-async fn process_request(
-    method: &str,
-    params: &serde_json::Value,
-    id_val: &serde_json::Value,
-) -> serde_json::Value {
-    match method {
-        "initialize" => {
-            let result = serde_json::json!({
-                "serverInfo": {
-                    "name": "memorized-mcp",
-                    "version": env!("CARGO_PKG_VERSION"),
-                    "instructions": "MemorizedMCP: hybrid memory server exposing tools over MCP."
-                },
-                "protocolVersion": "2024-11-05",
-                "capabilities": { "tools": { "listChanged": true, "call": {} }, "logging": {}, "sampling": {} }
-            });
-            serde_json::json!({ "jsonrpc": "2.0", "id": id_val, "result": result })
-        }
-        "tools/list" => {
-            let tools = list_tools().into_iter().map(|t| serde_json::json!({
-				"name": t.name,
-				"description": t.description,
-				"inputSchema": { "type": "object", "properties": {}, "additionalProperties": true }
-			})).collect::<Vec<_>>();
-            serde_json::json!({ "jsonrpc": "2.0", "id": id_val, "result": { "tools": tools } })
+fn markdown_to_text(md: &str) -> String {
+    let mut out = String::new();
+    let parser = MdParser::new_ext(
+        md,
+        MdOptions::ENABLE_STRIKETHROUGH | MdOptions::ENABLE_TABLES,
+    );
+    for event in parser {
+        match event {
+            MdEvent::Text(t) => {
+                out.push_str(&t);
+            }
+            MdEvent::SoftBreak | MdEvent::HardBreak => out.push('\n'),
+            _ => {}
         }
-        "tools/call" => {
-            let name = params.get("name").and_then(|n| n.as_str()).unwrap_or("");
-            let arguments = params
-                .get("arguments")
-                .cloned()
-                .unwrap_or(serde_json::json!({}));
+    }
+    out
+}
 
-            match proxy_tool_via_http(name, &arguments).await {
-                Ok(json_val) => {
-                    let text_payload = if let Some(s) = json_val.as_str() {
-                        s.to_string()
-                    } else {
-                        serde_json::to_string_pretty(&json_val)
-                            .unwrap_or_else(|_| json_val.to_string())
-                    };
-                    serde_json::json!({
-                        "jsonrpc": "2.0",
-                        "id": id_val,
-                        "result": { "content": [ { "type": "text", "text": text_payload } ] }
-                    })
+async fn document_analyze(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let id = params.get("id").cloned().unwrap_or_default();
+    let entities = kg::entities_for_doc(&state.db(), &id).unwrap_or_default();
+    // Derive simple key concepts as top frequent entities
+    let key_concepts = entities.iter().take(5).cloned().collect::<Vec<_>>();
+    // Compose a trivial summary from first chunk
+    let [chunks_tree, idx] =
+        open_trees(&state.db(), ["chunks", "text_index"]).map_err(internal_error)?;
+    let prefix = format!("{}:", id);
+    let mut first_text: Option<String> = None;
+    let mut summary_page: Option<usize> = None;
+    for item in chunks_tree.scan_prefix(prefix.as_bytes()).take(1) {
+        if let Ok((k, v)) = item {
+            summary_page = serde_json::from_slice::<ChunkHeader>(&v)
+                .ok()
+                .and_then(|ch| ch.page);
+            let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+            if let Some((_, _start_str)) = key.split_once(":") {
+                if let Ok(Some(v)) = idx.get(key.as_bytes()) {
+                    first_text = Some(String::from_utf8_lossy(&v).chars().take(300).collect());
                 }
-                Err(err) => {
-                    error!("Tool call failed: tool={}, error={}", name, err);
-                    serde_json::json!({
-                        "jsonrpc": "2.0",
-                        "id": id_val,
-                        "error": { "code": -32000, "message": err }
-                    })
+            }
+        }
+    }
+    let summary = first_text;
+    // Collect related documents from KG
+    let mut related: Vec<serde_json::Value> = Vec::new();
+    if let Ok(edges) = state.db().open_tree("kg_edges") {
+        let src = format!("Document::{}", id);
+        let prefix = kg::edges_prefix(&src);
+        for kv in edges.scan_prefix(prefix.as_bytes()) {
+            if let Ok((k, v)) = kv {
+                let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+                if key.ends_with("::RELATED") {
+                    if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&v) {
+                        if let Some(dst) = val.get("dst").and_then(|x| x.as_str()) {
+                            related.push(serde_json::json!({ "docId": dst.strip_prefix("Document::").unwrap_or(dst), "score": val.get("score").and_then(|s| s.as_f64()).unwrap_or(0.0) }));
+                        }
+                    }
                 }
             }
         }
-        _ => {
-            serde_json::json!({
-                "jsonrpc": "2.0",
-                "id": id_val,
-                "error": { "code": -32601, "message": format!("Unknown method: {}", method) }
-            })
+        // RELATED edges are written from the newer document, so include incoming ones too
+        for (_, val) in kg::incoming_edges(&state.db(), &src).unwrap_or_default() {
+            if val.get("relation").and_then(|r| r.as_str()) != Some("RELATED") {
+                continue;
+            }
+            if let Some(other) = val.get("src").and_then(|x| x.as_str()) {
+                let other = other.strip_prefix("Document::").unwrap_or(other);
+                if !related.iter().any(|r| r["docId"] == other) {
+                    related.push(serde_json::json!({ "docId": other, "score": val.get("score").and_then(|s| s.as_f64()).unwrap_or(0.0) }));
+                }
+            }
         }
     }
+    Ok(Json(
+        serde_json::json!({ "id": id, "keyConcepts": key_concepts, "entities": entities, "summary": summary, "summaryPage": summary_page, "docRefs": related }),
+    ))
 }
 
-async fn write_response(
-    stdout: Arc<AsyncMutex<tokio::io::Stdout>>,
-    response: &serde_json::Value,
-) -> Result<(), std::io::Error> {
-    use tokio::io::AsyncWriteExt;
-
-    let response_str = serde_json::to_string(response).unwrap();
-    let mut stdout_guard = stdout.lock().await;
-
-    stdout_guard.write_all(response_str.as_bytes()).await?;
-    stdout_guard.write_all(b"\n").await?;
-    stdout_guard.flush().await?;
+async fn kg_entities(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<serde_json::Value> {
+    let list = kg::list_entities(&state.db(), 50).unwrap_or_default();
+    Json(serde_json::json!({ "entities": list }))
+}
 
-    Ok(())
+async fn kg_docs_for_entity(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Json<serde_json::Value> {
+    let entity = kg::resolve_alias(
+        &state.db(),
+        &params.get("entity").cloned().unwrap_or_default(),
+    );
+    let docs = kg::docs_for_entity(&state.db(), &entity).unwrap_or_default();
+    Json(serde_json::json!({ "entity": entity, "docs": docs }))
 }
 
-async fn memory_add(
+async fn kg_snapshot(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(req): Json<AddMemoryRequest>,
+) -> Json<serde_json::Value> {
+    Json(graph_json(&state.db(), 100, kg::TimeWindow::default()))
+}
+
+async fn kg_list_entities(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Json<serde_json::Value> {
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(50);
+    let list = kg::list_entities(&state.db(), limit).unwrap_or_default();
+    Json(serde_json::json!({ "entities": list }))
+}
+
+async fn kg_get_entity(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Response {
-    let id = Uuid::new_v4().to_string();
-    let layer = req.layer_hint.unwrap_or_else(|| "STM".to_string());
-    if req.content.trim().is_empty() {
+    let entity = match params.get("entity").cloned() {
+        Some(e) => e,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "entity parameter required",
+                None,
+            )
+        }
+    };
+    let entity = kg::resolve_alias(&state.db(), &entity);
+    match kg::get_entity_details(&state.db(), &entity) {
+        Ok(details) => Json(details).into_response(),
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+async fn kg_path(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let (Some(src), Some(dst)) = (params.get("src"), params.get("dst")) else {
         return json_error(
             StatusCode::BAD_REQUEST,
             "INVALID_INPUT",
-            "content must not be empty",
+            "src and dst parameters required",
             None,
         );
-    }
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let expires_at = if layer == "STM" {
-        Some(now_ms + 60 * 60 * 1000)
-    } else {
-        None
     };
-    let tree = state.db.open_tree("memories").expect("mem tree");
-
-    // Create KG node for this memory and link any referenced documents as EVIDENCE
-    kg::ensure_memory_node(&state.db, &id, now_ms).ok();
-    // Semantic: link memory to mentioned entities
-    let mem_ents_vec = kg::extract_entities(&req.content);
-    for e in &mem_ents_vec {
-        kg::ensure_entity_node(&state.db, e, now_ms).ok();
-    }
-    for e in &mem_ents_vec {
-        let src = format!("Memory::{}", &id);
-        let dst = format!("Entity::{}", e);
-        kg::add_edge_generic(&state.db, &src, &dst, "MENTIONS", now_ms).ok();
-    }
-    if let Some(ep) = req.episode_id.as_ref() {
-        kg::ensure_episode_node(&state.db, ep, now_ms, None, req.session_id.as_deref()).ok();
-        let src = format!("Memory::{}", &id);
-        let dst = format!("Episode::{}", ep);
-        kg::add_edge_generic(&state.db, &src, &dst, "IN_EPISODE", now_ms).ok();
+    let max_depth = params
+        .get("maxDepth")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(3);
+    match kg::shortest_path(&state.db(), src, dst, max_depth) {
+        Ok(path) => Json(serde_json::json!({
+            "src": src,
+            "dst": dst,
+            "found": path.is_some(),
+            "length": path.as_ref().map(|p| p.len()),
+            "edges": path.unwrap_or_default(),
+        }))
+        .into_response(),
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
-    let mut computed_refs: Option<Vec<serde_json::Value>> = None;
-    if let Some(refs) = req.references.as_ref() {
-        let mem_ents: HashSet<String> = mem_ents_vec.into_iter().collect();
-        let mut out = Vec::new();
-        for r in refs {
-            let doc_id = &r.doc_id;
-            kg::ensure_document_node(&state.db, doc_id, now_ms).ok();
-            let src = format!("Memory::{}", &id);
-            let dst = format!("Document::{}", doc_id);
-            kg::add_edge_generic(&state.db, &src, &dst, "EVIDENCE", now_ms).ok();
-            // Score evidence using Jaccard of entities if score not provided
-            let doc_ents_vec = kg::entities_for_doc(&state.db, doc_id).unwrap_or_default();
-            let doc_ents: HashSet<String> = doc_ents_vec.into_iter().collect();
-            let inter = mem_ents.intersection(&doc_ents).count() as f32;
-            let uni = mem_ents.union(&doc_ents).count() as f32;
-            let jacc = if uni > 0.0 { inter / uni } else { 0.0 };
-            let score = r.score.unwrap_or(jacc);
-            out.push(serde_json::json!({ "docId": doc_id, "chunkId": r.chunk_id, "score": score }));
-            // Persist in doc_refs tree
-            if let Ok(tree_refs) = state.db.open_tree("doc_refs") {
-                let key = format!(
-                    "mem::{}::doc::{}::chunk::{}",
-                    id,
-                    doc_id,
-                    r.chunk_id.clone().unwrap_or_default()
-                );
-                let _ = tree_refs.insert(
-                    key.as_bytes(),
-                    serde_json::to_vec(&serde_json::json!({"score": score})).unwrap(),
-                );
-            }
+}
+
+async fn kg_neighbors(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(node) = params.get("node") else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "node parameter required",
+            None,
+        );
+    };
+    let depth = params
+        .get("depth")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(3);
+    match kg::neighborhood(&state.db(), node, depth) {
+        Ok((nodes, edges)) => {
+            let nodes: Vec<serde_json::Value> = nodes
+                .into_iter()
+                .map(|(key, hops)| serde_json::json!({ "node": key, "depth": hops }))
+                .collect();
+            Json(serde_json::json!({ "node": node, "nodes": nodes, "edges": edges }))
+                .into_response()
         }
-        computed_refs = Some(out);
-    }
-    let rec = serde_json::json!({
-        "id": id,
-        "content": req.content,
-        "metadata": req.metadata,
-        "layer": layer,
-        "session_id": req.session_id,
-        "episode_id": req.episode_id,
-        "created_at": now_ms,
-        "expires_at": expires_at,
-        "docRefs": computed_refs
-    });
-    tree.insert(id.as_bytes(), serde_json::to_vec(&rec).unwrap())
-        .expect("insert mem");
-    // Reusable text index for memory (sled) and tantivy
-    index_memory_sled(
-        &state.db,
-        &id,
-        &rec.get("content").and_then(|c| c.as_str()).unwrap_or(""),
-    )
-    .ok();
-    index_memory_tantivy(
-        &state.index_dir,
-        &id,
-        rec.get("content").and_then(|c| c.as_str()).unwrap_or(""),
-    )
-    .ok();
-    // Store embedding for memory content (placeholder if feature not enabled)
-    {
-        let emb_tree = state
-            .db
-            .open_tree("mem_embeddings")
-            .expect("mem_embeddings");
-        let vecs =
-            embeddings::embed_batch(&[rec.get("content").and_then(|c| c.as_str()).unwrap_or("")]);
-        let bytes: &[u8] = bytemuck::cast_slice(&vecs[0]);
-        let _ = emb_tree.insert(id.as_bytes(), bytes);
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
-    state.db.flush().expect("flush");
-    Json(AddMemoryResponse { id, layer }).into_response()
 }
 
-async fn memory_search(
+/// Nodes (with type and hop distance) and edges within `depth` hops of `node`, capped
+/// at `limit` nodes.
+async fn kg_subgraph(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<SearchResponse> {
-    let started = std::time::Instant::now();
-    let original_q = params.get("q").cloned().unwrap_or_default();
-    let query = original_q.to_lowercase();
+) -> Response {
+    let Some(node) = params.get("node") else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "node parameter required",
+            None,
+        );
+    };
+    let depth = params
+        .get("depth")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(2);
     let limit = params
         .get("limit")
         .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(10);
-    let layer = params.get("layer").cloned();
-    let episode = params.get("episode").cloned();
-    let time_from = params.get("from").and_then(|s| s.parse::<i64>().ok());
-    let time_to = params.get("to").and_then(|s| s.parse::<i64>().ok());
-    let tree = state.db.open_tree("memories").expect("mem tree");
-    let mut results: Vec<SearchResult> = Vec::new();
-    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let strengthen_mul: f64 = std::env::var("LTM_STRENGTHEN_ON_ACCESS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(1.05);
-    let stm_strengthen_add: f64 = std::env::var("STM_STRENGTHEN_DELTA")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0.05);
-    for kv in tree.iter() {
-        let (_, v) = kv.expect("ok");
-        if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-            let content = rec
-                .get("content")
-                .and_then(|c| c.as_str())
-                .unwrap_or("")
-                .to_lowercase();
-            let layer_v = rec
-                .get("layer")
-                .and_then(|c| c.as_str())
-                .unwrap_or("")
-                .to_string();
-            let created_at = rec.get("created_at").and_then(|c| c.as_i64());
-            let episode_v = rec.get("episode_id").and_then(|c| c.as_str());
-            let in_time = created_at
-                .map(|t| {
-                    time_from.map(|f| t >= f).unwrap_or(true)
-                        && time_to.map(|to| t <= to).unwrap_or(true)
+        .unwrap_or(100);
+    match kg::subgraph(&state.db(), node, depth, limit) {
+        Ok(((nodes, edges), truncated)) => {
+            let nodes: Vec<serde_json::Value> = nodes
+                .into_iter()
+                .map(|(key, hops)| {
+                    let kind = key.split_once("::").map(|(t, _)| t).unwrap_or("");
+                    serde_json::json!({ "node": key, "type": kind, "depth": hops })
                 })
-                .unwrap_or(true);
-            let episode_ok = episode
-                .as_deref()
-                .map(|e| Some(e) == episode_v)
-                .unwrap_or(true);
-            if content.contains(&query)
-                && layer.as_deref().map(|l| l == layer_v).unwrap_or(true)
-                && in_time
-                && episode_ok
-            {
-                let id = rec
-                    .get("id")
-                    .and_then(|c| c.as_str())
-                    .unwrap_or("")
-                    .to_string();
-                if !seen.contains(&id) {
-                    let doc_refs = rec.get("docRefs").and_then(|r| r.as_array()).map(|arr| {
-                        arr.iter()
-                            .filter_map(|x| {
-                                let doc_id = x.get("docId").and_then(|v| v.as_str())?.to_string();
-                                let chunk_id = x
-                                    .get("chunkId")
-                                    .and_then(|v| v.as_str())
-                                    .map(|s| s.to_string());
-                                let score =
-                                    x.get("score").and_then(|v| v.as_f64()).map(|f| f as f32);
-                                Some(DocRefOut {
-                                    doc_id,
-                                    chunk_id,
-                                    score,
-                                })
-                            })
-                            .collect::<Vec<_>>()
-                    });
-                    results.push(SearchResult {
-                        id: id.clone(),
-                        score: 1.0,
-                        layer: layer_v.clone(),
-                        doc_refs,
-                        explain: None,
-                    });
-                    seen.insert(id.clone());
-                }
-                // Access-based strengthening and stats bump
-                if let Ok(Some(old)) = tree.get(id.as_bytes()) {
-                    let mut r: serde_json::Value =
-                        serde_json::from_slice(&old).unwrap_or(serde_json::json!({}));
-                    let acc = r.get("access_count").and_then(|c| c.as_u64()).unwrap_or(0) + 1;
-                    r["access_count"] = serde_json::json!(acc);
-                    r["last_access_ts"] = serde_json::json!(now_ms);
-                    let imp = r.get("importance").and_then(|c| c.as_f64()).unwrap_or(1.0);
-                    let new_imp = if layer_v == "LTM" {
-                        imp * strengthen_mul
-                    } else {
-                        imp + stm_strengthen_add
-                    };
-                    r["importance"] = serde_json::json!(new_imp);
-                    let _ = tree.insert(id.as_bytes(), serde_json::to_vec(&r).unwrap());
-                }
-            }
-        }
-    }
-    // Vector: embed query and search over memory embeddings (placeholder when no model)
-    if !query.is_empty() {
-        let qvec = embeddings::embed_batch(&[query.as_str()]);
-        if let Some(vec) = qvec.get(0) {
-            let topk = vector_index::search_memories_by_vector(&state.db, vec, limit);
-            for (id, score) in topk {
-                if !seen.contains(&id) {
-                    results.push(SearchResult {
-                        id: id.clone(),
-                        score,
-                        layer: "LTM".to_string(),
-                        doc_refs: None,
-                        explain: Some(serde_json::json!({"source":"vector"})),
-                    });
-                    seen.insert(id);
-                }
-            }
+                .collect();
+            Json(serde_json::json!({
+                "node": node,
+                "depth": depth.min(kg::MAX_TRAVERSAL_DEPTH),
+                "nodes": nodes,
+                "edges": edges,
+                "truncated": truncated,
+            }))
+            .into_response()
         }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
-    Json(SearchResponse {
-        results,
-        took_ms: Some(started.elapsed().as_millis()),
-    })
 }
 
-async fn memory_update(
+async fn kg_create_entity(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(req): Json<UpdateMemoryRequest>,
+    Json(body): Json<serde_json::Value>,
 ) -> Response {
-    let tree = state.db.open_tree("memories").expect("mem tree");
-    if let Some(rec_v) = tree
-        .get(req.id.as_bytes())
-        .expect("get")
-        .map(|v| v.to_vec())
-    {
-        let mut rec: JsonValue = serde_json::from_slice(&rec_v).unwrap_or(serde_json::json!({}));
-        let mut reembed = false;
-        if let Some(c) = req.content {
-            rec["content"] = serde_json::json!(c);
-            reembed = true;
-        }
-        if let Some(m) = req.metadata {
-            rec["metadata"] = m;
-        }
-        let ver = rec.get("version").and_then(|v| v.as_u64()).unwrap_or(0) + 1;
-        rec["version"] = serde_json::json!(ver);
-        tree.insert(req.id.as_bytes(), serde_json::to_vec(&rec).unwrap())
-            .expect("insert");
-        // Re-embed and refresh indices on content change
-        if reembed {
-            let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
-            // Update memory embedding
-            if let Ok(emb_tree) = state.db.open_tree("mem_embeddings") {
-                let vecs = embeddings::embed_batch(&[content]);
-                let bytes: &[u8] = bytemuck::cast_slice(&vecs[0]);
-                let _ = emb_tree.insert(req.id.as_bytes(), bytes);
-            }
-            // Refresh text indices
-            let _ = index_memory_sled(&state.db, &req.id, content);
-            let _ = index_memory_tantivy(&state.index_dir, &req.id, content);
+    let entity = match body.get("entity").and_then(|e| e.as_str()) {
+        Some(e) => e,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "entity field required",
+                None,
+            )
         }
-        state.db.flush().expect("flush");
-        Json(serde_json::json!({ "id": req.id, "version": ver, "reembedded": reembed, "updatedIndices": ["text", "vector"] })).into_response()
-    } else {
-        json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None)
+    };
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    match kg::ensure_entity_node(&state.db(), entity, now_ms) {
+        Ok(_) => {
+            state.db().flush().ok();
+            Json(serde_json::json!({ "entity": entity, "created": true })).into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
 }
 
-async fn memory_delete(
+async fn kg_create_relation(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(req): Json<DeleteMemoryRequest>,
+    Json(body): Json<serde_json::Value>,
 ) -> Response {
-    let tree = state.db.open_tree("memories").expect("mem tree");
-    // Optional backup
-    if req.backup.unwrap_or(false) {
-        if let Ok(Some(v)) = tree.get(req.id.as_bytes()) {
-            if let Ok(backup) = state.db.open_tree("backups_memories") {
-                let ts = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                let key = format!("{}:{}", ts, &req.id);
-                let _ = backup.insert(key.as_bytes(), v);
-            }
-        }
-    }
-    // Dependency checks: remove KG edges from/to this memory; doc_refs
-    if let Ok(edges) = state.db.open_tree("kg_edges") {
-        let prefix = format!("Memory::{}->", &req.id);
-        let to_remove: Vec<_> = edges
-            .scan_prefix(prefix.as_bytes())
-            .filter_map(|kv| kv.ok().map(|(k, _)| k))
-            .collect();
-        for k in to_remove {
-            let _ = edges.remove(k);
+    let src = match body.get("src").and_then(|s| s.as_str()) {
+        Some(s) => s,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "src field required",
+                None,
+            )
         }
-    }
-    if let Ok(text_idx) = state.db.open_tree("text_index") {
-        let _ = text_idx.remove(format!("mem:{}", &req.id).as_bytes());
-    }
-    if let Ok(emb) = state.db.open_tree("mem_embeddings") {
-        let _ = emb.remove(req.id.as_bytes());
-    }
-    if let Ok(refs) = state.db.open_tree("doc_refs") {
-        let prefix = format!("mem::{}::", &req.id);
-        let to_remove: Vec<_> = refs
-            .scan_prefix(prefix.as_bytes())
-            .filter_map(|kv| kv.ok().map(|(k, _)| k))
-            .collect();
-        for k in to_remove {
-            let _ = refs.remove(k);
+    };
+    let dst = match body.get("dst").and_then(|d| d.as_str()) {
+        Some(d) => d,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "dst field required",
+                None,
+            )
         }
-    }
-    let existed = tree.remove(req.id.as_bytes()).expect("remove").is_some();
-    state.db.flush().expect("flush");
-    if existed {
-        Json(serde_json::json!({ "deleted": true, "cascaded": true })).into_response()
-    } else {
-        json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None)
-    }
-}
+    };
+    let relation = body
+        .get("relation")
+        .and_then(|r| r.as_str())
+        .unwrap_or("RELATED");
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
 
-async fn maintenance_loop(state: Arc<AppState>) {
-    let interval_ms: u64 = std::env::var("STM_CLEAN_INTERVAL_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(60_000);
-    loop {
-        if let Err(err) = run_maintenance(&state) {
-            error!(%err, "maintenance error");
+    match kg::add_edge_generic(&state.db(), src, dst, relation, now_ms) {
+        Ok(_) => {
+            state.invalidate_query_cache();
+            state.db().flush().ok();
+            Json(serde_json::json!({ "src": src, "dst": dst, "relation": relation, "created": true })).into_response()
         }
-        prune_query_cache(&state).await;
-        sleep(Duration::from_millis(interval_ms)).await;
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
 }
 
-fn run_maintenance(state: &Arc<AppState>) -> Result<()> {
+async fn kg_cooccurrence(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let min_count = body.get("minCount").and_then(|v| v.as_u64()).unwrap_or(1);
+    let limit = body.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
     let now_ms = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_millis() as i64;
-    let decay: f64 = std::env::var("LTM_DECAY_PER_CLEAN")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0.99);
-    let tree = state.db.open_tree("memories")?;
-    for kv in tree.iter() {
-        let (k, v) = kv?;
-        let mut rec: serde_json::Value =
-            serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
-        let layer = rec.get("layer").and_then(|c| c.as_str()).unwrap_or("");
-        if layer == "STM" {
-            if let Some(exp) = rec.get("expires_at").and_then(|c| c.as_i64()) {
-                if exp <= now_ms {
-                    let _ = tree.remove(k);
-                    continue;
-                }
-            }
-            // Promotion: if STM has high importance or frequently accessed, promote to LTM
-            let importance = rec
-                .get("importance")
-                .and_then(|c| c.as_f64())
-                .unwrap_or(1.0);
-            let accessed = rec
-                .get("access_count")
-                .and_then(|c| c.as_u64())
-                .unwrap_or(0);
-            let promote_threshold = std::env::var("CONSOLIDATE_IMPORTANCE_MIN")
-                .ok()
-                .and_then(|v| v.parse::<f64>().ok())
-                .unwrap_or(1.5);
-            let access_threshold = std::env::var("CONSOLIDATE_ACCESS_MIN")
-                .ok()
-                .and_then(|v| v.parse::<u64>().ok())
-                .unwrap_or(3);
-            if importance >= promote_threshold || accessed >= access_threshold {
-                rec["layer"] = serde_json::json!("LTM");
-                rec["promoted_at"] = serde_json::json!(now_ms);
-                tree.insert(&k, serde_json::to_vec(&rec)?)?;
-            }
-        } else if layer == "LTM" {
-            let imp = rec
-                .get("importance")
-                .and_then(|c| c.as_f64())
-                .unwrap_or(1.0)
-                * decay;
-            rec["importance"] = serde_json::json!(imp);
-            tree.insert(&k, serde_json::to_vec(&rec)?)?;
-        }
-        // Scheduled promotion based on thresholds
-        let importance = rec
-            .get("importance")
-            .and_then(|c| c.as_f64())
-            .unwrap_or(1.0);
-        let accessed = rec
-            .get("access_count")
-            .and_then(|c| c.as_u64())
-            .unwrap_or(0);
-        let promote_threshold = std::env::var("CONSOLIDATE_IMPORTANCE_MIN")
-            .ok()
-            .and_then(|v| v.parse::<f64>().ok())
-            .unwrap_or(1.5);
-        let access_threshold = std::env::var("CONSOLIDATE_ACCESS_MIN")
-            .ok()
-            .and_then(|v| v.parse::<u64>().ok())
-            .unwrap_or(3);
-        if importance >= promote_threshold || accessed >= access_threshold {
-            rec["layer"] = serde_json::json!("LTM");
-            rec["promoted_at"] = serde_json::json!(now_ms);
-            tree.insert(&k.clone(), serde_json::to_vec(&rec)?)?;
-            // Audit (best-effort)
-            if let Ok(log) = state.db.open_tree("consolidation_log") {
-                let id = rec.get("id").and_then(|c| c.as_str()).unwrap_or("");
-                let reason = if importance >= promote_threshold {
-                    "importance"
-                } else {
-                    "access"
-                };
-                let log_key = format!("{}:{}", now_ms, id);
-                let log_val = serde_json::json!({ "id": id, "from": "STM", "to": "LTM", "reason": reason, "ts": now_ms });
-                let _ = log.insert(log_key.as_bytes(), serde_json::to_vec(&log_val)?);
-            }
-        }
-    }
-    // Enforce STM LRU capacity if configured
-    let max_items: usize = std::env::var("STM_MAX_ITEMS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
-    if max_items > 0 {
-        let mut stm_items: Vec<(sled::IVec, i64)> = Vec::new();
-        for kv in tree.iter() {
-            let (k, v) = kv?;
-            let rec: serde_json::Value =
-                serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
-            let layer = rec.get("layer").and_then(|c| c.as_str()).unwrap_or("");
-            if layer == "STM" {
-                let ts = rec
-                    .get("last_access_ts")
-                    .and_then(|c| c.as_i64())
-                    .or_else(|| rec.get("created_at").and_then(|c| c.as_i64()))
-                    .unwrap_or(now_ms);
-                stm_items.push((k, ts));
-            }
-        }
-        if stm_items.len() > max_items {
-            stm_items.sort_by_key(|(_, ts)| *ts);
-            let to_remove = stm_items.len() - max_items;
-            for (k, _) in stm_items.into_iter().take(to_remove) {
-                let _ = tree.remove(k);
-            }
+    match kg::build_cooccurrence(&state.db(), min_count, now_ms) {
+        Ok(pairs) => {
+            state.db().flush().ok();
+            let top: Vec<serde_json::Value> = pairs
+                .iter()
+                .take(limit)
+                .map(|(a, b, count)| serde_json::json!({ "a": a, "b": b, "count": count }))
+                .collect();
+            Json(serde_json::json!({ "edges": pairs.len(), "minCount": min_count, "top": top }))
+                .into_response()
         }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
-    state.db.flush()?;
-    Ok(())
 }
 
-async fn prune_query_cache(state: &Arc<AppState>) {
-    let ttl_ms: i64 = std::env::var("FUSION_CACHE_TTL_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(3_000);
-    let max_entries: usize = std::env::var("FUSION_CACHE_MAX")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(1_000);
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let mut guard = state.query_cache.lock().await;
-    // TTL prune
-    let keys_to_remove: Vec<String> = guard
-        .iter()
-        .filter_map(|(k, (ts, _))| {
-            if now - *ts > ttl_ms {
-                Some(k.clone())
-            } else {
-                None
-            }
+/// Graph-health overview: node counts per type, edge counts per relation and the `top`
+/// (default 10) nodes with the most edges.
+async fn kg_stats(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let top = params
+        .get("top")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(10);
+    let stats = kg::stats(&state.db(), top).map_err(internal_error)?;
+    let top_nodes: Vec<serde_json::Value> = stats
+        .top_nodes
+        .into_iter()
+        .map(|(node, degree)| {
+            let kind = node.split("::").next().unwrap_or_default().to_string();
+            serde_json::json!({ "node": node, "type": kind, "degree": degree })
         })
         .collect();
-    for k in keys_to_remove {
-        guard.remove(&k);
+    Ok(Json(serde_json::json!({
+        "nodes": { "total": stats.nodes.values().sum::<u64>(), "byType": stats.nodes },
+        "edges": { "total": stats.edges.values().sum::<u64>(), "byRelation": stats.edges },
+        "topNodes": top_nodes,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+async fn kg_centrality(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(50);
+    let iterations = params
+        .get("iterations")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(20)
+        .min(100);
+    let damping = params
+        .get("damping")
+        .and_then(|s| s.parse::<f64>().ok())
+        .unwrap_or(0.85);
+    if !(0.0..=1.0).contains(&damping) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "damping must be between 0 and 1",
+            None,
+        );
     }
-    // Size prune (LRU-ish by oldest ts)
-    if guard.len() > max_entries {
-        let mut items: Vec<(String, i64)> =
-            guard.iter().map(|(k, (ts, _))| (k.clone(), *ts)).collect();
-        items.sort_by_key(|(_, ts)| *ts); // oldest first
-        let to_remove = guard.len() - max_entries;
-        for (k, _) in items.into_iter().take(to_remove) {
-            guard.remove(&k);
+    // Optional node type filter, e.g. type=Entity
+    let prefix = params.get("type").map(|t| format!("{}::", t));
+    match kg::pagerank(&state.db(), iterations, damping) {
+        Ok(ranked) => {
+            let nodes: Vec<serde_json::Value> = ranked
+                .into_iter()
+                .filter(|(key, _)| prefix.as_ref().is_none_or(|p| key.starts_with(p)))
+                .take(limit)
+                .map(|(key, score)| serde_json::json!({ "node": key, "score": score }))
+                .collect();
+            Json(
+                serde_json::json!({ "nodes": nodes, "iterations": iterations, "damping": damping }),
+            )
+            .into_response()
         }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
 }
 
-async fn advanced_consolidate(
+async fn kg_search_nodes(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Json<serde_json::Value> {
-    let limit = body.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
-    let dry = body
-        .get("dryRun")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let promote_threshold = std::env::var("CONSOLIDATE_IMPORTANCE_MIN")
-        .ok()
-        .and_then(|v| v.parse::<f64>().ok())
-        .unwrap_or(1.5);
-    let access_threshold = std::env::var("CONSOLIDATE_ACCESS_MIN")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(3);
-    let tree = state.db.open_tree("memories").expect("mem tree");
-    let mut promoted = 0usize;
-    let mut candidates = 0usize;
-    for kv in tree.iter() {
-        if promoted >= limit {
-            break;
+    let node_type = params.get("type").map(|s| s.as_str());
+    let pattern = params.get("pattern").map(|s| s.as_str());
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(50);
+    let fuzzy = params.get("fuzzy").is_some_and(|v| v == "true");
+    let max_distance = fuzzy.then(|| {
+        params
+            .get("maxDistance")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(2)
+    });
+    let results =
+        kg::search_nodes(&state.db(), node_type, pattern, max_distance, limit).unwrap_or_default();
+    Json(serde_json::json!({ "nodes": results, "count": results.len() }))
+}
+
+async fn kg_read_graph(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Json<serde_json::Value> {
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(100);
+    let window = kg::TimeWindow {
+        from: params.get("from").and_then(|s| s.parse().ok()),
+        to: params.get("to").and_then(|s| s.parse().ok()),
+    };
+    Json(graph_json(&state.db(), limit, window))
+}
+
+/// Node labels and `[src, dst, relation]` triples for the shared KG graph construction.
+fn graph_json(db: &sled::Db, limit: usize, window: kg::TimeWindow) -> serde_json::Value {
+    let Ok(g) = kg::build_graph(db, limit, window) else {
+        return serde_json::json!({ "nodes": [], "edges": [] });
+    };
+    let nodes_out: Vec<String> = g.node_indices().map(|i| g[i].label.clone()).collect();
+    let edges_out: Vec<(String, String, String)> = g
+        .edge_indices()
+        .map(|eidx| {
+            let (s, t) = g.edge_endpoints(eidx).unwrap();
+            (
+                g[s].label.clone(),
+                g[t].label.clone(),
+                g[eidx].relation.clone(),
+            )
+        })
+        .collect();
+    serde_json::json!({ "nodes": nodes_out, "edges": edges_out })
+}
+
+/// Serialize the KG as GraphML (`format=graphml`, default) or Graphviz DOT (`format=dot`).
+async fn kg_export(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(100);
+    let format = params
+        .get("format")
+        .map(|s| s.as_str())
+        .unwrap_or("graphml");
+    if format != "graphml" && format != "dot" {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "format must be graphml or dot",
+            Some(serde_json::json!({ "format": format })),
+        );
+    }
+    let g = match kg::build_graph(&state.db(), limit, kg::TimeWindow::default()) {
+        Ok(g) => g,
+        Err(err) => {
+            return json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL_ERROR",
+                err.to_string(),
+                None,
+            )
         }
-        let (k, v) = kv.expect("ok");
-        let mut rec: serde_json::Value =
-            serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
-        let layer = rec.get("layer").and_then(|c| c.as_str()).unwrap_or("");
-        if layer != "STM" {
-            continue;
+    };
+    let (content_type, body) = if format == "dot" {
+        ("text/plain; charset=utf-8", kg::to_dot(&g))
+    } else {
+        ("application/xml", kg::to_graphml(&g))
+    };
+    ([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+async fn kg_tag_entity(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let entity = match body.get("entity").and_then(|e| e.as_str()) {
+        Some(e) => e,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "entity field required",
+                None,
+            )
         }
-        let importance = rec
-            .get("importance")
-            .and_then(|c| c.as_f64())
-            .unwrap_or(1.0);
-        let accessed = rec
-            .get("access_count")
-            .and_then(|c| c.as_u64())
-            .unwrap_or(0);
-        if importance >= promote_threshold || accessed >= access_threshold {
-            candidates += 1;
-            if !dry {
-                rec["layer"] = serde_json::json!("LTM");
-                rec["promoted_at"] = serde_json::json!(now_ms);
-                tree.insert(k, serde_json::to_vec(&rec).expect("ser"))
-                    .expect("insert");
-                promoted += 1;
+    };
+
+    // Accept tags as either array or comma-separated string for flexibility
+    let tags: Vec<String> = match body.get("tags") {
+        Some(val) => {
+            if let Some(arr) = val.as_array() {
+                // Normal case: array of strings
+                arr.iter()
+                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                    .collect()
+            } else if let Some(s) = val.as_str() {
+                // Fallback: comma-separated string
+                s.split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect()
+            } else {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    format!("tags must be array or string, got: {:?}", val),
+                    None,
+                );
             }
         }
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "tags field required",
+                None,
+            )
+        }
+    };
+
+    if tags.is_empty() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "tags cannot be empty",
+            None,
+        );
+    }
+
+    match kg::tag_entity(&state.db(), entity, &tags) {
+        Ok(_) => {
+            state.db().flush().ok();
+            Json(serde_json::json!({ "entity": entity, "tags": tags, "tagged": true }))
+                .into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
     }
-    state.db.flush().expect("flush");
-    Json(serde_json::json!({ "promoted": promoted, "candidates": candidates, "tookMs": 0 }))
 }
 
-async fn search_fusion(
+async fn kg_get_tags(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Json<SearchResponse> {
-    let started = std::time::Instant::now();
-    let q = params.get("q").cloned().unwrap_or_default().to_lowercase();
-    let limit = params
-        .get("limit")
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(10);
-    let time_from = params.get("from").and_then(|s| s.parse::<i64>().ok());
-    let time_to = params.get("to").and_then(|s| s.parse::<i64>().ok());
-    let cache_key = format!("q={}::limit={}", q, limit);
+) -> Json<serde_json::Value> {
+    if let Some(tag) = params.get("tag") {
+        // Get entities by specific tag
+        let entities = kg::get_entities_by_tag(&state.db(), tag).unwrap_or_default();
+        Json(serde_json::json!({ "tag": tag, "entities": entities }))
+    } else {
+        // Get all tags
+        let tags = kg::get_all_tags(&state.db()).unwrap_or_default();
+        Json(serde_json::json!({ "tags": tags }))
+    }
+}
+
+async fn kg_remove_tag(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let entity = match body.get("entity").and_then(|e| e.as_str()) {
+        Some(e) => e,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "entity field required",
+                None,
+            )
+        }
+    };
+
+    // Accept tags as either array or comma-separated string for flexibility
+    let tags: Vec<String> = match body.get("tags") {
+        Some(val) => {
+            if let Some(arr) = val.as_array() {
+                // Normal case: array of strings
+                arr.iter()
+                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                    .collect()
+            } else if let Some(s) = val.as_str() {
+                // Fallback: comma-separated string
+                s.split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect()
+            } else {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    format!("tags must be array or string, got: {:?}", val),
+                    None,
+                );
+            }
+        }
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "tags field required",
+                None,
+            )
+        }
+    };
+
+    if tags.is_empty() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "tags cannot be empty",
+            None,
+        );
+    }
+
+    match kg::remove_tags_from_entity(&state.db(), entity, &tags) {
+        Ok(_) => {
+            state.db().flush().ok();
+            Json(serde_json::json!({ "entity": entity, "removed": tags, "success": true }))
+                .into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+/// Fold entity `from` into `into` and record `from` as its alias.
+async fn kg_merge_entity(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let field = |name: &str| {
+        body.get(name)
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    };
+    let (Some(from), Some(into)) = (field("from"), field("into")) else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "from and into fields required",
+            None,
+        );
+    };
+    if kg::resolve_alias(&state.db(), into) == from {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "cannot merge an entity into itself",
+            None,
+        );
+    }
     let now_ms = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_millis() as i64;
-    // Serve from cache if fresh
-    if let Some(cached) = {
-        let guard = state.query_cache.lock().await;
-        guard
-            .get(&cache_key)
-            .map(|(ts, items)| (*ts, items.clone()))
-    } {
-        let (ts, mut items) = cached;
-        if now_ms - ts
-            <= std::env::var("FUSION_CACHE_TTL_MS")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(3_000)
-        {
-            items.truncate(limit);
-            // metrics update: cache hit
-            {
-                let mut m = state.metrics.lock().await;
-                m.count += 1;
-                m.cache_hits += 1;
-                m.last_ms = 0;
-                // history window for percentiles/QPS
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as i64;
-                m.history.push_back((now, 0));
-                while let Some((ts, _)) = m.history.front().cloned() {
-                    if now - ts > 60_000 {
-                        m.history.pop_front();
-                    } else {
+    let outcome = match kg::merge_entity(&state.db(), from, into, now_ms) {
+        Ok(outcome) => outcome,
+        Err(e) => return internal_error(e),
+    };
+    state.invalidate_query_cache();
+    if let Err(e) = state.db().flush() {
+        return internal_error(e);
+    }
+    Json(serde_json::json!({
+        "from": from,
+        "into": outcome.into,
+        "linksMoved": outcome.links_moved,
+        "edgesMoved": outcome.edges_moved,
+        "mentions": outcome.mentions,
+    }))
+    .into_response()
+}
+
+async fn kg_delete_entity(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let entity = match body.get("entity").and_then(|e| e.as_str()) {
+        Some(e) => e,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "entity field required",
+                None,
+            )
+        }
+    };
+
+    match kg::delete_entity(&state.db(), entity) {
+        Ok(removed) => {
+            state.invalidate_query_cache();
+            state.db().flush().ok();
+            Json(serde_json::json!({ "entity": entity, "deleted": true, "removedItems": removed }))
+                .into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+async fn kg_delete_relation(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let src = match body.get("src").and_then(|s| s.as_str()) {
+        Some(s) => s,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "src field required",
+                None,
+            )
+        }
+    };
+    let dst = match body.get("dst").and_then(|d| d.as_str()) {
+        Some(d) => d,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "dst field required",
+                None,
+            )
+        }
+    };
+    let relation = body
+        .get("relation")
+        .and_then(|r| r.as_str())
+        .unwrap_or("RELATED");
+
+    match kg::delete_relation(&state.db(), src, dst, relation) {
+        Ok(deleted) => {
+            state.invalidate_query_cache();
+            state.db().flush().ok();
+            Json(serde_json::json!({ "src": src, "dst": dst, "relation": relation, "deleted": deleted })).into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+fn index_chunks_tantivy(
+    text_index: &text_index::TextIndex,
+    doc_id: &str,
+    chunks: &[TextChunk],
+) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    for ch in chunks {
+        let key = chunk_key(doc_id, ch.header.position.start);
+        text_index.add(&key, "chunk", &ch.text, now)?;
+    }
+    Ok(())
+}
+
+fn index_memory_tantivy(
+    text_index: &text_index::TextIndex,
+    mem_id: &str,
+    content: &str,
+) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let key = format!("mem:{}", mem_id);
+    delete_from_tantivy(text_index, &key)?;
+    text_index.add(&key, "memory", content, now)
+}
+
+/// Drop every tantivy document whose id is `id_term` (`mem:<id>` or `<docId>:<start>`).
+fn delete_from_tantivy(text_index: &text_index::TextIndex, id_term: &str) -> Result<()> {
+    text_index.delete(id_term)
+}
+
+/// Recreate a tantivy index left in an older schema from the sled text index, which
+/// holds the same id and text pairs. Returns the number of documents re-added.
+fn ensure_text_index_schema(db: &sled::Db, text_index: &text_index::TextIndex) -> Result<usize> {
+    if !text_index.ensure_current()? {
+        return Ok(0);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let mut added = 0;
+    for kv in db.open_tree("text_index")?.iter() {
+        let (k, v) = kv?;
+        let key = String::from_utf8_lossy(&k);
+        let kind = if key.starts_with("mem:") {
+            "memory"
+        } else {
+            "chunk"
+        };
+        text_index.add(&key, kind, &String::from_utf8_lossy(&v), now)?;
+        added += 1;
+    }
+    text_index.commit()?;
+    Ok(added)
+}
+
+/// BM25-ranked ids (`mem:<id>` or `<docId>:<start>`) matching `query` on the `content` field.
+/// Empty when the index is missing or can't be opened; callers fall back to substring scans.
+fn tantivy_search(
+    text_index: &text_index::TextIndex,
+    query: &str,
+    limit: usize,
+) -> Vec<(String, f32)> {
+    text_index.search(query, limit).unwrap_or_default()
+}
+
+fn index_memory_sled(db: &sled::Db, mem_id: &str, content: &str) -> Result<()> {
+    let text_idx = db.open_tree("text_index")?;
+    let key = format!("mem:{}", mem_id);
+    text_idx.insert(key.as_bytes(), content.as_bytes())?;
+    Ok(())
+}
+
+async fn run_stdio(state: Arc<AppState>) {
+    serve_jsonrpc_lines(state, tokio::io::stdin(), tokio::io::stdout()).await;
+}
+
+/// Line-delimited JSON-RPC over any reader/writer pair; requests run concurrently and
+/// server notifications are interleaved on the same output.
+async fn serve_jsonrpc_lines<R, W>(state: Arc<AppState>, input: R, output: W)
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use tokio::time::timeout;
+
+    let mut reader = BufReader::new(input).lines();
+    let stdout = Arc::new(AsyncMutex::new(output));
+
+    info!("STDIO MCP handler started");
+
+    let mut notifications = state.notifications.subscribe();
+    let notify_out = stdout.clone();
+    let notifier = tokio::spawn(async move {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            match notifications.recv().await {
+                Ok(n) => {
+                    if let Err(e) = write_response(notify_out.clone(), &n).await {
+                        error!("Failed to write notification: {}", e);
                         break;
                     }
                 }
-                let mut lat: Vec<u64> = m.history.iter().map(|(_, v)| *v).collect();
-                lat.sort_unstable();
-                if !lat.is_empty() {
-                    let p50_idx = (lat.len() as f64 * 0.5).floor() as usize;
-                    let p95_idx = (lat.len() as f64 * 0.95)
-                        .floor()
-                        .min((lat.len() - 1) as f64) as usize;
-                    m.p50_ms = lat[p50_idx] as f64;
-                    m.p95_ms = lat[p95_idx] as f64;
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Track active requests to limit concurrency
+    let active_requests = Arc::new(AsyncMutex::new(0_usize));
+    const MAX_CONCURRENT_REQUESTS: usize = 10;
+
+    loop {
+        // Read next line with proper error handling
+        let line_result = reader.next_line().await;
+
+        let line = match line_result {
+            Ok(Some(l)) => l,
+            Ok(None) => {
+                info!("Stdin closed (EOF), shutting down stdio handler");
+                break;
+            }
+            Err(e) => {
+                error!("Error reading from stdin: {}, shutting down", e);
+                break;
+            }
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Parse JSON-RPC request
+        let v: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to parse JSON-RPC request: {}", e);
+                // Send error response for malformed JSON
+                let error_response = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": -32700, "message": format!("Parse error: {}", e) }
+                });
+                if let Err(write_err) = write_response(stdout.clone(), &error_response).await {
+                    error!("Failed to write error response: {}", write_err);
+                    break;
                 }
-                m.qps_1m = m.history.len() as f64 / 60.0;
+                continue;
+            }
+        };
+
+        let id_val_opt = v.get("id").cloned();
+        let method = v.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = v.get("params").cloned().unwrap_or(serde_json::json!({}));
+
+        // Ignore notifications (no id), including notifications/initialized
+        if id_val_opt.is_none() || id_val_opt.as_ref().map(|x| x.is_null()).unwrap_or(true) {
+            info!("Received notification: {}", method);
+            continue;
+        }
+
+        let id_val = id_val_opt.unwrap();
+        info!("Received request: method={}, id={}", method, id_val);
+
+        // Check concurrent request limit
+        {
+            let mut count = active_requests.lock().await;
+            if *count >= MAX_CONCURRENT_REQUESTS {
+                error!("Too many concurrent requests, rejecting: method={}", method);
+                let error_response = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id_val,
+                    "error": { "code": -32000, "message": "Too many concurrent requests" }
+                });
+                if let Err(e) = write_response(stdout.clone(), &error_response).await {
+                    error!("Failed to write rate limit error: {}", e);
+                    break;
+                }
+                continue;
+            }
+            *count += 1;
+        }
+
+        // Process request concurrently
+        let stdout_clone = stdout.clone();
+        let active_requests_clone = active_requests.clone();
+        let method_str = method.to_string();
+        let id_val_clone = id_val.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            // Set timeout for request processing
+            let request_timeout = Duration::from_secs(60);
+
+            let response_result = timeout(request_timeout, async {
+                process_request(state.clone(), &method_str, &params, &id_val_clone).await
+            })
+            .await;
+
+            let response_json = match response_result {
+                Ok(response) => response,
+                Err(_) => {
+                    error!(
+                        "Request timeout: method={}, id={}",
+                        method_str, id_val_clone
+                    );
+                    serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id_val_clone,
+                        "error": { "code": -32000, "message": "Request timeout" }
+                    })
+                }
+            };
+
+            // Write response
+            if let Err(e) = write_response(stdout_clone.clone(), &response_json).await {
+                error!("Failed to write response for method={}: {}", method_str, e);
+            } else {
+                info!(
+                    "Response sent for method={}, id={}",
+                    method_str, id_val_clone
+                );
+                // Only this connection initialized; other clients get no notification
+                if method_str == "initialize" {
+                    if let Err(e) = write_response(stdout_clone, &tools_list_changed()).await {
+                        error!("Failed to write notification: {}", e);
+                    }
+                }
+            }
+
+            // Decrement active request count
+            let mut count = active_requests_clone.lock().await;
+            *count = count.saturating_sub(1);
+        });
+    }
+
+    // Wait for active requests to complete before exiting
+    let wait_start = std::time::Instant::now();
+    while wait_start.elapsed() < Duration::from_secs(2) {
+        let count = *active_requests.lock().await;
+        if count == 0 {
+            break;
+        }
+        info!("Waiting for {} active requests to complete...", count);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    notifier.abort();
+    info!(
+        "STDIO handler exiting (active requests: {})",
+        *active_requests.lock().await
+    );
+}
+
+async fn process_request(
+    state: Arc<AppState>,
+    method: &str,
+    params: &serde_json::Value,
+    id_val: &serde_json::Value,
+) -> serde_json::Value {
+    match method {
+        "initialize" => {
+            let result = serde_json::json!({
+                "serverInfo": {
+                    "name": "memorized-mcp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "instructions": "MemorizedMCP: hybrid memory server exposing tools over MCP."
+                },
+                "protocolVersion": "2024-11-05",
+                "capabilities": { "tools": { "listChanged": true, "call": {} }, "resources": { "listChanged": true }, "logging": {}, "sampling": {} }
+            });
+            serde_json::json!({ "jsonrpc": "2.0", "id": id_val, "result": result })
+        }
+        "tools/list" => {
+            let tools = list_tools()
+                .iter()
+                .map(ToolDescriptor::to_json)
+                .collect::<Vec<_>>();
+            serde_json::json!({ "jsonrpc": "2.0", "id": id_val, "result": { "tools": tools } })
+        }
+        "resources/list" => {
+            let all = list_resources(&state.db());
+            let offset = params
+                .get("cursor")
+                .and_then(|c| c.as_str())
+                .and_then(decode_cursor)
+                .unwrap_or(0);
+            let end = offset.saturating_add(RESOURCES_PAGE_SIZE);
+            let page: Vec<_> = all.iter().skip(offset).take(RESOURCES_PAGE_SIZE).collect();
+            let mut result = serde_json::json!({ "resources": page });
+            if end < all.len() {
+                result["nextCursor"] = serde_json::json!(encode_cursor(end));
+            }
+            serde_json::json!({ "jsonrpc": "2.0", "id": id_val, "result": result })
+        }
+        "resources/read" => {
+            let uri = params.get("uri").and_then(|u| u.as_str()).unwrap_or("");
+            match read_resource(&state.db(), uri) {
+                Some(contents) => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id_val,
+                    "result": { "contents": [contents] }
+                }),
+                None => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id_val,
+                    "error": { "code": -32002, "message": format!("Resource not found: {}", uri) }
+                }),
+            }
+        }
+        "tools/call" => {
+            let name = params.get("name").and_then(|n| n.as_str()).unwrap_or("");
+            let arguments = params
+                .get("arguments")
+                .cloned()
+                .unwrap_or(serde_json::json!({}));
+
+            match call_tool(state, name, &arguments).await {
+                Ok(json_val) => {
+                    let text_payload = if let Some(s) = json_val.as_str() {
+                        s.to_string()
+                    } else {
+                        serde_json::to_string_pretty(&json_val)
+                            .unwrap_or_else(|_| json_val.to_string())
+                    };
+                    serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id_val,
+                        "result": { "content": [ { "type": "text", "text": text_payload } ] }
+                    })
+                }
+                Err(err) => {
+                    error!("Tool call failed: tool={}, error={}", name, err.message);
+                    serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id_val,
+                        "error": err.to_json()
+                    })
+                }
+            }
+        }
+        _ => {
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id_val,
+                "error": { "code": -32601, "message": format!("Unknown method: {}", method) }
+            })
+        }
+    }
+}
+
+/// Removes an SSE session from `AppState` when its event stream is dropped.
+struct SseSessionGuard {
+    state: Arc<AppState>,
+    id: String,
+}
+
+impl Drop for SseSessionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut sessions) = self.state.sse_sessions.lock() {
+            sessions.remove(&self.id);
+        }
+        info!("SSE session {} closed", self.id);
+    }
+}
+
+/// MCP over SSE: opens a session and first sends an `endpoint` event naming the URL the
+/// client must POST its JSON-RPC messages to; responses arrive as `message` events.
+async fn mcp_sse(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> axum::response::sse::Sse<
+    impl futures_util::Stream<Item = Result<axum::response::sse::Event, std::convert::Infallible>>,
+> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use futures_util::StreamExt;
+
+    let id = Uuid::new_v4().to_string();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    let tx_for_notifications = tx.clone();
+    state
+        .sse_sessions
+        .lock()
+        .expect("sse sessions")
+        .insert(id.clone(), tx);
+    info!("SSE session {} opened", id);
+    // Forward server notifications until the session's stream goes away
+    let mut notifications = state.notifications.subscribe();
+    let notify_tx = tx_for_notifications;
+    tokio::spawn(async move {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            tokio::select! {
+                n = notifications.recv() => match n {
+                    Ok(n) => {
+                        if notify_tx.send(n).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = notify_tx.closed() => break,
+            }
+        }
+    });
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/messages?sessionId={}", id));
+    let guard = SseSessionGuard {
+        state: state.clone(),
+        id,
+    };
+    let messages = futures_util::stream::unfold((rx, guard), |(mut rx, guard)| async move {
+        let msg = rx.recv().await?;
+        let event = Event::default().event("message").data(msg.to_string());
+        Some((Ok(event), (rx, guard)))
+    });
+    Sse::new(futures_util::stream::once(async move { Ok(endpoint) }).chain(messages))
+        .keep_alive(KeepAlive::default())
+}
+
+/// Client -> server half of the SSE transport. Requests are answered on the session's
+/// event stream; notifications are accepted and dropped.
+async fn mcp_messages(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+    body: String,
+) -> Response {
+    let session = params.get("sessionId").cloned().unwrap_or_default();
+    let tx = state
+        .sse_sessions
+        .lock()
+        .expect("sse sessions")
+        .get(&session)
+        .cloned();
+    let Some(tx) = tx else {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Unknown SSE session",
+            Some(serde_json::json!({ "sessionId": session })),
+        );
+    };
+    let v: serde_json::Value = match serde_json::from_str(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = tx.send(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": format!("Parse error: {}", e) }
+            }));
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                e.to_string(),
+                None,
+            );
+        }
+    };
+    let id_val = v.get("id").cloned().filter(|x| !x.is_null());
+    if let Some(id_val) = id_val {
+        let method = v
+            .get("method")
+            .and_then(|m| m.as_str())
+            .unwrap_or("")
+            .to_string();
+        let params = v.get("params").cloned().unwrap_or(serde_json::json!({}));
+        tokio::spawn(async move {
+            let response = process_request(state, &method, &params, &id_val).await;
+            let _ = tx.send(response);
+            if method == "initialize" {
+                let _ = tx.send(tools_list_changed());
+            }
+        });
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+const RESOURCES_PAGE_SIZE: usize = 100;
+
+/// MCP resources: every stored document as `memory://document/{id}` followed by live
+/// memories as `memory://memory/{id}`.
+fn list_resources(db: &sled::Db) -> Vec<serde_json::Value> {
+    let mut out = Vec::new();
+    if let Ok(docs_info) = db.open_tree("docs_info") {
+        for (k, v) in docs_info.iter().flatten() {
+            let id = String::from_utf8_lossy(&k).to_string();
+            let info = serde_json::from_slice::<serde_json::Value>(&v).unwrap_or_default();
+            let name = match (
+                info.get("path").and_then(|p| p.as_str()),
+                info.get("version"),
+            ) {
+                (Some(path), Some(ver)) => format!("{} (v{})", path, ver),
+                (Some(path), None) => path.to_string(),
+                _ => id.clone(),
+            };
+            out.push(serde_json::json!({
+                "uri": format!("memory://document/{}", id),
+                "name": name,
+                "mimeType": "text/plain",
+            }));
+        }
+    }
+    if let Ok(mems) = db.open_tree("memories") {
+        for (k, v) in mems.iter().flatten() {
+            let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+                continue;
+            };
+            if is_tombstoned(&rec) {
+                continue;
+            }
+            let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+            let name: String = content.chars().take(60).collect();
+            out.push(serde_json::json!({
+                "uri": format!("memory://memory/{}", String::from_utf8_lossy(&k)),
+                "name": name,
+                "description": rec.get("layer"),
+                "mimeType": "text/plain",
+            }));
+        }
+    }
+    out
+}
+
+/// Text contents for a resource URI from `list_resources`, or `None` if it doesn't exist.
+fn read_resource(db: &sled::Db, uri: &str) -> Option<serde_json::Value> {
+    let text = if let Some(id) = uri.strip_prefix("memory://document/") {
+        db.open_tree("docs_info").ok()?.get(id.as_bytes()).ok()??;
+        document_text(db, id)
+    } else if let Some(id) = uri.strip_prefix("memory://memory/") {
+        let raw = db.open_tree("memories").ok()?.get(id.as_bytes()).ok()??;
+        let rec = serde_json::from_slice::<serde_json::Value>(&raw).ok()?;
+        if is_tombstoned(&rec) {
+            return None;
+        }
+        rec.get("content")?.as_str()?.to_string()
+    } else {
+        return None;
+    };
+    Some(serde_json::json!({ "uri": uri, "mimeType": "text/plain", "text": text }))
+}
+
+async fn write_response<W: tokio::io::AsyncWrite + Unpin>(
+    stdout: Arc<AsyncMutex<W>>,
+    response: &serde_json::Value,
+) -> Result<(), std::io::Error> {
+    use tokio::io::AsyncWriteExt;
+
+    let response_str = serde_json::to_string(response).unwrap();
+    let mut stdout_guard = stdout.lock().await;
+
+    stdout_guard.write_all(response_str.as_bytes()).await?;
+    stdout_guard.write_all(b"\n").await?;
+    stdout_guard.flush().await?;
+
+    Ok(())
+}
+
+async fn memory_add(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<AddMemoryRequest>,
+) -> Response {
+    if let Some(msg) = validate_add_request(&req) {
+        return json_error(StatusCode::BAD_REQUEST, "INVALID_INPUT", msg, None);
+    }
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let vecs = tracing::info_span!("memory_add.embed")
+        .in_scope(|| embeddings::embed_batch_cached(&state.db(), &[req.content.as_str()]));
+    let written = tracing::info_span!("memory_add.write")
+        .in_scope(|| write_memory(&state.db(), &state.text_index, req, now_ms));
+    let (id, layer) = match written {
+        Ok(written) => written,
+        Err(e) => return internal_error(e),
+    };
+    let embedded = tracing::info_span!("memory_add.vector_index")
+        .in_scope(|| store_memory_embedding(&state, &id, vecs[0]));
+    state.invalidate_query_cache();
+    if let Err(e) = embedded {
+        return internal_error(e);
+    }
+    if let Err(e) = tracing::info_span!("memory_add.flush").in_scope(|| state.db().flush()) {
+        return internal_error(e);
+    }
+    Json(AddMemoryResponse { id, layer }).into_response()
+}
+
+#[derive(Deserialize)]
+struct AddMemoryBatchRequest {
+    items: Vec<AddMemoryRequest>,
+}
+
+#[derive(Serialize)]
+struct AddMemoryBatchResponse {
+    items: Vec<AddMemoryResponse>,
+    #[serde(rename = "tookMs")]
+    took_ms: u128,
+}
+
+async fn memory_add_batch(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<AddMemoryBatchRequest>,
+) -> Response {
+    let started = std::time::Instant::now();
+    if let Some((idx, msg)) = req
+        .items
+        .iter()
+        .enumerate()
+        .find_map(|(idx, it)| validate_add_request(it).map(|msg| (idx, msg)))
+    {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            msg,
+            Some(serde_json::json!({ "index": idx })),
+        );
+    }
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    // One embedding call for the whole batch, one flush at the end
+    let texts: Vec<&str> = req.items.iter().map(|it| it.content.as_str()).collect();
+    let vecs = embeddings::embed_batch_cached(&state.db(), &texts);
+    let mut out = Vec::with_capacity(req.items.len());
+    for (item, vec) in req.items.into_iter().zip(vecs) {
+        let written =
+            write_memory(&state.db(), &state.text_index, item, now_ms).and_then(|(id, layer)| {
+                store_memory_embedding(&state, &id, vec)?;
+                Ok((id, layer))
+            });
+        match written {
+            Ok((id, layer)) => out.push(AddMemoryResponse { id, layer }),
+            Err(e) => {
+                state.invalidate_query_cache();
+                return internal_error(e);
+            }
+        }
+    }
+    state.invalidate_query_cache();
+    if let Err(e) = state.db().flush() {
+        return internal_error(e);
+    }
+    Json(AddMemoryBatchResponse {
+        items: out,
+        took_ms: started.elapsed().as_millis(),
+    })
+    .into_response()
+}
+
+/// Importance gained each time a memory is read: LTM multiplies by
+/// `LTM_STRENGTHEN_ON_ACCESS` (default 1.05), STM adds `STM_STRENGTHEN_DELTA` (default 0.05).
+struct AccessBoost {
+    ltm_mul: f64,
+    stm_add: f64,
+}
+
+impl AccessBoost {
+    fn from_env() -> Self {
+        AccessBoost {
+            ltm_mul: std::env::var("LTM_STRENGTHEN_ON_ACCESS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1.05),
+            stm_add: std::env::var("STM_STRENGTHEN_DELTA")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0.05),
+        }
+    }
+
+    /// Count an access on a memory record and strengthen its importance.
+    fn apply(&self, rec: &mut serde_json::Value, now_ms: i64) {
+        let acc = rec
+            .get("access_count")
+            .and_then(|c| c.as_u64())
+            .unwrap_or(0)
+            + 1;
+        rec["access_count"] = serde_json::json!(acc);
+        rec["last_access_ts"] = serde_json::json!(now_ms);
+        let imp = rec
+            .get("importance")
+            .and_then(|c| c.as_f64())
+            .unwrap_or(1.0);
+        let new_imp = if rec.get("layer").and_then(|c| c.as_str()) == Some("LTM") {
+            imp * self.ltm_mul
+        } else {
+            imp + self.stm_add
+        };
+        rec["importance"] = serde_json::json!(new_imp);
+    }
+}
+
+/// `STM_TTL_MS`, how long STM memories live without an explicit `ttlMs` (default 1 hour).
+fn stm_ttl_ms() -> i64 {
+    std::env::var("STM_TTL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|ms| *ms > 0)
+        .unwrap_or(3_600_000)
+}
+
+/// Minimum Jaccard score (`AUTO_LINK_MIN_SCORE`, default 0.2) and most documents
+/// (`AUTO_LINK_MAX_DOCS`, default 3) for `autoLinkDocs` references.
+fn auto_link_limits() -> (f32, usize) {
+    let min_score = std::env::var("AUTO_LINK_MIN_SCORE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0.2);
+    let max_docs = std::env::var("AUTO_LINK_MAX_DOCS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3);
+    (min_score, max_docs)
+}
+
+/// Prefix of every `doc_refs` key of memory `mem_id`.
+fn doc_refs_prefix(mem_id: &str) -> String {
+    format!("mem::{}::", kg::escape_key_part(mem_id))
+}
+
+/// `doc_refs` key `mem::<id>::doc::<docId>::chunk::<chunkId>`, each id escaped.
+fn doc_ref_key(mem_id: &str, doc_id: &str, chunk_id: &str) -> String {
+    format!(
+        "{}doc::{}::chunk::{}",
+        doc_refs_prefix(mem_id),
+        kg::escape_key_part(doc_id),
+        kg::escape_key_part(chunk_id)
+    )
+}
+
+/// Split a `doc_ref_key` into its `(memory, document, chunk)` ids.
+fn parse_doc_ref_key(key: &str) -> Option<(String, String, String)> {
+    let parts: Vec<&str> = key.split("::").collect();
+    match parts.as_slice() {
+        ["mem", mem_id, "doc", doc_id, "chunk", chunk_id] => Some((
+            kg::unescape_key_part(mem_id),
+            kg::unescape_key_part(doc_id),
+            kg::unescape_key_part(chunk_id),
+        )),
+        _ => None,
+    }
+}
+
+/// Record that memory `mem_id` cites `doc_id`: an EVIDENCE edge plus a `doc_refs` entry.
+fn add_doc_ref(
+    db: &sled::Db,
+    mem_id: &str,
+    doc_id: &str,
+    chunk_id: Option<&str>,
+    score: f32,
+    now_ms: i64,
+) {
+    kg::ensure_document_node(db, doc_id, now_ms).ok();
+    let src = format!("Memory::{}", mem_id);
+    let dst = format!("Document::{}", doc_id);
+    kg::add_edge_generic(db, &src, &dst, "EVIDENCE", now_ms).ok();
+    if let Ok(tree_refs) = db.open_tree("doc_refs") {
+        let key = doc_ref_key(mem_id, doc_id, chunk_id.unwrap_or_default());
+        let _ = tree_refs.insert(
+            key.as_bytes(),
+            serde_json::to_vec(&serde_json::json!({"score": score})).unwrap(),
+        );
+    }
+}
+
+/// Persist a new memory record with its KG links, doc refs and text indices.
+/// Returns the new id and the layer it was placed in.
+fn write_memory(
+    db: &sled::Db,
+    text_index: &text_index::TextIndex,
+    req: AddMemoryRequest,
+    now_ms: i64,
+) -> Result<(String, String)> {
+    let id = Uuid::new_v4().to_string();
+    let layer = req
+        .layer_hint
+        .as_deref()
+        .and_then(canonical_layer)
+        .unwrap_or("STM")
+        .to_string();
+    let expires_at = match req.ttl_ms {
+        Some(ttl) if ttl <= 0 => None,
+        Some(ttl) => Some(now_ms.saturating_add(ttl)),
+        None if layer == "STM" => Some(now_ms.saturating_add(stm_ttl_ms())),
+        None => None,
+    };
+    let tree = db.open_tree("memories")?;
+
+    // Create KG node for this memory and link any referenced documents as EVIDENCE
+    kg::ensure_memory_node(db, &id, now_ms).ok();
+    // Semantic: link memory to mentioned entities
+    let mem_ents_vec = kg::canonicalize_entities(db, kg::extract_entities(&req.content));
+    for e in &mem_ents_vec {
+        kg::record_entity_mention(db, e, now_ms).ok();
+    }
+    for e in &mem_ents_vec {
+        let src = format!("Memory::{}", &id);
+        let dst = format!("Entity::{}", e);
+        kg::add_edge_generic(db, &src, &dst, "MENTIONS", now_ms).ok();
+    }
+    if let Some(ep) = req.episode_id.as_ref() {
+        kg::ensure_episode_node(db, ep, now_ms, None, req.session_id.as_deref()).ok();
+        let src = format!("Memory::{}", &id);
+        let dst = format!("Episode::{}", ep);
+        kg::add_edge_generic(db, &src, &dst, "IN_EPISODE", now_ms).ok();
+    }
+    let mem_ents: HashSet<String> = mem_ents_vec.into_iter().collect();
+    let mut computed_refs: Option<Vec<serde_json::Value>> = None;
+    if let Some(refs) = req.references.as_ref() {
+        let mut out = Vec::new();
+        for r in refs {
+            let doc_id = &r.doc_id;
+            // Score evidence using Jaccard of entities if score not provided
+            let doc_ents_vec = kg::entities_for_doc(db, doc_id).unwrap_or_default();
+            let doc_ents: HashSet<String> = doc_ents_vec.into_iter().collect();
+            let score = r.score.unwrap_or(kg::jaccard(&mem_ents, &doc_ents));
+            add_doc_ref(db, &id, doc_id, r.chunk_id.as_deref(), score, now_ms);
+            out.push(serde_json::json!({ "docId": doc_id, "chunkId": r.chunk_id, "score": score }));
+        }
+        computed_refs = Some(out);
+    }
+    if req.auto_link_docs.unwrap_or(false) {
+        let (min_score, max_docs) = auto_link_limits();
+        let explicit: HashSet<&str> = req
+            .references
+            .iter()
+            .flatten()
+            .map(|r| r.doc_id.as_str())
+            .collect();
+        let auto: Vec<serde_json::Value> = kg::docs_by_entity_overlap(db, &mem_ents)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(doc_id, score)| *score >= min_score && !explicit.contains(doc_id.as_str()))
+            .take(max_docs)
+            .map(|(doc_id, score)| {
+                add_doc_ref(db, &id, &doc_id, None, score, now_ms);
+                serde_json::json!({ "docId": doc_id, "chunkId": null, "score": score, "auto": true })
+            })
+            .collect();
+        if !auto.is_empty() {
+            computed_refs.get_or_insert_with(Vec::new).extend(auto);
+        }
+    }
+    let rec = serde_json::json!({
+        "id": id,
+        "content": req.content,
+        "metadata": req.metadata,
+        "layer": layer,
+        "session_id": req.session_id,
+        "episode_id": req.episode_id,
+        "created_at": now_ms,
+        "expires_at": expires_at,
+        "importance": req.importance.unwrap_or(1.0),
+        "ttl_ms": req.ttl_ms,
+        "pinned": req.pinned.unwrap_or(false),
+        "docRefs": computed_refs
+    });
+    tree.insert(id.as_bytes(), serde_json::to_vec(&rec)?)?;
+    // Reusable text index for memory (sled) and tantivy
+    index_memory_sled(
+        db,
+        &id,
+        &rec.get("content").and_then(|c| c.as_str()).unwrap_or(""),
+    )
+    .ok();
+    index_memory_tantivy(
+        text_index,
+        &id,
+        rec.get("content").and_then(|c| c.as_str()).unwrap_or(""),
+    )
+    .ok();
+    Ok((id, layer))
+}
+
+/// Store the normalized embedding for a memory and link it into the ANN graph and the
+/// warm copy.
+fn store_memory_embedding(
+    state: &AppState,
+    id: &str,
+    mut vec: [f32; embeddings::EMBED_DIM],
+) -> Result<()> {
+    let db = state.db();
+    let emb_tree = db.open_tree("mem_embeddings")?;
+    vector_index::normalize(&mut vec);
+    let bytes = vector_index::encode_embedding(&vec, vector_index::quantization(&db));
+    let _ = emb_tree.insert(id.as_bytes(), bytes);
+    // Incremental ANN update so new memories are searchable without a rebuild
+    vector_index::insert_into_neighbor_graph(&db, id, &vec).ok();
+    state.warm_vectors.refresh(&db, id);
+    Ok(())
+}
+
+async fn memory_search(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<SearchResponse>, Response> {
+    let started = std::time::Instant::now();
+    let original_q = params.get("q").cloned().unwrap_or_default();
+    let query = original_q.to_lowercase();
+    let page = Page::from_params(&params);
+    let layer = match params.get("layer") {
+        Some(l) => match canonical_layer(l) {
+            Some(l) => Some(l.to_string()),
+            None => {
+                return Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    "layer must be STM or LTM",
+                    None,
+                ))
+            }
+        },
+        None => None,
+    };
+    let episode = params.get("episode").cloned();
+    let time_from = params.get("from").and_then(|s| s.parse::<i64>().ok());
+    let time_to = params.get("to").and_then(|s| s.parse::<i64>().ok());
+    let meta_filter = metadata_filter_from_params(&params);
+    let tree = state.db().open_tree("memories").map_err(internal_error)?;
+    let mut results: Vec<SearchResult> = Vec::new();
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let boost = AccessBoost::from_env();
+    let parsed = query::parse(&original_q);
+    // BM25 over the tantivy index; boolean/phrase matching over content when the index has nothing
+    let bm25: HashMap<String, f32> = tantivy_search(&state.text_index, &original_q, usize::MAX)
+        .into_iter()
+        .filter_map(|(id, score)| id.strip_prefix("mem:").map(|m| (m.to_string(), score)))
+        .collect();
+    for kv in tree.iter() {
+        let (_, v) = kv.map_err(internal_error)?;
+        if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+            if is_tombstoned(&rec) {
+                continue;
+            }
+            let content = rec
+                .get("content")
+                .and_then(|c| c.as_str())
+                .unwrap_or("")
+                .to_lowercase();
+            let layer_v = rec
+                .get("layer")
+                .and_then(|c| c.as_str())
+                .unwrap_or("")
+                .to_string();
+            let created_at = rec.get("created_at").and_then(|c| c.as_i64());
+            let episode_v = rec.get("episode_id").and_then(|c| c.as_str());
+            let in_time = created_at
+                .map(|t| {
+                    time_from.map(|f| t >= f).unwrap_or(true)
+                        && time_to.map(|to| t <= to).unwrap_or(true)
+                })
+                .unwrap_or(true);
+            let episode_ok = episode
+                .as_deref()
+                .map(|e| Some(e) == episode_v)
+                .unwrap_or(true);
+            let id_v = rec.get("id").and_then(|c| c.as_str()).unwrap_or("");
+            let text_score = if bm25.is_empty() {
+                parsed.matches(&content).then_some(1.0)
+            } else {
+                // tantivy ranks; the AST enforces phrase/exclusion semantics exactly
+                bm25.get(id_v).copied().filter(|_| parsed.matches(&content))
+            };
+            let layer_ok = layer.as_deref().map(|l| l == layer_v).unwrap_or(true);
+            let meta_ok = metadata_matches(&rec, &meta_filter);
+            if let Some(text_score) =
+                text_score.filter(|_| layer_ok && in_time && episode_ok && meta_ok)
+            {
+                let id = rec
+                    .get("id")
+                    .and_then(|c| c.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                if !seen.contains(&id) {
+                    let doc_refs = doc_refs_from_record(&rec);
+                    results.push(SearchResult {
+                        id: id.clone(),
+                        score: text_score,
+                        layer: layer_v.clone(),
+                        doc_refs,
+                        explain: None,
+                        snippet: None,
+                        kind: None,
+                    });
+                    seen.insert(id.clone());
+                }
+            }
+        }
+    }
+    // Vector: embed query and search over memory embeddings (placeholder when no model)
+    if !query.is_empty() {
+        let qvec = embeddings::embed_batch(&[query.as_str()]);
+        if let Some(vec) = qvec.get(0) {
+            let filter = vector_index::VectorFilter {
+                layer: layer.clone(),
+                episode: episode.clone(),
+                from: time_from,
+                to: time_to,
+            };
+            let topk = state.memory_vector_hits(vec, page.window(), &filter, false);
+            for (id, score) in topk {
+                let rec = tree
+                    .get(id.as_bytes())
+                    .ok()
+                    .flatten()
+                    .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok());
+                if !meta_filter.is_empty()
+                    && !rec
+                        .as_ref()
+                        .is_some_and(|r| metadata_matches(r, &meta_filter))
+                {
+                    continue;
+                }
+                if !seen.contains(&id) {
+                    let layer_v = rec
+                        .as_ref()
+                        .and_then(|r| r.get("layer").and_then(|l| l.as_str()))
+                        .unwrap_or("STM")
+                        .to_string();
+                    results.push(SearchResult {
+                        id: id.clone(),
+                        score,
+                        layer: layer_v,
+                        doc_refs: None,
+                        explain: Some(serde_json::json!({"source":"vector"})),
+                        snippet: None,
+                        kind: None,
+                    });
+                    seen.insert(id);
+                }
+            }
+        }
+    }
+    let best = effectiveness_max(&state.db());
+    boost_by_effectiveness(&mut results, effectiveness_boost(&params), best, |id| {
+        tree.get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+    });
+    let mut resp = page.apply(results, Some(started.elapsed().as_millis()));
+    // Access-based strengthening and stats bump, for the memories actually returned
+    for res in &resp.results {
+        if let Ok(Some(old)) = tree.get(res.id.as_bytes()) {
+            let mut r: serde_json::Value =
+                serde_json::from_slice(&old).unwrap_or(serde_json::json!({}));
+            boost.apply(&mut r, now_ms);
+            let _ = tree.insert(res.id.as_bytes(), serde_json::to_vec(&r).unwrap());
+        }
+    }
+    attach_snippets(
+        &state.db(),
+        &mut resp.results,
+        &original_q,
+        params
+            .get("highlight")
+            .map(|v| v == "true")
+            .unwrap_or(false),
+    );
+    Ok(Json(resp))
+}
+
+async fn memory_update(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<UpdateMemoryRequest>,
+) -> Response {
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let found = match tree.get(req.id.as_bytes()) {
+        Ok(found) => found,
+        Err(e) => return internal_error(e),
+    };
+    if let Some(rec_v) = found.map(|v| v.to_vec()) {
+        let mut rec: JsonValue = serde_json::from_slice(&rec_v).unwrap_or(serde_json::json!({}));
+        let mut reembed = false;
+        if let Some(c) = req.content {
+            rec["content"] = serde_json::json!(c);
+            reembed = true;
+        }
+        if let Some(m) = req.metadata {
+            rec["metadata"] = m;
+        }
+        let ver = rec.get("version").and_then(|v| v.as_u64()).unwrap_or(0) + 1;
+        rec["version"] = serde_json::json!(ver);
+        if let Err(e) = tree.insert(req.id.as_bytes(), serde_json::to_vec(&rec).unwrap()) {
+            return internal_error(e);
+        }
+        // Re-embed and refresh indices on content change
+        if reembed {
+            let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+            // Update memory embedding
+            if let Ok(emb_tree) = state.db().open_tree("mem_embeddings") {
+                let vecs = embeddings::embed_batch_cached(&state.db(), &[content]);
+                let mut vec = vecs[0];
+                vector_index::normalize(&mut vec);
+                let bytes =
+                    vector_index::encode_embedding(&vec, vector_index::quantization(&state.db()));
+                let _ = emb_tree.insert(req.id.as_bytes(), bytes);
+                let _ = vector_index::insert_into_neighbor_graph(&state.db(), &req.id, &vec);
+                state.warm_vectors.refresh(&state.db(), &req.id);
+            }
+            // Refresh text indices
+            let _ = index_memory_sled(&state.db(), &req.id, content);
+            let _ = index_memory_tantivy(&state.text_index, &req.id, content);
+        }
+        state.invalidate_query_cache();
+        if let Err(e) = state.db().flush() {
+            return internal_error(e);
+        }
+        Json(serde_json::json!({ "id": req.id, "version": ver, "reembedded": reembed, "updatedIndices": ["text", "vector"] })).into_response()
+    } else {
+        json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None)
+    }
+}
+
+/// Set or clear the `pinned` flag on a memory record.
+async fn memory_pin(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<PinMemoryRequest>,
+) -> Response {
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let mut rec = match tree.get(req.id.as_bytes()) {
+        Ok(Some(v)) => serde_json::from_slice::<serde_json::Value>(&v).unwrap_or_default(),
+        _ => {
+            return json_error(
+                StatusCode::NOT_FOUND,
+                "NOT_FOUND",
+                "Memory not found",
+                Some(serde_json::json!({ "id": req.id })),
+            )
+        }
+    };
+    rec["pinned"] = serde_json::json!(req.pinned);
+    if let Err(e) = tree.insert(req.id.as_bytes(), serde_json::to_vec(&rec).unwrap()) {
+        return json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            e.to_string(),
+            None,
+        );
+    }
+    state.db().flush().ok();
+    Json(serde_json::json!({ "id": req.id, "pinned": req.pinned })).into_response()
+}
+
+/// Find memories whose embeddings are near-identical and, unless `dryRun`, merge each
+/// cluster into its most important member: access counts are summed and KG edges and
+/// doc refs are moved onto the keeper before the others are removed.
+async fn memory_dedupe(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<DedupeRequest>,
+) -> Response {
+    let started = std::time::Instant::now();
+    let threshold = req.threshold.unwrap_or(0.95);
+    if !(threshold > 0.0 && threshold <= 1.0) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "threshold must be in (0, 1]",
+            Some(serde_json::json!({ "threshold": threshold })),
+        );
+    }
+    let dry = req.dry_run.unwrap_or(false);
+    let pairs = vector_index::near_duplicate_pairs(&state.db(), threshold, 10);
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let load = |id: &str| {
+        tree.get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+    };
+    let mut clusters = Vec::new();
+    let mut actions = Vec::new();
+    for members in vector_index::similarity_clusters(&pairs) {
+        let mut recs: Vec<(String, serde_json::Value)> = members
+            .iter()
+            .filter_map(|id| load(id).map(|r| (id.to_string(), r)))
+            .collect();
+        if recs.len() < 2 {
+            continue;
+        }
+        // Keeper: highest importance, then oldest, then smallest id
+        recs.sort_by(|(ia, a), (ib, b)| {
+            let imp =
+                |r: &serde_json::Value| r.get("importance").and_then(|c| c.as_f64()).unwrap_or(1.0);
+            let created =
+                |r: &serde_json::Value| r.get("created_at").and_then(|c| c.as_i64()).unwrap_or(0);
+            imp(b)
+                .partial_cmp(&imp(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(created(a).cmp(&created(b)))
+                .then(ia.cmp(ib))
+        });
+        let (keep_id, mut keep) = recs[0].clone();
+        let duplicates: Vec<String> = recs[1..].iter().map(|(id, _)| id.clone()).collect();
+        clusters.push(serde_json::json!({ "keep": keep_id, "duplicates": duplicates }));
+        if dry {
+            continue;
+        }
+        let count =
+            |r: &serde_json::Value| r.get("access_count").and_then(|c| c.as_u64()).unwrap_or(0);
+        let mut merged_from: Vec<serde_json::Value> = keep
+            .get("merged_from")
+            .and_then(|m| m.as_array())
+            .cloned()
+            .unwrap_or_default();
+        for (dup_id, dup) in &recs[1..] {
+            keep["access_count"] = serde_json::json!(count(&keep) + count(dup));
+            if dup.get("pinned").and_then(|p| p.as_bool()) == Some(true) {
+                keep["pinned"] = serde_json::json!(true);
+            }
+            let moved = kg::repoint_edges(
+                &state.db(),
+                &format!("Memory::{}", dup_id),
+                &format!("Memory::{}", keep_id),
+            )
+            .unwrap_or(0);
+            if let Ok(refs) = state.db().open_tree("doc_refs") {
+                let prefix = doc_refs_prefix(dup_id);
+                let entries: Vec<_> = refs.scan_prefix(prefix.as_bytes()).flatten().collect();
+                for (k, v) in entries {
+                    let rest = &k[prefix.len()..];
+                    let mut nk = doc_refs_prefix(&keep_id).into_bytes();
+                    nk.extend_from_slice(rest);
+                    let _ = refs.insert(nk, v);
+                }
+            }
+            let _ = purge_memory(&state, dup_id);
+            merged_from.push(serde_json::json!(dup_id));
+            actions.push(
+                serde_json::json!({ "kept": keep_id, "removed": dup_id, "edgesMoved": moved }),
+            );
+        }
+        keep["merged_from"] = serde_json::json!(merged_from);
+        let _ = tree.insert(keep_id.as_bytes(), serde_json::to_vec(&keep).unwrap());
+    }
+    if !dry {
+        state.invalidate_query_cache();
+        state.db().flush().ok();
+    }
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|(a, b, sim)| serde_json::json!({ "a": a, "b": b, "similarity": sim }))
+        .collect();
+    Json(serde_json::json!({
+        "threshold": threshold,
+        "dryRun": dry,
+        "pairs": pairs,
+        "clusters": clusters,
+        "actions": actions,
+        "tookMs": started.elapsed().as_millis(),
+    }))
+    .into_response()
+}
+
+/// Create a `Memory::src -> Memory::dst` edge; both memories must exist.
+async fn memory_relate(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<RelateMemoryRequest>,
+) -> Response {
+    let relation = req.relation.trim().to_uppercase();
+    if relation.is_empty()
+        || !relation
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "relation must be a non-empty identifier such as SUPPORTS",
+            None,
+        );
+    }
+    if req.src == req.dst {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "src and dst must differ",
+            None,
+        );
+    }
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let missing: Vec<&str> = [req.src.as_str(), req.dst.as_str()]
+        .into_iter()
+        .filter(|id| tree.get(id.as_bytes()).ok().flatten().is_none())
+        .collect();
+    if !missing.is_empty() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Memory not found",
+            Some(serde_json::json!({ "ids": missing })),
+        );
+    }
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let src = format!("Memory::{}", req.src);
+    let dst = format!("Memory::{}", req.dst);
+    match kg::add_edge_generic(&state.db(), &src, &dst, &relation, now_ms) {
+        Ok(_) => {
+            state.db().flush().ok();
+            Json(serde_json::json!({ "src": req.src, "dst": req.dst, "relation": relation, "created": true }))
+                .into_response()
+        }
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+/// Bookkeeping fields of a memory record left out of `memory/get`.
+const INTERNAL_MEMORY_FIELDS: &[&str] = &["decayed_at", "deleted_at"];
+
+/// One memory's stored record. Reading it counts as an access, as a search hit does,
+/// unless `touch=false`. Soft-deleted memories are not found.
+async fn memory_get(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(id) = params.get("id").cloned() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "id required",
+            None,
+        );
+    };
+    let touch = params.get("touch").map(|v| v != "false").unwrap_or(true);
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let found = match tree.get(id.as_bytes()) {
+        Ok(found) => found,
+        Err(e) => return internal_error(e),
+    };
+    let Some(mut rec) = found
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+        .filter(|rec| !is_tombstoned(rec))
+    else {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Memory not found",
+            Some(serde_json::json!({ "id": id })),
+        );
+    };
+    if touch {
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        AccessBoost::from_env().apply(&mut rec, now_ms);
+        let stored = serde_json::to_vec(&rec)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(tree.insert(id.as_bytes(), bytes)?));
+        if let Err(e) = stored {
+            return internal_error(e);
+        }
+    }
+    if let Some(fields) = rec.as_object_mut() {
+        for field in INTERNAL_MEMORY_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    Json(rec).into_response()
+}
+
+/// Memories linked to `id` in either direction, optionally restricted to one relation.
+async fn memory_related(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(id) = params.get("id").cloned() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "id required",
+            None,
+        );
+    };
+    let relation = params.get("relation").map(|r| r.to_uppercase());
+    let node = format!("Memory::{}", id);
+    let mut related: Vec<serde_json::Value> = Vec::new();
+    let mut push = |edge: &serde_json::Value, end: &str, direction: &str| {
+        let rel = edge.get("relation").and_then(|r| r.as_str()).unwrap_or("");
+        if relation.as_deref().is_some_and(|want| want != rel) {
+            return;
+        }
+        let Some(other) = edge
+            .get(end)
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.strip_prefix("Memory::"))
+        else {
+            return;
+        };
+        related.push(serde_json::json!({
+            "id": other,
+            "relation": rel,
+            "direction": direction,
+            "created_at": edge.get("created_at"),
+        }));
+    };
+    if let Ok(edges) = state.db().open_tree("kg_edges") {
+        let prefix = format!("{}Memory::", kg::edges_prefix(&node));
+        for (_, v) in edges.scan_prefix(prefix.as_bytes()).flatten() {
+            if let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) {
+                push(&edge, "dst", "out");
+            }
+        }
+    }
+    for (_, edge) in kg::incoming_edges(&state.db(), &node).unwrap_or_default() {
+        push(&edge, "src", "in");
+    }
+    Json(serde_json::json!({ "id": id, "related": related })).into_response()
+}
+
+/// Working set for a session: its memories ranked by effectiveness (newest first on ties)
+/// and taken greedily while their content fits in `maxChars` (default 4000).
+async fn memory_context(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(session) = params.get("session").cloned() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "session required",
+            None,
+        );
+    };
+    let max_chars = params
+        .get("maxChars")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(4000);
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let half_life_ms = effect_half_life_ms();
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let mut ranked: Vec<(f64, i64, serde_json::Value)> = Vec::new();
+    for (_, v) in tree.iter().flatten() {
+        let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        if is_tombstoned(&rec) || rec.get("session_id").and_then(|s| s.as_str()) != Some(&session) {
+            continue;
+        }
+        let created = rec.get("created_at").and_then(|c| c.as_i64()).unwrap_or(0);
+        ranked.push((
+            effectiveness_score(&rec, now_ms, half_life_ms),
+            created,
+            rec,
+        ));
+    }
+    ranked.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.1.cmp(&a.1))
+    });
+    let mut used = 0usize;
+    let mut skipped = 0usize;
+    let mut memories: Vec<serde_json::Value> = Vec::new();
+    for (score, created, rec) in ranked {
+        let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+        let len = content.chars().count();
+        if used + len > max_chars {
+            skipped += 1;
+            continue;
+        }
+        used += len;
+        memories.push(serde_json::json!({
+            "id": rec.get("id"),
+            "content": content,
+            "layer": rec.get("layer"),
+            "created_at": created,
+            "score": score,
+        }));
+    }
+    Json(serde_json::json!({
+        "session": session,
+        "maxChars": max_chars,
+        "usedChars": used,
+        "skipped": skipped,
+        "memories": memories,
+    }))
+    .into_response()
+}
+
+/// Memories linked to an episode via `IN_EPISODE`, oldest first, with the episode node.
+async fn memory_episode(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(id) = params.get("id").cloned() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "id required",
+            None,
+        );
+    };
+    let node = format!("Episode::{}", id);
+    let episode = state
+        .db()
+        .open_tree("kg_nodes")
+        .ok()
+        .and_then(|t| t.get(node.as_bytes()).ok().flatten())
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok());
+    let Some(episode) = episode else {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "Episode not found",
+            Some(serde_json::json!({ "id": id })),
+        );
+    };
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let mut memories: Vec<serde_json::Value> = Vec::new();
+    for (_, edge) in kg::incoming_edges(&state.db(), &node).unwrap_or_default() {
+        if edge.get("relation").and_then(|r| r.as_str()) != Some("IN_EPISODE") {
+            continue;
+        }
+        let Some(mem_id) = edge
+            .get("src")
+            .and_then(|s| s.as_str())
+            .and_then(|s| s.strip_prefix("Memory::"))
+        else {
+            continue;
+        };
+        if let Ok(Some(v)) = tree.get(mem_id.as_bytes()) {
+            if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+                if !is_tombstoned(&rec) {
+                    memories.push(serde_json::json!({
+                        "id": mem_id,
+                        "content": rec.get("content"),
+                        "layer": rec.get("layer"),
+                        "metadata": rec.get("metadata"),
+                        "created_at": rec.get("created_at"),
+                    }));
+                }
+            }
+        }
+    }
+    let created = |m: &serde_json::Value| m.get("created_at").and_then(|c| c.as_i64()).unwrap_or(0);
+    let mem_id = |m: &serde_json::Value| {
+        m.get("id")
+            .and_then(|c| c.as_str())
+            .unwrap_or("")
+            .to_string()
+    };
+    memories.sort_by(|a, b| {
+        created(a)
+            .cmp(&created(b))
+            .then_with(|| mem_id(a).cmp(&mem_id(b)))
+    });
+    Json(serde_json::json!({
+        "episode": {
+            "id": id,
+            "name": episode.get("name"),
+            "session_id": episode.get("session_id"),
+            "created_at": episode.get("created_at"),
+        },
+        "memories": memories,
+    }))
+    .into_response()
+}
+
+async fn memory_delete(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<DeleteMemoryRequest>,
+) -> Response {
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    // Optional backup
+    if req.backup.unwrap_or(false) {
+        if let Ok(Some(v)) = tree.get(req.id.as_bytes()) {
+            if let Ok(backup) = state.db().open_tree("backups_memories") {
+                let ts = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+                let key = format!("{}:{}", ts, &req.id);
+                let _ = backup.insert(key.as_bytes(), v);
+            }
+        }
+    }
+    if req.soft.unwrap_or(false) {
+        let mut rec = match tree.get(req.id.as_bytes()) {
+            Ok(Some(v)) => serde_json::from_slice::<serde_json::Value>(&v).unwrap_or_default(),
+            _ => return json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None),
+        };
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        rec["deleted_at"] = serde_json::json!(now_ms);
+        let _ = tree.insert(req.id.as_bytes(), serde_json::to_vec(&rec).unwrap());
+        // Drop search indices only; KG links and doc refs stay for undelete
+        unindex_memory(&state, &req.id);
+        state.invalidate_query_cache();
+        if let Err(e) = state.db().flush() {
+            return internal_error(e);
+        }
+        return Json(serde_json::json!({ "deleted": true, "soft": true, "cascaded": false }))
+            .into_response();
+    }
+    let purged = purge_memory(&state, &req.id)
+        .and_then(|existed| Ok(state.db().flush().map(|_| existed)?));
+    state.invalidate_query_cache();
+    let existed = match purged {
+        Ok(existed) => existed,
+        Err(e) => return internal_error(e),
+    };
+    if existed {
+        Json(serde_json::json!({ "deleted": true, "cascaded": true })).into_response()
+    } else {
+        json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None)
+    }
+}
+
+/// Hard-delete each id with the same cascade as `memory/delete`; unknown ids are listed
+/// in `missing`.
+async fn memory_delete_batch(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<DeleteMemoryBatchRequest>,
+) -> Response {
+    let mut deleted = 0usize;
+    let mut missing: Vec<String> = Vec::new();
+    for id in &req.ids {
+        match purge_memory(&state, id) {
+            Ok(true) => deleted += 1,
+            Ok(false) => missing.push(id.clone()),
+            Err(e) => {
+                state.invalidate_query_cache();
+                return internal_error(e);
+            }
+        }
+    }
+    state.invalidate_query_cache();
+    if let Err(e) = state.db().flush() {
+        return internal_error(e);
+    }
+    Json(serde_json::json!({ "deleted": deleted, "missing": missing })).into_response()
+}
+
+/// Hard-delete every memory matching the filter. An empty filter is refused unless
+/// `confirmAll` is set.
+async fn memory_delete_where(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<DeleteMemoryWhereRequest>,
+) -> Response {
+    if let Some(layer) = req.layer.as_deref() {
+        if layer != "STM" && layer != "LTM" {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "layer must be STM or LTM",
+                None,
+            );
+        }
+    }
+    let unfiltered = req.session_id.is_none()
+        && req.episode_id.is_none()
+        && req.layer.is_none()
+        && req.older_than.is_none();
+    if unfiltered && !req.confirm_all.unwrap_or(false) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "a filter is required; pass confirmAll: true to delete every memory",
+            None,
+        );
+    }
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let field = |rec: &serde_json::Value, key: &str| {
+        rec.get(key).and_then(|v| v.as_str()).map(|v| v.to_string())
+    };
+    let mut matched: Vec<String> = Vec::new();
+    for (k, v) in tree.iter().flatten() {
+        let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        let keep = (req.session_id.is_some() && field(&rec, "session_id") != req.session_id)
+            || (req.episode_id.is_some() && field(&rec, "episode_id") != req.episode_id)
+            || (req.layer.is_some() && field(&rec, "layer") != req.layer)
+            || req.older_than.is_some_and(|cutoff| {
+                rec.get("created_at").and_then(|c| c.as_i64()).unwrap_or(0) >= cutoff
+            });
+        if !keep {
+            matched.push(String::from_utf8_lossy(&k).to_string());
+        }
+    }
+    let mut deleted = 0usize;
+    for id in &matched {
+        match purge_memory(&state, id) {
+            Ok(true) => deleted += 1,
+            Ok(false) => {}
+            Err(e) => {
+                state.invalidate_query_cache();
+                return internal_error(e);
+            }
+        }
+    }
+    state.invalidate_query_cache();
+    if let Err(e) = state.db().flush() {
+        return internal_error(e);
+    }
+    Json(serde_json::json!({ "deleted": deleted })).into_response()
+}
+
+/// Remove a memory from both text indexes and the vector index.
+fn unindex_memory(state: &AppState, id: &str) {
+    let db = &state.db();
+    let key = format!("mem:{}", id);
+    if let Ok(text_idx) = db.open_tree("text_index") {
+        let _ = text_idx.remove(key.as_bytes());
+    }
+    let _ = delete_from_tantivy(&state.text_index, &key);
+    if let Ok(emb) = db.open_tree("mem_embeddings") {
+        let _ = emb.remove(id.as_bytes());
+        let _ = vector_index::remove_from_neighbor_graph(db, id);
+        state.warm_vectors.refresh(db, id);
+    }
+}
+
+/// Hard-delete a memory and cascade to KG edges, indices and doc refs.
+/// Returns whether the record existed.
+fn purge_memory(state: &AppState, id: &str) -> Result<bool> {
+    let db = &state.db();
+    let _ = kg::remove_edges_for_node(db, &format!("Memory::{}", id));
+    unindex_memory(state, id);
+    if let Ok(refs) = db.open_tree("doc_refs") {
+        let prefix = doc_refs_prefix(id);
+        let to_remove: Vec<_> = refs
+            .scan_prefix(prefix.as_bytes())
+            .filter_map(|kv| kv.ok().map(|(k, _)| k))
+            .collect();
+        for k in to_remove {
+            let _ = refs.remove(k);
+        }
+    }
+    Ok(db.open_tree("memories")?.remove(id.as_bytes())?.is_some())
+}
+
+/// Clear a soft-delete tombstone and put the memory back into the search indices.
+async fn memory_undelete(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(req): Json<UndeleteMemoryRequest>,
+) -> Response {
+    let tree = match state.db().open_tree("memories") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let mut rec = match tree.get(req.id.as_bytes()) {
+        Ok(Some(v)) => serde_json::from_slice::<serde_json::Value>(&v).unwrap_or_default(),
+        _ => return json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "Memory not found", None),
+    };
+    if !is_tombstoned(&rec) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "Memory is not deleted",
+            Some(serde_json::json!({ "id": req.id })),
+        );
+    }
+    rec["deleted_at"] = serde_json::Value::Null;
+    let _ = tree.insert(req.id.as_bytes(), serde_json::to_vec(&rec).unwrap());
+    let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+    index_memory_sled(&state.db(), &req.id, content).ok();
+    index_memory_tantivy(&state.text_index, &req.id, content).ok();
+    let vecs = embeddings::embed_batch_cached(&state.db(), &[content]);
+    let stored =
+        store_memory_embedding(&state, &req.id, vecs[0]).and_then(|_| Ok(state.db().flush()?));
+    state.invalidate_query_cache();
+    if let Err(e) = stored {
+        return internal_error(e);
+    }
+    Json(serde_json::json!({ "id": req.id, "restored": true })).into_response()
+}
+
+async fn text_index_commit_loop(state: Arc<AppState>) {
+    let interval_ms: u64 = std::env::var("TEXT_INDEX_COMMIT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    loop {
+        sleep(Duration::from_millis(interval_ms)).await;
+        if let Err(err) = state.text_index.commit() {
+            error!(%err, "text index commit error");
+        }
+    }
+}
+
+async fn maintenance_loop(state: Arc<AppState>) {
+    // MAINTENANCE_ENABLED=0 leaves maintenance to `system.run_maintenance`
+    if std::env::var("MAINTENANCE_ENABLED").is_ok_and(|v| v == "0" || v == "false") {
+        info!("Background maintenance disabled");
+        return;
+    }
+    let interval_ms: u64 = std::env::var("STM_CLEAN_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60_000);
+    loop {
+        if let Err(err) = run_maintenance(&state) {
+            error!(%err, "maintenance error");
+        }
+        prune_query_cache(&state).await;
+        sleep(Duration::from_millis(interval_ms)).await;
+    }
+}
+
+/// What one maintenance pass changed.
+#[derive(Serialize, Default, Debug)]
+struct MaintenanceReport {
+    /// STM memories past `expires_at`
+    expired: u64,
+    promoted: u64,
+    /// LTM memories whose importance decayed
+    decayed: u64,
+    /// STM memories dropped to respect `STM_MAX_ITEMS`
+    evicted: u64,
+    /// Tombstones purged after the undelete window
+    purged: u64,
+}
+
+fn run_maintenance(state: &Arc<AppState>) -> Result<MaintenanceReport> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    run_maintenance_at(state, now_ms)
+}
+
+/// Half-life of LTM importance (`LTM_HALF_LIFE_MS`, default 30 days).
+fn ltm_half_life_ms() -> f64 {
+    std::env::var("LTM_HALF_LIFE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|ms: &f64| *ms > 0.0)
+        .unwrap_or(30.0 * 24.0 * 3600.0 * 1000.0)
+}
+
+/// A single pass that takes away at least this share of a memory's importance is logged.
+const DECAY_LOG_MIN_DROP: f64 = 0.1;
+
+/// Decay an LTM record's importance for the time since it last decayed (first time:
+/// since its last access, promotion or creation). Compounding per pass gives the same
+/// curve however often maintenance runs. Returns the importance before and after.
+fn decay_ltm_importance(rec: &mut serde_json::Value, now_ms: i64, half_life_ms: f64) -> (f64, f64) {
+    let ts = |key: &str| rec.get(key).and_then(|c| c.as_i64());
+    let since = ts("decayed_at")
+        .or_else(|| ts("last_access_ts").max(ts("promoted_at")))
+        .or_else(|| ts("created_at"))
+        .unwrap_or(now_ms);
+    let before = rec
+        .get("importance")
+        .and_then(|c| c.as_f64())
+        .unwrap_or(1.0);
+    let elapsed = (now_ms - since).max(0) as f64;
+    let after = before * 0.5f64.powf(elapsed / half_life_ms);
+    rec["importance"] = serde_json::json!(after);
+    rec["decayed_at"] = serde_json::json!(now_ms);
+    (before, after)
+}
+
+/// How a `PromotionPolicy` combines its conditions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PolicyMode {
+    /// Promote when any condition holds
+    Any,
+    /// Promote only when every condition holds
+    All,
+}
+
+/// STM→LTM promotion policy. Env sets the defaults (`CONSOLIDATE_MODE`,
+/// `CONSOLIDATE_IMPORTANCE_MIN`, `CONSOLIDATE_ACCESS_MIN`, `CONSOLIDATE_DOC_REFS_MIN`,
+/// `CONSOLIDATE_MIN_AGE_MS`); a `consolidation_policy` JSON object in the `settings`
+/// tree overrides any of its fields.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PromotionPolicy {
+    mode: PolicyMode,
+    importance_min: f64,
+    access_min: u64,
+    /// Doc refs required; `None` leaves them out of the policy
+    doc_refs_min: Option<u64>,
+    /// STM items younger than this are never promoted
+    min_age_ms: i64,
+}
+
+impl PromotionPolicy {
+    fn from_env() -> Self {
+        fn env<T: std::str::FromStr>(key: &str) -> Option<T> {
+            std::env::var(key).ok().and_then(|v| v.parse().ok())
+        }
+        PromotionPolicy {
+            mode: match std::env::var("CONSOLIDATE_MODE").ok().as_deref() {
+                Some(m) if m.eq_ignore_ascii_case("all") => PolicyMode::All,
+                _ => PolicyMode::Any,
+            },
+            importance_min: env("CONSOLIDATE_IMPORTANCE_MIN").unwrap_or(1.5),
+            access_min: env("CONSOLIDATE_ACCESS_MIN").unwrap_or(3),
+            doc_refs_min: env("CONSOLIDATE_DOC_REFS_MIN"),
+            min_age_ms: env("CONSOLIDATE_MIN_AGE_MS").unwrap_or(0),
+        }
+    }
+
+    /// The env policy with the stored `settings` override applied; an unreadable
+    /// override is ignored.
+    fn load(db: &sled::Db) -> Self {
+        let base = Self::from_env();
+        let stored = db
+            .open_tree("settings")
+            .ok()
+            .and_then(|t| t.get(b"consolidation_policy").ok().flatten())
+            .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok());
+        let (Some(serde_json::Value::Object(fields)), Ok(serde_json::Value::Object(mut merged))) =
+            (stored, serde_json::to_value(&base))
+        else {
+            return base;
+        };
+        merged.extend(fields);
+        serde_json::from_value(serde_json::Value::Object(merged)).unwrap_or(base)
+    }
+}
+
+/// Move an STM record that satisfies `policy` to LTM, returning the reason: the first
+/// condition met (`importance`, `access` or `docRefs`) in `any` mode, `all` otherwise.
+/// Other layers are left alone.
+fn try_promote(
+    rec: &mut serde_json::Value,
+    now_ms: i64,
+    policy: &PromotionPolicy,
+) -> Option<&'static str> {
+    if rec.get("layer").and_then(|c| c.as_str()) != Some("STM") {
+        return None;
+    }
+    let created_at = rec.get("created_at").and_then(|c| c.as_i64());
+    if created_at.is_some_and(|t| now_ms.saturating_sub(t) < policy.min_age_ms) {
+        return None;
+    }
+    let importance = rec
+        .get("importance")
+        .and_then(|c| c.as_f64())
+        .unwrap_or(1.0);
+    let accessed = rec
+        .get("access_count")
+        .and_then(|c| c.as_u64())
+        .unwrap_or(0);
+    let mut checks = vec![
+        ("importance", importance >= policy.importance_min),
+        ("access", accessed >= policy.access_min),
+    ];
+    if let Some(min) = policy.doc_refs_min {
+        let refs = doc_refs_from_record(rec).map_or(0, |r| r.len()) as u64;
+        checks.push(("docRefs", refs >= min));
+    }
+    let reason = match policy.mode {
+        PolicyMode::Any => checks.iter().find(|(_, met)| *met).map(|(name, _)| *name)?,
+        PolicyMode::All => checks.iter().all(|(_, met)| *met).then_some("all")?,
+    };
+    rec["layer"] = serde_json::json!("LTM");
+    rec["promoted_at"] = serde_json::json!(now_ms);
+    Some(reason)
+}
+
+/// Record a promotion in the `consolidation_log` tree (best-effort).
+fn log_promotion(db: &sled::Db, rec: &serde_json::Value, reason: &str, now_ms: i64) {
+    if let Ok(log) = db.open_tree("consolidation_log") {
+        let id = rec.get("id").and_then(|c| c.as_str()).unwrap_or("");
+        let log_key = format!("{}:{}", now_ms, id);
+        let log_val = serde_json::json!({ "id": id, "from": "STM", "to": "LTM", "reason": reason, "ts": now_ms });
+        if let Ok(bytes) = serde_json::to_vec(&log_val) {
+            let _ = log.insert(log_key.as_bytes(), bytes);
+        }
+    }
+}
+
+fn run_maintenance_at(state: &Arc<AppState>, now_ms: i64) -> Result<MaintenanceReport> {
+    let mut report = MaintenanceReport::default();
+    let half_life_ms = ltm_half_life_ms();
+    let policy = PromotionPolicy::load(&state.db());
+    let undelete_window_ms: i64 = std::env::var("MEMORY_UNDELETE_WINDOW_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(7 * 24 * 3600 * 1000);
+    let tree = state.db().open_tree("memories")?;
+    for kv in tree.iter() {
+        let (k, v) = kv?;
+        let mut rec: serde_json::Value =
+            serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
+        // Tombstones are left alone until the undelete window passes, then purged
+        if is_tombstoned(&rec) {
+            let deleted_at = rec.get("deleted_at").and_then(|c| c.as_i64()).unwrap_or(0);
+            if now_ms - deleted_at >= undelete_window_ms {
+                let id = String::from_utf8_lossy(&k).to_string();
+                purge_memory(state, &id)?;
+                report.purged += 1;
+            }
+            continue;
+        }
+        let layer = rec.get("layer").and_then(|c| c.as_str()).unwrap_or("");
+        let pinned = rec.get("pinned").and_then(|c| c.as_bool()).unwrap_or(false);
+        if layer == "STM" {
+            if let Some(exp) = rec.get("expires_at").and_then(|c| c.as_i64()) {
+                if exp <= now_ms && !pinned {
+                    let _ = tree.remove(k);
+                    report.expired += 1;
+                    continue;
+                }
+            }
+            if let Some(reason) = try_promote(&mut rec, now_ms, &policy) {
+                tree.insert(&k, serde_json::to_vec(&rec)?)?;
+                log_promotion(&state.db(), &rec, reason, now_ms);
+                report.promoted += 1;
+            }
+        } else if layer == "LTM" && !pinned {
+            let (before, after) = decay_ltm_importance(&mut rec, now_ms, half_life_ms);
+            tree.insert(&k, serde_json::to_vec(&rec)?)?;
+            if after < before {
+                report.decayed += 1;
+            }
+            // Audit large drops, e.g. the first pass after a long downtime (best-effort)
+            if before > 0.0 && (before - after) / before >= DECAY_LOG_MIN_DROP {
+                if let Ok(log) = state.db().open_tree("decay_log") {
+                    let id = rec.get("id").and_then(|c| c.as_str()).unwrap_or("");
+                    let log_key = format!("{}:{}", now_ms, id);
+                    let log_val =
+                        serde_json::json!({ "id": id, "from": before, "to": after, "ts": now_ms });
+                    let _ = log.insert(log_key.as_bytes(), serde_json::to_vec(&log_val)?);
+                }
+            }
+        }
+    }
+    // Enforce STM LRU capacity if configured
+    let max_items: usize = std::env::var("STM_MAX_ITEMS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if max_items > 0 {
+        let mut stm_items: Vec<(sled::IVec, i64)> = Vec::new();
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let rec: serde_json::Value =
+                serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
+            let layer = rec.get("layer").and_then(|c| c.as_str()).unwrap_or("");
+            let pinned = rec.get("pinned").and_then(|c| c.as_bool()).unwrap_or(false);
+            if layer == "STM" && !pinned && !is_tombstoned(&rec) {
+                let ts = rec
+                    .get("last_access_ts")
+                    .and_then(|c| c.as_i64())
+                    .or_else(|| rec.get("created_at").and_then(|c| c.as_i64()))
+                    .unwrap_or(now_ms);
+                stm_items.push((k, ts));
+            }
+        }
+        if stm_items.len() > max_items {
+            stm_items.sort_by_key(|(_, ts)| *ts);
+            let to_remove = stm_items.len() - max_items;
+            for (k, _) in stm_items.into_iter().take(to_remove) {
+                let _ = tree.remove(k);
+                report.evicted += 1;
+            }
+        }
+    }
+    // Expiry, promotion and decay all change what searches return
+    state.invalidate_query_cache();
+    state.db().flush()?;
+    Ok(report)
+}
+
+/// Drop expired fusion cache entries, then the oldest beyond `FUSION_CACHE_MAX`.
+/// Returns the number dropped.
+async fn prune_query_cache(state: &Arc<AppState>) -> usize {
+    let ttl_ms: i64 = std::env::var("FUSION_CACHE_TTL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3_000);
+    let max_entries: usize = std::env::var("FUSION_CACHE_MAX")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1_000);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let mut guard = state.query_cache.lock().await;
+    let before = guard.len();
+    // TTL prune
+    let keys_to_remove: Vec<String> = guard
+        .iter()
+        .filter_map(|(k, (ts, _))| {
+            if now - *ts > ttl_ms {
+                Some(k.clone())
+            } else {
+                None
+            }
+        })
+        .collect();
+    for k in keys_to_remove {
+        guard.remove(&k);
+    }
+    // Size prune (LRU-ish by oldest ts)
+    if guard.len() > max_entries {
+        let mut items: Vec<(String, i64)> =
+            guard.iter().map(|(k, (ts, _))| (k.clone(), *ts)).collect();
+        items.sort_by_key(|(_, ts)| *ts); // oldest first
+        let to_remove = guard.len() - max_entries;
+        for (k, _) in items.into_iter().take(to_remove) {
+            guard.remove(&k);
+        }
+    }
+    before - guard.len()
+}
+
+/// One maintenance pass on demand (expiry, promotion, decay, eviction, tombstone purge)
+/// plus a query cache prune, as the background loop would run it.
+async fn system_run_maintenance(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let report = run_maintenance(&state).map_err(internal_error)?;
+    let cache_pruned = prune_query_cache(&state).await;
+    let mut body = serde_json::to_value(&report).map_err(internal_error)?;
+    body["cachePruned"] = serde_json::json!(cache_pruned);
+    body["tookMs"] = serde_json::json!(started.elapsed().as_millis());
+    Ok(Json(body))
+}
+
+async fn advanced_consolidate(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let limit = body.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
+    let dry = body
+        .get("dryRun")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let policy = PromotionPolicy::load(&state.db());
+    let tree = state.db().open_tree("memories").map_err(internal_error)?;
+    let mut promoted = 0usize;
+    let mut candidates = 0usize;
+    let mut scanned = 0usize;
+    for kv in tree.iter() {
+        if promoted >= limit {
+            break;
+        }
+        let (k, v) = kv.map_err(internal_error)?;
+        scanned += 1;
+        let mut rec: serde_json::Value =
+            serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
+        if is_tombstoned(&rec) {
+            continue;
+        }
+        if let Some(reason) = try_promote(&mut rec, now_ms, &policy) {
+            candidates += 1;
+            if !dry {
+                tree.insert(k, serde_json::to_vec(&rec).map_err(internal_error)?)
+                    .map_err(internal_error)?;
+                log_promotion(&state.db(), &rec, reason, now_ms);
+                promoted += 1;
+            }
+        }
+    }
+    if promoted > 0 {
+        state.invalidate_query_cache();
+    }
+    state.db().flush().map_err(internal_error)?;
+    Ok(Json(serde_json::json!({
+        "promoted": promoted,
+        "candidates": candidates,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Promotion events from `consolidation_log`, oldest first, optionally limited to
+/// `from..=to` (ms).
+async fn advanced_consolidation_log(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let from = params.get("from").and_then(|s| s.parse::<i64>().ok());
+    let to = params.get("to").and_then(|s| s.parse::<i64>().ok());
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(100);
+    let log = state
+        .db()
+        .open_tree("consolidation_log")
+        .map_err(internal_error)?;
+    let mut events: Vec<serde_json::Value> = Vec::new();
+    for kv in log.iter() {
+        let (_, v) = kv.map_err(internal_error)?;
+        let Ok(event) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        let ts = event.get("ts").and_then(|c| c.as_i64()).unwrap_or(0);
+        if from.is_some_and(|f| ts < f) || to.is_some_and(|t| ts > t) {
+            continue;
+        }
+        events.push(event);
+    }
+    // Keys are `<ts>:<id>` strings, so sort numerically rather than trusting key order
+    events.sort_by_key(|e| e.get("ts").and_then(|c| c.as_i64()).unwrap_or(0));
+    events.truncate(limit);
+    Ok(Json(serde_json::json!({ "events": events })))
+}
+
+/// Relative weight of each signal in `search_fusion`. Query params `w_text`, `w_vector`
+/// and `w_kg` override `FUSION_W_TEXT`, `FUSION_W_VECTOR` and `FUSION_W_KG`.
+#[derive(Clone, Copy, Debug)]
+struct FusionWeights {
+    text: f32,
+    vector: f32,
+    kg: f32,
+}
+
+impl FusionWeights {
+    fn from_params(params: &std::collections::HashMap<String, String>) -> Self {
+        let pick = |param: &str, env: &str, default: f32| {
+            params
+                .get(param)
+                .and_then(|v| v.parse::<f32>().ok())
+                .or_else(|| std::env::var(env).ok().and_then(|v| v.parse().ok()))
+                .unwrap_or(default)
+                .max(0.0)
+        };
+        FusionWeights {
+            text: pick("w_text", "FUSION_W_TEXT", 1.0),
+            vector: pick("w_vector", "FUSION_W_VECTOR", 1.0),
+            kg: pick("w_kg", "FUSION_W_KG", 0.5),
+        }
+    }
+
+    fn key(&self) -> String {
+        format!("{}:{}:{}", self.text, self.vector, self.kg)
+    }
+
+    fn weight(&self, signal: Signal) -> f32 {
+        match signal {
+            Signal::Text => self.text,
+            Signal::Vector => self.vector,
+            Signal::Kg => self.kg,
+        }
+    }
+}
+
+/// How `search_fusion` combines its sources: `fusion=weighted` (default) sums normalized
+/// weighted scores; `fusion=rrf` uses reciprocal rank fusion with `rrf_k` (default 60).
+#[derive(Clone, Copy, Debug)]
+enum FusionMode {
+    Weighted(FusionWeights),
+    Rrf { k: f32 },
+}
+
+impl FusionMode {
+    fn from_params(params: &std::collections::HashMap<String, String>) -> Self {
+        match params.get("fusion").map(|s| s.as_str()) {
+            Some("rrf") => FusionMode::Rrf {
+                k: params
+                    .get("rrf_k")
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .filter(|k| *k >= 0.0)
+                    .unwrap_or(60.0),
+            },
+            _ => FusionMode::Weighted(FusionWeights::from_params(params)),
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            FusionMode::Weighted(w) => format!("w={}", w.key()),
+            FusionMode::Rrf { k } => format!("rrf={}", k),
+        }
+    }
+
+    fn fuse(&self, hits: Vec<FusionHit>) -> Vec<SearchResult> {
+        match self {
+            FusionMode::Weighted(w) => fuse(hits, *w),
+            FusionMode::Rrf { k } => fuse_rrf(hits, *k),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Signal {
+    Text,
+    Vector,
+    Kg,
+}
+
+impl Signal {
+    fn name(self) -> &'static str {
+        match self {
+            Signal::Text => "text",
+            Signal::Vector => "vector",
+            Signal::Kg => "kg",
+        }
+    }
+}
+
+/// A raw hit from one fusion source, before normalization and weighting.
+struct FusionHit {
+    id: String,
+    layer: String,
+    signal: Signal,
+    /// Source tag reported in `explain.sources`; scores are normalized per tag.
+    source: &'static str,
+    score: f32,
+    doc_refs: Option<Vec<DocRefOut>>,
+    /// Extra fields merged into `explain` (e.g. chunk text for doc vectors).
+    extra: Option<serde_json::Value>,
+}
+
+/// Min-max normalize each source's scores to [0, 1], weight them by signal and sum the
+/// contributions per id. A source whose hits all tie counts fully if the tie is positive.
+fn fuse(hits: Vec<FusionHit>, weights: FusionWeights) -> Vec<SearchResult> {
+    let mut ranges: HashMap<&'static str, (f32, f32)> = HashMap::new();
+    for h in &hits {
+        let r = ranges.entry(h.source).or_insert((f32::MAX, f32::MIN));
+        r.0 = r.0.min(h.score);
+        r.1 = r.1.max(h.score);
+    }
+    let mut acc = FusionAcc::default();
+    for h in hits {
+        let (min, max) = ranges[h.source];
+        let norm = if max > min {
+            (h.score - min) / (max - min)
+        } else if max > 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+        let contribution = norm * weights.weight(h.signal);
+        let signal = h.signal.name();
+        let (res, explain) = acc.add(h);
+        // The same id from one signal (e.g. two KG edges) counts once, at its best
+        let prev = explain
+            .get(signal)
+            .and_then(|v| v.as_f64())
+            .map(|v| v as f32);
+        if prev.map(|p| contribution > p).unwrap_or(true) {
+            res.score += contribution - prev.unwrap_or(0.0);
+            explain.insert(signal.to_string(), serde_json::json!(contribution));
+        }
+    }
+    acc.finish()
+}
+
+/// Per-id accumulator shared by the fusion modes: keeps first-seen layer and doc refs,
+/// the list of contributing sources and any extra explain fields.
+#[derive(Default)]
+struct FusionAcc {
+    order: Vec<String>,
+    merged: HashMap<String, (SearchResult, serde_json::Map<String, serde_json::Value>)>,
+}
+
+impl FusionAcc {
+    fn add(
+        &mut self,
+        h: FusionHit,
+    ) -> (
+        &mut SearchResult,
+        &mut serde_json::Map<String, serde_json::Value>,
+    ) {
+        let order = &mut self.order;
+        let (res, explain) = self.merged.entry(h.id.clone()).or_insert_with(|| {
+            order.push(h.id.clone());
+            (
+                SearchResult {
+                    id: h.id.clone(),
+                    score: 0.0,
+                    layer: h.layer.clone(),
+                    doc_refs: None,
+                    explain: None,
+                    snippet: None,
+                    kind: None,
+                },
+                serde_json::Map::new(),
+            )
+        });
+        let sources = explain
+            .entry("sources")
+            .or_insert_with(|| serde_json::json!([]));
+        if let Some(arr) = sources.as_array_mut() {
+            if !arr.iter().any(|s| s == h.source) {
+                arr.push(serde_json::json!(h.source));
+            }
+        }
+        if res.doc_refs.is_none() {
+            res.doc_refs = h.doc_refs;
+        }
+        if let Some(serde_json::Value::Object(extra)) = h.extra {
+            for (k, v) in extra {
+                explain.entry(k).or_insert(v);
+            }
+        }
+        (res, explain)
+    }
+
+    fn finish(mut self) -> Vec<SearchResult> {
+        let mut out: Vec<SearchResult> = self
+            .order
+            .iter()
+            .filter_map(|id| self.merged.remove(id))
+            .map(|(mut res, explain)| {
+                res.explain = Some(serde_json::Value::Object(explain));
+                res
+            })
+            .collect();
+        sort_results(&mut out);
+        out
+    }
+}
+
+/// Reciprocal rank fusion: each signal (text, KG, vector) ranks its hits by raw score and
+/// an id scores `sum(1 / (k + rank))` over the lists it appears in (ranks start at 1).
+fn fuse_rrf(hits: Vec<FusionHit>, k: f32) -> Vec<SearchResult> {
+    let mut ranks: HashMap<(String, &'static str), usize> = HashMap::new();
+    for signal in [Signal::Text, Signal::Kg, Signal::Vector] {
+        let mut list: Vec<(&str, f32)> = hits
+            .iter()
+            .filter(|h| h.signal == signal)
+            .map(|h| (h.id.as_str(), h.score))
+            .collect();
+        list.sort_by(|a, b| cmp_score_desc(a.1, b.1).then_with(|| a.0.cmp(b.0)));
+        let mut rank = 0;
+        for (id, _) in list {
+            if let std::collections::hash_map::Entry::Vacant(e) =
+                ranks.entry((id.to_string(), signal.name()))
+            {
+                rank += 1;
+                e.insert(rank);
+            }
+        }
+    }
+    let mut acc = FusionAcc::default();
+    for h in hits {
+        let rank = ranks[&(h.id.clone(), h.signal.name())];
+        let rank_key = format!("{}Rank", h.signal.name());
+        let (res, explain) = acc.add(h);
+        if !explain.contains_key(&rank_key) {
+            res.score += 1.0 / (k + rank as f32);
+            explain.insert(rank_key, serde_json::json!(rank));
+        }
+    }
+    acc.finish()
+}
+
+/// Query cache key covering every input that shapes fusion results (paging and snippets
+/// are applied after the cache), plus the write generation. `q` goes last so its text
+/// can't pass for another field.
+fn fusion_cache_key(
+    q: &str,
+    window: usize,
+    mode: &FusionMode,
+    boost: f32,
+    kinds: ResultKinds,
+    (time_from, time_to): (Option<i64>, Option<i64>),
+    generation: u64,
+) -> String {
+    let bound = |b: Option<i64>| b.map(|v| v.to_string()).unwrap_or_default();
+    format!(
+        "limit={}::from={}::to={}::{}::boost={}::types={}::gen={}::q={}",
+        window,
+        bound(time_from),
+        bound(time_to),
+        mode.key(),
+        boost,
+        kinds.key(),
+        generation,
+        q
+    )
+}
+
+async fn search_fusion(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<SearchResponse>, Response> {
+    fused_search(&state, &params, ResultKinds::ALL)
+        .await
+        .map(Json)
+}
+
+/// Which hits a fused search keeps: memories, document chunks, or both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ResultKinds {
+    memory: bool,
+    document: bool,
+}
+
+impl ResultKinds {
+    const ALL: ResultKinds = ResultKinds {
+        memory: true,
+        document: true,
+    };
+
+    /// Parse a comma-separated `types` list (`memory`, `document`); empty means both.
+    fn parse(types: &str) -> std::result::Result<Self, String> {
+        let mut kinds = ResultKinds {
+            memory: false,
+            document: false,
+        };
+        for t in types.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match t.to_ascii_lowercase().as_str() {
+                "memory" | "memories" => kinds.memory = true,
+                "document" | "documents" => kinds.document = true,
+                _ => return Err(t.to_string()),
+            }
+        }
+        if !kinds.memory && !kinds.document {
+            return Ok(Self::ALL);
+        }
+        Ok(kinds)
+    }
+
+    /// Fusion tags document chunk hits with the `doc` layer.
+    fn kind_of(layer: &str) -> &'static str {
+        if layer == "doc" {
+            "document"
+        } else {
+            "memory"
+        }
+    }
+
+    fn allows(&self, layer: &str) -> bool {
+        match Self::kind_of(layer) {
+            "document" => self.document,
+            _ => self.memory,
+        }
+    }
+
+    fn key(&self) -> &'static str {
+        match (self.memory, self.document) {
+            (true, false) => "memory",
+            (false, true) => "document",
+            _ => "all",
+        }
+    }
+}
+
+/// Memories and document chunks ranked together by `search_fusion`'s text, KG and vector
+/// signals, each tagged with its `kind`. `types` (comma-separated) narrows the kinds.
+async fn unified_search(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> std::result::Result<Json<SearchResponse>, Response> {
+    let kinds = ResultKinds::parse(params.get("types").map(|s| s.as_str()).unwrap_or("")).map_err(
+        |bad| {
+            json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "types must list memory and/or document",
+                Some(serde_json::json!({ "type": bad })),
+            )
+        },
+    )?;
+    let mut resp = fused_search(&state, &params, kinds).await?;
+    for res in &mut resp.results {
+        res.kind = Some(ResultKinds::kind_of(&res.layer));
+    }
+    Ok(Json(resp))
+}
+
+async fn fused_search(
+    state: &Arc<AppState>,
+    params: &std::collections::HashMap<String, String>,
+    kinds: ResultKinds,
+) -> std::result::Result<SearchResponse, Response> {
+    let started = std::time::Instant::now();
+    let q = params.get("q").cloned().unwrap_or_default().to_lowercase();
+    let page = Page::from_params(params);
+    let window = page.window();
+    let time_from = params.get("from").and_then(|s| s.parse::<i64>().ok());
+    let time_to = params.get("to").and_then(|s| s.parse::<i64>().ok());
+    let mode = FusionMode::from_params(params);
+    let boost = effectiveness_boost(params);
+    let highlight = params
+        .get("highlight")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    let generation = state
+        .query_cache_gen
+        .load(std::sync::atomic::Ordering::SeqCst);
+    let cache_key = fusion_cache_key(
+        &q,
+        window,
+        &mode,
+        boost,
+        kinds,
+        (time_from, time_to),
+        generation,
+    );
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    // Serve from cache if fresh
+    if let Some(cached) = {
+        let guard = state.query_cache.lock().await;
+        guard
+            .get(&cache_key)
+            .map(|(ts, items)| (*ts, items.clone()))
+    } {
+        let (ts, items) = cached;
+        if now_ms - ts
+            <= std::env::var("FUSION_CACHE_TTL_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3_000)
+        {
+            // metrics update: cache hit
+            {
+                let mut m = state.metrics.lock().await;
+                m.count += 1;
+                m.cache_hits += 1;
+                m.last_ms = 0;
+                // history window for percentiles/QPS
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as i64;
+                m.history.push_back((now, 0));
+                while let Some((ts, _)) = m.history.front().cloned() {
+                    if now - ts > 60_000 {
+                        m.history.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                let mut lat: Vec<u64> = m.history.iter().map(|(_, v)| *v).collect();
+                lat.sort_unstable();
+                if !lat.is_empty() {
+                    let p50_idx = (lat.len() as f64 * 0.5).floor() as usize;
+                    let p95_idx = (lat.len() as f64 * 0.95)
+                        .floor()
+                        .min((lat.len() - 1) as f64) as usize;
+                    m.p50_ms = lat[p50_idx] as f64;
+                    m.p95_ms = lat[p95_idx] as f64;
+                }
+                m.qps_1m = m.history.len() as f64 / 60.0;
+            }
+            let mut resp = page.apply(items, Some(0));
+            attach_snippets(&state.db(), &mut resp.results, &q, highlight);
+            return Ok(resp);
+        }
+    }
+    let mut hits: Vec<FusionHit> = Vec::new();
+    let tree = state.db().open_tree("memories").map_err(internal_error)?;
+    let record = |id: &str| -> Option<serde_json::Value> {
+        tree.get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+    };
+    let in_time = |rec: &serde_json::Value| {
+        rec.get("created_at")
+            .and_then(|c| c.as_i64())
+            .map(|t| {
+                time_from.map(|f| t >= f).unwrap_or(true)
+                    && time_to.map(|to| t <= to).unwrap_or(true)
+            })
+            .unwrap_or(true)
+    };
+    let layer_of = |rec: Option<&serde_json::Value>| {
+        rec.and_then(|r| r.get("layer").and_then(|x| x.as_str()))
+            .unwrap_or("STM")
+            .to_string()
+    };
+    // Text: BM25 over memories and doc chunks, substring scan when the index has nothing
+    {
+        let phase = tracing::info_span!("fusion.text_scan", hits = tracing::field::Empty).entered();
+        let bm25 = tantivy_search(&state.text_index, &q, usize::MAX);
+        if !bm25.is_empty() {
+            for (key, score) in bm25 {
+                match key.strip_prefix("mem:") {
+                    Some(mem_id) => {
+                        let rec = match record(mem_id) {
+                            Some(r) if in_time(&r) => r,
+                            _ => continue,
+                        };
+                        hits.push(FusionHit {
+                            id: mem_id.to_string(),
+                            layer: layer_of(Some(&rec)),
+                            signal: Signal::Text,
+                            source: "text",
+                            score,
+                            doc_refs: doc_refs_from_record(&rec),
+                            extra: None,
+                        });
+                    }
+                    None => hits.push(FusionHit {
+                        // Indexes written before zero-padding still carry plain offsets
+                        id: canonical_chunk_key(&key).unwrap_or(key),
+                        layer: "doc".to_string(),
+                        signal: Signal::Text,
+                        source: "doc-index",
+                        score,
+                        doc_refs: None,
+                        extra: None,
+                    }),
+                }
+            }
+        } else {
+            for kv in tree.iter() {
+                let (_, v) = kv.map_err(internal_error)?;
+                if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+                    let content = rec
+                        .get("content")
+                        .and_then(|c| c.as_str())
+                        .unwrap_or("")
+                        .to_lowercase();
+                    if content.contains(&q) && in_time(&rec) {
+                        hits.push(FusionHit {
+                            id: rec
+                                .get("id")
+                                .and_then(|c| c.as_str())
+                                .unwrap_or("")
+                                .to_string(),
+                            layer: layer_of(Some(&rec)),
+                            signal: Signal::Text,
+                            source: "text",
+                            score: 1.0,
+                            doc_refs: doc_refs_from_record(&rec),
+                            extra: None,
+                        });
+                    }
+                }
+            }
+            // From doc text index (sled fallback)
+            if let Ok(text_idx) = state.db().open_tree("text_index") {
+                for (k, v) in text_idx.iter().flatten() {
+                    let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+                    // Memory entries are covered by the scan above
+                    if key.starts_with("mem:") {
+                        continue;
+                    }
+                    if String::from_utf8_lossy(&v).to_lowercase().contains(&q) {
+                        hits.push(FusionHit {
+                            id: key,
+                            layer: "doc".to_string(),
+                            signal: Signal::Text,
+                            source: "doc-index",
+                            score: 1.0,
+                            doc_refs: None,
+                            extra: None,
+                        });
+                    }
+                }
+            }
+        }
+        phase.record("hits", hits.len());
+    }
+    // KG semantic hits: if query matches an entity, include memories that mention it
+    let before = hits.len();
+    {
+        let phase = tracing::info_span!("fusion.kg_scan", hits = tracing::field::Empty).entered();
+        if !q.is_empty() {
+            if let Ok(edges) = state.db().open_tree("kg_edges") {
+                let entity = kg::resolve_alias_ignoring_case(&state.db(), &q).unwrap_or(q.clone());
+                let needle = format!(
+                    "->{}::MENTIONS",
+                    kg::node_key(&format!("Entity::{}", entity))
+                );
+                for (k, _) in edges.iter().flatten() {
+                    let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+                    if !key.to_lowercase().ends_with(&needle.to_lowercase()) {
+                        continue;
+                    }
+                    let src = kg::parse_edge_key(&key).map(|(src, _, _)| src);
+                    if let Some(mem_id) = src.as_deref().and_then(|s| s.strip_prefix("Memory::")) {
+                        hits.push(FusionHit {
+                            id: mem_id.to_string(),
+                            layer: layer_of(record(mem_id).as_ref()),
+                            signal: Signal::Kg,
+                            source: "kg",
+                            score: 1.0,
+                            doc_refs: None,
+                            extra: None,
+                        });
+                    }
+                }
+            }
+        }
+        phase.record("hits", hits.len() - before);
+    }
+    // Vector: ANN over memory embeddings plus document chunk vectors
+    let before = hits.len();
+    {
+        let phase =
+            tracing::info_span!("fusion.vector_ann", hits = tracing::field::Empty).entered();
+        if !q.is_empty() {
+            let qvec = embeddings::embed_batch(&[q.as_str()]);
+            if let Some(vec) = qvec.first() {
+                let filter = vector_index::VectorFilter {
+                    from: time_from,
+                    to: time_to,
+                    ..Default::default()
+                };
+                for (id, score) in state.memory_vector_hits(vec, window, &filter, true) {
+                    let layer = layer_of(record(&id).as_ref());
+                    hits.push(FusionHit {
+                        id,
+                        layer,
+                        signal: Signal::Vector,
+                        source: "vector-ann",
+                        score,
+                        doc_refs: None,
+                        extra: None,
+                    });
+                }
+                let text_idx = state.db().open_tree("text_index").ok();
+                for (doc_id, start, score) in
+                    vector_index::search_chunks_by_vector(&state.db(), vec, window)
+                {
+                    let chunk_id = chunk_key(&doc_id, start);
+                    let text = text_idx
+                        .as_ref()
+                        .and_then(|t| t.get(chunk_id.as_bytes()).ok().flatten())
+                        .map(|v| String::from_utf8_lossy(&v).to_string());
+                    hits.push(FusionHit {
+                        id: chunk_id.clone(),
+                        layer: "doc".to_string(),
+                        signal: Signal::Vector,
+                        source: "doc-vector",
+                        score,
+                        doc_refs: Some(vec![DocRefOut {
+                            doc_id,
+                            chunk_id: Some(chunk_id),
+                            score: Some(score),
+                        }]),
+                        extra: Some(serde_json::json!({ "chunkStart": start, "chunkText": text })),
+                    });
+                }
+            }
+        }
+        phase.record("hits", hits.len() - before);
+    }
+    let results = {
+        let _phase = tracing::info_span!("fusion.fuse", mode = mode.key()).entered();
+        // Soft-deleted memories can still surface through tantivy and KG edges
+        hits.retain(|h| !record(&h.id).as_ref().is_some_and(is_tombstoned));
+        hits.retain(|h| kinds.allows(&h.layer));
+        let mut results = mode.fuse(hits);
+        let best = effectiveness_max(&state.db());
+        boost_by_effectiveness(&mut results, boost, best, |id| record(id));
+        results.truncate(window);
+        results
+    };
+    // Cache after augmentation
+    {
+        let mut guard = state.query_cache.lock().await;
+        guard.insert(cache_key, (now_ms, results.clone()));
+    }
+    let took = started.elapsed().as_millis() as u64;
+    // metrics update: cache miss
+    {
+        let mut m = state.metrics.lock().await;
+        m.count += 1;
+        m.cache_misses += 1;
+        m.last_ms = took;
+        m.avg_ms =
+            ((m.avg_ms * ((m.count.saturating_sub(1)) as f64)) + took as f64) / (m.count as f64);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        m.history.push_back((now, took));
+        while let Some((ts, _)) = m.history.front().cloned() {
+            if now - ts > 60_000 {
+                m.history.pop_front();
+            } else {
+                break;
+            }
+        }
+        let mut lat: Vec<u64> = m.history.iter().map(|(_, v)| *v).collect();
+        lat.sort_unstable();
+        if !lat.is_empty() {
+            let p50_idx = (lat.len() as f64 * 0.5).floor() as usize;
+            let p95_idx = (lat.len() as f64 * 0.95)
+                .floor()
+                .min((lat.len() - 1) as f64) as usize;
+            m.p50_ms = lat[p50_idx] as f64;
+            m.p95_ms = lat[p95_idx] as f64;
+        }
+        m.qps_1m = m.history.len() as f64 / 60.0;
+    }
+    let mut resp = page.apply(results, Some(took as u128));
+    attach_snippets(&state.db(), &mut resp.results, &q, highlight);
+    Ok(resp)
+}
+
+async fn document_refs_for_memory(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let mem_id = match params.get("id").cloned() {
+        Some(s) => s,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "id required",
+                None,
+            )
+        }
+    };
+    let refs_tree = match state.db().open_tree("doc_refs") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let prefix = doc_refs_prefix(&mem_id);
+    let mut out: Vec<serde_json::Value> = Vec::new();
+    for kv in refs_tree.scan_prefix(prefix.as_bytes()) {
+        if let Ok((k, v)) = kv {
+            let key = String::from_utf8_lossy(&k);
+            if let Some((_, doc_id, chunk_id)) = parse_doc_ref_key(&key) {
+                let score = serde_json::from_slice::<serde_json::Value>(&v)
+                    .ok()
+                    .and_then(|x| x.get("score").and_then(|s| s.as_f64()))
+                    .unwrap_or(0.0);
+                out.push(
+                    serde_json::json!({ "docId": doc_id, "chunkId": chunk_id, "score": score }),
+                );
+            }
+        }
+    }
+    Json(serde_json::json!({ "id": mem_id, "docRefs": out })).into_response()
+}
+
+async fn document_refs_for_document(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let doc_id = match params.get("id").cloned() {
+        Some(s) => s,
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "id required",
+                None,
+            )
+        }
+    };
+    let refs_tree = match state.db().open_tree("doc_refs") {
+        Ok(tree) => tree,
+        Err(e) => return internal_error(e),
+    };
+    let mut out: Vec<serde_json::Value> = Vec::new();
+    // Referencing memories come from incoming EVIDENCE edges; doc_refs holds chunk and score
+    let incoming =
+        kg::incoming_edges(&state.db(), &format!("Document::{}", doc_id)).unwrap_or_default();
+    for (_, edge) in incoming {
+        if edge.get("relation").and_then(|r| r.as_str()) != Some("EVIDENCE") {
+            continue;
+        }
+        let Some(mem_id) = edge
+            .get("src")
+            .and_then(|s| s.as_str())
+            .and_then(|s| s.strip_prefix("Memory::"))
+        else {
+            continue;
+        };
+        let prefix = doc_ref_key(mem_id, &doc_id, "");
+        for (k, v) in refs_tree.scan_prefix(prefix.as_bytes()).flatten() {
+            let key = String::from_utf8_lossy(&k);
+            let Some((_, _, chunk_id)) = parse_doc_ref_key(&key) else {
+                continue;
+            };
+            let score = serde_json::from_slice::<serde_json::Value>(&v)
+                .ok()
+                .and_then(|x| x.get("score").and_then(|s| s.as_f64()))
+                .unwrap_or(0.0);
+            out.push(
+                serde_json::json!({ "memoryId": mem_id, "chunkId": chunk_id, "score": score }),
+            );
+        }
+    }
+    Json(serde_json::json!({ "id": doc_id, "memories": out })).into_response()
+}
+
+#[derive(Deserialize)]
+struct ValidateRefsBody {
+    fix: Option<bool>,
+}
+
+async fn document_validate_refs(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<ValidateRefsBody>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let fix = body.fix.unwrap_or(false);
+    let refs_tree = state.db().open_tree("doc_refs").map_err(internal_error)?;
+    let mems = state.db().open_tree("memories").map_err(internal_error)?;
+    let docs_meta = state.db().open_tree("chunks").map_err(internal_error)?;
+    let mut invalid: Vec<String> = Vec::new();
+    let mut removed = 0u64;
+    for kv in refs_tree.iter() {
+        if let Ok((k, _)) = kv {
+            let key = String::from_utf8_lossy(&k);
+            let Some((mem_id, doc_id, chunk_id)) = parse_doc_ref_key(&key) else {
+                invalid.push(key.to_string());
+                if fix {
+                    let _ = refs_tree.remove(&k);
+                    removed += 1;
+                }
+                continue;
+            };
+            let mem_ok = mems.get(mem_id.as_bytes()).ok().flatten().is_some();
+            // minimal doc/chunk check: presence of any chunk for doc
+            let prefix = format!("{}:", doc_id);
+            let mut doc_ok = false;
+            for it in docs_meta.scan_prefix(prefix.as_bytes()).take(1) {
+                if it.is_ok() {
+                    doc_ok = true;
+                    break;
+                }
+            }
+            if !mem_ok || !doc_ok || chunk_id.is_empty() {
+                invalid.push(key.to_string());
+                if fix {
+                    let _ = refs_tree.remove(&k);
+                    removed += 1;
+                }
+            }
+        }
+    }
+    Ok(Json(
+        serde_json::json!({ "invalid": invalid, "removed": if fix { Some(removed) } else { None } }),
+    ))
+}
+
+fn index_chunks_sled(db: &sled::Db, doc_id: &str, chunks: &[TextChunk]) -> Result<()> {
+    let text_idx = db.open_tree("text_index")?;
+    for ch in chunks {
+        let key = chunk_key(doc_id, ch.header.position.start);
+        text_idx.insert(key.as_bytes(), ch.text.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn run_index_maintenance(state: &Arc<AppState>) -> Result<(u64, u64)> {
+    let text_idx = state.db().open_tree("text_index")?;
+    let chunks = state.db().open_tree("chunks")?;
+    let mut removed_text = 0u64;
+    for kv in text_idx.iter() {
+        let (k, _) = kv?;
+        let key = String::from_utf8(k.to_vec()).unwrap_or_default();
+        if let Some((doc_id, _)) = key.split_once(":") {
+            let prefix = format!("{}:", doc_id);
+            let mut has_chunks = false;
+            for it in chunks.scan_prefix(prefix.as_bytes()).take(1) {
+                if it.is_ok() {
+                    has_chunks = true;
+                    break;
+                }
+            }
+            if !has_chunks {
+                let _ = text_idx.remove(k);
+                removed_text += 1;
+            }
+        }
+    }
+    let mut removed_edges = 0u64;
+    for k in kg::dangling_edges(&state.db())? {
+        let _ = kg::remove_edge_by_key(&state.db(), &k);
+        removed_edges += 1;
+    }
+    // Clean orphan memory embeddings
+    let removed_emb = vector_index::cleanup_orphan_mem_embeddings(&state.db()).unwrap_or(0);
+    if removed_emb > 0 {
+        state.warm_vectors.reload(&state.db())?;
+    }
+    state.db().flush()?;
+    Ok((removed_text + removed_emb, removed_edges))
+}
+
+async fn system_cleanup(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Json<serde_json::Value> {
+    let reindex = body
+        .get("reindex")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let compact = body
+        .get("compact")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let (removed_text, removed_edges) = run_index_maintenance(&state).unwrap_or((0, 0));
+    state.invalidate_query_cache();
+    if compact {
+        let _ = state.db().flush();
+    }
+    Json(
+        serde_json::json!({ "removedText": removed_text, "removedEdges": removed_edges, "reindexed": reindex, "compacted": compact }),
+    )
+}
+
+async fn system_validate(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<serde_json::Value> {
+    // Basic integrity checks: embeddings dimension, orphan embeddings, KG edge endpoints
+    let (total, invalid) = vector_index::validate_mem_embeddings(&state.db());
+    let mut orphan = 0u64;
+    if let Ok(tree) = state.db().open_tree("mem_embeddings") {
+        if let Ok(mems) = state.db().open_tree("memories") {
+            for kv in tree.iter() {
+                if let Ok((k, _)) = kv {
+                    if mems.get(&k).ok().flatten().is_none() {
+                        orphan += 1;
+                    }
+                }
+            }
+        }
+    }
+    let bad_edges = kg::dangling_edges(&state.db())
+        .map(|keys| keys.len())
+        .unwrap_or(0);
+    Json(
+        serde_json::json!({ "embeddings": { "total": total, "invalid": invalid, "orphans": orphan }, "kg": { "badEdges": bad_edges } }),
+    )
+}
+
+/// Report (and with `fix`, repair) KG inconsistencies: dangling edges, links to documents
+/// without chunks, and entity counts that disagree with the links.
+async fn kg_repair(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<ValidateRefsBody>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let fix = body.fix.unwrap_or(false);
+    let db = state.db();
+    let chunks = db.open_tree("chunks").map_err(internal_error)?;
+    let report = kg::repair(&db, fix, |doc_id| {
+        chunks
+            .scan_prefix(format!("{}:", doc_id).as_bytes())
+            .next()
+            .is_some()
+    })
+    .map_err(internal_error)?;
+    if fix {
+        state.invalidate_query_cache();
+        db.flush().ok();
+    }
+    Ok(Json(serde_json::json!({
+        "fix": fix,
+        "danglingEdges": report.dangling_edges,
+        "staleLinks": report.stale_links,
+        "recountedEntities": report.recounted_entities,
+    })))
+}
+
+async fn system_backup(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let dest = body
+        .get("destination")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .or_else(|| std::env::var("BACKUP_DIR").ok())
+        .unwrap_or_else(|| "./backup".to_string());
+    let include_indices = body
+        .get("includeIndices")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    let compress = body
+        .get("compress")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let base_snapshot = body
+        .get("baseSnapshot")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    if let Some(base) = &base_snapshot {
+        if compress {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "incremental backups cannot be compressed",
+                None,
+            );
+        }
+        if !std::path::Path::new(base).join("manifest.json").is_file() {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "baseSnapshot must be a snapshot directory",
+                None,
+            );
+        }
+    }
+    let _ = state.db().flush();
+    let _ = state.text_index.close();
+    match create_backup(
+        &data_root(&state),
+        &dest,
+        include_indices,
+        compress,
+        base_snapshot.as_deref(),
+    ) {
+        Ok(info) => Json(info.to_json()).into_response(),
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+async fn system_restore(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let source = match body.get("source").and_then(|v| v.as_str()) {
+        Some(s) => s.to_string(),
+        None => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "source required",
+                None,
+            )
+        }
+    };
+    let include_indices = body
+        .get("includeIndices")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    if body
+        .get("verify")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+    {
+        // Refuse to overwrite live data with a snapshot that doesn't match its manifest
+        match verify_backup(&source) {
+            Ok(Some(v)) if v.is_valid() => {}
+            Ok(Some(v)) => {
+                return json_error(
+                    StatusCode::CONFLICT,
+                    "CONFLICT",
+                    "backup failed verification",
+                    Some(serde_json::json!({ "mismatched": v.mismatched, "missing": v.missing })),
+                )
+            }
+            Ok(None) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    "backup manifest has no checksums",
+                    None,
+                )
+            }
+            Err(err) => {
+                return json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "INTERNAL_ERROR",
+                    err.to_string(),
+                    None,
+                )
+            }
+        }
+    }
+    match restore_live(&state, &source, include_indices).await {
+        Ok((took_ms, valid)) => Json(serde_json::json!({
+            "restored": true,
+            "validated": valid,
+            "rolledBack": false,
+            "tookMs": took_ms
+        }))
+        .into_response(),
+        Err(failure) => failure.into_response(),
+    }
+}
+
+async fn system_verify_backup(Json(body): Json<serde_json::Value>) -> Response {
+    use std::time::Instant as TInstant;
+    let started = TInstant::now();
+    let Some(source) = body.get("source").and_then(|v| v.as_str()) else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "source required",
+            None,
+        );
+    };
+    if !std::path::Path::new(source).exists() {
+        return json_error(StatusCode::NOT_FOUND, "NOT_FOUND", "source not found", None);
+    }
+    match verify_backup(source) {
+        Ok(Some(v)) => Json(serde_json::json!({
+            "source": source,
+            "valid": v.is_valid(),
+            "checked": v.checked,
+            "mismatched": v.mismatched,
+            "missing": v.missing,
+            "tookMs": started.elapsed().as_millis(),
+        }))
+        .into_response(),
+        Ok(None) => json_error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_INPUT",
+            "backup manifest has no checksums",
+            None,
+        ),
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+async fn system_compact(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(_body): Json<serde_json::Value>,
+) -> Response {
+    // Best-effort compaction: flush sled, rebuild vector neighbor graph, and tantivy merge by reindex
+    let _ = state.db().flush();
+    let _ = vector_index::build_mem_neighbor_graph(&state.db());
+    // Tantivy merge: trigger a lightweight reindex of memory docs
+    if let Ok(tree) = state.db().open_tree("memories") {
+        for kv in tree.iter() {
+            if let Ok((_, v)) = kv {
+                if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+                    if let Some(id) = rec.get("id").and_then(|x| x.as_str()) {
+                        let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+                        let _ = index_memory_tantivy(&state.text_index, id, content);
+                    }
+                }
+            }
+        }
+    }
+    Json(serde_json::json!({ "compacted": true })).into_response()
+}
+
+#[derive(Deserialize)]
+struct ExportBody {
+    #[serde(default)]
+    include_indices: Option<bool>,
+    #[serde(default)]
+    compress: Option<bool>,
+}
+
+async fn data_export(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<ExportBody>,
+) -> Response {
+    let dest = std::env::var("EXPORT_DIR").unwrap_or_else(|_| "./export".to_string());
+    let include_indices = body.include_indices.unwrap_or(true);
+    let _ = state.db().flush();
+    let _ = state.text_index.close();
+    match create_backup(
+        &data_root(&state),
+        &dest,
+        include_indices,
+        body.compress.unwrap_or(false),
+        None,
+    ) {
+        Ok(info) => Json(info.to_json()).into_response(),
+        Err(err) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            err.to_string(),
+            None,
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct ImportBody {
+    source: String,
+    #[serde(default)]
+    include_indices: Option<bool>,
+}
+
+async fn data_import(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<ImportBody>,
+) -> Response {
+    let include_indices = body.include_indices.unwrap_or(true);
+    match restore_live(&state, &body.source, include_indices).await {
+        Ok((took_ms, _)) => {
+            Json(serde_json::json!({ "imported": true, "rolledBack": false, "tookMs": took_ms }))
+                .into_response()
+        }
+        Err(failure) => failure.into_response(),
+    }
+}
+
+fn dir_size_mb(path: &std::path::Path) -> u64 {
+    fn walk(p: &std::path::Path) -> u64 {
+        let mut total = 0u64;
+        if let Ok(rd) = std::fs::read_dir(p) {
+            for e in rd.flatten() {
+                let m = match e.metadata() {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                };
+                if m.is_dir() {
+                    total += walk(&e.path());
+                } else {
+                    total += m.len();
+                }
+            }
+        }
+        total
+    }
+    (walk(path) / (1024 * 1024)) as u64
+}
+
+fn copy_dir(src: &std::path::Path, dst: &std::path::Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let to = dst.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &to)?;
+        } else {
+            let _ = std::fs::copy(&path, &to);
+        }
+    }
+    Ok(())
+}
+
+/// Root holding the hot/warm/cold/index tiers for this server.
+fn data_root(state: &AppState) -> std::path::PathBuf {
+    state
+        .index_dir
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| std::path::PathBuf::from("./data"))
+}
+
+/// Where a snapshot was written and how big it is.
+struct BackupInfo {
+    path: String,
+    size_mb: u64,
+    /// Archive size; only set for compressed snapshots
+    compressed_bytes: Option<u64>,
+    /// Base snapshot and number of files copied; only set for incremental snapshots
+    incremental: Option<(String, usize)>,
+    took_ms: u128,
+}
+
+impl BackupInfo {
+    fn to_json(&self) -> serde_json::Value {
+        let mut out = serde_json::json!({
+            "path": self.path,
+            "sizeMb": self.size_mb,
+            "tookMs": self.took_ms,
+        });
+        if let Some(bytes) = self.compressed_bytes {
+            out["compressed"] = serde_json::json!(true);
+            out["compressedBytes"] = serde_json::json!(bytes);
+        }
+        if let Some((parent, changed)) = &self.incremental {
+            out["incremental"] = serde_json::json!(true);
+            out["parent"] = serde_json::json!(parent);
+            out["changed"] = serde_json::json!(changed);
+        }
+        out
+    }
+}
+
+/// Snapshot the warm/cold (and optionally index) tiers under `destination`, either as a
+/// `snapshot-<ts>` directory or, with `compress`, a single `snapshot-<ts>.tar.gz`. Both
+/// carry a `manifest.json`. With `base_snapshot` only files whose size or mtime differ
+/// from that snapshot's inventory are copied.
+fn create_backup(
+    data_root: &std::path::Path,
+    destination: &str,
+    include_indices: bool,
+    compress: bool,
+    base_snapshot: Option<&str>,
+) -> Result<BackupInfo> {
+    use std::time::Instant as TInstant;
+    let started = TInstant::now();
+    let dest = std::path::PathBuf::from(destination);
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let mut tiers = vec!["warm", "cold"];
+    if include_indices {
+        tiers.push("index");
+    }
+    let tiers: Vec<&str> = tiers
+        .into_iter()
+        .filter(|t| data_root.join(t).exists())
+        .collect();
+    let inventory = snapshot_inventory(data_root, &tiers)?;
+    if let Some(base) = base_snapshot {
+        if compress {
+            anyhow::bail!("incremental backups are written as directories");
+        }
+        let base_dir = std::fs::canonicalize(base)?;
+        let base_manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(base_dir.join("manifest.json"))?)?;
+        let Some(base_inventory) = base_manifest.get("inventory").and_then(|v| v.as_object())
+        else {
+            anyhow::bail!("base snapshot has no file inventory");
+        };
+        let target = dest.join(format!("snapshot-{}", ts));
+        std::fs::create_dir_all(&target)?;
+        let mut changed = Vec::new();
+        let mut files = serde_json::Map::new();
+        for (rel, stat) in &inventory {
+            if base_inventory.get(rel) == Some(stat) {
+                continue;
+            }
+            let to = target.join(rel);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(data_root.join(rel), &to)?;
+            files.insert(rel.clone(), serde_json::json!(sha256_file(&to)?));
+            changed.push(rel.clone());
+        }
+        let size_mb = dir_size_mb(&target);
+        let parent = base_dir.to_string_lossy().to_string();
+        let manifest = serde_json::json!({
+            "createdAt": ts,
+            "includeIndices": include_indices,
+            "sizesMb": { "warmColdIndex": size_mb },
+            "parent": parent,
+            "changed": changed,
+            "files": files,
+            "inventory": inventory
+        });
+        std::fs::write(
+            target.join("manifest.json"),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        return Ok(BackupInfo {
+            path: target.to_string_lossy().to_string(),
+            size_mb,
+            compressed_bytes: None,
+            incremental: Some((parent, changed.len())),
+            took_ms: started.elapsed().as_millis(),
+        });
+    }
+    if compress {
+        std::fs::create_dir_all(&dest)?;
+        let target = dest.join(format!("snapshot-{}.tar.gz", ts));
+        let size_mb: u64 = tiers.iter().map(|t| dir_size_mb(&data_root.join(t))).sum();
+        let file = std::fs::File::create(&target)?;
+        let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut archive = tar::Builder::new(gz);
+        // Hash while archiving so the checksums describe exactly the bytes that were written
+        let mut files = serde_json::Map::new();
+        for t in &tiers {
+            for (rel, path) in snapshot_entries(data_root, t)? {
+                if path.is_dir() {
+                    archive.append_dir(&rel, &path)?;
+                    continue;
+                }
+                let src = std::fs::File::open(&path)?;
+                let len = src.metadata()?.len();
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&src.metadata()?);
+                header.set_size(len);
+                header.set_cksum();
+                let mut reader = HashingReader {
+                    inner: std::io::Read::take(src, len),
+                    hasher: Sha256::new(),
+                };
+                archive.append_data(&mut header, &rel, &mut reader)?;
+                files.insert(
+                    rel,
+                    serde_json::json!(format!("{:x}", reader.hasher.finalize())),
+                );
+            }
+        }
+        let manifest = serde_json::to_vec_pretty(&serde_json::json!({
+            "createdAt": ts,
+            "includeIndices": include_indices,
+            "compressed": true,
+            "sizesMb": { "warmColdIndex": size_mb },
+            "files": files,
+            "inventory": inventory
+        }))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime((ts / 1000) as u64);
+        header.set_cksum();
+        archive.append_data(&mut header, "manifest.json", manifest.as_slice())?;
+        archive.into_inner()?.finish()?;
+        let bytes = std::fs::metadata(&target)?.len();
+        return Ok(BackupInfo {
+            path: target.to_string_lossy().to_string(),
+            size_mb,
+            compressed_bytes: Some(bytes),
+            incremental: None,
+            took_ms: started.elapsed().as_millis(),
+        });
+    }
+    let target = dest.join(format!("snapshot-{}", ts));
+    std::fs::create_dir_all(&target)?;
+    for t in &tiers {
+        copy_dir(&data_root.join(t), &target.join(t))?;
+    }
+    let size_mb = dir_size_mb(&target);
+    let mut files = serde_json::Map::new();
+    for t in &tiers {
+        for (rel, path) in snapshot_entries(&target, t)? {
+            if path.is_file() {
+                files.insert(rel, serde_json::json!(sha256_file(&path)?));
+            }
+        }
+    }
+    let took = started.elapsed().as_millis();
+    // Write manifest
+    let manifest = serde_json::json!({
+        "createdAt": ts,
+        "includeIndices": include_indices,
+        "sizesMb": { "warmColdIndex": size_mb },
+        "files": files,
+        "inventory": inventory
+    });
+    let _ = std::fs::write(
+        target.join("manifest.json"),
+        serde_json::to_vec_pretty(&manifest)?,
+    );
+    Ok(BackupInfo {
+        path: target.to_string_lossy().to_string(),
+        size_mb,
+        compressed_bytes: None,
+        incremental: None,
+        took_ms: took,
+    })
+}
+
+/// Every directory and file under `base/tier`, keyed by its `/`-separated path relative to
+/// `base` (the layout used inside snapshots).
+fn snapshot_entries(
+    base: &std::path::Path,
+    tier: &str,
+) -> Result<Vec<(String, std::path::PathBuf)>> {
+    fn walk(
+        dir: &std::path::Path,
+        rel: &str,
+        out: &mut Vec<(String, std::path::PathBuf)>,
+    ) -> Result<()> {
+        let mut entries: Vec<_> = std::fs::read_dir(dir)?.flatten().collect();
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries {
+            let path = e.path();
+            let child = format!("{}/{}", rel, e.file_name().to_string_lossy());
+            out.push((child.clone(), path.clone()));
+            if path.is_dir() {
+                walk(&path, &child, out)?;
+            }
+        }
+        Ok(())
+    }
+    let mut out = Vec::new();
+    walk(&base.join(tier), tier, &mut out)?;
+    Ok(out)
+}
+
+/// Size and mtime of every file in the given tiers, keyed like `snapshot_entries`. Used to
+/// decide what an incremental snapshot must copy.
+fn snapshot_inventory(
+    base: &std::path::Path,
+    tiers: &[&str],
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut out = serde_json::Map::new();
+    for t in tiers {
+        for (rel, path) in snapshot_entries(base, t)? {
+            let meta = std::fs::metadata(&path)?;
+            if !meta.is_file() {
+                continue;
+            }
+            let mtime_ns = meta
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0);
+            out.insert(
+                rel,
+                serde_json::json!({ "size": meta.len(), "mtimeNs": mtime_ns }),
+            );
+        }
+    }
+    Ok(out)
+}
+
+fn sha256_file(path: &std::path::Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Passes reads through while hashing them.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: std::io::Read> std::io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Outcome of checking a snapshot's files against its manifest checksums.
+struct BackupVerification {
+    checked: usize,
+    mismatched: Vec<String>,
+    missing: Vec<String>,
+}
+
+impl BackupVerification {
+    fn is_valid(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Recompute the checksums of a snapshot directory or `.tar.gz` archive and compare them
+/// with its manifest. Returns `None` when the manifest is absent or predates checksums.
+fn verify_backup(source: &str) -> Result<Option<BackupVerification>> {
+    let mut actual: HashMap<String, String> = HashMap::new();
+    let manifest: Option<serde_json::Value>;
+    if source.ends_with(".tar.gz") || source.ends_with(".tgz") {
+        let file = std::fs::File::open(source)?;
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut found = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let rel = entry.path()?.to_string_lossy().to_string();
+            if rel == "manifest.json" {
+                let mut buf = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut buf)?;
+                found = serde_json::from_slice(&buf).ok();
+                continue;
+            }
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut entry, &mut hasher)?;
+            actual.insert(rel, format!("{:x}", hasher.finalize()));
+        }
+        manifest = found;
+    } else {
+        let src = std::path::Path::new(source);
+        manifest = std::fs::read(src.join("manifest.json"))
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok());
+        if let Some(files) = manifest
+            .as_ref()
+            .and_then(|m| m.get("files"))
+            .and_then(|f| f.as_object())
+        {
+            for rel in files.keys() {
+                let path = src.join(rel);
+                if path.is_file() {
+                    actual.insert(rel.clone(), sha256_file(&path)?);
+                }
+            }
+        }
+    }
+    let Some(expected) = manifest
+        .as_ref()
+        .and_then(|m| m.get("files"))
+        .and_then(|f| f.as_object())
+    else {
+        return Ok(None);
+    };
+    let mut out = BackupVerification {
+        checked: expected.len(),
+        mismatched: Vec::new(),
+        missing: Vec::new(),
+    };
+    for (rel, sum) in expected {
+        match actual.get(rel) {
+            None => out.missing.push(rel.clone()),
+            Some(got) if Some(got.as_str()) != sum.as_str() => out.mismatched.push(rel.clone()),
+            Some(_) => {}
+        }
+    }
+    out.mismatched.sort();
+    out.missing.sort();
+    Ok(Some(out))
+}
+
+fn max_concurrent_ingest() -> u32 {
+    std::env::var("MAX_CONCURRENT_INGEST")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4)
+}
+
+/// A restore that failed; `rolled_back` says the previous data was put back in place.
+struct RestoreFailure {
+    error: anyhow::Error,
+    rolled_back: bool,
+}
+
+impl IntoResponse for RestoreFailure {
+    fn into_response(self) -> Response {
+        json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            self.error.to_string(),
+            Some(serde_json::json!({ "rolledBack": self.rolled_back })),
+        )
+    }
+}
+
+/// Restore `source` over the live data without ever exposing a half-written state: the
+/// snapshot is unpacked into a staging directory, its tiers are renamed over the live ones
+/// and sled is reopened on them. Any failure moves the previous tiers back.
+async fn restore_live(
+    state: &AppState,
+    source: &str,
+    include_indices: bool,
+) -> std::result::Result<(u128, bool), RestoreFailure> {
+    let started = Instant::now();
+    // Hold every ingest permit so no ingestion is writing while tiers move
+    let _permits = state
+        .ingest_sema
+        .acquire_many(max_concurrent_ingest())
+        .await
+        .map_err(|e| RestoreFailure {
+            error: e.into(),
+            rolled_back: false,
+        })?;
+    let root = data_root(state);
+    let _ = state.db().flush();
+    // Release the tantivy writer so the index tier can be moved; it reopens on next use
+    let _ = state.text_index.close();
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let staging = root.join(format!(".restore-staging-{}", ts));
+    let previous = root.join(format!(".restore-prev-{}", ts));
+    let validated = match restore_backup(&staging, source, include_indices) {
+        Ok((_, validated)) => validated,
+        Err(error) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(RestoreFailure {
+                error,
+                rolled_back: true,
+            });
+        }
+    };
+    let tiers: Vec<&str> = ["warm", "cold", "index"]
+        .into_iter()
+        .filter(|t| staging.join(t).exists())
+        .collect();
+    let mut swapped: Vec<&str> = Vec::new();
+    let mut swap = || -> Result<Option<Db>> {
+        std::fs::create_dir_all(&previous)?;
+        for t in &tiers {
+            let live = root.join(t);
+            if live.exists() {
+                std::fs::rename(&live, previous.join(t))?;
+            }
+            swapped.push(t);
+            std::fs::rename(staging.join(t), &live)?;
+        }
+        if !tiers.contains(&"warm") {
+            return Ok(None);
+        }
+        // The pid file belongs to this process, not to the snapshot
+        let _ = std::fs::copy(
+            previous.join("warm").join("server.pid"),
+            root.join("warm").join("server.pid"),
+        );
+        let db = open_db(&root.join("warm").join("kv"))?;
+        run_migrations(&db)?;
+        Ok(Some(db))
+    };
+    match swap() {
+        Ok(reopened) => {
+            if let Some(db) = reopened {
+                *state.live_db.write().expect("db lock") = db;
+            }
+            // Snapshots taken before the text index schema changed carry the old layout
+            if let Err(e) = ensure_text_index_schema(&state.db(), &state.text_index) {
+                error!("Failed to rebuild restored text index: {}", e);
+            }
+            if let Err(e) = state.warm_vectors.reload(&state.db()) {
+                error!("Failed to warm restored memory vectors: {}", e);
+            }
+            state.invalidate_query_cache();
+            state.query_cache.lock().await.clear();
+            let _ = std::fs::remove_dir_all(&staging);
+            let _ = std::fs::remove_dir_all(&previous);
+            Ok((started.elapsed().as_millis(), validated))
+        }
+        Err(error) => {
+            for t in swapped.iter().rev() {
+                let live = root.join(t);
+                let _ = std::fs::remove_dir_all(&live);
+                let _ = std::fs::rename(previous.join(t), &live);
+            }
+            let _ = std::fs::remove_dir_all(&staging);
+            let _ = std::fs::remove_dir_all(&previous);
+            Err(RestoreFailure {
+                error,
+                rolled_back: true,
+            })
+        }
+    }
+}
+
+/// Restore a snapshot directory or `.tar.gz` archive into `data_root`. Returns the time
+/// taken and whether the snapshot carried a manifest.
+fn restore_backup(
+    data_root: &std::path::Path,
+    source: &str,
+    include_indices: bool,
+) -> Result<(u128, bool)> {
+    use std::time::Instant as TInstant;
+    let started = TInstant::now();
+    if source.ends_with(".tar.gz") || source.ends_with(".tgz") {
+        let file = std::fs::File::open(source)?;
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut has_manifest = false;
+        std::fs::create_dir_all(data_root)?;
+        // Entries are streamed straight into place; `unpack_in` refuses paths escaping the root
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_path_buf();
+            let top = path
+                .components()
+                .next()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .unwrap_or_default();
+            match top.as_str() {
+                "manifest.json" => has_manifest = true,
+                "warm" | "cold" => {
+                    entry.unpack_in(data_root)?;
+                }
+                "index" if include_indices => {
+                    entry.unpack_in(data_root)?;
+                }
+                _ => {}
+            }
+        }
+        return Ok((started.elapsed().as_millis(), has_manifest));
+    }
+    let src = std::path::PathBuf::from(source);
+    let read_manifest = |dir: &std::path::Path| -> Option<serde_json::Value> {
+        serde_json::from_slice(&std::fs::read(dir.join("manifest.json")).ok()?).ok()
+    };
+    if let Some(manifest) = read_manifest(&src).filter(|m| m.get("parent").is_some()) {
+        // Incremental: each file comes from the newest snapshot in the chain that holds it
+        let mut chain = vec![src.clone()];
+        let mut current = manifest.clone();
+        while let Some(parent) = current.get("parent").and_then(|p| p.as_str()) {
+            let dir = std::path::PathBuf::from(parent);
+            current = read_manifest(&dir)
+                .ok_or_else(|| anyhow::anyhow!("parent snapshot {} is missing", parent))?;
+            chain.push(dir);
+        }
+        let Some(inventory) = manifest.get("inventory").and_then(|v| v.as_object()) else {
+            anyhow::bail!("incremental snapshot has no file inventory");
+        };
+        for rel in inventory.keys() {
+            if !include_indices && rel.starts_with("index/") {
+                continue;
+            }
+            let Some(from) = chain.iter().map(|d| d.join(rel)).find(|p| p.is_file()) else {
+                anyhow::bail!("{} is missing from the snapshot chain", rel);
+            };
+            let to = data_root.join(rel);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(&from, &to)?;
+        }
+        return Ok((started.elapsed().as_millis(), true));
+    }
+    let warm_src = src.join("warm");
+    let cold_src = src.join("cold");
+    let index_src = src.join("index");
+    if warm_src.exists() {
+        copy_dir(&warm_src, &data_root.join("warm"))?;
+    }
+    if cold_src.exists() {
+        copy_dir(&cold_src, &data_root.join("cold"))?;
+    }
+    if include_indices && index_src.exists() {
+        copy_dir(&index_src, &data_root.join("index"))?;
+    }
+    Ok((
+        started.elapsed().as_millis(),
+        src.join("manifest.json").exists(),
+    ))
+}
+
+async fn advanced_reindex(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let vector = body.get("vector").and_then(|v| v.as_bool()).unwrap_or(true);
+    let text = body.get("text").and_then(|v| v.as_bool()).unwrap_or(true);
+    let graph = body.get("graph").and_then(|v| v.as_bool()).unwrap_or(true);
+    let _ = run_index_maintenance(&state);
+    let mut reindexed = 0;
+    if text {
+        reindexed = rebuild_text_index(&state.db(), &state.text_index).map_err(internal_error)?;
+    }
+    if vector {
+        let _ = vector_index::reembed_all_memories(&state.db(), 256);
+        let _ = vector_index::build_mem_neighbor_graph(&state.db());
+        let _ = state.warm_vectors.reload(&state.db());
+    }
+    state.invalidate_query_cache();
+    Ok(Json(serde_json::json!({
+        "vector": vector,
+        "text": text,
+        "graph": graph,
+        "reindexed": reindexed,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Rebuild both text indices from the live `memories` and the chunk entries of the sled
+/// text index, dropping anything deleted or soft-deleted. Returns the documents indexed.
+fn rebuild_text_index(db: &sled::Db, text_index: &text_index::TextIndex) -> Result<usize> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let text_idx = db.open_tree("text_index")?;
+    let mut docs: Vec<(String, &'static str, String, i64)> = Vec::new();
+    for kv in db.open_tree("memories")?.iter() {
+        let (_, v) = kv?;
+        let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        let Some(id) = rec.get("id").and_then(|x| x.as_str()) else {
+            continue;
+        };
+        if is_tombstoned(&rec) {
+            continue;
+        }
+        let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+        index_memory_sled(db, id, content)?;
+        docs.push((format!("mem:{}", id), "memory", content.to_string(), now));
+    }
+    // Memory entries without a live memory behind them are ghosts
+    let live: std::collections::HashSet<&str> = docs.iter().map(|d| d.0.as_str()).collect();
+    for kv in text_idx.scan_prefix(b"mem:") {
+        let (k, _) = kv?;
+        if !live.contains(String::from_utf8_lossy(&k).as_ref()) {
+            text_idx.remove(k)?;
+        }
+    }
+    for kv in text_idx.iter() {
+        let (k, v) = kv?;
+        let key = String::from_utf8_lossy(&k).to_string();
+        if !key.starts_with("mem:") {
+            docs.push((key, "chunk", String::from_utf8_lossy(&v).to_string(), now));
+        }
+    }
+    text_index.rebuild(docs)
+}
+
+async fn advanced_analyze_patterns(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let from = body
+        .get("window")
+        .and_then(|w| w.get("from"))
+        .and_then(|v| v.as_i64());
+    let to = body
+        .get("window")
+        .and_then(|w| w.get("to"))
+        .and_then(|v| v.as_i64());
+    let started = std::time::Instant::now();
+    let min_support = body.get("minSupport").and_then(|v| v.as_u64()).unwrap_or(2) as usize;
+    // Share of a concept's support that must shift between the window's halves to trend
+    let sensitivity = body
+        .get("sensitivity")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.2);
+    let tree = state.db().open_tree("memories").map_err(internal_error)?;
+    let mut counter: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut mentions: Vec<(String, i64)> = Vec::new();
+    let mut scanned = 0usize;
+    for kv in tree.iter() {
+        if let Ok((_, v)) = kv {
+            if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+                let created_at = rec.get("created_at").and_then(|c| c.as_i64());
+                let in_time = created_at
+                    .map(|t| {
+                        from.map(|f| t >= f).unwrap_or(true) && to.map(|to| t <= to).unwrap_or(true)
+                    })
+                    .unwrap_or(true);
+                if !in_time {
+                    continue;
+                }
+                scanned += 1;
+                if let Some(content) = rec.get("content").and_then(|c| c.as_str()) {
+                    for ent in kg::extract_entities(content) {
+                        if let Some(t) = created_at {
+                            mentions.push((ent.clone(), t));
+                        }
+                        *counter.entry(ent).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+    }
+    // Split the window (or, when open-ended, the span of mentions) at its midpoint
+    let lo = from.or_else(|| mentions.iter().map(|m| m.1).min());
+    let hi = to.or_else(|| mentions.iter().map(|m| m.1).max());
+    let mut halves: std::collections::HashMap<&str, (usize, usize)> =
+        std::collections::HashMap::new();
+    if let (Some(lo), Some(hi)) = (lo, hi) {
+        let mid = lo + (hi - lo) / 2;
+        for (ent, t) in &mentions {
+            let entry = halves.entry(ent.as_str()).or_default();
+            if *t < mid {
+                entry.0 += 1;
+            } else {
+                entry.1 += 1;
+            }
+        }
+    }
+    let trend = |concept: &str| {
+        let (early, late) = halves.get(concept).copied().unwrap_or_default();
+        let delta = (late as f64 - early as f64) / (early + late).max(1) as f64;
+        if delta > sensitivity {
+            "rising"
+        } else if delta < -sensitivity {
+            "falling"
+        } else {
+            "flat"
+        }
+    };
+    let mut patterns: Vec<(String, usize)> = counter
+        .into_iter()
+        .filter(|(_, c)| *c >= min_support)
+        .collect();
+    patterns.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let out: Vec<serde_json::Value> = patterns
+        .into_iter()
+        .map(|(concept, support)| {
+            let trend = trend(&concept);
+            serde_json::json!({ "concept": concept, "support": support, "trend": trend })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "patterns": out,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Memories per layer in each time bucket of `from..=to`. With `entities` (names) or
+/// `topN`, also counts the memories mentioning each of those (or the most mentioned)
+/// entities per bucket.
+async fn advanced_trends(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let from = body.get("from").and_then(|v| v.as_i64());
+    let to = body.get("to").and_then(|v| v.as_i64());
+    let buckets = body
+        .get("buckets")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(10)
+        .max(1) as i64;
+    let named: Option<Vec<String>> = body.get("entities").and_then(|v| v.as_array()).map(|a| {
+        a.iter()
+            .filter_map(|e| e.as_str().map(str::to_string))
+            .collect()
+    });
+    let top_n = body
+        .get("topN")
+        .and_then(|v| v.as_u64())
+        .map(|n| n as usize);
+    let tree = state.db().open_tree("memories").map_err(internal_error)?;
+    let mut timeline: Vec<serde_json::Value> = Vec::new();
+    let mut entity_timelines: Vec<serde_json::Value> = Vec::new();
+    let mut scanned = 0usize;
+    if let (Some(f), Some(t)) = (from, to) {
+        let span = (t - f).max(1);
+        let step = (span / buckets).max(1);
+        let ranges: Vec<(i64, i64)> = (0..buckets)
+            .map(|i| {
+                let start = f + i * step;
+                let end = if i == buckets - 1 {
+                    t
+                } else {
+                    start + step - 1
+                };
+                (start, end)
+            })
+            .collect();
+        let mut stm = vec![0u64; ranges.len()];
+        let mut ltm = vec![0u64; ranges.len()];
+        let mut mentions: HashMap<String, Vec<u64>> = HashMap::new();
+        let track_entities = named.is_some() || top_n.is_some();
+        for kv in tree.iter() {
+            let Ok((_, v)) = kv else { continue };
+            let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+                continue;
+            };
+            let Some(ts) = rec.get("created_at").and_then(|x| x.as_i64()) else {
+                continue;
+            };
+            let Some(bucket) = ranges.iter().position(|(s, e)| ts >= *s && ts <= *e) else {
+                continue;
+            };
+            scanned += 1;
+            match rec.get("layer").and_then(|x| x.as_str()) {
+                Some("STM") => stm[bucket] += 1,
+                Some("LTM") => ltm[bucket] += 1,
+                _ => {}
+            }
+            if track_entities {
+                let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
+                for ent in kg::extract_entities(content) {
+                    mentions.entry(ent).or_insert_with(|| vec![0; ranges.len()])[bucket] += 1;
+                }
+            }
+        }
+        for (i, (start, end)) in ranges.iter().enumerate() {
+            timeline.push(
+                serde_json::json!({ "start": start, "end": end, "STM": stm[i], "LTM": ltm[i] }),
+            );
+        }
+        let chosen: Vec<String> = match (named, top_n) {
+            (Some(names), _) => names,
+            (None, Some(n)) => {
+                let mut totals: Vec<(&String, u64)> = mentions
+                    .iter()
+                    .map(|(e, counts)| (e, counts.iter().sum()))
+                    .collect();
+                totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+                totals.into_iter().take(n).map(|(e, _)| e.clone()).collect()
+            }
+            (None, None) => Vec::new(),
+        };
+        for entity in chosen {
+            let counts = mentions
+                .get(&entity)
+                .cloned()
+                .unwrap_or_else(|| vec![0; ranges.len()]);
+            entity_timelines.push(serde_json::json!({ "entity": entity, "counts": counts }));
+        }
+    }
+    Ok(Json(serde_json::json!({
+        "timeline": timeline,
+        "entities": entity_timelines,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Clusters of documents linked by RELATED edges, or with `method: "embedding"` of
+/// memories whose embeddings are at least `threshold` similar (default 0.8).
+async fn advanced_clusters(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    match body.get("method").and_then(|v| v.as_str()).unwrap_or("kg") {
+        "kg" => {}
+        "embedding" => {
+            let threshold = body
+                .get("threshold")
+                .and_then(|v| v.as_f64())
+                .unwrap_or(0.8);
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                return Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_INPUT",
+                    "threshold must be in (0, 1]",
+                    Some(serde_json::json!({ "threshold": threshold })),
+                ));
+            }
+            let mut out = embedding_clusters(&state.db(), threshold as f32);
+            out["tookMs"] = serde_json::json!(started.elapsed().as_millis());
+            return Ok(Json(out));
+        }
+        other => {
+            return Err(json_error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_INPUT",
+                "method must be kg or embedding",
+                Some(serde_json::json!({ "method": other })),
+            ))
+        }
+    }
+    // Simple clustering: documents linked by RELATED edges -> connected components
+    let edges = state.db().open_tree("kg_edges").map_err(internal_error)?;
+    let mut graph: std::collections::HashMap<String, Vec<String>> =
+        std::collections::HashMap::new();
+    let mut scanned = 0usize;
+    for kv in edges.iter() {
+        if let Ok((k, _)) = kv {
+            scanned += 1;
+            let key = String::from_utf8_lossy(&k);
+            if let Some((src, dst, relation)) = kg::parse_edge_key(&key) {
+                if relation == "RELATED" {
+                    graph.entry(src.clone()).or_default().push(dst.clone());
+                    graph.entry(dst).or_default().push(src);
+                }
+            }
+        }
+    }
+    // Connected components
+    let mut seen = std::collections::HashSet::new();
+    let mut clusters: Vec<Vec<String>> = Vec::new();
+    for node in graph.keys() {
+        if seen.contains(node) {
+            continue;
+        }
+        let mut stack = vec![node.clone()];
+        let mut comp: Vec<String> = Vec::new();
+        while let Some(n) = stack.pop() {
+            if !seen.insert(n.clone()) {
+                continue;
+            }
+            comp.push(n.clone());
+            if let Some(nei) = graph.get(&n) {
+                for m in nei {
+                    if !seen.contains(m) {
+                        stack.push(m.clone());
+                    }
+                }
+            }
+        }
+        if comp.len() > 1 {
+            clusters.push(comp);
+        }
+    }
+    // Normalize to doc ids
+    let out: Vec<serde_json::Value> = clusters
+        .into_iter()
+        .map(|c| {
+            let docs: Vec<String> = c
+                .into_iter()
+                .filter_map(|n| n.strip_prefix("Document::").map(|s| s.to_string()))
+                .collect();
+            serde_json::json!({ "docs": docs })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "clusters": out,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Memory clusters by embedding similarity, each labelled with the entities its members
+/// mention most often.
+fn embedding_clusters(db: &sled::Db, threshold: f32) -> serde_json::Value {
+    let pairs = vector_index::near_duplicate_pairs(db, threshold, 10);
+    let scanned = db.open_tree("mem_embeddings").map(|t| t.len()).unwrap_or(0);
+    let edges = db.open_tree("kg_edges").ok();
+    let clusters: Vec<serde_json::Value> = vector_index::similarity_clusters(&pairs)
+        .into_iter()
+        .map(|members| {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for id in &members {
+                let prefix = format!("{}Entity::", kg::edges_prefix(&format!("Memory::{}", id)));
+                let keys = edges
+                    .iter()
+                    .flat_map(|t| t.scan_prefix(prefix.as_bytes()).keys().flatten());
+                for key in keys {
+                    let Some((_, dst, relation)) =
+                        kg::parse_edge_key(&String::from_utf8_lossy(&key))
+                    else {
+                        continue;
+                    };
+                    if let Some(entity) = dst
+                        .strip_prefix("Entity::")
+                        .filter(|_| relation == "MENTIONS")
+                    {
+                        *counts.entry(entity.to_string()).or_default() += 1;
+                    }
+                }
+            }
+            let mut entities: Vec<(String, usize)> = counts.into_iter().collect();
+            entities.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let top: Vec<String> = entities.into_iter().take(3).map(|(e, _)| e).collect();
+            serde_json::json!({ "memories": members, "label": top.join(", "), "entities": top })
+        })
+        .collect();
+    serde_json::json!({
+        "method": "embedding",
+        "threshold": threshold,
+        "clusters": clusters,
+        "scanned": scanned,
+    })
+}
+
+async fn advanced_relationships(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let window = kg::TimeWindow {
+        from: body.get("from").and_then(|v| v.as_i64()),
+        to: body.get("to").and_then(|v| v.as_i64()),
+    };
+    // Relationship strength per (src_type, relation, dst_type): distinct edges and the
+    // total weight of repeated assertions
+    let edges = state.db().open_tree("kg_edges").map_err(internal_error)?;
+    let mut groups: std::collections::HashMap<String, (u64, u64)> =
+        std::collections::HashMap::new();
+    let mut scanned = 0usize;
+    for kv in edges.iter() {
+        if let Ok((k, v)) = kv {
+            scanned += 1;
+            let key = String::from_utf8_lossy(&k);
+            if let Some((src, dst, rel)) = kg::parse_edge_key(&key) {
+                let src_t = src.split("::").next().unwrap_or("");
+                let dst_t = dst.split("::").next().unwrap_or("");
+                let grp = format!("{}:{}:{}", src_t, rel, dst_t);
+                let edge = serde_json::from_slice::<serde_json::Value>(&v).ok();
+                if !window.is_open() && !edge.as_ref().is_some_and(|e| window.contains(e)) {
+                    continue;
+                }
+                let weight = edge.as_ref().map(kg::edge_weight).unwrap_or(1);
+                let entry = groups.entry(grp).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += weight;
+            }
+        }
+    }
+    let mut items: Vec<(String, (u64, u64))> = groups.into_iter().collect();
+    items.sort_by(|a, b| {
+        b.1 .1
+            .cmp(&a.1 .1)
+            .then_with(|| b.1 .0.cmp(&a.1 .0))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    let out: Vec<serde_json::Value> = items
+        .into_iter()
+        .map(|(k, (count, weight))| {
+            serde_json::json!({ "group": k, "count": count, "weight": weight })
+        })
+        .collect();
+    // Strongest entity pairs from the last /kg/cooccurrence run
+    let cooccurrences = kg::top_cooccurrences(&state.db(), 20).unwrap_or_default();
+    Ok(Json(serde_json::json!({
+        "relationships": out,
+        "cooccurrences": cooccurrences,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Half-life of the recency term in `effectiveness_score` (`EFFECT_HALF_LIFE_MS`, default 30 days).
+fn effect_half_life_ms() -> f64 {
+    std::env::var("EFFECT_HALF_LIFE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30.0 * 24.0 * 3600.0 * 1000.0)
+}
+
+/// Effectiveness heuristic: importance, boosted by access count, decayed by age.
+fn effectiveness_score(rec: &serde_json::Value, now_ms: i64, half_life_ms: f64) -> f64 {
+    let imp = rec
+        .get("importance")
+        .and_then(|x| x.as_f64())
+        .unwrap_or(1.0);
+    let acc = rec
+        .get("access_count")
+        .and_then(|x| x.as_u64())
+        .unwrap_or(0) as f64;
+    let ts = rec
+        .get("created_at")
+        .and_then(|x| x.as_i64())
+        .unwrap_or(now_ms);
+    let age = (now_ms - ts).max(0) as f64;
+    let recency = (-(age / half_life_ms)).exp();
+    imp * (1.0 + acc.log10().max(0.0)) * recency
+}
+
+async fn advanced_effectiveness(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(_body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    // Effectiveness heuristic: combine access_count, importance, recency into a score
+    let mems = state.db().open_tree("memories").map_err(internal_error)?;
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let half_life_ms = effect_half_life_ms();
+    let mut out: Vec<serde_json::Value> = Vec::new();
+    for kv in mems.iter() {
+        if let Ok((_, v)) = kv {
+            if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
+                let id = rec
+                    .get("id")
+                    .and_then(|x| x.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                let score = effectiveness_score(&rec, now_ms, half_life_ms);
+                out.push(serde_json::json!({ "id": id, "score": score }));
+            }
+        }
+    }
+    out.sort_by(|a, b| {
+        b.get("score")
+            .and_then(|x| x.as_f64())
+            .partial_cmp(&a.get("score").and_then(|x| x.as_f64()))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let scanned = out.len();
+    Ok(Json(serde_json::json!({
+        "effectiveness": out,
+        "scanned": scanned,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+/// Store each live memory's `effectiveness_score` on its record as `effectiveness`, so
+/// searches can rank by it (`boostEffectiveness`) without recomputing.
+async fn advanced_recompute_effectiveness(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(_body): Json<serde_json::Value>,
+) -> std::result::Result<Json<serde_json::Value>, Response> {
+    let started = std::time::Instant::now();
+    let mems = state.db().open_tree("memories").map_err(internal_error)?;
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let half_life_ms = effect_half_life_ms();
+    let entries: Vec<(sled::IVec, sled::IVec)> = mems.iter().flatten().collect();
+    let mut scored: Vec<(String, f64)> = Vec::new();
+    for (k, v) in entries {
+        let Ok(mut rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        if is_tombstoned(&rec) {
+            continue;
+        }
+        let score = effectiveness_score(&rec, now_ms, half_life_ms);
+        rec["effectiveness"] = serde_json::json!(score);
+        rec["effectiveness_at"] = serde_json::json!(now_ms);
+        let bytes = serde_json::to_vec(&rec).map_err(internal_error)?;
+        mems.insert(&k, bytes).map_err(internal_error)?;
+        scored.push((String::from_utf8_lossy(&k).to_string(), score));
+    }
+    let best = scored.iter().map(|(_, s)| *s).fold(0.0f64, f64::max);
+    state
+        .db()
+        .open_tree("settings")
+        .and_then(|t| t.insert(b"effectiveness_max", serde_json::to_vec(&best).unwrap()))
+        .map_err(internal_error)?;
+    state.invalidate_query_cache();
+    state.db().flush().ok();
+    scored.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    let updated = scored.len();
+    let top: Vec<serde_json::Value> = scored
+        .into_iter()
+        .take(10)
+        .map(|(id, score)| serde_json::json!({ "id": id, "score": score }))
+        .collect();
+    Ok(Json(serde_json::json!({
+        "updated": updated,
+        "top": top,
+        "tookMs": started.elapsed().as_millis(),
+    })))
+}
+
+async fn shutdown_signal() {
+    let _ = signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State as AxState;
+    use axum::Json;
+    use rand::{distributions::Alphanumeric, Rng};
+    use std::collections::HashMap as Map;
+    use std::sync::Arc;
+
+    fn make_state() -> Arc<AppState> {
+        // No worker drains this queue, so stores index inline
+        make_state_with_jobs(tokio::sync::mpsc::unbounded_channel().0)
+    }
+
+    fn make_state_with_jobs(
+        index_jobs: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> Arc<AppState> {
+        let base = std::env::temp_dir().join(format!("mcp-test-{}", uuid::Uuid::new_v4()));
+        let base_str = base.to_string_lossy().to_string();
+        std::fs::create_dir_all(&base).unwrap();
+        std::env::set_var("DATA_DIR", &base_str);
+        let dirs = ensure_data_dirs(&base_str).unwrap();
+        let db_path = dirs.warm.join("kv");
+        let db = sled::open(db_path).unwrap();
+        Arc::new(AppState {
+            start_time: Instant::now(),
+            live_db: StdRwLock::new(db),
+            text_index: text_index::TextIndex::new(&dirs.index),
+            warm_vectors: Default::default(),
+            index_jobs,
+            index_dir: dirs.index,
+            query_cache: AsyncMutex::new(HashMap::new()),
+            query_cache_gen: Default::default(),
+            metrics: AsyncMutex::new(QueryMetrics::default()),
+            ingest_sema: Arc::new(Semaphore::new(max_concurrent_ingest() as usize)),
+            buf_pool: StdMutex::new(ByteBufPool::default()),
+            sse_sessions: StdMutex::new(HashMap::new()),
+            notifications: tokio::sync::broadcast::channel(16).0,
+            rate_buckets: StdMutex::new(HashMap::new()),
+            http_metrics: StdMutex::new(std::collections::BTreeMap::new()),
+            http_errors: StdMutex::new(ErrorWindow::default()),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_document_store_and_retrieve_by_path() {
+        let state = make_state();
+        let req = StoreDocRequest {
+            path: Some("docs/doc1.md".to_string()),
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Title\nHello world".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Verify latest id by path exists
+        let latest = state.db().open_tree("doc_path_latest").unwrap();
+        let id = String::from_utf8(latest.get("docs/doc1.md").unwrap().unwrap().to_vec()).unwrap();
+        // Verify chunks present
+        let chunks = state.db().open_tree("chunks").unwrap();
+        let prefix = format!("{}:", id);
+        let mut count = 0usize;
+        for kv in chunks.scan_prefix(prefix.as_bytes()) {
+            if kv.is_ok() {
+                count += 1;
+            }
+        }
+        assert!(count >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_document_list_shows_latest_versions() {
+        let state = make_state();
+        for (path, content) in [
+            ("docs/a.md", "# A\nfirst"),
+            ("docs/b.md", "# B\nonly"),
+            ("docs/a.md", "# A\nsecond"),
+        ] {
+            let req = StoreDocRequest {
+                path: Some(path.to_string()),
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: false,
+                dry_run: false,
+            };
+            let resp = document_store(AxState(state.clone()), Json(req)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        let resp = document_list(AxState(state.clone()), axum::extract::Query(Map::new())).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["total"], 2);
+        let docs = body["documents"].as_array().unwrap();
+        let find = |p: &str| docs.iter().find(|d| d["path"] == p).unwrap().clone();
+        assert_eq!(find("docs/a.md")["version"], 2);
+        assert_eq!(find("docs/b.md")["version"], 1);
+        assert!(find("docs/a.md")["chunks"].as_u64().unwrap() >= 1);
+        // Newest first
+        assert_eq!(docs[0]["path"], "docs/a.md");
+    }
+
+    #[tokio::test]
+    async fn test_document_rollback_and_diff() {
+        let state = make_state();
+        for content in ["# A\nfirst line\nshared", "# A\nsecond line\nshared"] {
+            let req = StoreDocRequest {
+                path: Some("docs/r.md".to_string()),
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: false,
+                dry_run: false,
+            };
+            let resp = document_store(AxState(state.clone()), Json(req)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let versions = state.db().open_tree("doc_versions").unwrap();
+        let v1 = String::from_utf8(versions.get("docs/r.md:1").unwrap().unwrap().to_vec()).unwrap();
+        let rb = RollbackDocRequest {
+            path: "docs/r.md".to_string(),
+            version: 1,
+        };
+        let resp = document_rollback(AxState(state.clone()), Json(rb)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut q = Map::new();
+        q.insert("path".to_string(), "docs/r.md".to_string());
+        let resp = document_retrieve(axum::extract::Query(q.clone()), AxState(state.clone())).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["id"], v1.as_str());
+        // History is intact and the listing marks v1 as latest
+        let resp = document_versions(AxState(state.clone()), axum::extract::Query(q.clone())).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let listed = body["versions"].as_array().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0]["latest"], true);
+        q.insert("from".to_string(), "1".to_string());
+        q.insert("to".to_string(), "2".to_string());
+        let resp = document_diff(AxState(state.clone()), axum::extract::Query(q)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let lines: Vec<&str> = body["lines"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l.as_str().unwrap())
+            .collect();
+        assert!(lines.contains(&"- first line"));
+        assert!(lines.contains(&"+ second line"));
+        assert!(lines.contains(&"  shared"));
+    }
+
+    #[test]
+    fn test_diff_lines_trims_shared_lines_and_caps_table() {
+        let from = "a\nb\nc\nd";
+        let to = "a\nx\nc\nd";
+        assert_eq!(
+            diff_lines(from, to).unwrap(),
+            vec!["  a", "- b", "+ x", "  c", "  d"]
+        );
+        let big_a: String = (0..3000).map(|i| format!("a{}\n", i)).collect();
+        let big_b: String = (0..3000).map(|i| format!("b{}\n", i)).collect();
+        assert!(diff_lines(&big_a, &big_b).is_none());
+        // Large documents with a small edit still diff
+        let edited = big_a.replacen("a1500\n", "changed\n", 1);
+        let lines = diff_lines(&big_a, &edited).unwrap();
+        assert_eq!(lines.len(), 3001);
+        assert!(lines.contains(&"+ changed".to_string()));
+    }
+
+    #[test]
+    fn test_chunk_keys_scan_in_numeric_order() {
+        let state = make_state();
+        let chunks = state.db().open_tree("chunks").unwrap();
+        let starts = [0usize, 200, 1000, 2000];
+        // Legacy plain-decimal keys scan as 0, 1000, 200, 2000
+        for start in [2000usize, 200, 1000, 0] {
+            let ch = ChunkHeader {
+                id: Uuid::new_v4().to_string(),
+                position: Position {
+                    start,
+                    end: start + 10,
+                },
+                page: None,
+            };
+            chunks
+                .insert(
+                    format!("doc1:{}", start).as_bytes(),
+                    serde_json::to_vec(&ch).unwrap(),
+                )
+                .unwrap();
+        }
+        assert_eq!(migrate_chunk_keys(&state.db()).unwrap(), 4);
+        assert_eq!(migrate_chunk_keys(&state.db()).unwrap(), 0);
+        let scanned: Vec<usize> = chunks
+            .scan_prefix(b"doc1:")
+            .flatten()
+            .map(|(_, v)| {
+                serde_json::from_slice::<ChunkHeader>(&v)
+                    .unwrap()
+                    .position
+                    .start
+            })
+            .collect();
+        assert_eq!(scanned, starts);
+        let keys: Vec<String> = starts.iter().map(|s| chunk_key("doc1", *s)).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert_eq!(
+            canonical_chunk_key("doc1:200").as_deref(),
+            Some(keys[1].as_str())
+        );
+        assert_eq!(canonical_chunk_key("mem:abc"), None);
+    }
+
+    #[test]
+    fn test_delete_entity_removes_forward_and_reverse_edges() {
+        let state = make_state();
+        let db = &state.db();
+        for e in ["Alice", "Bob"] {
+            kg::ensure_entity_node(db, e, 1).unwrap();
+        }
+        kg::add_edge_generic(db, "Entity::Alice", "Entity::Bob", "KNOWS", 1).unwrap();
+        kg::add_edge_generic(db, "Memory::m1", "Entity::Alice", "MENTIONS", 1).unwrap();
+        kg::add_edge_generic(db, "Memory::m1", "Entity::Bob", "MENTIONS", 1).unwrap();
+        let incoming = kg::incoming_edges(db, "Entity::Alice").unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].1["src"], "Memory::m1");
+
+        assert_eq!(kg::delete_entity(db, "Alice").unwrap(), 3);
+        let edges = db.open_tree("kg_edges").unwrap();
+        let rev = db.open_tree("kg_edges_rev").unwrap();
+        let mentions_alice = |k: &[u8]| String::from_utf8_lossy(k).contains("Entity::Alice");
+        assert!(!edges.iter().flatten().any(|(k, _)| mentions_alice(&k)));
+        assert!(!rev.iter().flatten().any(|(k, _)| mentions_alice(&k)));
+        // The unrelated edge survives in both trees
+        assert_eq!(edges.len(), 1);
+        assert_eq!(rev.len(), 1);
+        assert_eq!(kg::incoming_edges(db, "Entity::Bob").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_kg_shortest_path_and_neighborhood() {
+        let state = make_state();
+        let db = &state.db();
+        // Entity::A -> Document::d1 <- Entity::B -> Document::d2 <- Entity::C
+        kg::add_edge(db, "A", "d1", "MENTIONS", 1).unwrap();
+        kg::add_edge(db, "B", "d1", "MENTIONS", 1).unwrap();
+        kg::add_edge(db, "B", "d2", "MENTIONS", 1).unwrap();
+        kg::add_edge(db, "C", "d2", "MENTIONS", 1).unwrap();
+        let path = kg::shortest_path(db, "Entity::A", "Entity::C", 4)
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.len(), 4);
+        assert_eq!(path[0]["src"], "Entity::A");
+        assert_eq!(path[3]["src"], "Entity::C");
+        assert!(path.iter().all(|e| e["relation"] == "MENTIONS"));
+        let short = kg::shortest_path(db, "Entity::A", "Entity::B", 3)
+            .unwrap()
+            .unwrap();
+        assert_eq!(short.len(), 2);
+        // Depth cap
+        assert!(kg::shortest_path(db, "Entity::A", "Entity::C", 3)
+            .unwrap()
+            .is_none());
+        let (nodes, edges) = kg::neighborhood(db, "Entity::A", 2).unwrap();
+        let keys: Vec<&str> = nodes.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(keys, ["Entity::A", "Document::d1", "Entity::B"]);
+        assert_eq!(edges.len(), 2);
+    }
+
+    #[test]
+    fn test_relinking_a_document_counts_the_entity_once() {
+        let state = make_state();
+        let db = state.db();
+        let ents = ["Rust".to_string(), "Tokio".to_string()];
+        kg::link_entities(&db, "d1", &ents).unwrap();
+        kg::link_entities(&db, "d1", &ents).unwrap();
+        kg::link_entities(&db, "d1", &ents[..1]).unwrap();
+        kg::link_entities(&db, "d2", &ents[..1]).unwrap();
+        let counts: std::collections::HashMap<String, u64> =
+            kg::list_entities(&db, 10).unwrap().into_iter().collect();
+        assert_eq!(counts["Rust"], 2);
+        assert_eq!(counts["Tokio"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_warm_vectors_match_the_sled_scan() {
+        let state = make_state();
+        let vector = |i: usize| {
+            let mut v = [0.0f32; embeddings::EMBED_DIM];
+            v[i % 4] = 1.0;
+            v[4] = i as f32 * 0.1;
+            v
+        };
+        let add = |i: usize| {
+            let req = AddMemoryRequest {
+                content: format!("vector note {}", i),
+                layer_hint: Some(if i.is_multiple_of(2) { "LTM" } else { "STM" }.to_string()),
+                ..Default::default()
+            };
+            let (id, _) = write_memory(&state.db(), &state.text_index, req, 1).unwrap();
+            store_memory_embedding(&state, &id, vector(i)).unwrap();
+            id
+        };
+        for i in 0..8 {
+            add(i);
+        }
+        let query = vector(2);
+        let filters = [
+            vector_index::VectorFilter::default(),
+            vector_index::VectorFilter {
+                layer: Some("LTM".to_string()),
+                ..Default::default()
+            },
+        ];
+        let sled_hits = |filter: &vector_index::VectorFilter| {
+            vector_index::search_memories_by_vector(&state.db(), &query, 5, Some(filter))
+        };
+        let cold: Vec<_> = filters.iter().map(sled_hits).collect();
+        assert!(!state.warm_vectors.is_warm());
+        assert_eq!(state.warm_vectors.load(&state.db()).unwrap(), 8);
+        for (filter, expected) in filters.iter().zip(&cold) {
+            assert_eq!(&state.memory_vector_hits(&query, 5, filter, true), expected);
+        }
+
+        // Writes after warm-up reach the copy
+        let added = add(10);
+        let warm = state.memory_vector_hits(&query, 5, &filters[0], true);
+        assert_eq!(warm, sled_hits(&filters[0]));
+        assert!(warm.iter().any(|(id, _)| *id == added));
+        assert!(purge_memory(&state, &added).unwrap());
+        let warm = state.memory_vector_hits(&query, 5, &filters[0], true);
+        assert_eq!(warm, sled_hits(&filters[0]));
+        assert!(warm.iter().all(|(id, _)| *id != added));
+    }
+
+    #[tokio::test]
+    async fn test_unified_search_returns_memories_and_documents() {
+        let state = make_state();
+        let req = StoreDocRequest {
+            path: None,
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Runbook\nRestart the zeppelin service nightly".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: true,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = AddMemoryRequest {
+            content: "the zeppelin service crashed again".to_string(),
+            ..Default::default()
+        };
+        write_memory(&state.db(), &state.text_index, req, 1).unwrap();
+        let search = |types: &str| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "zeppelin".to_string());
+            q.insert("types".to_string(), types.to_string());
+            unified_search(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let kinds = |resp: &SearchResponse| {
+            let mut kinds: Vec<&str> = resp.results.iter().filter_map(|r| r.kind).collect();
+            kinds.sort();
+            kinds.dedup();
+            kinds
+        };
+        let Json(both) = search("").await.unwrap();
+        assert_eq!(kinds(&both), ["document", "memory"]);
+        let Json(docs) = search("document").await.unwrap();
+        assert_eq!(kinds(&docs), ["document"]);
+        let Json(mems) = search("memory").await.unwrap();
+        assert_eq!(kinds(&mems), ["memory"]);
+        let bad = search("memory,images").await.err().unwrap();
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_strengthens_only_returned_memories() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for i in 0..10 {
+            let req = AddMemoryRequest {
+                content: format!("quarterly roadmap item {}", i),
+                ..Default::default()
+            };
+            ids.push(
+                write_memory(&state.db(), &state.text_index, req, 1)
+                    .unwrap()
+                    .0,
+            );
+        }
+        let mut q = Map::new();
+        q.insert("q".to_string(), "roadmap".to_string());
+        q.insert("limit".to_string(), "3".to_string());
+        let Json(resp) = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        assert_eq!(resp.results.len(), 3);
+        let returned: std::collections::HashSet<&str> =
+            resp.results.iter().map(|r| r.id.as_str()).collect();
+        let mems = state.db().open_tree("memories").unwrap();
+        for id in &ids {
+            let rec: serde_json::Value =
+                serde_json::from_slice(&mems.get(id.as_bytes()).unwrap().unwrap()).unwrap();
+            let count = rec
+                .get("access_count")
+                .and_then(|c| c.as_u64())
+                .unwrap_or(0);
+            assert_eq!(count, u64::from(returned.contains(id.as_str())), "{id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recomputed_effectiveness_is_stored_and_boosts_search() {
+        let state = make_state();
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut ids = Vec::new();
+        for (content, importance) in [("alpha release notes", 0.2), ("alpha release plan", 5.0)] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                importance: Some(importance),
+                ..Default::default()
+            };
+            let (id, _) = write_memory(&state.db(), &state.text_index, req, now_ms).unwrap();
+            ids.push(id);
+        }
+        let Json(out) =
+            advanced_recompute_effectiveness(AxState(state.clone()), Json(serde_json::json!({})))
+                .await
+                .unwrap();
+        assert_eq!(out["updated"], 2);
+        assert_eq!(out["top"][0]["id"], ids[1].as_str());
+        let stored: serde_json::Value = serde_json::from_slice(
+            &state
+                .db()
+                .open_tree("memories")
+                .unwrap()
+                .get(ids[1].as_bytes())
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(stored["effectiveness"], out["top"][0]["score"]);
+
+        let search = |boost: &str| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "alpha".to_string());
+            q.insert("boostEffectiveness".to_string(), boost.to_string());
+            memory_search(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let Json(boosted) = search("10").await.unwrap();
+        assert_eq!(boosted.results[0].id, ids[1]);
+        let gap = boosted.results[0].score - boosted.results[1].score;
+        assert!(gap > 9.0, "gap {gap}");
+    }
+
+    #[tokio::test]
+    async fn test_effectiveness_boost_is_stable_across_pages() {
+        let state = make_state();
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut important = String::new();
+        for i in 0..8 {
+            // The important memory is also the weakest text match
+            let (content, importance) = if i == 7 {
+                (
+                    "kestrel kestrel with a much longer body that dilutes the term".to_string(),
+                    5.0,
+                )
+            } else {
+                (format!("kestrel kestrel {}", i), 0.2)
+            };
+            let req = AddMemoryRequest {
+                content,
+                importance: Some(importance),
+                ..Default::default()
+            };
+            let (id, _) = write_memory(&state.db(), &state.text_index, req, now_ms).unwrap();
+            if i == 7 {
+                important = id;
+            }
+        }
+        // The global maximum comes from a memory no query here matches
+        let req = AddMemoryRequest {
+            content: "unrelated heron".to_string(),
+            importance: Some(10.0),
+            ..Default::default()
+        };
+        write_memory(&state.db(), &state.text_index, req, now_ms).unwrap();
+        let Json(out) =
+            advanced_recompute_effectiveness(AxState(state.clone()), Json(serde_json::json!({})))
+                .await
+                .unwrap();
+        assert_eq!(out["updated"], 9);
+        assert!(effectiveness_max(&state.db()) > 4.0);
+        let run = |limit: usize, offset: usize| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "kestrel".to_string());
+            q.insert("boostEffectiveness".to_string(), "10".to_string());
+            q.insert("limit".to_string(), limit.to_string());
+            q.insert("offset".to_string(), offset.to_string());
+            search_fusion(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let Json(all) = run(8, 0).await.unwrap();
+        assert_eq!(all.results.len(), 8);
+        assert_eq!(all.results[0].id, important);
+        let contribution = all.results[0].explain.as_ref().unwrap()["effectiveness"]
+            .as_f64()
+            .unwrap();
+        assert!((contribution - 5.0).abs() < 0.1, "{contribution}");
+        for (offset, expected) in all.results.iter().enumerate() {
+            let Json(page) = run(1, offset).await.unwrap();
+            assert_eq!(page.results[0].id, expected.id);
+            assert_eq!(page.results[0].score, expected.score);
+        }
+    }
+
+    #[test]
+    fn test_entity_extraction_with_a_custom_pattern_or_tokens() {
+        let default = kg::EntityExtractor::parse(None, None, None).unwrap();
+        assert_eq!(
+            default.extract("Alice met bob in Paris"),
+            ["Alice", "Paris"]
+        );
+        // Lowercase tags; the capture group is the entity
+        let tags = kg::EntityExtractor::parse(None, Some(r"#(\p{Ll}+)"), None).unwrap();
+        assert_eq!(
+            tags.extract("notes on #tokio and #москва, again #tokio"),
+            ["tokio", "москва"]
+        );
+        let tokens = kg::EntityExtractor::parse(Some("tokens"), None, Some(5)).unwrap();
+        assert_eq!(
+            tokens.extract("встреча команды в москве 20240101"),
+            ["встреча", "команды", "москве"]
+        );
+        assert!(kg::EntityExtractor::parse(None, Some("(unclosed"), None).is_err());
+        assert!(kg::EntityExtractor::parse(Some("nouns"), None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kg_stats_breaks_down_a_small_graph() {
+        let state = make_state();
+        let db = state.db();
+        for e in ["Rust", "Tokio"] {
+            kg::ensure_entity_node(&db, e, 1).unwrap();
+        }
+        kg::ensure_document_node(&db, "d1", 1).unwrap();
+        kg::ensure_memory_node(&db, "m1", 1).unwrap();
+        kg::ensure_memory_node(&db, "m2", 1).unwrap();
+        kg::ensure_episode_node(&db, "e1", 1, None, None).unwrap();
+        for (src, dst, rel) in [
+            ("Entity::Rust", "Document::d1", "MENTIONS"),
+            ("Entity::Tokio", "Document::d1", "MENTIONS"),
+            ("Memory::m1", "Document::d1", "EVIDENCE"),
+            ("Memory::m1", "Episode::e1", "IN_EPISODE"),
+            ("Memory::m2", "Episode::e1", "IN_EPISODE"),
+        ] {
+            kg::add_edge_generic(&db, src, dst, rel, 1).unwrap();
+        }
+        let mut q = Map::new();
+        q.insert("top".to_string(), "2".to_string());
+        let Json(body) = kg_stats(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        assert_eq!(body["nodes"]["total"], 6);
+        assert_eq!(
+            body["nodes"]["byType"],
+            serde_json::json!({ "Entity": 2, "Document": 1, "Memory": 2, "Episode": 1 })
+        );
+        assert_eq!(body["edges"]["total"], 5);
+        assert_eq!(
+            body["edges"]["byRelation"],
+            serde_json::json!({ "MENTIONS": 2, "EVIDENCE": 1, "IN_EPISODE": 2 })
+        );
+        assert_eq!(
+            body["topNodes"],
+            serde_json::json!([
+                { "node": "Document::d1", "type": "Document", "degree": 3 },
+                { "node": "Episode::e1", "type": "Episode", "degree": 2 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_kg_repair_removes_dangling_edges_and_stale_links() {
+        let state = make_state();
+        let req = StoreDocRequest {
+            path: None,
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Notes\nRust and Tokio".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: true,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let db = state.db();
+        // A document that no longer exists, and an edge to a node that was never created
+        kg::link_entities(&db, "gone", &["Rust".to_string()]).unwrap();
+        kg::ensure_entity_node(&db, "Rust", 1).unwrap();
+        kg::add_edge_generic(&db, "Entity::Rust", "Entity::Ghost", "RELATED", 1).unwrap();
+        let validate = || async {
+            let Json(out) = system_validate(AxState(state.clone())).await;
+            out["kg"]["badEdges"].clone()
+        };
+        assert_eq!(validate().await, 1);
+
+        let repair = |fix: bool| {
+            kg_repair(
+                AxState(state.clone()),
+                Json(ValidateRefsBody { fix: Some(fix) }),
+            )
+        };
+        let Json(dry) = repair(false).await.unwrap();
+        assert_eq!(dry["danglingEdges"], 1);
+        assert_eq!(dry["staleLinks"], 1);
+        assert_eq!(dry["recountedEntities"], 1);
+        assert_eq!(validate().await, 1);
+
+        let Json(fixed) = repair(true).await.unwrap();
+        assert_eq!(fixed["danglingEdges"], 1);
+        assert_eq!(validate().await, 0);
+        let counts: std::collections::HashMap<String, u64> =
+            kg::list_entities(&db, 10).unwrap().into_iter().collect();
+        assert_eq!(counts["Rust"], 1);
+        assert_eq!(kg::docs_for_entity(&db, "Rust").unwrap().len(), 1);
+        let Json(again) = repair(true).await.unwrap();
+        assert_eq!(again["danglingEdges"], 0);
+        assert_eq!(again["staleLinks"], 0);
+        assert_eq!(again["recountedEntities"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_kg_subgraph_depth_and_limit() {
+        let state = make_state();
+        let db = state.db();
+        // Entity::A -> Document::d1 <- Entity::B -> Document::d2, plus Memory::m1 -> Entity::A
+        kg::add_edge(&db, "A", "d1", "MENTIONS", 1).unwrap();
+        kg::add_edge(&db, "B", "d1", "MENTIONS", 1).unwrap();
+        kg::add_edge(&db, "B", "d2", "MENTIONS", 1).unwrap();
+        kg::add_edge_generic(&db, "Memory::m1", "Entity::A", "MENTIONS", 1).unwrap();
+        let sub = |depth: &str, limit: &str| {
+            let mut q = Map::new();
+            q.insert("node".to_string(), "Entity::A".to_string());
+            q.insert("depth".to_string(), depth.to_string());
+            q.insert("limit".to_string(), limit.to_string());
+            kg_subgraph(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let body = |resp: Response| async move {
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+        let out = body(sub("1", "100").await).await;
+        let nodes: Vec<&str> = out["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n["node"].as_str().unwrap())
+            .collect();
+        // Both directions, but nothing beyond the immediate neighbors
+        assert_eq!(nodes, ["Entity::A", "Document::d1", "Memory::m1"]);
+        assert_eq!(out["nodes"][1]["type"], "Document");
+        assert_eq!(out["nodes"][1]["depth"], 1);
+        assert_eq!(out["edges"].as_array().unwrap().len(), 2);
+        assert_eq!(out["truncated"], false);
+
+        let deep = body(sub("3", "100").await).await;
+        assert_eq!(deep["nodes"].as_array().unwrap().len(), 5);
+        let capped = body(sub("3", "2").await).await;
+        assert_eq!(capped["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(capped["edges"].as_array().unwrap().len(), 1);
+        assert_eq!(capped["truncated"], true);
+
+        let resp = kg_subgraph(AxState(state.clone()), axum::extract::Query(Map::new())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_kg_cooccurrence_counts_shared_documents() {
+        let state = make_state();
+        for content in ["# Notes\nAlice met Bob", "# Later\nBob and Alice again"] {
+            let req = StoreDocRequest {
+                path: None,
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: false,
+                dry_run: false,
+            };
+            let resp = document_store(AxState(state.clone()), Json(req)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let edges = state.db().open_tree("kg_edges").unwrap();
+        let key = b"Entity::Alice->Entity::Bob::CO_OCCURS";
+        // Re-running recomputes instead of accumulating
+        for _ in 0..2 {
+            let body = serde_json::json!({ "minCount": 2 });
+            let resp = kg_cooccurrence(AxState(state.clone()), Json(body)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let edge: serde_json::Value =
+                serde_json::from_slice(&edges.get(key).unwrap().unwrap()).unwrap();
+            assert_eq!(edge["count"], 2);
+        }
+        // Pairs seen in only one document fall under minCount
+        assert!(edges
+            .get(b"Entity::Alice->Entity::Notes::CO_OCCURS")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_kg_pagerank_ranks_star_hub_highest() {
+        let state = make_state();
+        let db = &state.db();
+        for leaf in ["a", "b", "c", "d", "e"] {
+            kg::add_edge_generic(
+                db,
+                "Entity::Hub",
+                &format!("Entity::{}", leaf),
+                "RELATED",
+                1,
+            )
+            .unwrap();
+        }
+        // A spoke-to-spoke edge must not unseat the hub
+        kg::add_edge_generic(db, "Entity::a", "Entity::b", "RELATED", 1).unwrap();
+        let ranked = kg::pagerank(db, 30, 0.85).unwrap();
+        assert_eq!(ranked.len(), 6);
+        assert_eq!(ranked[0].0, "Entity::Hub");
+        assert!(ranked[0].1 > ranked[1].1);
+        let total: f64 = ranked.iter().map(|(_, r)| r).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_kg_export_dot_and_graphml() {
+        let state = make_state();
+        let req = StoreDocRequest {
+            path: Some("docs/kg.md".to_string()),
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Notes\nAlice met Bob".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut q = Map::new();
+        q.insert("format".to_string(), "dot".to_string());
+        let resp = kg_export(AxState(state.clone()), axum::extract::Query(q.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let dot = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(dot.starts_with("digraph kg {"));
+        // Three entities plus the one document
+        assert_eq!(
+            dot.matches("[label=").count() - dot.matches(" -> ").count(),
+            4
+        );
+        assert_eq!(dot.matches("label=\"MENTIONS\"").count(), 3);
+        q.insert("format".to_string(), "graphml".to_string());
+        let resp = kg_export(AxState(state.clone()), axum::extract::Query(q.clone())).await;
+        assert_eq!(resp.headers()["content-type"], "application/xml");
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let xml = String::from_utf8(bytes.to_vec()).unwrap();
+        assert_eq!(xml.matches("<node ").count(), 4);
+        assert_eq!(xml.matches("<edge ").count(), 3);
+        q.insert("format".to_string(), "csv".to_string());
+        let resp = kg_export(AxState(state.clone()), axum::extract::Query(q)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_memory_relate_and_related() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in [
+            "the sky is blue",
+            "rayleigh scattering favours short wavelengths",
+        ] {
+            let add = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(add)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        let rel = RelateMemoryRequest {
+            src: ids[1].clone(),
+            dst: ids[0].clone(),
+            relation: "supports".to_string(),
+        };
+        let resp = memory_relate(AxState(state.clone()), Json(rel)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let missing = RelateMemoryRequest {
+            src: ids[0].clone(),
+            dst: "no-such-memory".to_string(),
+            relation: "SUPPORTS".to_string(),
+        };
+        let resp = memory_relate(AxState(state.clone()), Json(missing)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        for (id, other, direction) in [(&ids[1], &ids[0], "out"), (&ids[0], &ids[1], "in")] {
+            let mut q = Map::new();
+            q.insert("id".to_string(), id.clone());
+            q.insert("relation".to_string(), "SUPPORTS".to_string());
+            let resp = memory_related(AxState(state.clone()), axum::extract::Query(q)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            let related = body["related"].as_array().unwrap();
+            assert_eq!(related.len(), 1);
+            assert_eq!(related[0]["id"], other.as_str());
+            assert_eq!(related[0]["relation"], "SUPPORTS");
+            assert_eq!(related[0]["direction"], direction);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_add_importance_and_ttl() {
+        let state = make_state();
+        let add = |content: &str, importance: Option<f64>, ttl_ms: Option<i64>| {
+            let state = state.clone();
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("STM".to_string()),
+                importance,
+                ttl_ms,
+                ..Default::default()
+            };
+            async move {
+                let resp = memory_add(AxState(state), Json(req)).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                body["id"].as_str().unwrap().to_string()
+            }
+        };
+        let important = add("critical decision", Some(5.0), None).await;
+        let short_lived = add("fleeting thought", None, Some(1)).await;
+        let forever = add("keep this around", None, Some(0)).await;
+        let plain = add("ordinary note", None, None).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        run_maintenance(&state).unwrap();
+        let mems = state.db().open_tree("memories").unwrap();
+        let rec = |id: &str| -> Option<serde_json::Value> {
+            mems.get(id.as_bytes())
+                .unwrap()
+                .map(|v| serde_json::from_slice(&v).unwrap())
+        };
+        let promoted = rec(&important).unwrap();
+        assert_eq!(promoted["layer"], "LTM");
+        assert_eq!(promoted["importance"], 5.0);
+        assert!(rec(&short_lived).is_none());
+        let kept = rec(&forever).unwrap();
+        assert_eq!(kept["layer"], "STM");
+        assert!(kept["expires_at"].is_null());
+        assert_eq!(rec(&plain).unwrap()["layer"], "STM");
+        let bad = AddMemoryRequest {
+            content: "x".to_string(),
+            importance: Some(-1.0),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(bad)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_memory_survives_maintenance() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for pinned in [Some(true), None, Some(false)] {
+            let req = AddMemoryRequest {
+                content: "short lived scratch note".to_string(),
+                layer_hint: Some("STM".to_string()),
+                ttl_ms: Some(1),
+                pinned,
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        // Pin the third one after the fact
+        let pin = PinMemoryRequest {
+            id: ids[2].clone(),
+            pinned: true,
+        };
+        let resp = memory_pin(AxState(state.clone()), Json(pin)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        run_maintenance(&state).unwrap();
+        let mems = state.db().open_tree("memories").unwrap();
+        assert!(mems.get(ids[0].as_bytes()).unwrap().is_some());
+        assert!(mems.get(ids[1].as_bytes()).unwrap().is_none());
+        assert!(mems.get(ids[2].as_bytes()).unwrap().is_some());
+        // Still searchable
+        let mut q = Map::new();
+        q.insert("q".to_string(), "scratch".to_string());
+        let out = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        for id in [&ids[0], &ids[2]] {
+            assert!(out.results.iter().any(|r| &r.id == id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_hides_from_search_and_undelete_restores() {
+        let state = make_state();
+        let req = AddMemoryRequest {
+            content: "tombstone candidate zephyr".to_string(),
+            layer_hint: Some("LTM".to_string()),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let id = body["id"].as_str().unwrap().to_string();
+        let search = |state: Arc<AppState>| async move {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "zephyr".to_string());
+            memory_search(AxState(state), axum::extract::Query(q))
+                .await
+                .unwrap()
+        };
+        assert!(search(state.clone())
+            .await
+            .results
+            .iter()
+            .any(|r| r.id == id));
+
+        let del = DeleteMemoryRequest {
+            id: id.clone(),
+            backup: None,
+            soft: Some(true),
+        };
+        let resp = memory_delete(AxState(state.clone()), Json(del)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!search(state.clone())
+            .await
+            .results
+            .iter()
+            .any(|r| r.id == id));
+        // Record is kept until the undelete window passes
+        let mems = state.db().open_tree("memories").unwrap();
+        assert!(mems.get(id.as_bytes()).unwrap().is_some());
+        run_maintenance(&state).unwrap();
+        assert!(mems.get(id.as_bytes()).unwrap().is_some());
+
+        let undel = UndeleteMemoryRequest { id: id.clone() };
+        let resp = memory_undelete(AxState(state.clone()), Json(undel)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(search(state.clone())
+            .await
+            .results
+            .iter()
+            .any(|r| r.id == id));
+        // Undeleting a live memory is rejected
+        let undel = UndeleteMemoryRequest { id: id.clone() };
+        let resp = memory_undelete(AxState(state.clone()), Json(undel)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_memory_search_filters_by_metadata() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for meta in [
+            serde_json::json!({"project": "x", "owner": {"team": "core"}, "priority": 2}),
+            serde_json::json!({"project": "y", "owner": {"team": "infra"}, "done": true}),
+        ] {
+            let req = AddMemoryRequest {
+                content: "quarterly planning notes".to_string(),
+                metadata: Some(meta),
+                layer_hint: Some("LTM".to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        let search = |filters: &[(&str, &str)]| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "planning".to_string());
+            for (k, v) in filters {
+                q.insert(k.to_string(), v.to_string());
+            }
+            memory_search(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let ids_of = |out: std::result::Result<Json<SearchResponse>, Response>| -> Vec<String> {
+            out.unwrap().results.iter().map(|r| r.id.clone()).collect()
+        };
+        assert_eq!(ids_of(search(&[]).await).len(), 2);
+        assert_eq!(
+            ids_of(search(&[("meta.project", "x")]).await),
+            vec![ids[0].clone()]
+        );
+        assert_eq!(
+            ids_of(search(&[("meta.owner.team", "infra")]).await),
+            vec![ids[1].clone()]
+        );
+        assert_eq!(
+            ids_of(search(&[("meta.priority", "2.0")]).await),
+            vec![ids[0].clone()]
+        );
+        assert_eq!(
+            ids_of(search(&[("meta.done", "true")]).await),
+            vec![ids[1].clone()]
+        );
+        assert!(ids_of(search(&[("meta.project", "x"), ("meta.done", "true")]).await).is_empty());
+        assert!(ids_of(search(&[("meta.project", "z")]).await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_dedupe_reports_and_merges_identical_content() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for (content, importance) in [
+            ("the deploy window is friday afternoon", 1.0),
+            ("the deploy window is friday afternoon", 2.0),
+            ("lunch order: two pizzas and a salad", 1.0),
+        ] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("LTM".to_string()),
+                importance: Some(importance),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        // The stub embedder returns zero vectors; give the memories real ones
+        for (i, id) in ids.iter().enumerate() {
+            let mut v = [0.0f32; embeddings::EMBED_DIM];
+            v[if i < 2 { 0 } else { 1 }] = 1.0;
+            v[2] = 0.1;
+            store_memory_embedding(&state, id, v).unwrap();
+        }
+        kg::add_edge_generic(
+            &state.db(),
+            &format!("Memory::{}", ids[0]),
+            &format!("Memory::{}", ids[2]),
+            "SUPPORTS",
+            1,
+        )
+        .unwrap();
+        let dedupe = |dry: bool| {
+            let req = DedupeRequest {
+                threshold: Some(0.99),
+                dry_run: Some(dry),
+            };
+            memory_dedupe(AxState(state.clone()), Json(req))
+        };
+        let resp = dedupe(true).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let clusters = body["clusters"].as_array().unwrap();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0]["keep"], serde_json::json!(ids[1]));
+        assert_eq!(clusters[0]["duplicates"], serde_json::json!([ids[0]]));
+        assert!(body["actions"].as_array().unwrap().is_empty());
+        let mems = state.db().open_tree("memories").unwrap();
+        assert!(mems.get(ids[0].as_bytes()).unwrap().is_some());
+
+        let resp = dedupe(false).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(mems.get(ids[0].as_bytes()).unwrap().is_none());
+        assert!(mems.get(ids[2].as_bytes()).unwrap().is_some());
+        // The duplicate's edge now hangs off the keeper
+        let edges = state.db().open_tree("kg_edges").unwrap();
+        let moved = format!("Memory::{}->Memory::{}::SUPPORTS", ids[1], ids[2]);
+        assert!(edges.get(moved.as_bytes()).unwrap().is_some());
+        assert!(
+            kg::incoming_edges(&state.db(), &format!("Memory::{}", ids[0]))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_episode_timeline_is_chronological() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for (content, episode) in [
+            ("step one: open the ticket", "ep-42"),
+            ("unrelated note", "ep-7"),
+            ("step two: reproduce the bug", "ep-42"),
+            ("step three: ship the fix", "ep-42"),
+        ] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("STM".to_string()),
+                session_id: Some("sess-1".to_string()),
+                episode_id: Some(episode.to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            if episode == "ep-42" {
+                ids.push(body["id"].as_str().unwrap().to_string());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        let mut q = Map::new();
+        q.insert("id".to_string(), "ep-42".to_string());
+        let resp = memory_episode(AxState(state.clone()), axum::extract::Query(q)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["episode"]["session_id"], "sess-1");
+        let got: Vec<&str> = body["memories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(got, ids);
+        let mut q = Map::new();
+        q.insert("id".to_string(), "missing".to_string());
+        let resp = memory_episode(AxState(state.clone()), axum::extract::Query(q)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_memory_context_respects_char_budget() {
+        let state = make_state();
+        let mut important = String::new();
+        for (i, session) in ["s1", "s1", "s2", "s1", "s1"].iter().enumerate() {
+            let req = AddMemoryRequest {
+                content: format!("context note {} {}", i, "x".repeat(30)),
+                layer_hint: Some("LTM".to_string()),
+                session_id: Some(session.to_string()),
+                importance: Some(if i == 3 { 5.0 } else { 1.0 }),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            if i == 3 {
+                important = body["id"].as_str().unwrap().to_string();
             }
-            return Json(SearchResponse {
-                results: items,
-                took_ms: Some(0),
-            });
         }
+        let context = |max: &str| {
+            let mut q = Map::new();
+            q.insert("session".to_string(), "s1".to_string());
+            q.insert("maxChars".to_string(), max.to_string());
+            let state = state.clone();
+            async move {
+                let resp = memory_context(AxState(state), axum::extract::Query(q)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+        // Each note is 45 chars: a 100 char budget fits two of the four s1 notes
+        let body = context("100").await;
+        let mems = body["memories"].as_array().unwrap();
+        assert_eq!(mems.len(), 2);
+        assert_eq!(mems[0]["id"], serde_json::json!(important));
+        let used: usize = mems
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().chars().count())
+            .sum();
+        assert_eq!(body["usedChars"], serde_json::json!(used));
+        assert!(used <= 100);
+        assert_eq!(body["skipped"], 2);
+        let body = context("10000").await;
+        assert_eq!(body["memories"].as_array().unwrap().len(), 4);
+        let body = context("10").await;
+        assert!(body["memories"].as_array().unwrap().is_empty());
     }
-    // Text: naive scan of tantivy is non-trivial; reuse memories substring for demo and include doc chunks via sled text_index fallback
-    let mut results: Vec<SearchResult> = Vec::new();
-    // From memories (apply temporal filters if provided)
-    let tree = state.db.open_tree("memories").expect("mem");
-    for kv in tree.iter() {
-        let (_, v) = kv.expect("ok");
-        if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-            let content = rec
-                .get("content")
-                .and_then(|c| c.as_str())
-                .unwrap_or("")
-                .to_lowercase();
-            let created_at = rec.get("created_at").and_then(|c| c.as_i64());
-            let in_time = created_at
-                .map(|t| {
-                    time_from.map(|f| t >= f).unwrap_or(true)
-                        && time_to.map(|to| t <= to).unwrap_or(true)
-                })
-                .unwrap_or(true);
-            if content.contains(&q) && in_time {
-                let id = rec
-                    .get("id")
-                    .and_then(|c| c.as_str())
-                    .unwrap_or("")
-                    .to_string();
-                let layer_v = rec
-                    .get("layer")
-                    .and_then(|c| c.as_str())
-                    .unwrap_or("")
-                    .to_string();
-                let refs = rec.get("docRefs").and_then(|r| r.as_array()).map(|arr| {
-                    arr.iter()
-                        .filter_map(|x| {
-                            let doc_id = x.get("docId").and_then(|v| v.as_str())?.to_string();
-                            let chunk_id = x
-                                .get("chunkId")
-                                .and_then(|v| v.as_str())
-                                .map(|s| s.to_string());
-                            let score = x.get("score").and_then(|v| v.as_f64()).map(|f| f as f32);
-                            Some(DocRefOut {
-                                doc_id,
-                                chunk_id,
-                                score,
-                            })
-                        })
-                        .collect::<Vec<_>>()
-                });
-                results.push(SearchResult {
-                    id,
-                    score: 0.0,
-                    layer: layer_v,
-                    doc_refs: refs,
-                    explain: Some(serde_json::json!({"text": 1.0})),
-                });
+
+    #[tokio::test]
+    async fn test_stdio_tool_call_works_without_http() {
+        let state = make_state();
+        std::env::set_var("HTTP_BIND", "");
+        let params = serde_json::json!({
+            "name": "memory.add",
+            "arguments": { "content": "stdio only memory", "layer_hint": "LTM" }
+        });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(1)).await;
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        let body: serde_json::Value = serde_json::from_str(text).unwrap();
+        let id = body["id"].as_str().unwrap();
+        let mems = state.db().open_tree("memories").unwrap();
+        assert!(mems.get(id.as_bytes()).unwrap().is_some());
+        // GET tools get their arguments as query params
+        let params = serde_json::json!({
+            "name": "memory_search",
+            "arguments": { "q": "stdio only", "limit": 5 }
+        });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(2)).await;
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        assert!(text.contains(id));
+        // Handler errors and unknown tools surface as JSON-RPC errors
+        let params = serde_json::json!({ "name": "memory.add", "arguments": { "content": "" } });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(3)).await;
+        assert_eq!(resp["error"]["code"], -32602);
+        let params = serde_json::json!({ "name": "nope", "arguments": {} });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(4)).await;
+        assert_eq!(resp["error"]["message"], "Unknown tool: nope");
+        // Tool calls are not HTTP traffic: no route metrics, no error-rate samples
+        assert!(state.http_metrics.lock().unwrap().is_empty());
+        assert!(state.http_errors.lock().unwrap().seconds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tool_errors_map_to_jsonrpc_codes() {
+        let state = make_state();
+        let call = |name: &str, args: serde_json::Value| {
+            let params = serde_json::json!({ "name": name, "arguments": args });
+            let state = state.clone();
+            async move {
+                process_request(state, "tools/call", &params, &serde_json::json!(1)).await["error"]
+                    .clone()
             }
-        }
+        };
+        let err = call("memory.add", serde_json::json!({ "content": "" })).await;
+        assert_eq!(err["code"], -32602);
+        assert_eq!(err["message"], "content must not be empty");
+        assert_eq!(err["data"]["code"], "INVALID_INPUT");
+        assert_eq!(err["data"]["status"], 400);
+        let err = call(
+            "memory.pin",
+            serde_json::json!({ "id": "missing", "pinned": true }),
+        )
+        .await;
+        assert_eq!(err["code"], -32002);
+        assert_eq!(err["data"]["details"]["id"], "missing");
+        // Bodies the extractor rejects are invalid params too
+        let err = call("memory.pin", serde_json::json!({ "id": "missing" })).await;
+        assert_eq!(err["code"], -32602);
+        let err = call("no.such_tool", serde_json::json!({})).await;
+        assert_eq!(err["code"], -32601);
     }
-    // From doc text index (sled fallback)
-    if let Ok(text_idx) = state.db.open_tree("text_index") {
-        for kv in text_idx.iter() {
-            if let Ok((k, v)) = kv {
-                let s = String::from_utf8_lossy(&v).to_lowercase();
-                if s.contains(&q) {
-                    let id = String::from_utf8(k.to_vec()).unwrap_or_default();
-                    results.push(SearchResult {
-                        id,
-                        score: 0.0,
-                        layer: "doc".to_string(),
-                        doc_refs: None,
-                        explain: Some(serde_json::json!({"text": 1.0, "source":"doc-index"})),
-                    });
-                }
+
+    #[test]
+    fn test_tools_advertise_input_schemas() {
+        let tools = list_tools();
+        let search = tools.iter().find(|t| t.name == "memory.search").unwrap();
+        let schema = search.input_schema();
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["properties"]["q"]["type"], "string");
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("q")));
+        let add = tool_input_schema("memory.add");
+        assert_eq!(add["required"], serde_json::json!(["content"]));
+        assert!(add["properties"]["layer_hint"].is_object());
+        assert!(add["properties"]["references"]["items"].is_object());
+        // Underscore aliases resolve to the same schema
+        assert_eq!(tool_input_schema("memory_search"), schema);
+        assert_eq!(
+            tool_input_schema("kg_list_entities"),
+            tool_input_schema("kg.list_entities")
+        );
+        // Every advertised tool has a concrete schema and required names are declared
+        for t in &tools {
+            let schema = t.input_schema();
+            assert!(
+                schema.get("additionalProperties").is_none(),
+                "{} falls back to the open schema",
+                t.name
+            );
+            for r in schema["required"].as_array().into_iter().flatten() {
+                assert!(schema["properties"].get(r.as_str().unwrap()).is_some());
             }
         }
     }
-    results.sort_by(|a, b| {
-        b.score
-            .partial_cmp(&a.score)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-    results.truncate(limit);
-    // KG semantic hits: if query matches an entity, include memories that mention it
-    if !q.is_empty() {
-        if let Ok(edges) = state.db.open_tree("kg_edges") {
-            let needle = format!("->Entity::{}::MENTIONS", q);
-            for kv in edges.iter() {
-                if let Ok((k, _)) = kv {
-                    let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-                    if key.ends_with(&needle)
-                        || key.to_lowercase().ends_with(&needle.to_lowercase())
-                    {
-                        if let Some((src, _)) = key.split_once("->") {
-                            if let Some(mem_id) = src.strip_prefix("Memory::") {
-                                let already = results.iter().any(|r| r.id == mem_id);
-                                if !already {
-                                    let layer_v = if let Ok(Some(v)) = tree.get(mem_id.as_bytes()) {
-                                        serde_json::from_slice::<serde_json::Value>(&v)
-                                            .ok()
-                                            .and_then(|r| {
-                                                r.get("layer")
-                                                    .and_then(|x| x.as_str())
-                                                    .map(|s| s.to_string())
-                                            })
-                                            .unwrap_or_else(|| "STM".to_string())
-                                    } else {
-                                        "STM".to_string()
-                                    };
-                                    results.push(SearchResult {
-                                        id: mem_id.to_string(),
-                                        score: 0.0,
-                                        layer: layer_v,
-                                        doc_refs: None,
-                                        explain: Some(serde_json::json!({"kg": 1.0})),
-                                    });
-                                }
-                            }
+
+    #[tokio::test]
+    async fn test_mcp_resources_list_and_read() {
+        let state = make_state();
+        let params = serde_json::json!({
+            "name": "memory.add",
+            "arguments": { "content": "resource backed memory" }
+        });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(1)).await;
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        let id = serde_json::from_str::<serde_json::Value>(text).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let uri = format!("memory://memory/{}", id);
+        let resp = process_request(
+            state.clone(),
+            "resources/list",
+            &serde_json::json!({}),
+            &serde_json::json!(2),
+        )
+        .await;
+        let listed = resp["result"]["resources"].as_array().unwrap();
+        assert!(listed.iter().any(|r| r["uri"] == uri.as_str()));
+        let resp = process_request(
+            state.clone(),
+            "resources/read",
+            &serde_json::json!({ "uri": uri }),
+            &serde_json::json!(3),
+        )
+        .await;
+        let contents = &resp["result"]["contents"][0];
+        assert_eq!(contents["uri"], uri.as_str());
+        assert_eq!(contents["text"], "resource backed memory");
+        let params = serde_json::json!({
+            "name": "document.store",
+            "arguments": { "path": "notes/res.md", "content": "# Title\n\nDocument body text." }
+        });
+        let resp =
+            process_request(state.clone(), "tools/call", &params, &serde_json::json!(5)).await;
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        let doc_id = serde_json::from_str::<serde_json::Value>(text).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let resp = process_request(
+            state.clone(),
+            "resources/read",
+            &serde_json::json!({ "uri": format!("memory://document/{}", doc_id) }),
+            &serde_json::json!(6),
+        )
+        .await;
+        let text = resp["result"]["contents"][0]["text"].as_str().unwrap();
+        assert!(text.contains("Document body text."));
+        let resp = process_request(
+            state,
+            "resources/read",
+            &serde_json::json!({ "uri": "memory://memory/missing" }),
+            &serde_json::json!(4),
+        )
+        .await;
+        assert_eq!(resp["error"]["code"], -32002);
+    }
+
+    #[tokio::test]
+    async fn test_mcp_over_sse_lists_tools() {
+        let state = make_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = build_router(state.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let client = reqwest::Client::new();
+        let mut stream = client
+            .get(format!("http://{}/sse", addr))
+            .send()
+            .await
+            .unwrap();
+        assert!(stream.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/event-stream"));
+        // Read events until `want` shows up; returns that event's data
+        let mut buf = String::new();
+        async fn next_event(
+            stream: &mut reqwest::Response,
+            buf: &mut String,
+            want: &str,
+        ) -> String {
+            loop {
+                while let Some(end) = buf.find("\n\n") {
+                    let block: String = buf.drain(..end + 2).collect();
+                    let mut event = "message";
+                    let mut data = String::new();
+                    for line in block.lines() {
+                        if let Some(e) = line.strip_prefix("event: ") {
+                            event = e;
+                        } else if let Some(d) = line.strip_prefix("data: ") {
+                            data.push_str(d);
                         }
                     }
+                    if event == want {
+                        return data;
+                    }
                 }
+                let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), stream.chunk())
+                    .await
+                    .expect("event timeout")
+                    .unwrap()
+                    .expect("stream ended");
+                buf.push_str(&String::from_utf8_lossy(&chunk));
             }
         }
-    }
-    // Vector ANN augmentation via neighbor graph
-    if !q.is_empty() {
-        let qvec = embeddings::embed_batch(&[q.as_str()]);
-        if let Some(vec) = qvec.get(0) {
-            let topk = vector_index::ann_search_memories(&state.db, vec, limit);
-            for (id, score) in topk {
-                let already = results.iter().any(|r| r.id == id);
-                if !already {
-                    let layer_v = if let Ok(Some(v)) = tree.get(id.as_bytes()) {
-                        serde_json::from_slice::<serde_json::Value>(&v)
-                            .ok()
-                            .and_then(|r| {
-                                r.get("layer")
-                                    .and_then(|x| x.as_str())
-                                    .map(|s| s.to_string())
-                            })
-                            .unwrap_or_else(|| "STM".to_string())
-                    } else {
-                        "STM".to_string()
-                    };
-                    results.push(SearchResult {
-                        id,
-                        score: 0.0,
-                        layer: layer_v,
-                        doc_refs: None,
-                        explain: Some(serde_json::json!({"vector": score, "source":"vector-ann"})),
-                    });
-                }
+        let endpoint = next_event(&mut stream, &mut buf, "endpoint").await;
+        assert!(endpoint.starts_with("/messages?sessionId="));
+        assert_eq!(state.sse_sessions.lock().unwrap().len(), 1);
+        let resp = client
+            .post(format!("http://{}{}", addr, endpoint))
+            .json(&serde_json::json!({ "jsonrpc": "2.0", "id": 7, "method": "tools/list" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::ACCEPTED);
+        let data = next_event(&mut stream, &mut buf, "message").await;
+        let msg: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(msg["id"], 7);
+        let tools = msg["result"]["tools"].as_array().unwrap();
+        assert!(tools.iter().any(|t| t["name"] == "memory.add"));
+        // Unknown sessions are rejected; dropping the stream ends the session
+        let resp = client
+            .post(format!("http://{}/messages?sessionId=nope", addr))
+            .body("{}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        drop(stream);
+        for _ in 0..50 {
+            if state.sse_sessions.lock().unwrap().is_empty() {
+                break;
             }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
         }
+        assert!(state.sse_sessions.lock().unwrap().is_empty());
     }
-    // Cache after augmentation
-    {
-        let mut guard = state.query_cache.lock().await;
-        guard.insert(cache_key, (now_ms, results.clone()));
+
+    #[tokio::test]
+    async fn test_initialize_is_followed_by_tools_list_changed() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+        let state = make_state();
+        let mut other_session = state.notifications.subscribe();
+        let (mut client_in, server_in) = tokio::io::duplex(4096);
+        let (server_out, client_out) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(serve_jsonrpc_lines(state.clone(), server_in, server_out));
+        client_in
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{}}\n")
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(client_out).lines();
+        async fn next(
+            lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::io::DuplexStream>>,
+        ) -> serde_json::Value {
+            let line = tokio::time::timeout(std::time::Duration::from_secs(5), lines.next_line())
+                .await
+                .expect("line timeout")
+                .unwrap()
+                .expect("output closed");
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()
+        }
+        let first = next(&mut lines).await;
+        assert_eq!(first["id"], 1);
+        assert!(first["result"]["capabilities"]["tools"]["listChanged"]
+            .as_bool()
+            .unwrap());
+        let second = next(&mut lines).await;
+        assert_eq!(second["method"], "notifications/tools/list_changed");
+        assert!(second.get("id").is_none());
+        // The post-initialize notice is not broadcast to other sessions
+        assert!(other_session.try_recv().is_err());
+        // Later registry changes reach the same stream
+        state.notify_tools_changed();
+        assert_eq!(
+            next(&mut lines).await["method"],
+            "notifications/tools/list_changed"
+        );
+        drop(client_in);
+        server.await.unwrap();
     }
-    let took = started.elapsed().as_millis() as u64;
-    // metrics update: cache miss
-    {
-        let mut m = state.metrics.lock().await;
-        m.count += 1;
-        m.cache_misses += 1;
-        m.last_ms = took;
-        m.avg_ms =
-            ((m.avg_ms * ((m.count.saturating_sub(1)) as f64)) + took as f64) / (m.count as f64);
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
+
+    #[tokio::test]
+    async fn test_memory_writes_notify_resources_list_changed() {
+        let state = make_state();
+        let mut client = state.notifications.subscribe();
+        let add = AddMemoryRequest {
+            content: "listed as a resource".into(),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(add)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["id"]
+            .as_str()
             .unwrap()
-            .as_millis() as i64;
-        m.history.push_back((now, took));
-        while let Some((ts, _)) = m.history.front().cloned() {
-            if now - ts > 60_000 {
-                m.history.pop_front();
-            } else {
-                break;
+            .to_string();
+        assert_eq!(
+            client.try_recv().unwrap()["method"],
+            "notifications/resources/list_changed"
+        );
+        while client.try_recv().is_ok() {}
+
+        let del = DeleteMemoryRequest {
+            id,
+            backup: None,
+            soft: None,
+        };
+        let resp = memory_delete(AxState(state.clone()), Json(del)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            client.try_recv().unwrap()["method"],
+            "notifications/resources/list_changed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_key_middleware() {
+        use tower::ServiceExt;
+        let state = make_state();
+        let app = require_api_keys(
+            build_router(state.clone()),
+            vec!["k1".to_string(), "k2".to_string()],
+        );
+        let send = |path: &str, auth: Option<&str>| {
+            let mut req = axum::http::Request::get(path);
+            if let Some(a) = auth {
+                req = req.header("authorization", a);
             }
+            app.clone()
+                .oneshot(req.body(axum::body::Body::empty()).unwrap())
+        };
+        let resp = send("/status", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "UNAUTHORIZED");
+        let resp = send("/status", Some("Bearer nope")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = send("/status", Some("k2")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = send("/status", Some("Bearer k2")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Health stays open for probes
+        let resp = send("/health", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        // No keys configured: open as before
+        let open = require_api_keys(build_router(state), Vec::new());
+        let resp = open
+            .oneshot(
+                axum::http::Request::get("/status")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_rejects_writes_past_burst() {
+        use tower::ServiceExt;
+        let state = make_state();
+        let limit = config::RateLimit {
+            rps: 0.5,
+            burst: 3.0,
+        };
+        let app = rate_limit_writes(build_router(state.clone()), state, Some(limit));
+        let send = |method: &str, path: &str, ip: [u8; 4]| {
+            let mut req = axum::http::Request::builder()
+                .method(method)
+                .uri(path)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(r#"{"content":"rate limited note"}"#))
+                .unwrap();
+            let addr = std::net::SocketAddr::from((ip, 4000));
+            req.extensions_mut()
+                .insert(axum::extract::ConnectInfo(addr));
+            app.clone().oneshot(req)
+        };
+        for _ in 0..3 {
+            let resp = send("POST", "/memory/add", [10, 0, 0, 1]).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
         }
-        let mut lat: Vec<u64> = m.history.iter().map(|(_, v)| *v).collect();
-        lat.sort_unstable();
-        if !lat.is_empty() {
-            let p50_idx = (lat.len() as f64 * 0.5).floor() as usize;
-            let p95_idx = (lat.len() as f64 * 0.95)
-                .floor()
-                .min((lat.len() - 1) as f64) as usize;
-            m.p50_ms = lat[p50_idx] as f64;
-            m.p95_ms = lat[p95_idx] as f64;
-        }
-        m.qps_1m = m.history.len() as f64 / 60.0;
+        let resp = send("POST", "/memory/add", [10, 0, 0, 1]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry: u64 = resp.headers()["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=2).contains(&retry));
+        // Reads and other clients are unaffected
+        let resp = send("GET", "/status", [10, 0, 0, 1]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = send("POST", "/memory/add", [10, 0, 0, 2]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
     }
-    Json(SearchResponse {
-        results,
-        took_ms: Some(took as u128),
-    })
-}
 
-async fn document_refs_for_memory(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Response {
-    let mem_id = match params.get("id").cloned() {
-        Some(s) => s,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "id required",
-                None,
+    #[tokio::test]
+    async fn test_deep_health_checks_storage() {
+        let state = make_state();
+        let get = |deep: bool| {
+            let mut q = Map::new();
+            if deep {
+                q.insert("deep".to_string(), "true".to_string());
+            }
+            health(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let resp = get(false).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(body.get("checks").is_none());
+        let read_deep = |resp: Response| async move {
+            let status = resp.status();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
             )
+        };
+        let (status, body) = read_deep(get(true).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        for check in ["storage", "textIndex", "embeddings"] {
+            assert_eq!(body["checks"][check]["ok"], true, "{}", check);
         }
-    };
-    let refs_tree = state.db.open_tree("doc_refs").expect("doc_refs");
-    let prefix = format!("mem::{}::", mem_id);
-    let mut out: Vec<serde_json::Value> = Vec::new();
-    for kv in refs_tree.scan_prefix(prefix.as_bytes()) {
-        if let Ok((k, v)) = kv {
-            let key = String::from_utf8_lossy(&k);
-            // mem::<id>::doc::<docId>::chunk::<chunkId>
-            let parts: Vec<&str> = key.split("::").collect();
-            if parts.len() >= 6 {
-                let doc_id = parts[3].to_string();
-                let chunk_id = if parts.len() >= 6 {
-                    Some(parts[5].to_string())
-                } else {
-                    None
-                };
-                let score = serde_json::from_slice::<serde_json::Value>(&v)
-                    .ok()
-                    .and_then(|x| x.get("score").and_then(|s| s.as_f64()))
-                    .unwrap_or(0.0);
-                out.push(
-                    serde_json::json!({ "docId": doc_id, "chunkId": chunk_id, "score": score }),
-                );
-            }
+        assert!(state.db().open_tree("health_probe").unwrap().is_empty());
+        // A truncated embedding fails the deep check
+        state
+            .db()
+            .open_tree("mem_embeddings")
+            .unwrap()
+            .insert(b"broken", &[0u8; 8])
+            .unwrap();
+        let (status, body) = read_deep(get(true).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"]["embeddings"]["invalid"], 1);
+        assert_eq!(body["checks"]["storage"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_backup_round_trip() {
+        let state = make_state();
+        let req = AddMemoryRequest {
+            content: "survives the archive".to_string(),
+            layer_hint: Some("LTM".to_string()),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let dest = tempfile::tempdir().unwrap();
+        let body = serde_json::json!({
+            "destination": dest.path().to_string_lossy(),
+            "compress": true,
+        });
+        let resp = system_backup(AxState(state.clone()), Json(body)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let archive = out["path"].as_str().unwrap().to_string();
+        assert!(archive.ends_with(".tar.gz"));
+        let on_disk = std::fs::metadata(&archive).unwrap().len();
+        assert_eq!(out["compressedBytes"], serde_json::json!(on_disk));
+        // Restore into an empty root and open the restored store
+        let root = tempfile::tempdir().unwrap();
+        let (_, has_manifest) = restore_backup(root.path(), &archive, true).unwrap();
+        assert!(has_manifest);
+        assert!(!root.path().join("manifest.json").exists());
+        let db = sled::open(root.path().join("warm").join("kv")).unwrap();
+        let rec = db
+            .open_tree("memories")
+            .unwrap()
+            .get(id.as_bytes())
+            .unwrap();
+        let rec: serde_json::Value = serde_json::from_slice(&rec.unwrap()).unwrap();
+        assert_eq!(rec["content"], "survives the archive");
+    }
+
+    #[tokio::test]
+    async fn test_verify_backup_detects_corruption() {
+        let state = make_state();
+        let _ = memory_add(
+            AxState(state.clone()),
+            Json(AddMemoryRequest {
+                content: "checksummed".to_string(),
+                layer_hint: Some("LTM".to_string()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        let _ = state.db().flush();
+        state.text_index.close().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let info = create_backup(
+            &data_root(&state),
+            &dest.path().to_string_lossy(),
+            false,
+            false,
+            None,
+        )
+        .unwrap();
+        let v = verify_backup(&info.path).unwrap().unwrap();
+        assert!(v.checked > 0 && v.is_valid());
+
+        let manifest: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(std::path::Path::new(&info.path).join("manifest.json")).unwrap(),
+        )
+        .unwrap();
+        let files: Vec<String> = manifest["files"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert!(files
+            .iter()
+            .all(|f| f.starts_with("warm/") || f.starts_with("cold/")));
+        let root = std::path::Path::new(&info.path);
+        std::fs::write(root.join(&files[0]), b"corrupted").unwrap();
+        if files.len() > 1 {
+            std::fs::remove_file(root.join(&files[1])).unwrap();
+        }
+        let v = verify_backup(&info.path).unwrap().unwrap();
+        assert!(!v.is_valid());
+        assert_eq!(v.mismatched, vec![files[0].clone()]);
+        assert_eq!(v.missing.len(), usize::from(files.len() > 1));
+
+        // A verified restore refuses the damaged snapshot
+        let resp = system_restore(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "source": info.path, "verify": true })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Archives are verified from the stream
+        let info = create_backup(
+            &data_root(&state),
+            &dest.path().to_string_lossy(),
+            false,
+            true,
+            None,
+        )
+        .unwrap();
+        assert!(verify_backup(&info.path).unwrap().unwrap().is_valid());
+    }
+
+    #[tokio::test]
+    async fn test_failed_restore_keeps_original_data() {
+        async fn add(state: &Arc<AppState>, content: &str) -> String {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("LTM".to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+        fn exists(state: &AppState, id: &str) -> bool {
+            let mems = state.db().open_tree("memories").unwrap();
+            mems.get(id.as_bytes()).unwrap().is_some()
+        }
+        let state = make_state();
+        let kept = add(&state, "in the snapshot").await;
+        let _ = state.db().flush();
+        state.text_index.close().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let info = create_backup(
+            &data_root(&state),
+            &dest.path().to_string_lossy(),
+            true,
+            true,
+            None,
+        )
+        .unwrap();
+        let later = add(&state, "written after the snapshot").await;
+
+        // A truncated archive fails partway through unpacking
+        let bytes = std::fs::read(&info.path).unwrap();
+        let broken = dest.path().join("broken.tar.gz");
+        std::fs::write(&broken, &bytes[..bytes.len() / 2]).unwrap();
+        let resp = system_restore(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "source": broken.to_string_lossy() })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(out["error"]["details"]["rolledBack"], true);
+        assert!(exists(&state, &kept) && exists(&state, &later));
+        let leftovers = std::fs::read_dir(data_root(&state))
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with(".restore-"))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // The intact archive swaps in and the store is reopened on it
+        let resp = system_restore(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "source": info.path })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(exists(&state, &kept));
+        assert!(!exists(&state, &later));
+        assert!(!add(&state, "after restore").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_incremental_backup_copies_only_changed_files() {
+        async fn add(state: &Arc<AppState>, content: &str) -> String {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("LTM".to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+        let state = make_state();
+        let first = add(&state, "before the full backup").await;
+        let _ = state.db().flush();
+        state.text_index.close().unwrap();
+        let full_dest = tempfile::tempdir().unwrap();
+        let full = create_backup(
+            &data_root(&state),
+            &full_dest.path().to_string_lossy(),
+            false,
+            false,
+            None,
+        )
+        .unwrap();
+        let second = add(&state, "only in the increment").await;
+        let _ = state.db().flush();
+        state.text_index.close().unwrap();
+        let inc_dest = tempfile::tempdir().unwrap();
+        let inc = create_backup(
+            &data_root(&state),
+            &inc_dest.path().to_string_lossy(),
+            false,
+            false,
+            Some(&full.path),
+        )
+        .unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(std::path::Path::new(&inc.path).join("manifest.json")).unwrap(),
+        )
+        .unwrap();
+        let changed: Vec<String> = manifest["changed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .collect();
+        let inventory = manifest["inventory"].as_object().unwrap();
+        assert!(!changed.is_empty() && changed.len() < inventory.len());
+        // Only sled's own files moved; the snapshot holds exactly those
+        assert!(changed.iter().all(|f| f.starts_with("warm/kv/")));
+        let mut copied: Vec<String> = snapshot_entries(std::path::Path::new(&inc.path), "warm")
+            .unwrap()
+            .into_iter()
+            .filter(|(_, p)| p.is_file())
+            .map(|(rel, _)| rel)
+            .collect();
+        copied.sort();
+        let mut expected = changed.clone();
+        expected.sort();
+        assert_eq!(copied, expected);
+        assert!(verify_backup(&inc.path).unwrap().unwrap().is_valid());
+
+        // Restoring the increment replays the full base underneath it
+        let root = tempfile::tempdir().unwrap();
+        restore_backup(root.path(), &inc.path, true).unwrap();
+        let db = sled::open(root.path().join("warm").join("kv")).unwrap();
+        let mems = db.open_tree("memories").unwrap();
+        assert!(mems.get(first.as_bytes()).unwrap().is_some());
+        assert!(mems.get(second.as_bytes()).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_per_route_requests() {
+        use tower::ServiceExt;
+        let state = make_state();
+        for _ in 0..3 {
+            let req = axum::http::Request::get("/status")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
         }
+        let req = axum::http::Request::get("/memory/episode?id=missing")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let _ = build_router(state.clone()).oneshot(req).await.unwrap();
+        let req = axum::http::Request::get("/metrics")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("mcp_queries_total "));
+        assert!(text.contains("mcp_http_requests_total{path=\"/status\",status=\"200\"} 3\n"));
+        assert!(text.contains("mcp_http_request_duration_seconds_count{path=\"/status\"} 3\n"));
+        assert!(text.contains(
+            "mcp_http_request_duration_seconds_bucket{path=\"/status\",le=\"+Inf\"} 3\n"
+        ));
+        assert!(text.contains("mcp_http_request_errors_total{path=\"/status\"} 0\n"));
+        assert!(text.contains("mcp_http_request_errors_total{path=\"/memory/episode\"} 1\n"));
     }
-    Json(serde_json::json!({ "id": mem_id, "docRefs": out })).into_response()
-}
 
-async fn document_refs_for_document(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Response {
-    let doc_id = match params.get("id").cloned() {
-        Some(s) => s,
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "id required",
-                None,
-            )
+    #[tokio::test]
+    async fn test_request_id_echoed_and_in_errors() {
+        use tower::ServiceExt;
+        let state = make_state();
+        let req = axum::http::Request::get("/status")
+            .header("x-request-id", "client-abc.123")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.headers()["x-request-id"], "client-abc.123");
+
+        // Unusable ids are replaced, and error bodies carry the id that was sent back
+        let req = axum::http::Request::get("/memory/episode?id=missing")
+            .header("x-request-id", "has spaces")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(out["error"]["details"]["requestId"], id.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_degrade_health() {
+        use tower::ServiceExt;
+        let state = make_state();
+        async fn send(state: &Arc<AppState>, req: axum::http::Request<axum::body::Body>) -> u16 {
+            let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+            resp.status().as_u16()
         }
-    };
-    let refs_tree = state.db.open_tree("doc_refs").expect("doc_refs");
-    let needle = format!("::doc::{}::", doc_id);
-    let mut out: Vec<serde_json::Value> = Vec::new();
-    for kv in refs_tree.iter() {
-        if let Ok((k, v)) = kv {
-            let key = String::from_utf8_lossy(&k);
-            if key.contains(&needle) {
-                let parts: Vec<&str> = key.split("::").collect();
-                if parts.len() >= 6 {
-                    let mem_id = parts[1].to_string();
-                    let chunk_id = if parts.len() >= 6 {
-                        Some(parts[5].to_string())
-                    } else {
-                        None
-                    };
-                    let score = serde_json::from_slice::<serde_json::Value>(&v)
-                        .ok()
-                        .and_then(|x| x.get("score").and_then(|s| s.as_f64()))
-                        .unwrap_or(0.0);
-                    out.push(serde_json::json!({ "memoryId": mem_id, "chunkId": chunk_id, "score": score }));
-                }
-            }
+        for _ in 0..10 {
+            let req = axum::http::Request::get("/health")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            assert_eq!(send(&state, req).await, 200);
+        }
+        assert_eq!(build_status(state.clone()).await.health, "ok");
+        // A missing archive makes restore fail with a 500
+        for _ in 0..2 {
+            let req = axum::http::Request::post("/system/restore")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(
+                    r#"{"source":"/nonexistent/snapshot.tar.gz"}"#,
+                ))
+                .unwrap();
+            assert_eq!(send(&state, req).await, 500);
         }
+        let status = build_status(state.clone()).await;
+        assert_eq!(status.http_errors.errors_1m, 2);
+        assert_eq!(status.http_errors.requests_1m, 12);
+        assert_eq!(status.health, "degraded");
+        let req = axum::http::Request::get("/metrics")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("mcp_http_errors_total 2\n"));
     }
-    Json(serde_json::json!({ "id": doc_id, "memories": out })).into_response()
-}
 
-#[derive(Deserialize)]
-struct ValidateRefsBody {
-    fix: Option<bool>,
-}
+    #[tokio::test]
+    async fn test_oversized_body_rejected_with_413() {
+        use tower::ServiceExt;
+        let state = make_state();
+        let mut body = br#"{"content":""#.to_vec();
+        body.resize(max_body_bytes() + 1, b'a');
+        let req = axum::http::Request::post("/memory/add")
+            .header("content-type", "application/json")
+            .header("content-length", body.len())
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let resp = build_router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(out["error"]["code"], "PAYLOAD_TOO_LARGE");
+        assert_eq!(out["error"]["details"]["maxBytes"], max_body_bytes());
+        assert!(out["error"]["details"]["hint"]
+            .as_str()
+            .unwrap()
+            .contains("path"));
+        // Nothing was stored
+        assert_eq!(state.db().open_tree("memories").unwrap().len(), 0);
+    }
 
-async fn document_validate_refs(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<ValidateRefsBody>,
-) -> Json<serde_json::Value> {
-    let fix = body.fix.unwrap_or(false);
-    let refs_tree = state.db.open_tree("doc_refs").expect("doc_refs");
-    let mems = state.db.open_tree("memories").expect("memories");
-    let docs_meta = state.db.open_tree("chunks").expect("chunks");
-    let mut invalid: Vec<String> = Vec::new();
-    let mut removed = 0u64;
-    for kv in refs_tree.iter() {
-        if let Ok((k, _)) = kv {
-            let key = String::from_utf8_lossy(&k);
-            // mem::<id>::doc::<docId>::chunk::<chunkId>
-            let parts: Vec<&str> = key.split("::").collect();
-            if parts.len() < 6 {
-                invalid.push(key.to_string());
-                if fix {
-                    let _ = refs_tree.remove(&k);
-                    removed += 1;
-                }
-                continue;
+    fn chunk_markdown_with(content: &str, size: usize, overlap: usize) -> Vec<ChunkHeader> {
+        let mut chunker = ChunkStream::new(size, overlap);
+        chunker.push(content);
+        chunker.finish();
+        chunker.take().into_iter().map(|ch| ch.header).collect()
+    }
+
+    #[tokio::test]
+    async fn test_storing_identical_content_twice_reports_dedup() {
+        let state = make_state();
+        let store = || {
+            let req = StoreDocRequest {
+                path: Some("notes/dedup.md".to_string()),
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some("# Same\nidentical body".to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: true,
+                dry_run: false,
+            };
+            let state = state.clone();
+            async move {
+                let resp = document_store(AxState(state), Json(req)).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
             }
-            let mem_id = parts[1];
-            let doc_id = parts[3];
-            let chunk_id = parts[5];
-            let mem_ok = mems.get(mem_id.as_bytes()).ok().flatten().is_some();
-            // minimal doc/chunk check: presence of any chunk for doc
-            let prefix = format!("{}:", doc_id);
-            let mut doc_ok = false;
-            for it in docs_meta.scan_prefix(prefix.as_bytes()).take(1) {
-                if it.is_ok() {
-                    doc_ok = true;
-                    break;
-                }
+        };
+        let first = store().await;
+        assert_eq!(first["deduped"], false);
+        assert_eq!(first["version"], 1);
+        assert!(first["chunks"].as_u64().unwrap() > 0);
+        let second = store().await;
+        assert_eq!(second["deduped"], true);
+        assert_eq!(second["id"], first["id"]);
+        assert_eq!(second["version"], 1);
+        assert_eq!(second["chunks"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_document_dry_run_previews_without_storing() {
+        let state = make_state();
+        let content = "# Rust Notes\nOwnership and Borrowing explained.\n\n\
+                       ## Tokio\nAsync runtime details for Tokio tasks.\n\n\
+                       Plain paragraph about Serde derives."
+            .to_string();
+        let store = |dry_run: bool| {
+            let req = StoreDocRequest {
+                path: None,
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.clone()),
+                metadata: None,
+                chunk_size: Some(40),
+                chunk_overlap: Some(5),
+                sync: true,
+                dry_run,
+            };
+            let state = state.clone();
+            async move {
+                let resp = document_store(AxState(state), Json(req)).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
             }
-            if !mem_ok || !doc_ok || chunk_id.is_empty() {
-                invalid.push(key.to_string());
-                if fix {
-                    let _ = refs_tree.remove(&k);
-                    removed += 1;
-                }
+        };
+        let preview = store(true).await;
+        assert_eq!(
+            preview["chunkCount"],
+            chunk_markdown_with(&content, 40, 5).len()
+        );
+        assert_eq!(preview["chunks"].as_array().unwrap().len(), 5);
+        assert_eq!(preview["chunks"][0]["start"], 0);
+        assert_eq!(
+            preview["entities"],
+            serde_json::json!([
+                "Async",
+                "Borrowing",
+                "Notes",
+                "Ownership",
+                "Plain",
+                "Rust",
+                "Serde",
+                "Tokio"
+            ])
+        );
+        assert!(preview["duplicateOf"].is_null());
+        for name in state.db().tree_names() {
+            let tree = state.db().open_tree(&name).unwrap();
+            assert!(tree.is_empty(), "{}", String::from_utf8_lossy(&name));
+        }
+
+        // The hash matches a real store, which a later preview reports as the duplicate
+        let stored = store(false).await;
+        assert_eq!(stored["hash"], preview["hash"]);
+        assert_eq!(stored["chunks"], preview["chunkCount"]);
+        assert_eq!(store(true).await["duplicateOf"], stored["id"]);
+    }
+
+    #[tokio::test]
+    async fn test_large_text_file_streams_into_expected_chunks() {
+        let state = make_state();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.txt");
+        // Multi-byte chars make 64 KiB reads land mid-char; long lines force splits mid-read
+        let mut raw = String::new();
+        for i in 0..3500 {
+            if i % 50 == 0 {
+                raw.push_str(&format!("# Section {}\n\n", i / 50));
+            }
+            let words = if i % 7 == 0 { 400 } else { 90 };
+            for w in 0..words {
+                raw.push_str(&format!("Größe{} ", (i * 31 + w) % 997));
             }
+            raw.push_str("\n\n");
+        }
+        assert!(raw.len() > 2 * 1024 * 1024);
+        std::fs::write(&path, &raw).unwrap();
+        let expected = chunk_markdown_with(&raw, 1000, 100);
+
+        let req = StoreDocRequest {
+            path: Some(path.to_string_lossy().to_string()),
+            url: None,
+            mime: Some("txt".to_string()),
+            content: None,
+            metadata: None,
+            chunk_size: Some(1000),
+            chunk_overlap: Some(100),
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(out["chunks"], expected.len());
+        assert_eq!(out["hash"], format!("{:x}", Sha256::digest(raw.as_bytes())));
+        let id = out["id"].as_str().unwrap();
+        let chunks = state.db().open_tree("chunks").unwrap();
+        assert_eq!(
+            chunks.scan_prefix(format!("{}:", id)).count(),
+            expected.len()
+        );
+        // Streamed chunk text matches the same ranges of the whole file
+        let text_idx = state.db().open_tree("text_index").unwrap();
+        for ch in [
+            &expected[0],
+            &expected[expected.len() / 2],
+            expected.last().unwrap(),
+        ] {
+            let (start, end) = (ch.position.start, ch.position.end);
+            let stored = text_idx.get(chunk_key(id, start)).unwrap().unwrap();
+            assert_eq!(&stored[..], &raw.as_bytes()[start..end]);
         }
     }
-    Json(
-        serde_json::json!({ "invalid": invalid, "removed": if fix { Some(removed) } else { None } }),
-    )
-}
 
-fn index_chunks_sled(
-    db: &sled::Db,
-    doc_id: &str,
-    chunks: &[ChunkHeader],
-    full_text: &str,
-) -> Result<()> {
-    let text_idx = db.open_tree("text_index")?;
-    for ch in chunks {
-        let start = ch.position.start;
-        let end = ch.position.end.min(full_text.len());
-        let text_slice = &full_text[start..end];
-        let key = format!("{}:{}", doc_id, start);
-        text_idx.insert(key.as_bytes(), text_slice.as_bytes())?;
+    #[tokio::test]
+    async fn test_file_edited_before_indexing_is_a_conflict() {
+        let state = make_state();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "original text").unwrap();
+        // The job carries the hash taken by the store; the file changes before it runs
+        let job = IndexJob {
+            hash: format!("{:x}", Sha256::digest(b"original text")),
+            kind: "txt".to_string(),
+            text: DocText::Streamed(path.to_string_lossy().to_string()),
+            chunk_size: 1000,
+            chunk_overlap: 100,
+        };
+        std::fs::write(&path, "edited text").unwrap();
+        assert!(matches!(
+            run_index_job(&state, "doc-1", &job),
+            Err(IndexFailure::Changed)
+        ));
+
+        std::fs::write(&path, [0x66, 0x6f, 0xff, 0x6f]).unwrap();
+        let req = StoreDocRequest {
+            path: Some(path.to_string_lossy().to_string()),
+            url: None,
+            mime: Some("txt".to_string()),
+            content: None,
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
-    Ok(())
-}
 
-fn run_index_maintenance(state: &Arc<AppState>) -> Result<(u64, u64)> {
-    let text_idx = state.db.open_tree("text_index")?;
-    let chunks = state.db.open_tree("chunks")?;
-    let mut removed_text = 0u64;
-    for kv in text_idx.iter() {
-        let (k, _) = kv?;
-        let key = String::from_utf8(k.to_vec()).unwrap_or_default();
-        if let Some((doc_id, _)) = key.split_once(":") {
-            let prefix = format!("{}:", doc_id);
-            let mut has_chunks = false;
-            for it in chunks.scan_prefix(prefix.as_bytes()).take(1) {
-                if it.is_ok() {
-                    has_chunks = true;
-                    break;
-                }
+    /// Write a PDF whose pages each show `lines` as separate text runs.
+    fn write_test_pdf(path: &std::path::Path, pages: &[Vec<String>]) {
+        use lopdf::content::{Content, Operation};
+        use lopdf::{dictionary, Object, Stream};
+        let mut doc = LoDocument::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+        let mut kids: Vec<Object> = Vec::new();
+        for lines in pages {
+            let mut operations = vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                Operation::new("Td", vec![72.into(), 720.into()]),
+            ];
+            for line in lines {
+                operations.push(Operation::new(
+                    "Tj",
+                    vec![Object::string_literal(line.as_str())],
+                ));
             }
-            if !has_chunks {
-                let _ = text_idx.remove(k);
-                removed_text += 1;
+            operations.push(Operation::new("ET", vec![]));
+            let content = Content { operations }.encode().unwrap();
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => content_id,
+            });
+            kids.push(page_id.into());
+        }
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc.save(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunk_vectors_embed_the_chunk_text() {
+        let state = make_state();
+        let axis = |i: usize| {
+            let mut v = [0.0f32; embeddings::EMBED_DIM];
+            v[i] = 1.0;
+            v
+        };
+        embeddings::seed_cache(
+            &state.db(),
+            "# Cats Cats purr and nap in the sun.",
+            &axis(0),
+        );
+        embeddings::seed_cache(
+            &state.db(),
+            "# Rockets Rockets burn fuel to reach orbit.",
+            &axis(1),
+        );
+        let req = StoreDocRequest {
+            path: Some("docs/topics.md".to_string()),
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some(
+                "# Cats\nCats purr and nap in the sun.\n\n# Rockets\nRockets burn fuel to reach orbit.\n"
+                    .to_string(),
+            ),
+            metadata: None,
+            chunk_size: Some(60),
+            chunk_overlap: Some(0),
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut query = axis(1);
+        query[0] = 0.2;
+        let hits = vector_index::search_chunks_by_vector(&state.db(), &query, 10);
+        assert_eq!(hits.len(), 2);
+        assert!(hits[0].1 > 0, "rocket chunk should rank first: {hits:?}");
+        assert!(hits[0].2 > hits[1].2);
+    }
+
+    #[tokio::test]
+    async fn test_pdf_chunks_carry_page_numbers() {
+        let state = make_state();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        let pages: Vec<Vec<String>> = (1..=3)
+            .map(|p| {
+                (0..6)
+                    .map(|l| format!("page {} line {} of the quarterly report", p, l))
+                    .collect()
+            })
+            .collect();
+        write_test_pdf(&path, &pages);
+
+        let req = StoreDocRequest {
+            path: Some(path.to_string_lossy().to_string()),
+            url: None,
+            mime: None,
+            content: None,
+            metadata: None,
+            chunk_size: Some(100),
+            chunk_overlap: Some(10),
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let id = out["id"].as_str().unwrap().to_string();
+
+        let mut params = std::collections::HashMap::new();
+        params.insert("id".to_string(), id.clone());
+        let resp =
+            document_retrieve(axum::extract::Query(params.clone()), AxState(state.clone())).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let chunks = doc["chunks"].as_array().unwrap();
+        assert!(chunks.len() > 3);
+        let page_of: Vec<u64> = chunks.iter().map(|c| c["page"].as_u64().unwrap()).collect();
+        assert!(page_of.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(page_of.first(), Some(&1));
+        assert_eq!(page_of.last(), Some(&3));
+        // Past the 10-byte overlap, a chunk holds only its own page's lines
+        for (chunk, page) in chunks.iter().zip(&page_of) {
+            let text = chunk["text"].as_str().unwrap();
+            for (at, _) in text.match_indices("page ").filter(|(at, _)| *at >= 10) {
+                assert!(
+                    text[at..].starts_with(&format!("page {} ", page)),
+                    "{:?}",
+                    text
+                );
             }
         }
+
+        let analysis = document_analyze(AxState(state.clone()), axum::extract::Query(params))
+            .await
+            .unwrap();
+        assert_eq!(analysis.0["summaryPage"], 1);
     }
-    let nodes = state.db.open_tree("kg_nodes")?;
-    let edges = state.db.open_tree("kg_edges")?;
-    let mut removed_edges = 0u64;
-    for kv in edges.iter() {
-        let (k, v) = kv?;
-        let val: serde_json::Value = serde_json::from_slice(&v).unwrap_or(serde_json::json!({}));
-        let src = val.get("src").and_then(|c| c.as_str()).unwrap_or("");
-        let dst = val.get("dst").and_then(|c| c.as_str()).unwrap_or("");
-        let src_exists = nodes.get(src.as_bytes())?.is_some();
-        let dst_exists = nodes.get(dst.as_bytes())?.is_some();
-        if !src_exists || !dst_exists {
-            let _ = edges.remove(k);
-            removed_edges += 1;
+
+    #[tokio::test]
+    async fn test_document_store_fetches_url() {
+        let state = make_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mock = Router::new().route(
+            "/notes",
+            get(|| async {
+                (
+                    [(
+                        axum::http::header::CONTENT_TYPE,
+                        "text/markdown; charset=utf-8",
+                    )],
+                    "# Kickoff Notes\n\nMeeting with **Alice** about the *Atlas* launch.\n",
+                )
+            }),
+        );
+        tokio::spawn(async move {
+            axum::serve(listener, mock).await.unwrap();
+        });
+        let url = format!("http://{}/notes", addr);
+        let store = |url: String| {
+            let state = state.clone();
+            async move {
+                let req = StoreDocRequest {
+                    path: None,
+                    url: Some(url),
+                    mime: None,
+                    content: None,
+                    metadata: None,
+                    chunk_size: None,
+                    chunk_overlap: None,
+                    sync: false,
+                    dry_run: false,
+                };
+                let resp = document_store(AxState(state), Json(req)).await;
+                let status = resp.status();
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+                )
+            }
+        };
+
+        // Loopback is refused unless explicitly allowed
+        std::env::remove_var("ALLOW_PRIVATE_URLS");
+        let (status, out) = store(url.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(out["error"]["code"], "FORBIDDEN");
+
+        std::env::set_var("ALLOW_PRIVATE_URLS", "1");
+        let (status, out) = store(url.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(out["chunks"].as_u64().unwrap() > 0);
+        let id = out["id"].as_str().unwrap().to_string();
+        // Same content again dedups to the same document
+        let (_, again) = store(url.clone()).await;
+        assert_eq!(again["id"], id.as_str());
+        assert_eq!(again["chunks"], 0);
+        let (status, _) = store(format!("http://{}/missing", addr)).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        std::env::remove_var("ALLOW_PRIVATE_URLS");
+
+        let mut params = std::collections::HashMap::new();
+        params.insert("path".to_string(), url);
+        let resp = document_retrieve(axum::extract::Query(params), AxState(state.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(doc["id"], id.as_str());
+        // Markdown was converted: emphasis markers are gone
+        let text = doc["chunks"][0]["text"].as_str().unwrap();
+        assert!(text.contains("Meeting with Alice about the Atlas launch."));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_memory_ingest_stays_searchable() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for i in 0..200 {
+            let req = AddMemoryRequest {
+                content: format!("standup note {} mentions ticket{}", i, i),
+                layer_hint: Some("LTM".to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(out["id"].as_str().unwrap().to_string());
+        }
+        // One writer served every add; the first search commits what is still buffered
+        let all = tantivy_search(&state.text_index, "standup", usize::MAX);
+        assert_eq!(all.len(), 200);
+        for (i, id) in ids.iter().enumerate().step_by(37) {
+            let hits = tantivy_search(&state.text_index, &format!("ticket{}", i), 10);
+            assert_eq!(hits[0].0, format!("mem:{}", id));
         }
+        // Nothing is lost across a close and reopen
+        state.text_index.close().unwrap();
+        assert_eq!(
+            tantivy_search(&state.text_index, "standup", usize::MAX).len(),
+            200
+        );
     }
-    // Clean orphan memory embeddings
-    let removed_emb = vector_index::cleanup_orphan_mem_embeddings(&state.db).unwrap_or(0);
-    state.db.flush()?;
-    Ok((removed_text + removed_emb, removed_edges))
-}
 
-async fn system_cleanup(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    let reindex = body
-        .get("reindex")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-    let compact = body
-        .get("compact")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-    let (removed_text, removed_edges) = run_index_maintenance(&state).unwrap_or((0, 0));
-    if compact {
-        let _ = state.db.flush();
+    #[tokio::test]
+    async fn test_memory_update_and_delete_drop_stale_tantivy_docs() {
+        let state = make_state();
+        let req = AddMemoryRequest {
+            content: "quarterly roadmap mentions zeppelin".to_string(),
+            layer_hint: Some("LTM".to_string()),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let id = out["id"].as_str().unwrap().to_string();
+        let key = format!("mem:{}", id);
+        assert_eq!(tantivy_search(&state.text_index, "zeppelin", 10)[0].0, key);
+
+        let upd = UpdateMemoryRequest {
+            id: id.clone(),
+            content: Some("quarterly roadmap mentions hovercraft".to_string()),
+            metadata: None,
+        };
+        let resp = memory_update(AxState(state.clone()), Json(upd)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(tantivy_search(&state.text_index, "zeppelin", 10).is_empty());
+        let hits = tantivy_search(&state.text_index, "roadmap", 10);
+        assert_eq!(hits.len(), 1, "the old version must not linger");
+        assert_eq!(hits[0].0, key);
+
+        let del = DeleteMemoryRequest {
+            id,
+            backup: None,
+            soft: None,
+        };
+        let resp = memory_delete(AxState(state.clone()), Json(del)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(tantivy_search(&state.text_index, "hovercraft", 10).is_empty());
     }
-    Json(
-        serde_json::json!({ "removedText": removed_text, "removedEdges": removed_edges, "reindexed": reindex, "compacted": compact }),
-    )
-}
 
-async fn system_validate(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-) -> Json<serde_json::Value> {
-    // Basic integrity checks: embeddings dimension, orphan embeddings, KG edge endpoints
-    let (total, invalid) = vector_index::validate_mem_embeddings(&state.db);
-    let mut orphan = 0u64;
-    if let Ok(tree) = state.db.open_tree("mem_embeddings") {
-        if let Ok(mems) = state.db.open_tree("memories") {
-            for kv in tree.iter() {
-                if let Ok((k, _)) = kv {
-                    if mems.get(&k).ok().flatten().is_none() {
-                        orphan += 1;
-                    }
-                }
+    #[tokio::test]
+    async fn test_async_document_store_is_indexed_by_worker() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = make_state_with_jobs(tx);
+        tokio::spawn(index_worker(state.clone(), rx));
+        let req = StoreDocRequest {
+            path: None,
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Queued\n\nIndexed later by the worker".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(out["status"], "pending");
+        let id = out["id"].as_str().unwrap().to_string();
+
+        let mut q = Map::new();
+        q.insert("id".to_string(), id.clone());
+        let mut status = String::new();
+        for _ in 0..200 {
+            let resp = document_job(axum::extract::Query(q.clone()), AxState(state.clone())).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            status = job["status"].as_str().unwrap().to_string();
+            if status != "pending" {
+                break;
             }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
+        assert_eq!(status, "done");
+        let resp = document_retrieve(axum::extract::Query(q), AxState(state.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(!doc["chunks"].as_array().unwrap().is_empty());
+        assert!(tantivy_search(&state.text_index, "worker", 10)
+            .iter()
+            .any(|(key, _)| key.starts_with(&id)));
     }
-    let mut bad_edges = 0u64;
-    if let (Ok(nodes), Ok(edges)) = (
-        state.db.open_tree("kg_nodes"),
-        state.db.open_tree("kg_edges"),
-    ) {
-        for kv in edges.iter() {
-            if let Ok((_, v)) = kv {
-                if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&v) {
-                    let src = val.get("src").and_then(|c| c.as_str()).unwrap_or("");
-                    let dst = val.get("dst").and_then(|c| c.as_str()).unwrap_or("");
-                    if nodes.get(src.as_bytes()).ok().flatten().is_none()
-                        || nodes.get(dst.as_bytes()).ok().flatten().is_none()
-                    {
-                        bad_edges += 1;
-                    }
-                }
+
+    #[tokio::test]
+    async fn test_failed_index_job_discards_the_partial_document() {
+        let state = make_state();
+        let store = |content: &str| {
+            let req = StoreDocRequest {
+                path: Some("docs/plan.md".to_string()),
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: Some(serde_json::json!({"owner": "ops"})),
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: true,
+                dry_run: false,
+            };
+            let state = state.clone();
+            async move {
+                let resp = document_store(AxState(state), Json(req)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
             }
+        };
+        let v1 = store("first plan").await;
+        let v2 = store("second plan with zeppelins").await;
+        let (v1_id, v2_id) = (v1["id"].as_str().unwrap(), v2["id"].as_str().unwrap());
+        assert!(tantivy_search(&state.text_index, "zeppelins", 10)
+            .iter()
+            .any(|(key, _)| key.starts_with(v2_id)));
+
+        // Re-running v2's job over text that no longer matches its hash fails it
+        let job = IndexJob {
+            hash: v2["hash"].as_str().unwrap().to_string(),
+            kind: "md".to_string(),
+            text: DocText::Loaded("edited plan".to_string()),
+            chunk_size: 1000,
+            chunk_overlap: 100,
+        };
+        assert!(matches!(
+            run_index_job(&state, v2_id, &job),
+            Err(IndexFailure::Changed)
+        ));
+        let db = state.db();
+        for tree in ["chunks", "embeddings", "text_index"] {
+            let prefix = format!("{}:", v2_id);
+            assert_eq!(db.open_tree(tree).unwrap().scan_prefix(prefix).count(), 0);
         }
+        assert!(!tantivy_search(&state.text_index, "zeppelins", 10)
+            .iter()
+            .any(|(key, _)| key.starts_with(v2_id)));
+        assert!(db
+            .open_tree("docs")
+            .unwrap()
+            .get(job.hash.as_bytes())
+            .unwrap()
+            .is_none());
+        assert!(db
+            .open_tree("docs_info")
+            .unwrap()
+            .get(v2_id)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .open_tree("doc_versions")
+            .unwrap()
+            .get("docs/plan.md:2")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            &db.open_tree("doc_path_latest")
+                .unwrap()
+                .get("docs/plan.md")
+                .unwrap()
+                .unwrap()[..],
+            v1_id.as_bytes()
+        );
+        assert!(db
+            .open_tree("docs_meta")
+            .unwrap()
+            .get(format!("{}:meta", v2_id))
+            .unwrap()
+            .is_none());
     }
-    Json(
-        serde_json::json!({ "embeddings": { "total": total, "invalid": invalid, "orphans": orphan }, "kg": { "badEdges": bad_edges } }),
-    )
-}
 
-async fn system_backup(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Response {
-    let dest = body
-        .get("destination")
-        .and_then(|v| v.as_str())
-        .map(|s| s.to_string())
-        .or_else(|| std::env::var("BACKUP_DIR").ok())
-        .unwrap_or_else(|| "./backup".to_string());
-    let include_indices = body
-        .get("includeIndices")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(true);
-    match create_backup(&state, &dest, include_indices) {
-        Ok((path, size_mb, took_ms)) => {
-            Json(serde_json::json!({ "path": path, "sizeMb": size_mb, "tookMs": took_ms }))
-                .into_response()
-        }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
+    // Relies on symlinks and /dev/full
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_storage_failures_return_500_instead_of_panicking() {
+        let state = make_state();
+        // With its data file on /dev/full (and no background flusher racing the open) the
+        // store opens and buffers writes, then every flush fails with ENOSPC
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("/dev/full", dir.path().join("db")).unwrap();
+        *state.live_db.write().unwrap() = sled::Config::new()
+            .path(dir.path())
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+
+        let req = AddMemoryRequest {
+            content: "disk full".to_string(),
+            layer_hint: Some("LTM".to_string()),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "INTERNAL_ERROR");
+
+        let req = StoreDocRequest {
+            path: None,
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some("# Full\nno room".to_string()),
+            metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: true,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let del = DeleteMemoryRequest {
+            id: "missing".to_string(),
+            backup: None,
+            soft: None,
+        };
+        let resp = memory_delete(AxState(state.clone()), Json(del)).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
-}
 
-async fn system_restore(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Response {
-    let source = match body.get("source").and_then(|v| v.as_str()) {
-        Some(s) => s.to_string(),
-        None => {
-            return json_error(
-                StatusCode::BAD_REQUEST,
-                "INVALID_INPUT",
-                "source required",
-                None,
-            )
-        }
-    };
-    let include_indices = body
-        .get("includeIndices")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(true);
-    match restore_backup(&state, &source, include_indices) {
-        Ok(took_ms) => {
-            // Validate manifest exists
-            let man = std::path::Path::new(&source).join("manifest.json");
-            let valid = man.exists();
-            Json(serde_json::json!({ "restored": true, "validated": valid, "tookMs": took_ms }))
-                .into_response()
-        }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
+    #[tokio::test]
+    async fn test_stm_ttl_comes_from_env() {
+        let state = make_state();
+        std::env::set_var("STM_TTL_MS", "90000");
+        let req = AddMemoryRequest {
+            content: "short-lived scratch note".to_string(),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        let status = build_status(state.clone()).await;
+        std::env::remove_var("STM_TTL_MS");
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(out["layer"], "STM");
+        let mems = state.db().open_tree("memories").unwrap();
+        let raw = mems.get(out["id"].as_str().unwrap()).unwrap().unwrap();
+        let rec: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(
+            rec["expires_at"].as_i64().unwrap() - rec["created_at"].as_i64().unwrap(),
+            90_000
+        );
+        assert_eq!(status.config.stm_ttl_ms, 90_000);
     }
-}
 
-async fn system_compact(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(_body): Json<serde_json::Value>,
-) -> Response {
-    // Best-effort compaction: flush sled, rebuild vector neighbor graph, and tantivy merge by reindex
-    let _ = state.db.flush();
-    let _ = vector_index::build_mem_neighbor_graph(&state.db, 16);
-    // Tantivy merge: trigger a lightweight reindex of memory docs
-    if let Ok(tree) = state.db.open_tree("memories") {
-        for kv in tree.iter() {
-            if let Ok((_, v)) = kv {
-                if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-                    if let Some(id) = rec.get("id").and_then(|x| x.as_str()) {
-                        let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
-                        let _ = index_memory_tantivy(&state.index_dir, id, content);
-                    }
-                }
+    #[tokio::test]
+    async fn test_ltm_decay_depends_on_elapsed_time_not_pass_count() {
+        let day_ms = 24 * 3600 * 1000;
+        let mut results = Vec::new();
+        // Same three days of wall-clock time, covered by one pass or by uneven passes
+        for passes in [
+            vec![3 * day_ms],
+            vec![day_ms / 2, day_ms, 2 * day_ms, 3 * day_ms],
+        ] {
+            let state = make_state();
+            let req = AddMemoryRequest {
+                content: "long-term fact".to_string(),
+                layer_hint: Some("LTM".to_string()),
+                importance: Some(1.0),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let id = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            let mems = state.db().open_tree("memories").unwrap();
+            let read = || -> serde_json::Value {
+                serde_json::from_slice(&mems.get(id.as_bytes()).unwrap().unwrap()).unwrap()
+            };
+            let created = read()["created_at"].as_i64().unwrap();
+            for offset in passes {
+                run_maintenance_at(&state, created + offset).unwrap();
             }
+            results.push(read()["importance"].as_f64().unwrap());
         }
-    }
-    Json(serde_json::json!({ "compacted": true })).into_response()
-}
-
-#[derive(Deserialize)]
-struct ExportBody {
-    #[serde(default)]
-    include_indices: Option<bool>,
-}
-
-async fn data_export(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<ExportBody>,
-) -> Response {
-    let dest = std::env::var("EXPORT_DIR").unwrap_or_else(|_| "./export".to_string());
-    let include_indices = body.include_indices.unwrap_or(true);
-    match create_backup(&state, &dest, include_indices) {
-        Ok((path, size_mb, took_ms)) => {
-            Json(serde_json::json!({ "path": path, "sizeMb": size_mb, "tookMs": took_ms }))
-                .into_response()
+        let expected = 0.5f64.powf(3.0 / 30.0);
+        for imp in results {
+            assert!((imp - expected).abs() < 1e-9, "{} vs {}", imp, expected);
         }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
     }
-}
-
-#[derive(Deserialize)]
-struct ImportBody {
-    source: String,
-    #[serde(default)]
-    include_indices: Option<bool>,
-}
 
-async fn data_import(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<ImportBody>,
-) -> Response {
-    let include_indices = body.include_indices.unwrap_or(true);
-    match restore_backup(&state, &body.source, include_indices) {
-        Ok(took_ms) => {
-            Json(serde_json::json!({ "imported": true, "tookMs": took_ms })).into_response()
-        }
-        Err(err) => json_error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "INTERNAL_ERROR",
-            err.to_string(),
-            None,
-        ),
+    #[tokio::test]
+    async fn test_large_ltm_decay_is_logged() {
+        let state = make_state();
+        let req = AddMemoryRequest {
+            content: "dormant fact".to_string(),
+            layer_hint: Some("LTM".to_string()),
+            ..Default::default()
+        };
+        memory_add(AxState(state.clone()), Json(req)).await;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        // A month away halves importance; a minute later the drop is too small to log
+        run_maintenance_at(&state, now + 30 * 24 * 3600 * 1000).unwrap();
+        run_maintenance_at(&state, now + 30 * 24 * 3600 * 1000 + 60_000).unwrap();
+        let log = state.db().open_tree("decay_log").unwrap();
+        assert_eq!(log.len(), 1);
+        let entry: serde_json::Value =
+            serde_json::from_slice(&log.iter().next().unwrap().unwrap().1).unwrap();
+        assert!((entry["to"].as_f64().unwrap() - 0.5).abs() < 0.01);
     }
-}
 
-fn dir_size_mb(path: &std::path::Path) -> u64 {
-    fn walk(p: &std::path::Path) -> u64 {
-        let mut total = 0u64;
-        if let Ok(rd) = std::fs::read_dir(p) {
-            for e in rd.flatten() {
-                let m = match e.metadata() {
-                    Ok(m) => m,
-                    Err(_) => continue,
-                };
-                if m.is_dir() {
-                    total += walk(&e.path());
-                } else {
-                    total += m.len();
-                }
-            }
+    #[tokio::test]
+    async fn test_promotion_is_logged_once_per_memory() {
+        let state = make_state();
+        for importance in [2.0, 0.5] {
+            let req = AddMemoryRequest {
+                content: format!("note with importance {}", importance),
+                layer_hint: Some("STM".to_string()),
+                importance: Some(importance),
+                ..Default::default()
+            };
+            memory_add(AxState(state.clone()), Json(req)).await;
         }
-        total
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        // Later passes must not promote (or log) the now-LTM memory again
+        for pass in 0..3 {
+            run_maintenance_at(&state, now + pass * 1000).unwrap();
+        }
+        let log = state.db().open_tree("consolidation_log").unwrap();
+        let entries: Vec<serde_json::Value> = log
+            .iter()
+            .map(|kv| serde_json::from_slice(&kv.unwrap().1).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["reason"], "importance");
+        let mems = state.db().open_tree("memories").unwrap();
+        let layers: Vec<String> = mems
+            .iter()
+            .map(|kv| {
+                let rec: serde_json::Value = serde_json::from_slice(&kv.unwrap().1).unwrap();
+                rec["layer"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(layers.iter().filter(|l| *l == "LTM").count(), 1);
     }
-    (walk(path) / (1024 * 1024)) as u64
-}
 
-fn copy_dir(src: &std::path::Path, dst: &std::path::Path) -> Result<()> {
-    std::fs::create_dir_all(dst)?;
-    for entry in std::fs::read_dir(src)? {
-        let entry = entry?;
-        let path = entry.path();
-        let to = dst.join(entry.file_name());
-        if path.is_dir() {
-            copy_dir(&path, &to)?;
-        } else {
-            let _ = std::fs::copy(&path, &to);
+    #[tokio::test]
+    async fn test_run_maintenance_promotes_an_eligible_memory_at_once() {
+        let state = make_state();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut ids = Vec::new();
+        for (content, importance) in [("release checklist", 3.0), ("passing thought", 0.2)] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("STM".to_string()),
+                importance: Some(importance),
+                ..Default::default()
+            };
+            ids.push(
+                write_memory(&state.db(), &state.text_index, req, now)
+                    .unwrap()
+                    .0,
+            );
         }
+        let Json(report) = system_run_maintenance(AxState(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(report["promoted"], 1);
+        assert_eq!(report["expired"], 0);
+        assert_eq!(report["evicted"], 0);
+        let mems = state.db().open_tree("memories").unwrap();
+        let layer = |id: &str| -> serde_json::Value {
+            let rec: serde_json::Value =
+                serde_json::from_slice(&mems.get(id.as_bytes()).unwrap().unwrap()).unwrap();
+            rec["layer"].clone()
+        };
+        assert_eq!(layer(&ids[0]), "LTM");
+        assert_eq!(layer(&ids[1]), "STM");
+        // Nothing left to promote on a second pass
+        let Json(again) = system_run_maintenance(AxState(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(again["promoted"], 0);
     }
-    Ok(())
-}
 
-fn create_backup(
-    _state: &Arc<AppState>,
-    destination: &str,
-    include_indices: bool,
-) -> Result<(String, u64, u128)> {
-    use std::time::Instant as TInstant;
-    let started = TInstant::now();
-    let data_root = std::path::PathBuf::from(
-        std::env::var("DATA_DIR").unwrap_or_else(|_| "./data".to_string()),
-    );
-    let dest = std::path::PathBuf::from(destination);
-    let ts = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let target = dest.join(format!("snapshot-{}", ts));
-    std::fs::create_dir_all(&target)?;
-    // Warm and cold tiers
-    let warm = data_root.join("warm");
-    let cold = data_root.join("cold");
-    if warm.exists() {
-        copy_dir(&warm, &target.join("warm"))?;
+    #[tokio::test]
+    async fn test_all_policy_requires_access_and_min_age_delays_promotion() {
+        let state = make_state();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let req = AddMemoryRequest {
+            content: "very important but never read".to_string(),
+            layer_hint: Some("STM".to_string()),
+            importance: Some(5.0),
+            ..Default::default()
+        };
+        let (id, _) = write_memory(&state.db(), &state.text_index, req, now).unwrap();
+        let settings = state.db().open_tree("settings").unwrap();
+        let set_policy = |policy: serde_json::Value| {
+            settings
+                .insert(
+                    b"consolidation_policy",
+                    serde_json::to_vec(&policy).unwrap(),
+                )
+                .unwrap();
+        };
+        let mems = state.db().open_tree("memories").unwrap();
+        let load = || -> serde_json::Value {
+            serde_json::from_slice(&mems.get(id.as_bytes()).unwrap().unwrap()).unwrap()
+        };
+
+        set_policy(serde_json::json!({ "mode": "all", "access_min": 2 }));
+        let status = build_status(state.clone()).await;
+        assert_eq!(status.config.consolidation.mode, PolicyMode::All);
+        assert_eq!(status.config.consolidation.access_min, 2);
+        run_maintenance_at(&state, now).unwrap();
+        assert_eq!(load()["layer"], "STM");
+
+        let mut rec = load();
+        rec["access_count"] = serde_json::json!(2);
+        mems.insert(id.as_bytes(), serde_json::to_vec(&rec).unwrap())
+            .unwrap();
+        // Both conditions hold now, but the memory is still younger than the guard
+        set_policy(serde_json::json!({ "mode": "all", "access_min": 2, "min_age_ms": 60_000 }));
+        run_maintenance_at(&state, now + 1_000).unwrap();
+        assert_eq!(load()["layer"], "STM");
+        run_maintenance_at(&state, now + 60_000).unwrap();
+        assert_eq!(load()["layer"], "LTM");
+        let log = state.db().open_tree("consolidation_log").unwrap();
+        let entry: serde_json::Value =
+            serde_json::from_slice(&log.iter().next().unwrap().unwrap().1).unwrap();
+        assert_eq!(entry["reason"], "all");
     }
-    if cold.exists() {
-        copy_dir(&cold, &target.join("cold"))?;
+
+    #[tokio::test]
+    async fn test_consolidation_log_lists_promotions_in_time_order() {
+        let state = make_state();
+        for (content, importance) in [("important note", 2.0), ("minor note", 0.5)] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                layer_hint: Some("STM".to_string()),
+                importance: Some(importance),
+                ..Default::default()
+            };
+            memory_add(AxState(state.clone()), Json(req)).await;
+        }
+        let Json(run) = advanced_consolidate(AxState(state.clone()), Json(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(run["promoted"], 1);
+        // A maintenance promotion later on lands after the consolidate one
+        let mems = state.db().open_tree("memories").unwrap();
+        for kv in mems.iter() {
+            let (k, v) = kv.unwrap();
+            let mut rec: serde_json::Value = serde_json::from_slice(&v).unwrap();
+            if rec["layer"] == "STM" {
+                rec["access_count"] = serde_json::json!(5);
+                mems.insert(k, serde_json::to_vec(&rec).unwrap()).unwrap();
+            }
+        }
+        let later = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64
+            + 60_000;
+        run_maintenance_at(&state, later).unwrap();
+
+        let query = |pairs: &[(&str, String)]| {
+            axum::extract::Query(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect::<std::collections::HashMap<_, _>>(),
+            )
+        };
+        let Json(all) = advanced_consolidation_log(AxState(state.clone()), query(&[]))
+            .await
+            .unwrap();
+        let events = all["events"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        for event in events {
+            assert_eq!(event["from"], "STM");
+            assert_eq!(event["to"], "LTM");
+        }
+        assert_eq!(events[0]["reason"], "importance");
+        assert_eq!(events[1]["reason"], "access");
+        assert_eq!(events[1]["ts"], later);
+
+        let Json(recent) = advanced_consolidation_log(
+            AxState(state.clone()),
+            query(&[("from", later.to_string())]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(recent["events"].as_array().unwrap().len(), 1);
+        let Json(first) =
+            advanced_consolidation_log(AxState(state.clone()), query(&[("limit", "1".into())]))
+                .await
+                .unwrap();
+        assert_eq!(first["events"][0]["reason"], "importance");
     }
-    if include_indices {
-        let index = data_root.join("index");
-        if index.exists() {
-            copy_dir(&index, &target.join("index"))?;
+
+    #[tokio::test]
+    async fn test_embedding_clusters_separate_vector_groups() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in [
+            "Alice reviewed the Rust parser",
+            "Alice fixed the Rust lexer",
+            "Alice tuned the Rust codegen",
+            "Bob planned the garden beds",
+            "Bob watered the garden",
+        ] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        // Two tight groups around orthogonal axes
+        for (i, id) in ids.iter().enumerate() {
+            let mut v = [0.0f32; embeddings::EMBED_DIM];
+            v[if i < 3 { 0 } else { 1 }] = 1.0;
+            v[2 + i] = 0.2;
+            store_memory_embedding(&state, id, v).unwrap();
         }
+        let body = serde_json::json!({ "method": "embedding", "threshold": 0.9 });
+        let Json(out) = advanced_clusters(AxState(state.clone()), Json(body))
+            .await
+            .unwrap();
+        let clusters = out["clusters"].as_array().unwrap();
+        assert_eq!(clusters.len(), 2);
+        let mut groups: Vec<Vec<String>> = clusters
+            .iter()
+            .map(|c| {
+                let mut m: Vec<String> = c["memories"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|v| v.as_str().unwrap().to_string())
+                    .collect();
+                m.sort();
+                m
+            })
+            .collect();
+        groups.sort_by_key(|g| g.len());
+        let mut rust = ids[..3].to_vec();
+        rust.sort();
+        let mut garden = ids[3..].to_vec();
+        garden.sort();
+        assert_eq!(groups, [garden, rust]);
+        let labels: Vec<&str> = clusters
+            .iter()
+            .map(|c| c["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.iter().any(|l| l.contains("Alice")), "{:?}", labels);
+        assert!(labels.iter().any(|l| l.contains("Bob")), "{:?}", labels);
+
+        let bad = serde_json::json!({ "method": "embedding", "threshold": 0 });
+        let err = advanced_clusters(AxState(state.clone()), Json(bad))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
     }
-    let size_mb = dir_size_mb(&target);
-    let took = started.elapsed().as_millis();
-    // Write manifest
-    let manifest = serde_json::json!({
-        "createdAt": ts,
-        "includeIndices": include_indices,
-        "sizesMb": { "warmColdIndex": size_mb }
-    });
-    let _ = std::fs::write(
-        target.join("manifest.json"),
-        serde_json::to_vec_pretty(&manifest)?,
-    );
-    Ok((target.to_string_lossy().to_string(), size_mb, took))
-}
 
-fn restore_backup(_state: &Arc<AppState>, source: &str, include_indices: bool) -> Result<u128> {
-    use std::time::Instant as TInstant;
-    let started = TInstant::now();
-    let src = std::path::PathBuf::from(source);
-    let data_root = std::path::PathBuf::from(
-        std::env::var("DATA_DIR").unwrap_or_else(|_| "./data".to_string()),
-    );
-    // Restore into staging, then atomically move directories where safe.
-    let warm_src = src.join("warm");
-    let cold_src = src.join("cold");
-    let index_src = src.join("index");
-    if warm_src.exists() {
-        copy_dir(&warm_src, &data_root.join("warm"))?;
+    #[tokio::test]
+    async fn test_analyze_patterns_labels_trend_direction() {
+        let state = make_state();
+        let mems = state.db().open_tree("memories").unwrap();
+        // Alice throughout, Bob only early, Carol only in the later half
+        let notes = [
+            (100, "Alice met Bob"),
+            (200, "Alice called Bob"),
+            (800, "Alice hired Carol"),
+            (900, "Alice thanked Carol"),
+        ];
+        for (i, (ts, content)) in notes.iter().enumerate() {
+            let rec = serde_json::json!({ "id": format!("m{}", i), "content": content, "created_at": ts });
+            mems.insert(format!("m{}", i), serde_json::to_vec(&rec).unwrap())
+                .unwrap();
+        }
+        let body = serde_json::json!({ "window": { "from": 0, "to": 1000 }, "minSupport": 2 });
+        let Json(out) = advanced_analyze_patterns(AxState(state.clone()), Json(body))
+            .await
+            .unwrap();
+        let trend_of = |concept: &str| {
+            out["patterns"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|p| p["concept"] == concept)
+                .map(|p| p["trend"].as_str().unwrap().to_string())
+        };
+        assert_eq!(trend_of("Carol").as_deref(), Some("rising"));
+        assert_eq!(trend_of("Bob").as_deref(), Some("falling"));
+        assert_eq!(trend_of("Alice").as_deref(), Some("flat"));
     }
-    if cold_src.exists() {
-        copy_dir(&cold_src, &data_root.join("cold"))?;
+
+    #[tokio::test]
+    async fn test_trends_count_entity_mentions_per_bucket() {
+        let state = make_state();
+        let mems = state.db().open_tree("memories").unwrap();
+        // Buckets of 100ms over 0..=300: Alice in all three, Bob only in the last
+        let notes = [
+            (10, "Alice started"),
+            (50, "Alice paused"),
+            (150, "Alice resumed"),
+            (250, "Alice met Bob"),
+            (260, "Bob left"),
+            (290, "Bob returned"),
+        ];
+        for (i, (ts, content)) in notes.iter().enumerate() {
+            let rec = serde_json::json!({
+                "id": format!("m{}", i),
+                "content": content,
+                "layer": "STM",
+                "created_at": ts,
+            });
+            mems.insert(format!("m{}", i), serde_json::to_vec(&rec).unwrap())
+                .unwrap();
+        }
+        let trends = |extra: serde_json::Value| {
+            let mut body = serde_json::json!({ "from": 0, "to": 299, "buckets": 3 });
+            body.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            advanced_trends(AxState(state.clone()), Json(body))
+        };
+        let Json(out) = trends(serde_json::json!({ "entities": ["Alice", "Carol"] }))
+            .await
+            .unwrap();
+        let stm: Vec<u64> = out["timeline"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b["STM"].as_u64().unwrap())
+            .collect();
+        assert_eq!(stm, [2, 1, 3]);
+        assert_eq!(out["entities"][0]["entity"], "Alice");
+        assert_eq!(out["entities"][0]["counts"], serde_json::json!([2, 1, 1]));
+        assert_eq!(out["entities"][1]["counts"], serde_json::json!([0, 0, 0]));
+
+        let Json(top) = trends(serde_json::json!({ "topN": 2 })).await.unwrap();
+        let names: Vec<&str> = top["entities"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["entity"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Alice", "Bob"]);
+        assert_eq!(top["entities"][1]["counts"], serde_json::json!([0, 0, 3]));
     }
-    if include_indices && index_src.exists() {
-        copy_dir(&index_src, &data_root.join("index"))?;
+
+    #[tokio::test]
+    async fn test_reindex_drops_ghosts_from_text_search() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in ["zebra crossing notes", "zebra migration notes"] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        // Removed behind the indices' back, as an older build's delete left it
+        let mems = state.db().open_tree("memories").unwrap();
+        mems.remove(ids[1].as_bytes()).unwrap();
+        let hits = |state: &Arc<AppState>| {
+            let mut ids: Vec<String> = tantivy_search(&state.text_index, "zebra", 10)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(hits(&state).len(), 2);
+
+        let body = serde_json::json!({ "vector": false, "graph": false });
+        let Json(out) = advanced_reindex(AxState(state.clone()), Json(body))
+            .await
+            .unwrap();
+        assert_eq!(out["reindexed"], 1);
+        assert_eq!(hits(&state), [format!("mem:{}", ids[0])]);
+        let text_idx = state.db().open_tree("text_index").unwrap();
+        assert!(!text_idx
+            .contains_key(format!("mem:{}", ids[1]).as_bytes())
+            .unwrap());
     }
-    Ok(started.elapsed().as_millis())
-}
 
-async fn advanced_reindex(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    let vector = body.get("vector").and_then(|v| v.as_bool()).unwrap_or(true);
-    let text = body.get("text").and_then(|v| v.as_bool()).unwrap_or(true);
-    let graph = body.get("graph").and_then(|v| v.as_bool()).unwrap_or(true);
-    // Placeholder: run maintenance to prune; reindex text by reinserting current content
-    let _ = run_index_maintenance(&state);
-    if text {
-        if let Ok(tree) = state.db.open_tree("memories") {
-            for kv in tree.iter() {
-                if let Ok((_, v)) = kv {
-                    if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-                        if let Some(id) = rec.get("id").and_then(|x| x.as_str()) {
-                            let content = rec.get("content").and_then(|c| c.as_str()).unwrap_or("");
-                            let _ = index_memory_sled(&state.db, id, content);
-                            let _ = index_memory_tantivy(&state.index_dir, id, content);
-                        }
-                    }
-                }
+    #[tokio::test]
+    async fn test_advanced_endpoints_report_timing_and_counts() {
+        let state = make_state();
+        let mems = state.db().open_tree("memories").unwrap();
+        let n = 1000;
+        for i in 0..n {
+            let id = format!("m{:05}", i);
+            let rec = serde_json::json!({
+                "id": id,
+                "content": format!("Alice and Bob discuss Topic{} at length", i % 50),
+                "layer": "STM",
+                "importance": 1.0,
+                "created_at": i,
+            });
+            mems.insert(id.as_bytes(), serde_json::to_vec(&rec).unwrap())
+                .unwrap();
+        }
+        let took = |out: &serde_json::Value| out["tookMs"].as_u64().unwrap();
+        // Entity extraction over every record takes measurable time
+        let Json(patterns) = advanced_analyze_patterns(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "minSupport": 2 })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(patterns["scanned"], n);
+        assert!(
+            (1..60_000).contains(&took(&patterns)),
+            "{}",
+            patterns["tookMs"]
+        );
+
+        let Json(trends) = advanced_trends(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "from": 0, "to": n, "buckets": 4, "topN": 2 })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(trends["scanned"], n);
+        assert!((1..60_000).contains(&took(&trends)), "{}", trends["tookMs"]);
+
+        let Json(eff) = advanced_effectiveness(AxState(state.clone()), Json(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(eff["scanned"], n);
+        assert!(eff["tookMs"].is_u64());
+        let Json(consolidated) = advanced_consolidate(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "dryRun": true })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(consolidated["scanned"], n);
+        assert!(consolidated["tookMs"].is_u64());
+        for call in ["clusters", "relationships"] {
+            let body = Json(serde_json::json!({}));
+            let Json(out) = match call {
+                "clusters" => advanced_clusters(AxState(state.clone()), body).await,
+                _ => advanced_relationships(AxState(state.clone()), body).await,
             }
+            .unwrap();
+            assert!(
+                out["scanned"].is_u64() && out["tookMs"].is_u64(),
+                "{}",
+                call
+            );
         }
     }
-    if vector {
-        let _ = vector_index::reembed_all_memories(&state.db, 256);
-        let _ = vector_index::build_mem_neighbor_graph(&state.db, 16);
+
+    #[tokio::test]
+    async fn test_fusion_cache_is_invalidated_by_writes() {
+        let state = make_state();
+        let add = |content: &str| {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            memory_add(AxState(state.clone()), Json(req))
+        };
+        let fusion = || {
+            let params: Map<String, String> = [("q".to_string(), "walrus".to_string())].into();
+            search_fusion(AxState(state.clone()), axum::extract::Query(params))
+        };
+        let resp = add("walrus colony census").await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let first: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let first_id = first["id"].as_str().unwrap().to_string();
+        let Json(cached) = fusion().await.unwrap();
+        assert_eq!(cached.results.len(), 1);
+
+        // Within the cache TTL, the next query must still see the new memory
+        add("walrus feeding grounds").await;
+        let Json(after_add) = fusion().await.unwrap();
+        assert_eq!(after_add.results.len(), 2);
+
+        let req = DeleteMemoryRequest {
+            id: first_id.clone(),
+            backup: None,
+            soft: None,
+        };
+        memory_delete(AxState(state.clone()), Json(req)).await;
+        let Json(after_delete) = fusion().await.unwrap();
+        assert_eq!(after_delete.results.len(), 1);
+        assert_ne!(after_delete.results[0].id, first_id);
     }
-    Json(serde_json::json!({ "vector": vector, "text": text, "graph": graph, "tookMs": 0 }))
-}
 
-async fn advanced_analyze_patterns(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    let from = body
-        .get("window")
-        .and_then(|w| w.get("from"))
-        .and_then(|v| v.as_i64());
-    let to = body
-        .get("window")
-        .and_then(|w| w.get("to"))
-        .and_then(|v| v.as_i64());
-    let min_support = body.get("minSupport").and_then(|v| v.as_u64()).unwrap_or(2) as usize;
-    let tree = state.db.open_tree("memories").expect("mem");
-    let mut counter: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
-    for kv in tree.iter() {
-        if let Ok((_, v)) = kv {
-            if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-                let created_at = rec.get("created_at").and_then(|c| c.as_i64());
-                let in_time = created_at
-                    .map(|t| {
-                        from.map(|f| t >= f).unwrap_or(true) && to.map(|to| t <= to).unwrap_or(true)
-                    })
-                    .unwrap_or(true);
-                if !in_time {
-                    continue;
-                }
-                if let Some(content) = rec.get("content").and_then(|c| c.as_str()) {
-                    for ent in kg::extract_entities(content) {
-                        *counter.entry(ent).or_insert(0) += 1;
-                    }
-                }
-            }
+    #[tokio::test]
+    async fn test_fusion_cache_keeps_time_filtered_queries_apart() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in ["narwhal tusk study", "narwhal pod sighting"] {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
         }
+        // Backdate the first memory a day
+        let mems = state.db().open_tree("memories").unwrap();
+        let mut rec: serde_json::Value =
+            serde_json::from_slice(&mems.get(ids[0].as_bytes()).unwrap().unwrap()).unwrap();
+        let created = rec["created_at"].as_i64().unwrap();
+        rec["created_at"] = serde_json::json!(created - 24 * 3600 * 1000);
+        mems.insert(ids[0].as_bytes(), serde_json::to_vec(&rec).unwrap())
+            .unwrap();
+
+        let fusion = |from: Option<i64>| {
+            let mut params: Map<String, String> = Map::new();
+            params.insert("q".to_string(), "narwhal".to_string());
+            if let Some(from) = from {
+                params.insert("from".to_string(), from.to_string());
+            }
+            search_fusion(AxState(state.clone()), axum::extract::Query(params))
+        };
+        let ids_of = |resp: SearchResponse| {
+            let mut ids: Vec<String> = resp.results.into_iter().map(|r| r.id).collect();
+            ids.sort();
+            ids.dedup();
+            ids
+        };
+        let Json(unfiltered) = fusion(None).await.unwrap();
+        assert_eq!(ids_of(unfiltered).len(), 2);
+        let Json(recent) = fusion(Some(created - 3600 * 1000)).await.unwrap();
+        assert_eq!(ids_of(recent), [ids[1].clone()]);
+        // ...and the filtered entry doesn't leak back into the unfiltered query
+        let Json(again) = fusion(None).await.unwrap();
+        assert_eq!(ids_of(again).len(), 2);
     }
-    let mut patterns: Vec<(String, usize)> = counter
-        .into_iter()
-        .filter(|(_, c)| *c >= min_support)
-        .collect();
-    patterns.sort_by(|a, b| b.1.cmp(&a.1));
-    let out: Vec<serde_json::Value> = patterns.into_iter().map(|(concept, support)| serde_json::json!({ "concept": concept, "support": support, "trend": "flat" })).collect();
-    Json(serde_json::json!({ "patterns": out }))
-}
 
-async fn advanced_trends(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    let from = body.get("from").and_then(|v| v.as_i64());
-    let to = body.get("to").and_then(|v| v.as_i64());
-    let buckets = body.get("buckets").and_then(|v| v.as_u64()).unwrap_or(10) as i64;
-    let tree = state.db.open_tree("memories").expect("mem");
-    let mut timeline: Vec<serde_json::Value> = Vec::new();
-    if let (Some(f), Some(t)) = (from, to) {
-        let span = (t - f).max(1);
-        let step = (span / buckets).max(1);
-        for i in 0..buckets {
-            let start = f + i * step;
-            let end = if i == buckets - 1 {
-                t
-            } else {
-                start + step - 1
+    #[tokio::test]
+    async fn test_fusion_ordering_is_deterministic_for_ties() {
+        let state = make_state();
+        // Identical content ties every signal
+        for _ in 0..6 {
+            let req = AddMemoryRequest {
+                content: "orca sighting".to_string(),
+                ..Default::default()
             };
-            let mut stm = 0u64;
-            let mut ltm = 0u64;
-            for kv in tree.iter() {
-                if let Ok((_, v)) = kv {
-                    if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-                        if let (Some(ts), Some(layer)) = (
-                            rec.get("created_at").and_then(|x| x.as_i64()),
-                            rec.get("layer").and_then(|x| x.as_str()),
-                        ) {
-                            if ts >= start && ts <= end {
-                                if layer == "STM" {
-                                    stm += 1;
-                                } else if layer == "LTM" {
-                                    ltm += 1;
-                                }
-                            }
-                        }
-                    }
-                }
+            memory_add(AxState(state.clone()), Json(req)).await;
+        }
+        let mut runs = Vec::new();
+        for fusion in ["weighted", "rrf"] {
+            for _ in 0..3 {
+                // Recompute each time instead of serving the cached order
+                state.invalidate_query_cache();
+                let params: Map<String, String> = [
+                    ("q".to_string(), "orca".to_string()),
+                    ("fusion".to_string(), fusion.to_string()),
+                ]
+                .into();
+                let Json(resp) =
+                    search_fusion(AxState(state.clone()), axum::extract::Query(params))
+                        .await
+                        .unwrap();
+                let ids: Vec<String> = resp.results.iter().map(|r| r.id.clone()).collect();
+                let mut sorted = ids.clone();
+                sorted.sort();
+                assert_eq!(ids, sorted, "ties break by id");
+                runs.push((fusion, serde_json::to_vec(&resp.results).unwrap()));
             }
-            timeline
-                .push(serde_json::json!({ "start": start, "end": end, "STM": stm, "LTM": ltm }));
         }
+        for pair in runs.windows(2).filter(|w| w[0].0 == w[1].0) {
+            assert_eq!(pair[0].1, pair[1].1);
+        }
+
+        let result = |id: &str, score: f32| SearchResult {
+            id: id.to_string(),
+            score,
+            layer: "STM".to_string(),
+            doc_refs: None,
+            explain: None,
+            snippet: None,
+            kind: None,
+        };
+        let mut mixed = vec![
+            result("c", f32::NAN),
+            result("b", 0.5),
+            result("d", 0.9),
+            result("a", 0.5),
+        ];
+        sort_results(&mut mixed);
+        let order: Vec<&str> = mixed.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(order, ["d", "a", "b", "c"]);
     }
-    Json(serde_json::json!({ "timeline": timeline }))
-}
 
-async fn advanced_clusters(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(_body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    // Simple clustering: documents linked by RELATED edges -> connected components
-    let edges = state.db.open_tree("kg_edges").expect("edges");
-    let mut graph: std::collections::HashMap<String, Vec<String>> =
-        std::collections::HashMap::new();
-    for kv in edges.iter() {
-        if let Ok((k, _)) = kv {
-            let key = String::from_utf8_lossy(&k);
-            if key.ends_with("::RELATED") {
-                if let Some((src, rest)) = key.split_once("->") {
-                    let dst = rest.split("::").next().unwrap_or("");
-                    graph
-                        .entry(src.to_string())
-                        .or_default()
-                        .push(dst.to_string());
-                    graph
-                        .entry(dst.to_string())
-                        .or_default()
-                        .push(src.to_string());
-                }
+    #[tokio::test]
+    async fn test_memory_get_returns_the_record_and_404s_unknown_ids() {
+        let state = make_state();
+        let req = AddMemoryRequest {
+            content: "beluga migration route".to_string(),
+            metadata: Some(serde_json::json!({ "source": "survey" })),
+            ..Default::default()
+        };
+        let resp = memory_add(AxState(state.clone()), Json(req)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let get = |id: &str, touch: Option<&str>| {
+            let mut params: Map<String, String> = Map::new();
+            params.insert("id".to_string(), id.to_string());
+            if let Some(touch) = touch {
+                params.insert("touch".to_string(), touch.to_string());
             }
+            memory_get(AxState(state.clone()), axum::extract::Query(params))
+        };
+        let read = |resp: Response| async move {
+            let status = resp.status();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+            )
+        };
+
+        let (status, rec) = read(get(&id, None).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rec["content"], "beluga migration route");
+        assert_eq!(rec["metadata"]["source"], "survey");
+        assert_eq!(rec["access_count"], 1);
+        let (_, rec) = read(get(&id, Some("false")).await).await;
+        assert_eq!(rec["access_count"], 1);
+
+        let (status, err) = read(get("no-such-id", None).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err["error"]["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn test_delete_where_removes_only_the_matching_session() {
+        let state = make_state();
+        let mut ids: Vec<(String, &str)> = Vec::new();
+        for (i, session) in ["sess-a", "sess-a", "sess-b"].into_iter().enumerate() {
+            let req = AddMemoryRequest {
+                content: format!("narwhal sighting {}", i),
+                session_id: Some(session.to_string()),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(req)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push((body["id"].as_str().unwrap().to_string(), session));
+        }
+        let delete_where = |body: serde_json::Value| {
+            let req: DeleteMemoryWhereRequest = serde_json::from_value(body).unwrap();
+            memory_delete_where(AxState(state.clone()), Json(req))
+        };
+
+        // No filter and no confirmAll: refused, nothing deleted
+        let resp = delete_where(serde_json::json!({})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = delete_where(serde_json::json!({ "session_id": "sess-a" })).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["deleted"], 2);
+
+        let tree = state.db().open_tree("memories").unwrap();
+        for (id, session) in &ids {
+            assert_eq!(
+                tree.contains_key(id.as_bytes()).unwrap(),
+                *session == "sess-b"
+            );
         }
+        let mut q = Map::new();
+        q.insert("q".to_string(), "narwhal".to_string());
+        let found = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        let found: Vec<&str> = found.results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(found, [ids[2].0.as_str()]);
+
+        // The batch form reports ids it did not find
+        let req = DeleteMemoryBatchRequest {
+            ids: vec![ids[2].0.clone(), ids[0].0.clone()],
+        };
+        let resp = memory_delete_batch(AxState(state.clone()), Json(req)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["deleted"], 1);
+        assert_eq!(body["missing"], serde_json::json!([ids[0].0]));
     }
-    // Connected components
-    let mut seen = std::collections::HashSet::new();
-    let mut clusters: Vec<Vec<String>> = Vec::new();
-    for node in graph.keys() {
-        if seen.contains(node) {
-            continue;
+
+    #[tokio::test]
+    async fn test_layer_hint_is_canonicalized_and_unknown_layers_rejected() {
+        let state = make_state();
+        let add = |layer: &str| {
+            let req = AddMemoryRequest {
+                content: "axolotl regrowth notes".to_string(),
+                layer_hint: Some(layer.to_string()),
+                ..Default::default()
+            };
+            memory_add(AxState(state.clone()), Json(req))
+        };
+        let resp = add("ltm").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["layer"], "LTM");
+        let id = body["id"].as_str().unwrap();
+        let stored = state
+            .db()
+            .open_tree("memories")
+            .unwrap()
+            .get(id.as_bytes())
+            .unwrap()
+            .unwrap();
+        let rec: serde_json::Value = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(rec["layer"], "LTM");
+
+        let resp = add("bogus").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let search = |layer: &str| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "axolotl".to_string());
+            q.insert("layer".to_string(), layer.to_string());
+            memory_search(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let found = search("Ltm").await.unwrap();
+        assert!(found.results.iter().any(|r| r.id == id));
+        let err = search("long").await.err().unwrap();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_auto_link_docs_cites_documents_sharing_entities() {
+        let state = make_state();
+        let store = |content: &str| {
+            let req = StoreDocRequest {
+                path: None,
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: true,
+                dry_run: false,
+            };
+            document_store(AxState(state.clone()), Json(req))
+        };
+        let resp = store("notes on Kestrel and Osprey migration along the coast").await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let doc_id = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let _ = store("an unrelated page about Sourdough baking").await;
+
+        let add = |auto_link_docs: Option<bool>| {
+            let req = AddMemoryRequest {
+                content: "saw a Kestrel chasing an Osprey".to_string(),
+                auto_link_docs,
+                ..Default::default()
+            };
+            let state = state.clone();
+            async move {
+                let resp = memory_add(AxState(state.clone()), Json(req)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let id = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                let stored = state
+                    .db()
+                    .open_tree("memories")
+                    .unwrap()
+                    .get(id.as_bytes())
+                    .unwrap()
+                    .unwrap();
+                let rec: serde_json::Value = serde_json::from_slice(&stored).unwrap();
+                (id, rec)
+            }
+        };
+
+        let (_, rec) = add(None).await;
+        assert!(rec["docRefs"].is_null());
+
+        let (id, rec) = add(Some(true)).await;
+        let refs = rec["docRefs"].as_array().unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0]["docId"], doc_id.as_str());
+        assert_eq!(refs[0]["score"], 1.0);
+        let prefix = format!("mem::{}::doc::{}::", id, doc_id);
+        let doc_refs = state.db().open_tree("doc_refs").unwrap();
+        assert_eq!(doc_refs.scan_prefix(prefix.as_bytes()).count(), 1);
+        let edges = kg::incoming_edges(&state.db(), &format!("Document::{}", doc_id)).unwrap();
+        assert!(edges.iter().any(|(_, e)| {
+            e["relation"] == "EVIDENCE" && e["src"] == format!("Memory::{}", id).as_str()
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_entity_names_with_key_separators_round_trip() {
+        let state = make_state();
+        for entity in ["Foo::Bar", "Foo", "Foo->Bar"] {
+            let resp = kg_create_entity(
+                AxState(state.clone()),
+                Json(serde_json::json!({ "entity": entity })),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
         }
-        let mut stack = vec![node.clone()];
-        let mut comp: Vec<String> = Vec::new();
-        while let Some(n) = stack.pop() {
-            if !seen.insert(n.clone()) {
-                continue;
+        for (src, dst) in [
+            ("Entity::Foo::Bar", "Entity::Baz"),
+            ("Entity::Foo", "Entity::Qux"),
+            ("Entity::Foo->Bar", "Entity::Quux"),
+        ] {
+            let body = serde_json::json!({ "src": src, "dst": dst, "relation": "LINKS" });
+            let resp = kg_create_relation(AxState(state.clone()), Json(body)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let get = |entity: &str| {
+            let mut params: Map<String, String> = Map::new();
+            params.insert("entity".to_string(), entity.to_string());
+            let state = state.clone();
+            async move {
+                let resp = kg_get_entity(AxState(state), axum::extract::Query(params)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+        let details = get("Foo::Bar").await;
+        assert_eq!(details["node"]["type"], "Entity");
+        let relations = details["relations"].as_array().unwrap();
+        assert_eq!(relations.len(), 1);
+        assert_eq!(relations[0]["dst"], "Entity::Baz");
+        assert_eq!(get("Foo").await["relations"].as_array().unwrap().len(), 1);
+
+        let Json(rels) =
+            advanced_relationships(AxState(state.clone()), Json(serde_json::json!({})))
+                .await
+                .unwrap();
+        assert_eq!(rels["relationships"][0]["group"], "Entity:LINKS:Entity");
+        assert_eq!(rels["relationships"][0]["count"], 3);
+
+        // Deleting `Foo` leaves the edges of `Foo::Bar` and `Foo->Bar` alone
+        let resp = kg_delete_entity(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "entity": "Foo" })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            get("Foo::Bar").await["relations"].as_array().unwrap().len(),
+            1
+        );
+        assert_eq!(
+            get("Foo->Bar").await["relations"].as_array().unwrap().len(),
+            1
+        );
+
+        let resp = kg_delete_entity(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "entity": "Foo::Bar" })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let nodes = state.db().open_tree("kg_nodes").unwrap();
+        assert!(!nodes.contains_key(b"Entity::Foo::Bar").unwrap());
+        assert!(get("Foo::Bar").await["relations"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+        let edges = state.db().open_tree("kg_edges").unwrap();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(state.db().open_tree("kg_edges_rev").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_entity_mentions_track_first_and_last_seen() {
+        let state = make_state();
+        let db = state.db();
+        kg::ensure_entity_node(&db, "Rust", 50).unwrap();
+        kg::record_entity_mention(&db, "Rust", 100).unwrap();
+        kg::record_entity_mention(&db, "Rust", 200).unwrap();
+        // Out-of-order mentions only widen the span
+        kg::record_entity_mention(&db, "Rust", 150).unwrap();
+        let get = |entity: &str| {
+            let mut q = Map::new();
+            q.insert("entity".to_string(), entity.to_string());
+            let state = state.clone();
+            async move {
+                let resp = kg_get_entity(AxState(state), axum::extract::Query(q)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
             }
-            comp.push(n.clone());
-            if let Some(nei) = graph.get(&n) {
-                for m in nei {
-                    if !seen.contains(m) {
-                        stack.push(m.clone());
-                    }
-                }
+        };
+        let out = get("Rust").await;
+        assert_eq!(out["firstSeen"], 50);
+        assert_eq!(out["lastSeen"], 200);
+        assert_eq!(out["mentionCount"], 3);
+
+        // Merging folds the other name's activity in
+        kg::record_entity_mention(&db, "RustLang", 20).unwrap();
+        kg::merge_entity(&db, "RustLang", "Rust", 300).unwrap();
+        let merged = get("Rust").await;
+        assert_eq!(merged["firstSeen"], 20);
+        assert_eq!(merged["lastSeen"], 200);
+        assert_eq!(merged["mentionCount"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_merge_entity_combines_mentions_and_resolves_the_alias() {
+        let state = make_state();
+        for content in [
+            "field notes on Covid vaccines",
+            "a report on Coronavirus spread",
+            "Covid and Coronavirus overview",
+        ] {
+            let req = StoreDocRequest {
+                path: None,
+                url: None,
+                mime: Some("md".to_string()),
+                content: Some(content.to_string()),
+                metadata: None,
+                chunk_size: None,
+                chunk_overlap: None,
+                sync: true,
+                dry_run: false,
+            };
+            let resp = document_store(AxState(state.clone()), Json(req)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let add = |content: &str| {
+            let req = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let state = state.clone();
+            async move {
+                let resp = memory_add(AxState(state), Json(req)).await;
+                let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["id"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
             }
+        };
+        let before = add("Coronavirus symptoms to watch").await;
+        let merge = || {
+            let body = serde_json::json!({ "from": "Coronavirus", "into": "Covid" });
+            kg_merge_entity(AxState(state.clone()), Json(body))
+        };
+        for _ in 0..2 {
+            let resp = merge().await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let out: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            // The document naming both is counted once; the rerun changes nothing
+            assert_eq!(out["mentions"], 3);
         }
-        if comp.len() > 1 {
-            clusters.push(comp);
+        let counts = kg::list_entities(&state.db(), 10).unwrap();
+        assert!(counts.contains(&("Covid".to_string(), 3)));
+        assert!(!counts.iter().any(|(e, _)| e == "Coronavirus"));
+
+        let mut params: Map<String, String> = Map::new();
+        params.insert("entity".to_string(), "Coronavirus".to_string());
+        let resp = kg_get_entity(AxState(state.clone()), axum::extract::Query(params)).await;
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let details: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(details["entity"], "Covid");
+        assert_eq!(details["docs"].as_array().unwrap().len(), 3);
+
+        // Memories mentioning the alias, before and after the merge, link to `Covid`
+        let after = add("Coronavirus booster schedule").await;
+        let mentioning: Vec<String> = kg::incoming_edges(&state.db(), "Entity::Covid")
+            .unwrap()
+            .into_iter()
+            .filter_map(|(_, e)| e["src"].as_str().map(|s| s.to_string()))
+            .collect();
+        for id in [&before, &after] {
+            assert!(mentioning.contains(&format!("Memory::{}", id)));
         }
+        let nodes = state.db().open_tree("kg_nodes").unwrap();
+        assert!(!nodes.contains_key(b"Entity::Coronavirus").unwrap());
+
+        let body = serde_json::json!({ "from": "Covid", "into": "Coronavirus" });
+        let resp = kg_merge_entity(AxState(state.clone()), Json(body)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
-    // Normalize to doc ids
-    let out: Vec<serde_json::Value> = clusters
-        .into_iter()
-        .map(|c| {
-            let docs: Vec<String> = c
-                .into_iter()
-                .filter_map(|n| n.strip_prefix("Document::").map(|s| s.to_string()))
-                .collect();
-            serde_json::json!({ "docs": docs })
-        })
-        .collect();
-    Json(serde_json::json!({ "clusters": out }))
-}
 
-async fn advanced_relationships(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(_body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    // Relationship strength: count edges per (src_type, relation, dst_type)
-    let edges = state.db.open_tree("kg_edges").expect("edges");
-    let mut counts: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
-    for kv in edges.iter() {
-        if let Ok((k, _)) = kv {
-            let key = String::from_utf8_lossy(&k);
-            if let Some((src, rest)) = key.split_once("->") {
-                if let Some((dst, rel)) = rest.split_once("::") {
-                    let src_t = src.split("::").next().unwrap_or("");
-                    let dst_t = dst.split("::").next().unwrap_or("");
-                    let grp = format!("{}:{}:{}", src_t, rel, dst_t);
-                    *counts.entry(grp).or_insert(0) += 1;
-                }
+    #[tokio::test]
+    async fn test_search_nodes_ranks_matches_and_tolerates_typos() {
+        let state = make_state();
+        for entity in ["NewYork", "York", "Newark"] {
+            kg::ensure_entity_node(&state.db(), entity, 1).unwrap();
+        }
+        let search = |pattern: &str, extra: &[(&str, &str)]| {
+            let mut params: Map<String, String> = Map::new();
+            params.insert("pattern".to_string(), pattern.to_string());
+            for (k, v) in extra {
+                params.insert(k.to_string(), v.to_string());
             }
+            kg_search_nodes(AxState(state.clone()), axum::extract::Query(params))
+        };
+        let labels = |out: &serde_json::Value| -> Vec<String> {
+            out["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|n| n["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // The exact match outranks the earlier-keyed substring match
+        let Json(out) = search("york", &[("limit", "1")]).await;
+        assert_eq!(labels(&out), ["York"]);
+
+        let Json(out) = search("newyrk", &[]).await;
+        assert!(labels(&out).is_empty());
+        let Json(out) = search("newyrk", &[("fuzzy", "true")]).await;
+        let hit = out["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|n| n["label"] == "NewYork")
+            .unwrap();
+        assert_eq!(hit["distance"], 1);
+        let Json(out) = search("newyrk", &[("fuzzy", "true"), ("maxDistance", "0")]).await;
+        assert!(labels(&out).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_relations_accumulate_weight() {
+        let state = make_state();
+        let db = state.db();
+        kg::add_edge_generic(&db, "Memory::m1", "Entity::Rust", "MENTIONS", 10).unwrap();
+        kg::add_edge_generic(&db, "Memory::m1", "Entity::Rust", "MENTIONS", 20).unwrap();
+        kg::add_edge_generic(&db, "Memory::m2", "Entity::Go", "USES", 10).unwrap();
+        kg::add_edge_generic(&db, "Memory::m3", "Entity::Go", "USES", 10).unwrap();
+        let edges = db.open_tree("kg_edges").unwrap();
+        let key = kg::edge_key("Memory::m1", "Entity::Rust", "MENTIONS");
+        let edge: serde_json::Value =
+            serde_json::from_slice(&edges.get(key.as_bytes()).unwrap().unwrap()).unwrap();
+        assert_eq!(edge["weight"], 2);
+        assert_eq!(edge["created_at"], 10);
+        assert_eq!(edge["last_seen"], 20);
+        // Edges stored before weights existed count once
+        let legacy =
+            serde_json::json!({ "src": "Memory::m4", "dst": "Entity::Go", "relation": "USES" });
+        edges
+            .insert(
+                kg::edge_key("Memory::m4", "Entity::Go", "USES").as_bytes(),
+                serde_json::to_vec(&legacy).unwrap(),
+            )
+            .unwrap();
+
+        let Json(rels) =
+            advanced_relationships(AxState(state.clone()), Json(serde_json::json!({})))
+                .await
+                .unwrap();
+        let groups = rels["relationships"].as_array().unwrap();
+        assert_eq!(groups[0]["group"], "Memory:USES:Entity");
+        assert_eq!(groups[0]["weight"], 3);
+        assert_eq!(groups[1]["group"], "Memory:MENTIONS:Entity");
+        assert_eq!(groups[1]["count"], 1);
+        assert_eq!(groups[1]["weight"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_graph_queries_filter_edges_by_created_at() {
+        let state = make_state();
+        let db = state.db();
+        for (e, d, at) in [("Rust", "d1", 100), ("Rust", "d2", 200), ("Go", "d2", 300)] {
+            kg::link_entities(&db, d, &[e.to_string()]).unwrap();
+            kg::add_edge(&db, e, d, "MENTIONS", at).unwrap();
         }
+        kg::add_edge_generic(&db, "Memory::m1", "Entity::Rust", "USES", 150).unwrap();
+
+        let read = |from: &str, to: &str| {
+            let mut q = Map::new();
+            if !from.is_empty() {
+                q.insert("from".to_string(), from.to_string());
+            }
+            if !to.is_empty() {
+                q.insert("to".to_string(), to.to_string());
+            }
+            kg_read_graph(AxState(state.clone()), axum::extract::Query(q))
+        };
+        let Json(all) = read("", "").await;
+        assert_eq!(all["edges"].as_array().unwrap().len(), 3);
+        let Json(mid) = read("150", "250").await;
+        let edges = mid["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0], serde_json::json!(["Rust", "d2", "MENTIONS"]));
+        // Bounds are inclusive
+        let Json(late) = read("300", "").await;
+        assert_eq!(
+            late["edges"][0],
+            serde_json::json!(["Go", "d2", "MENTIONS"])
+        );
+
+        let Json(rels) = advanced_relationships(
+            AxState(state.clone()),
+            Json(serde_json::json!({ "from": 100, "to": 150 })),
+        )
+        .await
+        .unwrap();
+        let groups = rels["relationships"].as_array().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert!(groups.iter().all(|g| g["count"] == 1));
+        assert!(groups.iter().any(|g| g["group"] == "Memory:USES:Entity"));
     }
-    let mut items: Vec<(String, u64)> = counts.into_iter().collect();
-    items.sort_by(|a, b| b.1.cmp(&a.1));
-    let out: Vec<serde_json::Value> = items
-        .into_iter()
-        .map(|(k, v)| serde_json::json!({ "group": k, "count": v }))
-        .collect();
-    Json(serde_json::json!({ "relationships": out }))
-}
 
-async fn advanced_effectiveness(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    Json(_body): Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    // Effectiveness heuristic: combine access_count, importance, recency into a score
-    let mems = state.db.open_tree("memories").expect("mem");
-    let now_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let half_life_ms: f64 = std::env::var("EFFECT_HALF_LIFE_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(30.0 * 24.0 * 3600.0 * 1000.0);
-    let mut out: Vec<serde_json::Value> = Vec::new();
-    for kv in mems.iter() {
-        if let Ok((_, v)) = kv {
-            if let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) {
-                let id = rec
-                    .get("id")
-                    .and_then(|x| x.as_str())
-                    .unwrap_or("")
-                    .to_string();
-                let imp = rec
-                    .get("importance")
-                    .and_then(|x| x.as_f64())
-                    .unwrap_or(1.0);
-                let acc = rec
-                    .get("access_count")
-                    .and_then(|x| x.as_u64())
-                    .unwrap_or(0) as f64;
-                let ts = rec
-                    .get("created_at")
-                    .and_then(|x| x.as_i64())
-                    .unwrap_or(now_ms);
-                let age = (now_ms - ts).max(0) as f64;
-                let recency = (-(age / half_life_ms)).exp();
-                let score = imp * (1.0 + acc.log10().max(0.0)) * recency;
-                out.push(serde_json::json!({ "id": id, "score": score }));
+    #[test]
+    fn test_chunking_respects_utf8_and_structure() {
+        let para = "Größe café naïve 日本語のテキスト emoji 🦀🦀 ".repeat(12);
+        let content = format!("# Título\n\n{para}\n\n{para}\n\n## Sección 2\n\n{para}");
+        for (size, overlap) in [(1000, 0), (97, 0), (64, 16), (5, 2)] {
+            let chunks = chunk_markdown_with(&content, size, overlap);
+            assert!(!chunks.is_empty());
+            let mut prev_end = 0;
+            for ch in &chunks {
+                let (start, end) = (ch.position.start, ch.position.end);
+                assert!(content.is_char_boundary(start) && content.is_char_boundary(end));
+                assert!(start < end && end <= content.len());
+                // Cores are contiguous: no gaps between chunks
+                assert!(start <= prev_end);
+                prev_end = end;
+                let _ = &content[start..end];
             }
+            assert_eq!(prev_end, content.len());
         }
+        // Headings open a new chunk
+        let chunks = chunk_markdown_with(&content, 100_000, 0);
+        assert_eq!(chunks.len(), 2);
+        assert!(content[chunks[1].position.start..].starts_with("## Sección 2"));
     }
-    out.sort_by(|a, b| {
-        b.get("score")
-            .and_then(|x| x.as_f64())
-            .partial_cmp(&a.get("score").and_then(|x| x.as_f64()))
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-    Json(serde_json::json!({ "effectiveness": out }))
-}
-
-async fn shutdown_signal() {
-    let _ = signal::ctrl_c().await;
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::extract::State as AxState;
-    use axum::Json;
-    use rand::{distributions::Alphanumeric, Rng};
-    use std::collections::HashMap as Map;
-    use std::sync::Arc;
+    #[tokio::test]
+    async fn test_document_store_chunk_overlap() {
+        let state = make_state();
+        let content = "word ".repeat(200);
+        let req = StoreDocRequest {
+            path: Some("docs/overlap.md".to_string()),
+            url: None,
+            mime: Some("md".to_string()),
+            content: Some(content.clone()),
+            metadata: None,
+            chunk_size: Some(100),
+            chunk_overlap: Some(20),
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let latest = state.db().open_tree("doc_path_latest").unwrap();
+        let id =
+            String::from_utf8(latest.get("docs/overlap.md").unwrap().unwrap().to_vec()).unwrap();
+        let mut ranges: Vec<(usize, usize)> = state
+            .db()
+            .open_tree("chunks")
+            .unwrap()
+            .scan_prefix(format!("{}:", id).as_bytes())
+            .flatten()
+            .map(|(_, v)| {
+                let ch: ChunkHeader = serde_json::from_slice(&v).unwrap();
+                (ch.position.start, ch.position.end)
+            })
+            .collect();
+        ranges.sort();
+        assert!(ranges.len() > 2);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].1 - pair[1].0, 20);
+        }
+        let info = state
+            .db()
+            .open_tree("docs_info")
+            .unwrap()
+            .get(id.as_bytes())
+            .unwrap()
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&info).unwrap();
+        assert_eq!(info["chunk_size"], 100);
+        assert_eq!(info["chunk_overlap"], 20);
+        // Overlap must be smaller than the chunk size
+        let bad = StoreDocRequest {
+            path: None,
+            url: None,
+            mime: None,
+            content: Some("other".to_string()),
+            metadata: None,
+            chunk_size: Some(50),
+            chunk_overlap: Some(50),
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(bad)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
 
-    fn make_state() -> Arc<AppState> {
-        let base = std::env::temp_dir().join(format!("mcp-test-{}", uuid::Uuid::new_v4()));
-        let base_str = base.to_string_lossy().to_string();
-        std::fs::create_dir_all(&base).unwrap();
-        std::env::set_var("DATA_DIR", &base_str);
-        let dirs = ensure_data_dirs(&base_str).unwrap();
-        let db_path = dirs.warm.join("kv");
-        let db = sled::open(db_path).unwrap();
-        Arc::new(AppState {
-            start_time: Instant::now(),
-            db,
-            index_dir: dirs.index,
-            query_cache: AsyncMutex::new(HashMap::new()),
-            metrics: AsyncMutex::new(QueryMetrics::default()),
-            ingest_sema: Arc::new(Semaphore::new(4)),
-            buf_pool: StdMutex::new(ByteBufPool::default()),
-        })
+    #[tokio::test]
+    async fn test_document_retrieve_returns_ordered_chunk_text() {
+        let state = make_state();
+        let content = format!(
+            "{}\n\n{}\n\n{}",
+            "a".repeat(80),
+            "b".repeat(80),
+            "c".repeat(80)
+        );
+        let req = StoreDocRequest {
+            path: Some("docs/three.md".to_string()),
+            url: None,
+            mime: Some("txt".to_string()),
+            content: Some(content),
+            metadata: None,
+            chunk_size: Some(100),
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
+        };
+        let resp = document_store(AxState(state.clone()), Json(req)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut q = Map::new();
+        q.insert("path".to_string(), "docs/three.md".to_string());
+        let resp = document_retrieve(axum::extract::Query(q.clone()), AxState(state.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let chunks = v["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 3);
+        let starts: Vec<u64> = chunks
+            .iter()
+            .map(|c| c["position"]["start"].as_u64().unwrap())
+            .collect();
+        assert!(starts.windows(2).all(|w| w[0] < w[1]));
+        for (c, letter) in chunks.iter().zip(["a", "b", "c"]) {
+            assert!(c["text"].as_str().unwrap().contains(&letter.repeat(80)));
+        }
+        // Lightweight mode omits bodies
+        q.insert("withText".to_string(), "false".to_string());
+        let resp = document_retrieve(axum::extract::Query(q), AxState(state.clone())).await;
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(v["chunks"][0].get("text").is_none());
     }
 
     #[tokio::test]
-    async fn test_document_store_and_retrieve_by_path() {
+    async fn test_document_store_strips_html() {
         let state = make_state();
+        let html = "<html><head><style>p { color: red; }</style><script>var x = 1;</script></head>\
+            <body><h1>Otter&nbsp;Notes</h1><p>Otters &amp; <b>kelp</b> forests.</p>\
+            <!-- hidden --><ul><li>one</li><li>two</li></ul></body></html>";
         let req = StoreDocRequest {
-            path: Some("docs/doc1.md".to_string()),
-            mime: Some("md".to_string()),
-            content: Some("# Title\nHello world".to_string()),
+            path: Some("docs/otters.html".to_string()),
+            url: None,
+            mime: None,
+            content: Some(html.to_string()),
             metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
         };
         let resp = document_store(AxState(state.clone()), Json(req)).await;
         assert_eq!(resp.status(), StatusCode::OK);
-        // Verify latest id by path exists
-        let latest = state.db.open_tree("doc_path_latest").unwrap();
-        let id = String::from_utf8(latest.get("docs/doc1.md").unwrap().unwrap().to_vec()).unwrap();
-        // Verify chunks present
-        let chunks = state.db.open_tree("chunks").unwrap();
-        let prefix = format!("{}:", id);
-        let mut count = 0usize;
-        for kv in chunks.scan_prefix(prefix.as_bytes()) {
-            if kv.is_ok() {
-                count += 1;
+        let latest = state.db().open_tree("doc_path_latest").unwrap();
+        let id =
+            String::from_utf8(latest.get("docs/otters.html").unwrap().unwrap().to_vec()).unwrap();
+        let text: String = state
+            .db()
+            .open_tree("text_index")
+            .unwrap()
+            .scan_prefix(format!("{}:", id).as_bytes())
+            .flatten()
+            .map(|(_, v)| String::from_utf8_lossy(&v).to_string())
+            .collect();
+        assert!(!text.contains('<') && !text.contains('>'), "{}", text);
+        assert!(text.contains("Otter Notes"));
+        assert!(text.contains("Otters & kelp forests."));
+        assert!(!text.contains("color") && !text.contains("var x") && !text.contains("hidden"));
+        // Explicit mime wins over the extension
+        assert_eq!(detect_doc_kind(Some("text/plain"), Some("a.html")), "txt");
+        assert_eq!(detect_doc_kind(None, Some("a.HTM")), "html");
+        assert_eq!(detect_doc_kind(None, None), "md");
+    }
+
+    #[tokio::test]
+    async fn test_memory_add_batch_all_searchable() {
+        let state = make_state();
+        let items: Vec<AddMemoryRequest> = (0..50)
+            .map(|i| AddMemoryRequest {
+                content: format!("batch note {} quokka{}x", i, i),
+                layer_hint: if i % 2 == 0 {
+                    Some("LTM".to_string())
+                } else {
+                    None
+                },
+                ..Default::default()
+            })
+            .collect();
+        let resp = memory_add_batch(
+            AxState(state.clone()),
+            Json(AddMemoryBatchRequest { items }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let out = body["items"].as_array().unwrap();
+        assert_eq!(out.len(), 50);
+        assert!(body.get("tookMs").is_some());
+        for (i, item) in out.iter().enumerate() {
+            let expected_layer = if i % 2 == 0 { "LTM" } else { "STM" };
+            assert_eq!(item["layer"], expected_layer);
+            let id = item["id"].as_str().unwrap().to_string();
+            let mut q = Map::new();
+            q.insert("q".to_string(), format!("quokka{}x", i));
+            let found = memory_search(AxState(state.clone()), axum::extract::Query(q))
+                .await
+                .unwrap();
+            assert!(
+                found.results.iter().any(|r| r.id == id),
+                "item {} not found",
+                i
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_pagination_is_stable() {
+        let state = make_state();
+        let items: Vec<AddMemoryRequest> = (0..15)
+            .map(|i| AddMemoryRequest {
+                content: format!("pagination wombat {}", i),
+                ..Default::default()
+            })
+            .collect();
+        let _ = memory_add_batch(
+            AxState(state.clone()),
+            Json(AddMemoryBatchRequest { items }),
+        )
+        .await;
+        let query = |extra: &[(&str, &str)]| {
+            let mut q = Map::new();
+            q.insert("q".to_string(), "wombat".to_string());
+            for (k, v) in extra {
+                q.insert(k.to_string(), v.to_string());
             }
+            axum::extract::Query(q)
+        };
+        let ids = |r: &SearchResponse| r.results.iter().map(|x| x.id.clone()).collect::<Vec<_>>();
+        for fusion in [false, true] {
+            let run = |extra: Vec<(&'static str, String)>| {
+                let state = state.clone();
+                let extra: Vec<(&str, &str)> =
+                    extra.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                let q = query(&extra);
+                async move {
+                    if fusion {
+                        search_fusion(AxState(state), q).await.unwrap().0
+                    } else {
+                        memory_search(AxState(state), q).await.unwrap().0
+                    }
+                }
+            };
+            let all = run(vec![("limit", "10".into()), ("countTotal", "true".into())]).await;
+            assert_eq!(all.results.len(), 10);
+            assert!(all.total.unwrap() >= 15);
+            let p1 = run(vec![("limit", "5".into())]).await;
+            let cursor = p1.next_cursor.clone().expect("next page");
+            let p2 = run(vec![("limit", "5".into()), ("cursor", cursor)]).await;
+            let p2_offset = run(vec![("limit", "5".into()), ("offset", "5".into())]).await;
+            let mut joined = ids(&p1);
+            joined.extend(ids(&p2));
+            assert_eq!(joined, ids(&all));
+            assert_eq!(ids(&p2), ids(&p2_offset));
         }
-        assert!(count >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_search_ranks_by_bm25() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in [
+            "ownership rules in rust keep rust programs memory safe with rust",
+            "rust has a borrow checker",
+            "python uses reference counting",
+        ] {
+            let add = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(add)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        let mut q = Map::new();
+        // Terms out of order: a plain substring match would find nothing
+        q.insert("q".to_string(), "Rust ownership".to_string());
+        let out = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        let text_hits: Vec<&SearchResult> =
+            out.results.iter().filter(|r| r.explain.is_none()).collect();
+        assert_eq!(text_hits.len(), 2);
+        assert_eq!(text_hits[0].id, ids[0]);
+        assert_eq!(text_hits[1].id, ids[1]);
+        assert!(text_hits[0].score > text_hits[1].score);
+    }
+
+    #[test]
+    fn test_fusion_text_and_vector_outranks_text_only() {
+        let hit = |id: &str, signal: Signal, source: &'static str, score: f32| FusionHit {
+            id: id.to_string(),
+            layer: "STM".to_string(),
+            signal,
+            source,
+            score,
+            doc_refs: None,
+            extra: None,
+        };
+        let weights = FusionWeights {
+            text: 1.0,
+            vector: 1.0,
+            kg: 0.5,
+        };
+        // "a" sorts first by id, so only the fused score can put "b" ahead
+        let hits = vec![
+            hit("a", Signal::Text, "text", 2.0),
+            hit("b", Signal::Text, "text", 2.0),
+            hit("b", Signal::Vector, "vector-ann", 0.8),
+            hit("c", Signal::Vector, "vector-ann", 0.2),
+        ];
+        let out = fuse(hits, weights);
+        let ids: Vec<&str> = out.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "a", "c"]);
+        assert!((out[0].score - 2.0).abs() < 1e-6);
+        let explain = out[0].explain.as_ref().unwrap();
+        assert_eq!(explain["text"], 1.0);
+        assert_eq!(explain["vector"], 1.0);
+        assert_eq!(
+            explain["sources"],
+            serde_json::json!(["text", "vector-ann"])
+        );
+        // Zeroing the vector weight leaves a tie broken by id
+        let out = fuse(
+            vec![
+                hit("a", Signal::Text, "text", 2.0),
+                hit("b", Signal::Text, "text", 2.0),
+                hit("b", Signal::Vector, "vector-ann", 0.8),
+            ],
+            FusionWeights {
+                vector: 0.0,
+                ..weights
+            },
+        );
+        assert_eq!(out[0].id, "a");
+    }
+
+    #[test]
+    fn test_rrf_orders_by_reciprocal_rank() {
+        let hit = |id: &str, signal: Signal, source: &'static str, score: f32| FusionHit {
+            id: id.to_string(),
+            layer: "STM".to_string(),
+            signal,
+            source,
+            score,
+            doc_refs: None,
+            extra: None,
+        };
+        // text: x, y, z   kg: z, y   vector: y, w (scores on very different scales)
+        let hits = vec![
+            hit("x", Signal::Text, "text", 12.0),
+            hit("y", Signal::Text, "text", 7.5),
+            hit("z", Signal::Text, "text", 3.0),
+            hit("z", Signal::Kg, "kg", 1.0),
+            hit("y", Signal::Kg, "kg", 0.5),
+            hit("y", Signal::Vector, "vector-ann", 0.91),
+            hit("w", Signal::Vector, "vector-ann", 0.40),
+        ];
+        let out = fuse_rrf(hits, 60.0);
+        let ids: Vec<&str> = out.iter().map(|r| r.id.as_str()).collect();
+        // y: 1/62 + 1/62 + 1/61; z: 1/63 + 1/61; x: 1/61; w: 1/62
+        assert_eq!(ids, vec!["y", "z", "x", "w"]);
+        let expected_y = 2.0 / 62.0 + 1.0 / 61.0;
+        assert!((out[0].score - expected_y).abs() < 1e-6);
+        let explain = out[0].explain.as_ref().unwrap();
+        assert_eq!(explain["textRank"], 2);
+        assert_eq!(explain["kgRank"], 2);
+        assert_eq!(explain["vectorRank"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_results_carry_snippets() {
+        let state = make_state();
+        let content = format!(
+            "{} the Platypus lays eggs {}",
+            "lorem ".repeat(60),
+            "ipsum ".repeat(60)
+        );
+        let add = AddMemoryRequest {
+            content,
+            ..Default::default()
+        };
+        let _ = memory_add(AxState(state.clone()), Json(add)).await;
+        let mut q = Map::new();
+        q.insert("q".to_string(), "platypus".to_string());
+        q.insert("highlight".to_string(), "true".to_string());
+        let out = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
+        let snippet = out.results[0].snippet.clone().unwrap();
+        assert!(snippet.contains("<em>Platypus</em>"));
+        assert!(snippet.starts_with("...") && snippet.ends_with("..."));
+        assert!(snippet.chars().count() <= SNIPPET_MAX_CHARS + "<em></em>......".len());
+        let plain = make_snippet("short Platypus note", "platypus", false);
+        assert_eq!(plain, "short Platypus note");
+    }
+
+    #[tokio::test]
+    async fn test_memory_search_boolean_operators() {
+        let state = make_state();
+        let mut ids = Vec::new();
+        for content in ["alpha bravo", "alpha charlie", "bravo alpha delta"] {
+            let add = AddMemoryRequest {
+                content: content.to_string(),
+                ..Default::default()
+            };
+            let resp = memory_add(AxState(state.clone()), Json(add)).await;
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            ids.push(body["id"].as_str().unwrap().to_string());
+        }
+        let search = |q: &str| {
+            let state = state.clone();
+            let mut params = Map::new();
+            params.insert("q".to_string(), q.to_string());
+            async move {
+                let out = memory_search(AxState(state), axum::extract::Query(params))
+                    .await
+                    .unwrap();
+                let mut hits: Vec<String> = out
+                    .results
+                    .iter()
+                    .filter(|r| r.explain.is_none())
+                    .map(|r| r.id.clone())
+                    .collect();
+                hits.sort();
+                hits
+            }
+        };
+        let sorted = |mut v: Vec<String>| {
+            v.sort();
+            v
+        };
+        assert_eq!(
+            search("alpha AND bravo").await,
+            sorted(vec![ids[0].clone(), ids[2].clone()])
+        );
+        assert_eq!(search("\"alpha bravo\"").await, vec![ids[0].clone()]);
+        assert_eq!(
+            search("alpha -charlie").await,
+            sorted(vec![ids[0].clone(), ids[2].clone()])
+        );
     }
 
     #[tokio::test]
@@ -3885,16 +14402,12 @@ mod tests {
         // Add memory
         let add = AddMemoryRequest {
             content: "alpha bravo charlie".to_string(),
-            metadata: None,
-            layer_hint: None,
-            session_id: None,
-            episode_id: None,
-            references: None,
+            ..Default::default()
         };
         let resp = memory_add(AxState(state.clone()), Json(add)).await;
         assert_eq!(resp.status(), StatusCode::OK);
         // Find id by scanning memories
-        let mems = state.db.open_tree("memories").unwrap();
+        let mems = state.db().open_tree("memories").unwrap();
         let mut found_id = String::new();
         for kv in mems.iter() {
             if let Ok((_, v)) = kv {
@@ -3914,12 +14427,15 @@ mod tests {
         // Search
         let mut q = Map::new();
         q.insert("q".to_string(), "bravo".to_string());
-        let out = memory_search(AxState(state.clone()), axum::extract::Query(q)).await;
+        let out = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
         assert!(out.results.iter().any(|r| r.id == found_id));
         // Delete
         let del = DeleteMemoryRequest {
             id: found_id.clone(),
             backup: Some(false),
+            soft: None,
         };
         let del_resp = memory_delete(AxState(state.clone()), Json(del)).await;
         assert_eq!(del_resp.status(), StatusCode::OK);
@@ -3930,19 +14446,20 @@ mod tests {
         let state = make_state();
         let bad = AddMemoryRequest {
             content: "".to_string(),
-            metadata: None,
-            layer_hint: None,
-            session_id: None,
-            episode_id: None,
-            references: None,
+            ..Default::default()
         };
         let resp = memory_add(AxState(state.clone()), Json(bad)).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
         let req = StoreDocRequest {
             path: None,
+            url: None,
             mime: Some("md".to_string()),
             content: None,
             metadata: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            sync: false,
+            dry_run: false,
         };
         let resp2 = document_store(AxState(state.clone()), Json(req)).await;
         assert_eq!(resp2.status(), StatusCode::BAD_REQUEST);
@@ -3954,11 +14471,8 @@ mod tests {
         // Create one memory to persist
         let add = AddMemoryRequest {
             content: "persist me".to_string(),
-            metadata: None,
             layer_hint: Some("STM".to_string()),
-            session_id: None,
-            episode_id: None,
-            references: None,
+            ..Default::default()
         };
         let _ = memory_add(AxState(state.clone()), Json(add)).await;
         // Export
@@ -3998,11 +14512,7 @@ mod tests {
                 let content = format!("common token {}", i);
                 let add = AddMemoryRequest {
                     content,
-                    metadata: None,
-                    layer_hint: None,
-                    session_id: None,
-                    episode_id: None,
-                    references: None,
+                    ..Default::default()
                 };
                 let _ = memory_add(AxState(s), Json(add)).await;
             }));
@@ -4012,7 +14522,9 @@ mod tests {
         }
         let mut q = Map::new();
         q.insert("q".to_string(), "common".to_string());
-        let out = memory_search(AxState(state.clone()), axum::extract::Query(q)).await;
+        let out = memory_search(AxState(state.clone()), axum::extract::Query(q))
+            .await
+            .unwrap();
         assert!(out.results.len() >= 10);
     }
 
@@ -4025,11 +14537,7 @@ mod tests {
             let s: String = (0..len).map(|_| rng.sample(Alphanumeric) as char).collect();
             let add = AddMemoryRequest {
                 content: s,
-                metadata: None,
-                layer_hint: None,
-                session_id: None,
-                episode_id: None,
-                references: None,
+                ..Default::default()
             };
             let resp = memory_add(AxState(state.clone()), Json(add)).await;
             // Empty content should be rejected; non-empty should be OK
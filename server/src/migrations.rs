@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+
+/// On-disk layout version this build reads and writes.
+pub const CURRENT_SCHEMA_VERSION: u64 = 4;
+
+type Migration = fn(&sled::Db) -> Result<()>;
+
+/// Ordered upgrades; entry `i` takes a store from version `i` to `i + 1`.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("baseline", baseline),
+    ("backfill memory importance", backfill_importance),
+    ("escape graph and doc-ref keys", escape_composite_keys),
+    ("zero-pad chunk key offsets", pad_chunk_keys),
+];
+
+const _: () = assert!(MIGRATIONS.len() as u64 == CURRENT_SCHEMA_VERSION);
+
+/// `schema_version` from the `settings` tree; stores that predate it are version 0.
+pub fn schema_version(db: &sled::Db) -> Result<u64> {
+    let settings = db.open_tree("settings")?;
+    Ok(settings
+        .get(b"schema_version")?
+        .and_then(|v| std::str::from_utf8(&v).ok()?.parse().ok())
+        .unwrap_or(0))
+}
+
+fn set_schema_version(db: &sled::Db, version: u64) -> Result<()> {
+    let settings = db.open_tree("settings")?;
+    settings.insert(b"schema_version", version.to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Run every migration between the persisted version and `CURRENT_SCHEMA_VERSION`,
+/// recording progress after each one. Returns the number applied.
+pub fn migrate(db: &sled::Db) -> Result<usize> {
+    let from = schema_version(db)?;
+    if from > CURRENT_SCHEMA_VERSION {
+        anyhow::bail!(
+            "store schema version {} is newer than this build supports ({})",
+            from,
+            CURRENT_SCHEMA_VERSION
+        );
+    }
+    let mut applied = 0;
+    for (version, (name, step)) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        let to = version as u64 + 1;
+        step(db).with_context(|| format!("schema migration to v{} ({}) failed", to, name))?;
+        set_schema_version(db, to)?;
+        applied += 1;
+    }
+    db.flush()?;
+    Ok(applied)
+}
+
+/// v1 only starts tracking the version; earlier layouts are already readable.
+fn baseline(_db: &sled::Db) -> Result<()> {
+    Ok(())
+}
+
+/// v2: memories written before `importance` existed get the default of 1.0.
+fn backfill_importance(db: &sled::Db) -> Result<()> {
+    let mems = db.open_tree("memories")?;
+    for kv in mems.iter() {
+        let (k, v) = kv?;
+        let mut rec: serde_json::Value = match serde_json::from_slice(&v) {
+            Ok(rec) => rec,
+            Err(_) => continue,
+        };
+        if !rec.is_object() || rec.get("importance").is_some() {
+            continue;
+        }
+        rec["importance"] = serde_json::json!(1.0);
+        mems.insert(k, serde_json::to_vec(&rec)?)?;
+    }
+    Ok(())
+}
+
+/// v3: ids in `kg_edges`, `kg_edges_rev` and `doc_refs` keys are escaped, so ids
+/// containing `::` or `->` no longer break key parsing. Keys are rebuilt from the edge
+/// values, and from the old doc-ref layout; the reverse index is regenerated.
+fn escape_composite_keys(db: &sled::Db) -> Result<()> {
+    // Snapshot first, so rewritten keys are not visited (and escaped) again
+    let edges = db.open_tree("kg_edges")?;
+    let entries: Vec<(sled::IVec, sled::IVec)> = edges.iter().collect::<sled::Result<_>>()?;
+    for (k, v) in entries {
+        let Ok(edge) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        let field = |f: &str| edge.get(f).and_then(|x| x.as_str()).unwrap_or("");
+        let key = crate::kg::edge_key(field("src"), field("dst"), field("relation"));
+        if key.as_bytes() != k.as_ref() {
+            edges.insert(key.as_bytes(), v)?;
+            edges.remove(k)?;
+        }
+    }
+    db.open_tree("kg_edges_rev")?.clear()?;
+    crate::kg::ensure_reverse_index(db)?;
+
+    let refs = db.open_tree("doc_refs")?;
+    let entries: Vec<(sled::IVec, sled::IVec)> = refs.iter().collect::<sled::Result<_>>()?;
+    for (k, v) in entries {
+        let old = String::from_utf8_lossy(&k).to_string();
+        // Old layout, ids unescaped: mem::<id>::doc::<docId>::chunk::<chunkId>
+        let parsed = old
+            .strip_prefix("mem::")
+            .and_then(|rest| rest.split_once("::doc::"))
+            .and_then(|(mem_id, rest)| {
+                let (doc_id, chunk_id) = rest.rsplit_once("::chunk::")?;
+                Some(crate::doc_ref_key(mem_id, doc_id, chunk_id))
+            });
+        if let Some(key) = parsed.filter(|key| *key != old) {
+            refs.insert(key.as_bytes(), v)?;
+            refs.remove(k)?;
+        }
+    }
+    Ok(())
+}
+
+/// v4: chunk keys in `chunks`, `embeddings` and `text_index` carry zero-padded offsets,
+/// so prefix scans return a document's chunks in position order.
+fn pad_chunk_keys(db: &sled::Db) -> Result<()> {
+    crate::migrate_chunk_keys(db)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v0_store_upgrades_to_current() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mems = db.open_tree("memories").unwrap();
+        let legacy = serde_json::json!({ "id": "old", "content": "from v0", "layer": "LTM" });
+        mems.insert(b"old", serde_json::to_vec(&legacy).unwrap())
+            .unwrap();
+        let current = serde_json::json!({ "id": "new", "content": "x", "importance": 3.0 });
+        mems.insert(b"new", serde_json::to_vec(&current).unwrap())
+            .unwrap();
+        assert_eq!(schema_version(&db).unwrap(), 0);
+
+        assert_eq!(migrate(&db).unwrap(), MIGRATIONS.len());
+        assert_eq!(schema_version(&db).unwrap(), CURRENT_SCHEMA_VERSION);
+        let rec: serde_json::Value =
+            serde_json::from_slice(&mems.get(b"old").unwrap().unwrap()).unwrap();
+        assert_eq!(rec["importance"], 1.0);
+        let rec: serde_json::Value =
+            serde_json::from_slice(&mems.get(b"new").unwrap().unwrap()).unwrap();
+        assert_eq!(rec["importance"], 3.0);
+
+        // Already current: nothing runs, and a newer store is refused
+        assert_eq!(migrate(&db).unwrap(), 0);
+        set_schema_version(&db, CURRENT_SCHEMA_VERSION + 1).unwrap();
+        assert!(migrate(&db).is_err());
+    }
+
+    #[test]
+    fn test_v3_escapes_ids_in_edge_and_doc_ref_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        set_schema_version(&db, 2).unwrap();
+        let edges = db.open_tree("kg_edges").unwrap();
+        let edge =
+            serde_json::json!({ "src": "Entity::A::B", "dst": "Entity::C", "relation": "LINKS" });
+        edges
+            .insert(
+                b"Entity::A::B->Entity::C::LINKS",
+                serde_json::to_vec(&edge).unwrap(),
+            )
+            .unwrap();
+        let refs = db.open_tree("doc_refs").unwrap();
+        refs.insert(
+            b"mem::m1::doc::d1::chunk::d1:00000000000000000000",
+            b"{}".to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(migrate(&db).unwrap(), 2);
+        let key = crate::kg::edge_key("Entity::A::B", "Entity::C", "LINKS");
+        assert_eq!(key, "Entity::A%3A%3AB->Entity::C::LINKS");
+        assert_eq!(edges.len(), 1);
+        assert!(edges.contains_key(key.as_bytes()).unwrap());
+        let incoming = crate::kg::incoming_edges(&db, "Entity::C").unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].1["src"], "Entity::A::B");
+        let (k, _) = refs.iter().next().unwrap().unwrap();
+        assert_eq!(
+            crate::parse_doc_ref_key(&String::from_utf8_lossy(&k)),
+            Some((
+                "m1".to_string(),
+                "d1".to_string(),
+                "d1:00000000000000000000".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_v4_pads_chunk_key_offsets() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        set_schema_version(&db, 3).unwrap();
+        let chunks = db.open_tree("chunks").unwrap();
+        chunks.insert(b"d1:120", b"later".to_vec()).unwrap();
+        chunks.insert(b"d1:0", b"first".to_vec()).unwrap();
+
+        assert_eq!(migrate(&db).unwrap(), 1);
+        let keys: Vec<String> = chunks
+            .iter()
+            .keys()
+            .map(|k| String::from_utf8_lossy(&k.unwrap()).to_string())
+            .collect();
+        assert_eq!(
+            keys,
+            vec!["d1:00000000000000000000", "d1:00000000000000000120"]
+        );
+    }
+}
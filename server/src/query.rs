@@ -0,0 +1,239 @@
+/// Parsed search query. Bare terms are alternatives (like tantivy's default), `AND` binds
+/// tighter than juxtaposition/`OR`, and `-term` / `NOT term` exclude matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Phrase(String),
+    Term(String),
+    /// Empty query; matches everything.
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    And,
+    Or,
+    Not,
+    Minus,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let phrase: String = chars.by_ref().take_while(|c| *c != '"').collect();
+            let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !phrase.is_empty() {
+                tokens.push(Token::Phrase(phrase));
+            }
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RParen);
+        } else if c == '-' {
+            chars.next();
+            tokens.push(Token::Minus);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' || c == '(' || c == ')' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(match word.as_str() {
+                "AND" | "&&" => Token::And,
+                "OR" | "||" => Token::Or,
+                "NOT" => Token::Not,
+                _ => Token::Word(word),
+            });
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    /// Clauses up to `)` or the end: positives are OR'ed, negations must all hold.
+    fn sequence(&mut self) -> Query {
+        let mut positives = Vec::new();
+        let mut negatives = Vec::new();
+        while let Some(t) = self.peek() {
+            match t {
+                Token::RParen => break,
+                Token::Or | Token::And => {
+                    self.pos += 1;
+                }
+                _ => match self.and_chain() {
+                    Some(q @ Query::Not(_)) => negatives.push(q),
+                    Some(q) => positives.push(q),
+                    None => {}
+                },
+            }
+        }
+        let positive = match positives.len() {
+            0 => None,
+            1 => positives.pop(),
+            _ => Some(Query::Or(positives)),
+        };
+        match (positive, negatives.is_empty()) {
+            (None, true) => Query::All,
+            (Some(p), true) => p,
+            (None, false) if negatives.len() == 1 => negatives.remove(0),
+            (None, false) => Query::And(negatives),
+            (Some(p), false) => {
+                let mut all = vec![p];
+                all.extend(negatives);
+                Query::And(all)
+            }
+        }
+    }
+
+    fn and_chain(&mut self) -> Option<Query> {
+        let mut parts = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            if let Some(q) = self.unary() {
+                parts.push(q);
+            }
+        }
+        Some(if parts.len() == 1 {
+            parts.remove(0)
+        } else {
+            Query::And(parts)
+        })
+    }
+
+    fn unary(&mut self) -> Option<Query> {
+        match self.next()? {
+            Token::Minus | Token::Not => self.unary().map(|q| Query::Not(Box::new(q))),
+            Token::Word(w) => Some(Query::Term(w.to_lowercase())),
+            Token::Phrase(p) => Some(Query::Phrase(p.to_lowercase())),
+            Token::LParen => {
+                let inner = self.sequence();
+                if self.peek() == Some(&Token::RParen) {
+                    self.pos += 1;
+                }
+                Some(inner)
+            }
+            // Stray operators are ignored
+            Token::And | Token::Or | Token::RParen => None,
+        }
+    }
+}
+
+/// Parse a user query; never fails, malformed input degrades to the terms it contains.
+pub fn parse(input: &str) -> Query {
+    let mut parser = Parser {
+        tokens: tokenize(input),
+        pos: 0,
+    };
+    let mut q = parser.sequence();
+    // Unbalanced `)` ends a sequence early; keep parsing what follows
+    while parser.pos < parser.tokens.len() {
+        parser.pos += 1;
+        let rest = parser.sequence();
+        if rest == Query::All {
+            continue;
+        }
+        q = if q == Query::All {
+            rest
+        } else {
+            Query::Or(vec![q, rest])
+        };
+    }
+    q
+}
+
+impl Query {
+    /// Case-insensitive match against `text`; terms match as substrings.
+    pub fn matches(&self, text: &str) -> bool {
+        let lower = text.to_lowercase();
+        let collapsed = lower.split_whitespace().collect::<Vec<_>>().join(" ");
+        self.eval(&lower, &collapsed)
+    }
+
+    fn eval(&self, lower: &str, collapsed: &str) -> bool {
+        match self {
+            Query::All => true,
+            Query::Term(t) => lower.contains(t.as_str()),
+            Query::Phrase(p) => collapsed.contains(p.as_str()),
+            Query::Not(q) => !q.eval(lower, collapsed),
+            Query::And(qs) => qs.iter().all(|q| q.eval(lower, collapsed)),
+            Query::Or(qs) => qs.iter().any(|q| q.eval(lower, collapsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_and_requires_both_terms() {
+        let q = parse("alpha AND bravo");
+        assert_eq!(
+            q,
+            Query::And(vec![
+                Query::Term("alpha".into()),
+                Query::Term("bravo".into())
+            ])
+        );
+        assert!(q.matches("Bravo then alpha"));
+        assert!(!q.matches("alpha only"));
+    }
+
+    #[test]
+    fn test_phrase_matches_adjacent_words_only() {
+        let q = parse("\"alpha bravo\"");
+        assert_eq!(q, Query::Phrase("alpha bravo".into()));
+        assert!(q.matches("say ALPHA   bravo now"));
+        assert!(!q.matches("bravo alpha"));
+    }
+
+    #[test]
+    fn test_minus_excludes_term() {
+        let q = parse("alpha -charlie");
+        assert!(q.matches("alpha bravo"));
+        assert!(!q.matches("alpha charlie"));
+        assert!(!q.matches("bravo"));
+        // Bare terms are alternatives
+        assert!(parse("alpha bravo").matches("just bravo"));
+        assert!(parse("(alpha OR bravo) AND NOT delta").matches("bravo echo"));
+        assert_eq!(parse("  "), Query::All);
+    }
+
+    #[test]
+    fn test_leading_stray_paren_does_not_match_everything() {
+        let q = parse(") foo");
+        assert_eq!(q, Query::Term("foo".into()));
+        assert!(!q.matches("bar"));
+    }
+}
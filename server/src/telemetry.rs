@@ -0,0 +1,251 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Spans waiting for export; beyond this new spans are dropped rather than blocking requests.
+const QUEUE_CAPACITY: usize = 4096;
+const BATCH_MAX: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` (the collector's OTLP/HTTP base URL), if set.
+pub fn otlp_endpoint() -> Option<String> {
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .map(|e| e.trim().trim_end_matches('/').to_string())
+        .filter(|e| !e.is_empty())
+}
+
+/// Exports closed spans as OTLP/HTTP JSON to `<endpoint>/v1/traces`, batching on a
+/// background thread. Trace and parent ids follow the span tree.
+pub struct OtlpLayer {
+    tx: SyncSender<Value>,
+}
+
+impl OtlpLayer {
+    /// A layer exporting to `endpoint`, or `None` (plain stderr logging) without one.
+    pub fn new(endpoint: Option<String>) -> Option<Self> {
+        let endpoint = endpoint?;
+        let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_CAPACITY);
+        let service = std::env::var("OTEL_SERVICE_NAME")
+            .unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+        std::thread::Builder::new()
+            .name("otlp-export".to_string())
+            .spawn(move || export_loop(&format!("{}/v1/traces", endpoint), &service, rx))
+            .ok()?;
+        Some(Self { tx })
+    }
+}
+
+/// Per-span state kept in the registry's extensions until the span closes.
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start_ns: u128,
+    attributes: Vec<Value>,
+}
+
+impl OtlpSpan {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": value }));
+    }
+}
+
+impl Visit for OtlpSpan {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // OTLP JSON encodes 64-bit integers as strings
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+}
+
+fn now_ns() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|p| {
+            p.extensions()
+                .get::<OtlpSpan>()
+                .map(|d| (d.trace_id.clone(), d.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace, parent_id)) => (trace, Some(parent_id)),
+            None => (uuid::Uuid::new_v4().simple().to_string(), None),
+        };
+        let mut data = OtlpSpan {
+            trace_id,
+            span_id: uuid::Uuid::new_v4().simple().to_string()[..16].to_string(),
+            parent_span_id,
+            start_ns: now_ns(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<OtlpSpan>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<OtlpSpan>() else {
+            return;
+        };
+        let mut out = json!({
+            "traceId": data.trace_id,
+            "spanId": data.span_id,
+            "name": span.name(),
+            "kind": 1,
+            "startTimeUnixNano": data.start_ns.to_string(),
+            "endTimeUnixNano": now_ns().to_string(),
+            "attributes": data.attributes,
+        });
+        if let Some(parent) = data.parent_span_id {
+            out["parentSpanId"] = json!(parent);
+        }
+        // A full queue means the collector is behind; drop rather than stall the request
+        let _ = self.tx.try_send(out);
+    }
+}
+
+fn export_loop(url: &str, service: &str, rx: Receiver<Value>) {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default();
+    let mut batch = Vec::new();
+    let mut last_flush = Instant::now();
+    let mut warned = false;
+    loop {
+        let disconnected = match rx.recv_timeout(BATCH_INTERVAL) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        let due = batch.len() >= BATCH_MAX || last_flush.elapsed() >= BATCH_INTERVAL;
+        if !batch.is_empty() && (due || disconnected) {
+            let body = export_request(service, std::mem::take(&mut batch));
+            let sent = client.post(url).json(&body).send();
+            if let Err(e) = sent.and_then(|r| r.error_for_status()) {
+                // Once is enough; the collector being down shouldn't flood stderr
+                if !warned {
+                    eprintln!("OTLP export to {} failed: {}", url, e);
+                    warned = true;
+                }
+            }
+            last_flush = Instant::now();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Wrap spans in an OTLP `ExportTraceServiceRequest`.
+fn export_request(service: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_no_endpoint_means_no_exporter() {
+        assert!(OtlpLayer::new(None).is_none());
+        // The layer is optional in the stack, so the subscriber still builds without it
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(None));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("noop").in_scope(|| {});
+        });
+    }
+
+    #[test]
+    fn test_child_spans_share_trace_and_link_parent() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(16);
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer { tx });
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("search_fusion", q = "kickoff");
+            let _entered = outer.enter();
+            let inner = tracing::info_span!("fusion.text_scan", hits = tracing::field::Empty);
+            inner.record("hits", 3u64);
+            inner.in_scope(|| {});
+        });
+        let inner = rx.try_recv().unwrap();
+        let outer = rx.try_recv().unwrap();
+        assert_eq!(inner["name"], "fusion.text_scan");
+        assert_eq!(inner["traceId"], outer["traceId"]);
+        assert_eq!(inner["parentSpanId"], outer["spanId"]);
+        assert!(outer.get("parentSpanId").is_none());
+        assert_eq!(outer["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(outer["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            inner["attributes"][0],
+            json!({ "key": "hits", "value": { "intValue": "3" } })
+        );
+        let body = export_request("svc", vec![inner]);
+        assert_eq!(
+            body["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"],
+            "fusion.text_scan"
+        );
+    }
+}
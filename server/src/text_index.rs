@@ -0,0 +1,325 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{AllQuery, QueryParser};
+use tantivy::schema::{Field, Schema, Value, INDEXED, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+/// Arena shared by the writer's indexing threads.
+const WRITER_HEAP_BYTES: usize = 50_000_000;
+/// Buffered adds that force a commit without waiting for the timer.
+const COMMIT_EVERY: usize = 1000;
+
+/// Fields of the one tantivy schema used for memories and document chunks.
+#[derive(Clone, Copy)]
+struct Fields {
+    id: Field,
+    /// The id untokenized, the key for deletes
+    id_raw: Field,
+    kind: Field,
+    content: Field,
+    timestamp: Field,
+}
+
+fn schema() -> &'static (Schema, Fields) {
+    static SCHEMA: OnceLock<(Schema, Fields)> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            id: builder.add_text_field("id", TEXT | STORED),
+            id_raw: builder.add_text_field("id_raw", STRING | STORED),
+            kind: builder.add_text_field("type", STRING | STORED),
+            content: builder.add_text_field("content", TEXT),
+            timestamp: builder.add_i64_field("timestamp", INDEXED),
+        };
+        (builder.build(), fields)
+    })
+}
+
+struct Open {
+    index: Index,
+    writer: IndexWriter,
+    reader: IndexReader,
+    /// Adds not yet committed
+    pending: usize,
+}
+
+/// The BM25 index under `<index_dir>/tantivy`, opened on first use with a single
+/// long-lived writer. Adds are buffered and committed every `COMMIT_EVERY` documents, by
+/// `commit` (run on a timer), or just before a search so results never miss them.
+pub struct TextIndex {
+    dir: PathBuf,
+    open: Mutex<Option<Open>>,
+}
+
+impl TextIndex {
+    pub fn new(index_dir: &Path) -> Self {
+        Self {
+            dir: index_dir.join("tantivy"),
+            open: Mutex::new(None),
+        }
+    }
+
+    /// Run `f` against the open index, opening it if needed. A failure drops the writer
+    /// so the next call starts from the last commit.
+    fn with_open<T>(
+        &self,
+        create: bool,
+        f: impl FnOnce(&mut Open) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let mut guard = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if guard.is_none() {
+            if !create && !self.dir.exists() {
+                return Ok(None);
+            }
+            let index = open_index(&self.dir)?.0;
+            let writer = index.writer(WRITER_HEAP_BYTES)?;
+            let reader = index
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()?;
+            *guard = Some(Open {
+                index,
+                writer,
+                reader,
+                pending: 0,
+            });
+        }
+        let result = f(guard.as_mut().expect("opened above"));
+        if result.is_err() {
+            *guard = None;
+        }
+        result.map(Some)
+    }
+
+    /// Buffer one document; `kind` is `memory` or `chunk`.
+    pub fn add(&self, id: &str, kind: &str, content: &str, timestamp: i64) -> Result<()> {
+        let f = schema().1;
+        self.with_open(true, |open| {
+            open.writer.add_document(doc!(
+                f.id => id,
+                f.id_raw => id,
+                f.kind => kind,
+                f.content => content,
+                f.timestamp => timestamp,
+            ))?;
+            open.pending += 1;
+            if open.pending >= COMMIT_EVERY {
+                commit_open(open)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Buffer the removal of every document with this `id`; it applies to adds buffered
+    /// before it, so a delete followed by an add replaces the document.
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let f = schema().1;
+        self.with_open(false, |open| {
+            open.writer.delete_term(Term::from_field_text(f.id_raw, id));
+            open.pending += 1;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Replace every document with `docs` (`(id, kind, content, timestamp)`) in one
+    /// commit, so searches see either the old index or the complete new one. Returns the
+    /// number of documents added.
+    pub fn rebuild<I>(&self, docs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, &'static str, String, i64)>,
+    {
+        let f = schema().1;
+        let added = self.with_open(true, |open| {
+            // Unlike `delete_all_documents`, this also covers adds still buffered
+            open.writer.delete_query(Box::new(AllQuery))?;
+            let mut added = 0;
+            for (id, kind, content, timestamp) in docs {
+                open.writer.add_document(doc!(
+                    f.id => id.as_str(),
+                    f.id_raw => id.as_str(),
+                    f.kind => kind,
+                    f.content => content,
+                    f.timestamp => timestamp,
+                ))?;
+                added += 1;
+            }
+            // Force the commit even when nothing was added, so the clear lands
+            open.pending += 1;
+            commit_open(open)?;
+            Ok(added)
+        })?;
+        Ok(added.unwrap_or(0))
+    }
+
+    /// Discard an index written with an older schema, returning whether one was dropped;
+    /// the caller re-adds its documents.
+    pub fn ensure_current(&self) -> Result<bool> {
+        let guard = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if guard.is_some() || !self.dir.exists() {
+            return Ok(false);
+        }
+        Ok(open_index(&self.dir)?.1)
+    }
+
+    /// Commit buffered adds, if any.
+    pub fn commit(&self) -> Result<()> {
+        self.with_open(false, commit_open)?;
+        Ok(())
+    }
+
+    /// Commit, let background merges finish and release the writer (and its lock), leaving
+    /// the directory quiet for copying or replacing; the next call reopens it.
+    pub fn close(&self) -> Result<()> {
+        let mut guard = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(mut open) = guard.take() {
+            commit_open(&mut open)?;
+            open.writer.wait_merging_threads()?;
+        }
+        Ok(())
+    }
+
+    /// BM25-ranked ids matching `query` on the `content` field.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<(String, f32)>> {
+        if query.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        let f = schema().1;
+        // Only the commit needs the lock; searching runs on a snapshot outside it
+        let Some((index, searcher)) = self.with_open(false, |open| {
+            commit_open(open)?;
+            Ok((open.index.clone(), open.reader.searcher()))
+        })?
+        else {
+            return Ok(Vec::new());
+        };
+        let cap = limit.min(searcher.num_docs() as usize);
+        if cap == 0 {
+            return Ok(Vec::new());
+        }
+        let (parsed, _errors) =
+            QueryParser::for_index(&index, vec![f.content]).parse_query_lenient(query);
+        let mut out: Vec<(String, f32)> = Vec::new();
+        for (score, addr) in searcher.search(&parsed, &TopDocs::with_limit(cap))? {
+            let doc: TantivyDocument = searcher.doc(addr)?;
+            if let Some(id) = doc.get_first(f.id_raw).and_then(|v| v.as_str()) {
+                out.push((id.to_string(), score));
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Open or create the index at `dir`, replacing one whose schema differs from ours.
+/// The flag reports a replacement.
+fn open_index(dir: &Path) -> Result<(Index, bool)> {
+    let mut reset = false;
+    if dir.exists() {
+        let stale = Index::open(MmapDirectory::open(dir)?)?.schema() != schema().0;
+        if stale {
+            std::fs::remove_dir_all(dir)?;
+            reset = true;
+        }
+    }
+    std::fs::create_dir_all(dir)?;
+    let index = Index::open_or_create(MmapDirectory::open(dir)?, schema().0.clone())?;
+    Ok((index, reset))
+}
+
+fn commit_open(open: &mut Open) -> Result<()> {
+    if open.pending > 0 {
+        open.writer.commit()?;
+        open.reader.reload()?;
+        open.pending = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_adds_are_searchable_before_the_timer_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = super::TextIndex::new(dir.path());
+        // Nothing stored yet: searching neither fails nor creates the index
+        assert!(index.search("rust", 10).unwrap().is_empty());
+        assert!(!dir.path().join("tantivy").exists());
+
+        index
+            .add("mem:a", "memory", "rust borrow checker", 1)
+            .unwrap();
+        index.add("mem:b", "memory", "python notebooks", 1).unwrap();
+        let hits = index.search("rust", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0, "mem:a");
+
+        // Closing releases the writer lock; the index reopens with its data intact
+        index.close().unwrap();
+        let reopened = super::TextIndex::new(dir.path());
+        assert_eq!(reopened.search("python", 10).unwrap()[0].0, "mem:b");
+    }
+
+    #[test]
+    fn test_index_with_an_older_schema_is_replaced() {
+        use tantivy::schema::{Schema, TEXT};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tantivy");
+        std::fs::create_dir_all(&path).unwrap();
+        let mut old = Schema::builder();
+        old.add_text_field("id", TEXT);
+        tantivy::Index::create_in_dir(&path, old.build()).unwrap();
+
+        let index = super::TextIndex::new(dir.path());
+        assert!(index.ensure_current().unwrap());
+        assert!(!index.ensure_current().unwrap());
+        index.add("mem:a", "memory", "rust", 1).unwrap();
+        index.delete("mem:a").unwrap();
+        index.add("mem:a", "memory", "rust again", 1).unwrap();
+        assert_eq!(index.search("rust", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rebuild_replaces_all_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = super::TextIndex::new(dir.path());
+        index
+            .add("mem:ghost", "memory", "stale rust note", 1)
+            .unwrap();
+        index.add("mem:a", "memory", "old rust text", 1).unwrap();
+        let docs = vec![(
+            "mem:a".to_string(),
+            "memory",
+            "fresh rust text".to_string(),
+            2,
+        )];
+        assert_eq!(index.rebuild(docs).unwrap(), 1);
+        let hits = index.search("rust", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0, "mem:a");
+        assert!(index.search("stale", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_delete_removes_only_the_exact_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = super::TextIndex::new(dir.path());
+        // Tokenized, all three ids share the tokens of `doc1:0`
+        for id in ["doc1:0", "doc1:0:extra", "mem:doc1:0"] {
+            index.add(id, "chunk", "shared passage", 1).unwrap();
+        }
+        index.delete("doc1:0").unwrap();
+        let mut left: Vec<String> = index
+            .search("passage", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        left.sort();
+        assert_eq!(left, ["doc1:0:extra", "mem:doc1:0"]);
+    }
+}
@@ -1,8 +1,25 @@
 use crate::embeddings::EMBED_DIM;
 use anyhow::Result;
-use std::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::RwLock;
 
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+/// Cosine similarity; uses the AVX2/FMA kernel when the CPU supports it at runtime.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // SAFETY: required CPU features were checked just above
+            return unsafe { cosine_avx2(a, b) };
+        }
+    }
+    cosine_similarity_scalar(a, b)
+}
+
+/// Portable scalar cosine; the fallback and reference for the SIMD path.
+pub fn cosine_similarity_scalar(a: &[f32], b: &[f32]) -> f32 {
     let mut dot = 0.0f32;
     let mut na = 0.0f32;
     let mut nb = 0.0f32;
@@ -13,12 +30,359 @@ fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
         na += x * x;
         nb += y * y;
     }
+    finish_cosine(dot, na, nb)
+}
+
+fn finish_cosine(dot: f32, na: f32, nb: f32) -> f32 {
     if na == 0.0 || nb == 0.0 {
         return 0.0;
     }
     dot / (na.sqrt() * nb.sqrt())
 }
 
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn hsum256(v: std::arch::x86_64::__m256) -> f32 {
+    let mut lanes = [0.0f32; 8];
+    std::arch::x86_64::_mm256_storeu_ps(lanes.as_mut_ptr(), v);
+    lanes.iter().sum()
+}
+
+/// 8 lanes per step with fused multiply-add; the tail is handled scalar.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn cosine_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+    let n = a.len().min(b.len());
+    let body = n - n % 8;
+    let mut vdot = _mm256_setzero_ps();
+    let mut va = _mm256_setzero_ps();
+    let mut vb = _mm256_setzero_ps();
+    let mut i = 0;
+    while i < body {
+        let x = _mm256_loadu_ps(a.as_ptr().add(i));
+        let y = _mm256_loadu_ps(b.as_ptr().add(i));
+        vdot = _mm256_fmadd_ps(x, y, vdot);
+        va = _mm256_fmadd_ps(x, x, va);
+        vb = _mm256_fmadd_ps(y, y, vb);
+        i += 8;
+    }
+    let mut dot = hsum256(vdot);
+    let mut na = hsum256(va);
+    let mut nb = hsum256(vb);
+    for j in body..n {
+        dot += a[j] * b[j];
+        na += a[j] * a[j];
+        nb += b[j] * b[j];
+    }
+    finish_cosine(dot, na, nb)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+    let n = a.len().min(b.len());
+    let body = n - n % 8;
+    let mut acc = _mm256_setzero_ps();
+    let mut i = 0;
+    while i < body {
+        let x = _mm256_loadu_ps(a.as_ptr().add(i));
+        let y = _mm256_loadu_ps(b.as_ptr().add(i));
+        acc = _mm256_fmadd_ps(x, y, acc);
+        i += 8;
+    }
+    let mut sum = hsum256(acc);
+    for j in body..n {
+        sum += a[j] * b[j];
+    }
+    sum
+}
+
+/// L2-normalize a vector in place; zero vectors are left untouched.
+pub fn normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in v.iter_mut() {
+            *x /= norm;
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // SAFETY: required CPU features were checked just above
+            return unsafe { dot_avx2(a, b) };
+        }
+    }
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Similarity used by searches: plain dot product once every stored vector is unit length,
+/// full cosine for legacy un-normalized data.
+fn similarity(a: &[f32], b: &[f32], normalized: bool) -> f32 {
+    if normalized {
+        dot(a, b)
+    } else {
+        cosine_similarity(a, b)
+    }
+}
+
+fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Scoring function for memory vector search. Every metric reports "higher is better";
+/// euclidean distance is negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+    #[default]
+    Cosine,
+    Dot,
+    Euclidean,
+}
+
+impl DistanceMetric {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cosine" => Some(Self::Cosine),
+            "dot" => Some(Self::Dot),
+            "euclidean" | "l2" => Some(Self::Euclidean),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cosine => "cosine",
+            Self::Dot => "dot",
+            Self::Euclidean => "euclidean",
+        }
+    }
+
+    /// Metric requested via `VECTOR_METRIC` (cosine, dot, euclidean); cosine when unset.
+    pub fn from_env() -> Self {
+        std::env::var("VECTOR_METRIC")
+            .ok()
+            .and_then(|v| Self::parse(&v))
+            .unwrap_or_default()
+    }
+
+    fn score(self, a: &[f32], b: &[f32], normalized: bool) -> f32 {
+        match self {
+            Self::Cosine => similarity(a, b, normalized),
+            Self::Dot => dot(a, b),
+            Self::Euclidean => -euclidean_distance(a, b),
+        }
+    }
+}
+
+/// Metric the stored graph was built with (`metric` in vec_meta); cosine if never set.
+pub fn metric(db: &sled::Db) -> DistanceMetric {
+    db.open_tree("vec_meta")
+        .ok()
+        .and_then(|meta| meta.get(b"metric").ok().flatten())
+        .and_then(|v| DistanceMetric::parse(&String::from_utf8_lossy(&v)))
+        .unwrap_or_default()
+}
+
+pub fn set_metric(db: &sled::Db, metric: DistanceMetric) -> Result<()> {
+    let meta = db.open_tree("vec_meta")?;
+    meta.insert(b"metric", metric.name().as_bytes())?;
+    Ok(())
+}
+
+/// Persist the `VECTOR_METRIC` choice; when it differs from the stored one an existing
+/// HNSW graph is rebuilt since its links were chosen under the old metric. Returns true if changed.
+pub fn ensure_metric(db: &sled::Db) -> Result<bool> {
+    let wanted = DistanceMetric::from_env();
+    let meta = db.open_tree("vec_meta")?;
+    let stored = meta.get(b"metric")?;
+    if stored.is_some() && metric(db) == wanted {
+        return Ok(false);
+    }
+    set_metric(db, wanted)?;
+    if meta.get(b"hnsw_entry")?.is_some() && stored.is_some() {
+        build_mem_neighbor_graph(db)?;
+    }
+    Ok(stored.is_some())
+}
+
+/// Whether all memory embeddings are stored L2-normalized (`normalized` flag in vec_meta).
+pub fn is_normalized(db: &sled::Db) -> bool {
+    db.open_tree("vec_meta")
+        .ok()
+        .and_then(|meta| meta.get(b"normalized").ok().flatten())
+        .map(|v| v.as_ref() == b"true")
+        .unwrap_or(false)
+}
+
+fn set_normalized(db: &sled::Db) -> Result<()> {
+    let meta = db.open_tree("vec_meta")?;
+    meta.insert(b"normalized", b"true".as_ref())?;
+    Ok(())
+}
+
+/// Normalize legacy memory embeddings once and record the flag; no-op on later restarts.
+pub fn ensure_normalized(db: &sled::Db) -> Result<u64> {
+    if is_normalized(db) {
+        return Ok(0);
+    }
+    let emb = db.open_tree("mem_embeddings")?;
+    let mode = quantization(db);
+    let mut updated: u64 = 0;
+    for kv in emb.iter() {
+        let (k, v) = kv?;
+        let Some(mut vec) = decode_embedding(&v) else {
+            continue;
+        };
+        normalize(&mut vec);
+        emb.insert(k, encode_embedding(&vec, mode))?;
+        updated += 1;
+    }
+    set_normalized(db)?;
+    Ok(updated)
+}
+
+/// Storage format of memory embeddings: raw f32, or int8 with a per-vector scale
+/// (about a quarter of the size).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quantization {
+    #[default]
+    F32,
+    Int8,
+}
+
+impl Quantization {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "f32" | "none" => Some(Self::F32),
+            "int8" => Some(Self::Int8),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::Int8 => "int8",
+        }
+    }
+
+    /// From `EMBED_QUANTIZE`; full precision when unset or unrecognized.
+    pub fn from_env() -> Self {
+        std::env::var("EMBED_QUANTIZE")
+            .ok()
+            .and_then(|v| Self::parse(&v))
+            .unwrap_or_default()
+    }
+
+    /// Stored bytes per embedding.
+    fn byte_len(self) -> usize {
+        match self {
+            Self::F32 => EMBED_DIM * 4,
+            Self::Int8 => 4 + EMBED_DIM,
+        }
+    }
+}
+
+/// Format memory embeddings are written in (`quantization` in vec_meta); f32 if never set.
+pub fn quantization(db: &sled::Db) -> Quantization {
+    db.open_tree("vec_meta")
+        .ok()
+        .and_then(|meta| meta.get(b"quantization").ok().flatten())
+        .and_then(|v| Quantization::parse(&String::from_utf8_lossy(&v)))
+        .unwrap_or_default()
+}
+
+pub fn set_quantization(db: &sled::Db, mode: Quantization) -> Result<()> {
+    let meta = db.open_tree("vec_meta")?;
+    meta.insert(b"quantization", mode.name().as_bytes())?;
+    Ok(())
+}
+
+/// Encode a memory embedding for `mem_embeddings`. Int8 stores the scale (max |x| / 127)
+/// as a little-endian f32 followed by one signed byte per component.
+pub fn encode_embedding(vec: &[f32], mode: Quantization) -> Vec<u8> {
+    match mode {
+        Quantization::F32 => bytemuck::cast_slice::<f32, u8>(vec).to_vec(),
+        Quantization::Int8 => {
+            let max = vec.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            let scale = if max > 0.0 { max / 127.0 } else { 0.0 };
+            let mut out = Vec::with_capacity(4 + vec.len());
+            out.extend_from_slice(&scale.to_le_bytes());
+            for x in vec {
+                let q = if scale > 0.0 {
+                    (x / scale).round()
+                } else {
+                    0.0
+                };
+                out.push(q.clamp(-127.0, 127.0) as i8 as u8);
+            }
+            out
+        }
+    }
+}
+
+/// Decode a `mem_embeddings` value in either format, told apart by length; None for any
+/// other length.
+pub fn decode_embedding(bytes: &[u8]) -> Option<Vec<f32>> {
+    if bytes.len() == Quantization::F32.byte_len() {
+        // Copy out rather than cast: sled values carry no alignment guarantee
+        return Some(
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        );
+    }
+    if bytes.len() == Quantization::Int8.byte_len() {
+        let scale = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        return Some(bytes[4..].iter().map(|&b| b as i8 as f32 * scale).collect());
+    }
+    None
+}
+
+/// Record the `EMBED_QUANTIZE` choice; when it differs from the recorded one, stored
+/// memory embeddings are rewritten in the new format so the tree never mixes modes.
+/// Returns the number rewritten.
+pub fn ensure_quantization(db: &sled::Db) -> Result<u64> {
+    let wanted = Quantization::from_env();
+    if quantization(db) == wanted {
+        set_quantization(db, wanted)?;
+        return Ok(0);
+    }
+    let emb = db.open_tree("mem_embeddings")?;
+    let mut rewritten: u64 = 0;
+    for kv in emb.iter() {
+        let (k, v) = kv?;
+        if v.len() == wanted.byte_len() {
+            continue;
+        }
+        if let Some(vec) = decode_embedding(&v) {
+            emb.insert(k, encode_embedding(&vec, wanted))?;
+            rewritten += 1;
+        }
+    }
+    set_quantization(db, wanted)?;
+    Ok(rewritten)
+}
+
+/// Normalize a query vector when stored vectors are unit length so dot == cosine.
+fn prepare_query(query: &[f32], normalized: bool) -> Vec<f32> {
+    let mut q = query.to_vec();
+    if normalized {
+        normalize(&mut q);
+    }
+    q
+}
+
 pub fn record_vectors(
     db: &sled::Db,
     doc_id: &str,
@@ -45,28 +409,229 @@ pub fn record_vectors(
     Ok(())
 }
 
-/// Search memory embeddings by cosine similarity. Returns (id, score) top_k.
-pub fn search_memories_by_vector(db: &sled::Db, query: &[f32], top_k: usize) -> Vec<(String, f32)> {
-    let mut hits: Vec<(String, f32)> = Vec::new();
-    if let Ok(tree) = db.open_tree("mem_embeddings") {
-        for kv in tree.iter() {
-            if let Ok((k, v)) = kv {
-                let id = String::from_utf8_lossy(&k).to_string();
-                // Validate dimension
-                if v.len() != EMBED_DIM * 4 {
-                    continue;
-                }
-                let emb: &[f32] = bytemuck::cast_slice(&v);
-                let score = cosine_similarity(query, emb);
-                hits.push((id, score));
+/// Search document chunk embeddings (`embeddings` tree, keyed `docId:start`) by cosine
+/// similarity. Zero-norm placeholder vectors are skipped. Returns (docId, chunkStart, score) top_k.
+pub fn search_chunks_by_vector(
+    db: &sled::Db,
+    query: &[f32],
+    top_k: usize,
+) -> Vec<(String, usize, f32)> {
+    let mut hits: Vec<(String, usize, f32)> = Vec::new();
+    if let Ok(tree) = db.open_tree("embeddings") {
+        for (k, v) in tree.iter().flatten() {
+            if v.len() != EMBED_DIM * 4 {
+                continue;
+            }
+            let key = String::from_utf8_lossy(&k).to_string();
+            let (doc_id, start) = match key.rsplit_once(':') {
+                Some((d, s)) => match s.parse::<usize>() {
+                    Ok(start) => (d.to_string(), start),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let Some(emb) = decode_embedding(&v) else {
+                continue;
+            };
+            if emb.iter().all(|x| *x == 0.0) {
+                continue;
             }
+            hits.push((doc_id, start, cosine_similarity(query, &emb)));
         }
     }
-    hits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    hits.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
     hits.truncate(top_k);
     hits
 }
 
+/// Optional layer/episode/time constraints for memory vector searches, checked against each
+/// candidate's `memories` record. Missing `created_at` passes the time window, as in `memory_search`.
+#[derive(Debug, Clone, Default)]
+pub struct VectorFilter {
+    pub layer: Option<String>,
+    pub episode: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl VectorFilter {
+    pub fn is_empty(&self) -> bool {
+        self.layer.is_none() && self.episode.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    fn matches(&self, mems: &sled::Tree, id: &str) -> bool {
+        let rec = match mems.get(id.as_bytes()) {
+            Ok(Some(v)) => match serde_json::from_slice::<serde_json::Value>(&v) {
+                Ok(r) => r,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        if let Some(l) = self.layer.as_deref() {
+            if rec.get("layer").and_then(|x| x.as_str()) != Some(l) {
+                return false;
+            }
+        }
+        if let Some(e) = self.episode.as_deref() {
+            if rec.get("episode_id").and_then(|x| x.as_str()) != Some(e) {
+                return false;
+            }
+        }
+        match rec.get("created_at").and_then(|x| x.as_i64()) {
+            Some(t) => {
+                self.from.map(|f| t >= f).unwrap_or(true)
+                    && self.to.map(|to| t <= to).unwrap_or(true)
+            }
+            None => true,
+        }
+    }
+}
+
+/// The `memories` tree when `filter` has constraints to check, else None.
+fn filter_tree(db: &sled::Db, filter: Option<&VectorFilter>) -> Option<sled::Tree> {
+    filter
+        .filter(|f| !f.is_empty())
+        .and_then(|_| db.open_tree("memories").ok())
+}
+
+/// Scores candidate memory embeddings for one query under the stored metric and keeps
+/// those passing `filter`; shared by the sled scan and the warm copy.
+struct Ranker<'a> {
+    query: Vec<f32>,
+    normalized: bool,
+    metric: DistanceMetric,
+    filter: Option<&'a VectorFilter>,
+    mems: Option<sled::Tree>,
+    hits: Vec<(String, f32)>,
+}
+
+impl<'a> Ranker<'a> {
+    fn new(db: &sled::Db, query: &[f32], filter: Option<&'a VectorFilter>) -> Self {
+        let normalized = is_normalized(db);
+        Self {
+            query: prepare_query(query, normalized),
+            normalized,
+            metric: metric(db),
+            filter,
+            mems: filter_tree(db, filter),
+            hits: Vec::new(),
+        }
+    }
+
+    fn offer(&mut self, id: &str, emb: &[f32]) {
+        if let (Some(f), Some(m)) = (self.filter, self.mems.as_ref()) {
+            if !f.matches(m, id) {
+                return;
+            }
+        }
+        let score = self.metric.score(&self.query, emb, self.normalized);
+        self.hits.push((id.to_string(), score));
+    }
+
+    /// Best `top_k` by score; ties keep the order candidates were offered in.
+    fn finish(mut self, top_k: usize) -> Vec<(String, f32)> {
+        self.hits
+            .sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        self.hits.truncate(top_k);
+        self.hits
+    }
+}
+
+/// Search memory embeddings by cosine similarity, skipping ids rejected by `filter`.
+/// Returns (id, score) top_k.
+pub fn search_memories_by_vector(
+    db: &sled::Db,
+    query: &[f32],
+    top_k: usize,
+    filter: Option<&VectorFilter>,
+) -> Vec<(String, f32)> {
+    let mut ranker = Ranker::new(db, query, filter);
+    if let Ok(tree) = db.open_tree("mem_embeddings") {
+        for (k, v) in tree.iter().flatten() {
+            // Skips values of the wrong dimension
+            if let Some(emb) = decode_embedding(&v) {
+                ranker.offer(&String::from_utf8_lossy(&k), &emb);
+            }
+        }
+    }
+    ranker.finish(top_k)
+}
+
+/// In-memory copy of `mem_embeddings`, filled by `load` (at startup when
+/// `WARM_VECTOR_INDEX=1`). Until then it is cold and searches read sled. Kept in key
+/// order so ties rank exactly as in the sled scan.
+#[derive(Default)]
+pub struct WarmVectors {
+    vectors: RwLock<Option<BTreeMap<String, Vec<f32>>>>,
+}
+
+impl WarmVectors {
+    /// Whether `WARM_VECTOR_INDEX` asks for the copy at startup.
+    pub fn enabled_by_env() -> bool {
+        std::env::var("WARM_VECTOR_INDEX")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(false)
+    }
+
+    /// Replace the copy with every valid stored embedding; returns how many were loaded.
+    pub fn load(&self, db: &sled::Db) -> Result<usize> {
+        let mut vectors = BTreeMap::new();
+        for kv in db.open_tree("mem_embeddings")?.iter() {
+            let (k, v) = kv?;
+            if let Some(emb) = decode_embedding(&v) {
+                vectors.insert(String::from_utf8_lossy(&k).to_string(), emb);
+            }
+        }
+        let n = vectors.len();
+        *self.vectors.write().unwrap_or_else(|e| e.into_inner()) = Some(vectors);
+        Ok(n)
+    }
+
+    /// Reload the copy if it is warm, e.g. after a restore or re-embedding.
+    pub fn reload(&self, db: &sled::Db) -> Result<()> {
+        if self.is_warm() {
+            self.load(db)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_warm(&self) -> bool {
+        self.vectors
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    /// Sync one memory's entry with sled after its embedding was written or removed.
+    pub fn refresh(&self, db: &sled::Db, id: &str) {
+        let mut guard = self.vectors.write().unwrap_or_else(|e| e.into_inner());
+        let Some(vectors) = guard.as_mut() else {
+            return;
+        };
+        match get_mem_embedding(db, id) {
+            Some(v) => vectors.insert(id.to_string(), v),
+            None => vectors.remove(id),
+        };
+    }
+
+    /// `search_memories_by_vector` over the copy, or None while it is cold.
+    pub fn search(
+        &self,
+        db: &sled::Db,
+        query: &[f32],
+        top_k: usize,
+        filter: Option<&VectorFilter>,
+    ) -> Option<Vec<(String, f32)>> {
+        let guard = self.vectors.read().unwrap_or_else(|e| e.into_inner());
+        let vectors = guard.as_ref()?;
+        let mut ranker = Ranker::new(db, query, filter);
+        for (id, emb) in vectors {
+            ranker.offer(id, emb);
+        }
+        Some(ranker.finish(top_k))
+    }
+}
+
 /// Remove mem_embeddings entries whose memory record no longer exists.
 pub fn cleanup_orphan_mem_embeddings(db: &sled::Db) -> Result<u64> {
     let emb = db.open_tree("mem_embeddings")?;
@@ -82,17 +647,17 @@ pub fn cleanup_orphan_mem_embeddings(db: &sled::Db) -> Result<u64> {
     Ok(removed)
 }
 
-/// Validate embedding dimensions; returns (total, invalid) counts.
+/// Validate embedding sizes against the recorded quantization, so vectors of the wrong
+/// dimension and vectors left in the other format both count; returns (total, invalid).
 pub fn validate_mem_embeddings(db: &sled::Db) -> (u64, u64) {
+    let expected = quantization(db).byte_len();
     let mut total: u64 = 0;
     let mut invalid: u64 = 0;
     if let Ok(tree) = db.open_tree("mem_embeddings") {
-        for kv in tree.iter() {
-            if let Ok((_, v)) = kv {
-                total += 1;
-                if v.len() != EMBED_DIM * 4 {
-                    invalid += 1;
-                }
+        for (_, v) in tree.iter().flatten() {
+            total += 1;
+            if v.len() != expected {
+                invalid += 1;
             }
         }
     }
@@ -100,174 +665,582 @@ pub fn validate_mem_embeddings(db: &sled::Db) -> (u64, u64) {
 }
 
 fn get_mem_embedding(db: &sled::Db, id: &str) -> Option<Vec<f32>> {
-    if let Ok(tree) = db.open_tree("mem_embeddings") {
-        if let Ok(Some(v)) = tree.get(id.as_bytes()) {
-            if v.len() != EMBED_DIM * 4 {
-                return None;
-            }
-            let slice: &[f32] = bytemuck::cast_slice(&v);
-            return Some(slice.to_vec());
+    let tree = db.open_tree("mem_embeddings").ok()?;
+    let v = tree.get(id.as_bytes()).ok()??;
+    decode_embedding(&v)
+}
+
+/// HNSW parameters: `m` links per node (2*m on layer 0), beam widths for build and search.
+#[derive(Clone, Copy, Debug)]
+pub struct HnswParams {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
         }
     }
-    None
 }
 
-/// Build a neighbor graph for memories (HNSW-like single layer), storing top-M neighbors by cosine.
-pub fn build_mem_neighbor_graph(db: &sled::Db, m_neighbors: usize) -> Result<u64> {
-    let emb = db.open_tree("mem_embeddings")?;
-    let mut ids: Vec<String> = Vec::new();
-    let mut vecs: Vec<Vec<f32>> = Vec::new();
-    for kv in emb.iter() {
-        let (k, v) = kv?;
-        if v.len() != EMBED_DIM * 4 {
-            continue;
+impl HnswParams {
+    /// Read HNSW_M, HNSW_EF_CONSTRUCTION and HNSW_EF_SEARCH, falling back to defaults.
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        let read = |key: &str, default: usize| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(default)
+        };
+        Self {
+            m: read("HNSW_M", d.m).max(2),
+            ef_construction: read("HNSW_EF_CONSTRUCTION", d.ef_construction),
+            ef_search: read("HNSW_EF_SEARCH", d.ef_search),
         }
-        ids.push(String::from_utf8_lossy(&k).to_string());
-        let sl: &[f32] = bytemuck::cast_slice(&v);
-        vecs.push(sl.to_vec());
     }
-    let n = ids.len();
-    if n == 0 {
-        return Ok(0);
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.m * 2
+        } else {
+            self.m
+        }
     }
-    let neigh = db.open_tree("hnsw_mem_neighbors")?;
-    use rayon::prelude::*;
-    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..n)
-        .into_par_iter()
-        .map(|i| {
-            let a = &vecs[i];
-            let mut top: Vec<(f32, usize)> = Vec::with_capacity(m_neighbors + 1);
-            for j in 0..n {
-                if i == j {
+}
+
+const HNSW_MAX_LEVEL: usize = 16;
+
+/// Serializes graph writers; concurrent read-modify-write of adjacency lists would drop links.
+static HNSW_WRITE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Persisted node in `hnsw_mem_neighbors`: its top layer and neighbor ids per layer.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct HnswNode {
+    level: usize,
+    neighbors: Vec<Vec<String>>,
+}
+
+/// Deterministic level from the id hash, geometric with multiplier 1/ln(M).
+fn assign_level(id: &str, m: usize) -> usize {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(id.as_bytes());
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&digest[..8]);
+    let r = (u64::from_le_bytes(b) as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+    let mult = 1.0 / (m.max(2) as f64).ln();
+    ((-r.ln() * mult).floor() as usize).min(HNSW_MAX_LEVEL)
+}
+
+#[derive(PartialEq)]
+struct Scored {
+    score: f32,
+    id: String,
+}
+impl Eq for Scored {}
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Working view over the persisted graph with read-through caches; writes are buffered
+/// until `flush` so a full rebuild doesn't reserialize nodes on every insert.
+struct Hnsw<'a> {
+    db: &'a sled::Db,
+    tree: sled::Tree,
+    params: HnswParams,
+    normalized: bool,
+    metric: DistanceMetric,
+    vecs: HashMap<String, Option<Rc<Vec<f32>>>>,
+    nodes: HashMap<String, Option<HnswNode>>,
+    dirty: HashSet<String>,
+    entry: Option<(String, usize)>,
+}
+
+impl<'a> Hnsw<'a> {
+    fn open(db: &'a sled::Db, params: HnswParams) -> Result<Self> {
+        let tree = db.open_tree("hnsw_mem_neighbors")?;
+        let meta = db.open_tree("vec_meta")?;
+        let entry_id = meta
+            .get(b"hnsw_entry")?
+            .map(|v| String::from_utf8_lossy(&v).to_string());
+        let max_level = meta
+            .get(b"hnsw_max_level")?
+            .map(|v| u64::from_le_bytes(v.as_ref().try_into().unwrap_or([0u8; 8])) as usize)
+            .unwrap_or(0);
+        Ok(Self {
+            db,
+            tree,
+            params,
+            normalized: is_normalized(db),
+            metric: metric(db),
+            vecs: HashMap::new(),
+            nodes: HashMap::new(),
+            dirty: HashSet::new(),
+            entry: entry_id.map(|id| (id, max_level)),
+        })
+    }
+
+    fn vector(&mut self, id: &str) -> Option<Rc<Vec<f32>>> {
+        if let Some(v) = self.vecs.get(id) {
+            return v.clone();
+        }
+        let v = get_mem_embedding(self.db, id).map(Rc::new);
+        self.vecs.insert(id.to_string(), v.clone());
+        v
+    }
+
+    fn node(&mut self, id: &str) -> Option<HnswNode> {
+        if let Some(n) = self.nodes.get(id) {
+            return n.clone();
+        }
+        let n = self
+            .tree
+            .get(id.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|raw| serde_json::from_slice::<HnswNode>(&raw).ok());
+        self.nodes.insert(id.to_string(), n.clone());
+        n
+    }
+
+    fn put_node(&mut self, id: &str, node: HnswNode) {
+        self.nodes.insert(id.to_string(), Some(node));
+        self.dirty.insert(id.to_string());
+    }
+
+    fn score(&mut self, query: &[f32], id: &str) -> Option<f32> {
+        let v = self.vector(id)?;
+        Some(self.metric.score(query, &v, self.normalized))
+    }
+
+    fn neighbors_at(&mut self, id: &str, layer: usize) -> Vec<String> {
+        self.node(id)
+            .and_then(|n| n.neighbors.get(layer).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Beam search on one layer; returns up to `ef` hits sorted by descending score.
+    /// Ids without an embedding are skipped.
+    fn search_layer(
+        &mut self,
+        query: &[f32],
+        entry_points: &[(f32, String)],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<(f32, String)> {
+        let mut visited: HashSet<String> = HashSet::new();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for (score, id) in entry_points {
+            if visited.insert(id.clone()) {
+                candidates.push(Scored {
+                    score: *score,
+                    id: id.clone(),
+                });
+                results.push(Reverse(Scored {
+                    score: *score,
+                    id: id.clone(),
+                }));
+            }
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+        while let Some(cur) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0.score).unwrap_or(f32::MIN);
+            if cur.score < worst && results.len() >= ef {
+                break;
+            }
+            for nid in self.neighbors_at(&cur.id, layer) {
+                if !visited.insert(nid.clone()) {
                     continue;
                 }
-                let score = cosine_similarity(a, &vecs[j]);
-                if top.len() < m_neighbors {
-                    top.push((score, j));
-                } else {
-                    top.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(Ordering::Equal));
-                    if let Some((min_score, _)) = top.first() {
-                        if score > *min_score {
-                            top[0] = (score, j);
-                        }
+                let s = match self.score(query, &nid) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let worst = results.peek().map(|r| r.0.score).unwrap_or(f32::MIN);
+                if results.len() < ef || s > worst {
+                    candidates.push(Scored {
+                        score: s,
+                        id: nid.clone(),
+                    });
+                    results.push(Reverse(Scored { score: s, id: nid }));
+                    if results.len() > ef {
+                        results.pop();
                     }
                 }
             }
-            top.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
-            let arr: Vec<serde_json::Value> = top
-                .into_iter()
-                .map(|(s, idx)| serde_json::json!({ "id": ids[idx], "score": s }))
+        }
+        let mut out: Vec<(f32, String)> = results
+            .into_iter()
+            .map(|Reverse(s)| (s.score, s.id))
+            .collect();
+        out.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        out
+    }
+
+    /// Greedy descent from the entry point to `target_layer`, then a beam search there.
+    fn descend(
+        &mut self,
+        query: &[f32],
+        target_layer: usize,
+        ef: usize,
+    ) -> Option<Vec<(f32, String)>> {
+        let (entry_id, max_level) = self.entry.clone()?;
+        let score = self.score(query, &entry_id)?;
+        let mut cur = vec![(score, entry_id)];
+        let mut layer = max_level;
+        while layer > target_layer {
+            cur = self.search_layer(query, &cur, 1, layer);
+            layer -= 1;
+        }
+        Some(self.search_layer(query, &cur, ef, target_layer))
+    }
+
+    /// Keep the `max` closest links of `id` on `layer`.
+    fn prune(&mut self, id: &str, layer: usize, max: usize) {
+        let base = match self.vector(id) {
+            Some(v) => v,
+            None => return,
+        };
+        let mut node = match self.node(id) {
+            Some(n) => n,
+            None => return,
+        };
+        let links = node.neighbors.get(layer).cloned().unwrap_or_default();
+        if links.len() <= max {
+            return;
+        }
+        let mut scored: Vec<(f32, String)> = links
+            .into_iter()
+            .filter_map(|n| self.score(&base, &n).map(|s| (s, n)))
+            .collect();
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        scored.truncate(max);
+        node.neighbors[layer] = scored.into_iter().map(|(_, n)| n).collect();
+        self.put_node(id, node);
+    }
+
+    fn insert(&mut self, id: &str, vec: &[f32]) {
+        let query = prepare_query(vec, self.normalized);
+        self.vecs
+            .insert(id.to_string(), Some(Rc::new(query.clone())));
+        let existing = self.node(id);
+        let level = existing
+            .as_ref()
+            .map(|n| n.level)
+            .unwrap_or_else(|| assign_level(id, self.params.m));
+        let mut node = HnswNode {
+            level,
+            neighbors: vec![Vec::new(); level + 1],
+        };
+        let (entry_id, max_level) = match self.entry.clone() {
+            Some(e) if e.0 != id => e,
+            Some(_) => {
+                // Re-embedding the entry point: keep its links, only the vector changed
+                if existing.is_none() {
+                    self.put_node(id, node);
+                }
+                return;
+            }
+            None => {
+                self.put_node(id, node);
+                self.entry = Some((id.to_string(), level));
+                return;
+            }
+        };
+        let mut cur = match self.score(&query, &entry_id) {
+            Some(s) => vec![(s, entry_id)],
+            None => Vec::new(),
+        };
+        let mut layer = max_level;
+        while layer > level && !cur.is_empty() {
+            cur = self.search_layer(&query, &cur, 1, layer);
+            layer -= 1;
+        }
+        for l in (0..=level.min(max_level)).rev() {
+            if cur.is_empty() {
+                break;
+            }
+            let found = self.search_layer(&query, &cur, self.params.ef_construction, l);
+            let links: Vec<String> = found
+                .iter()
+                .filter(|(_, n)| n != id)
+                .take(self.params.m)
+                .map(|(_, n)| n.clone())
                 .collect();
-            (
-                ids[i].as_bytes().to_vec(),
-                serde_json::to_vec(&arr).unwrap_or_default(),
-            )
-        })
-        .collect();
-    for (k, v) in entries {
-        neigh.insert(k, v)?;
-    }
-    let edges_written: u64 = n as u64;
-    Ok(edges_written)
-}
-
-/// ANN search over the neighbor graph; falls back to brute force if graph missing.
-pub fn ann_search_memories(db: &sled::Db, query: &[f32], top_k: usize) -> Vec<(String, f32)> {
-    let neigh = db.open_tree("hnsw_mem_neighbors");
-    if neigh.is_err() {
-        return search_memories_by_vector(db, query, top_k);
-    }
-    let neigh = neigh.unwrap();
-    // choose entry: pick first with highest sim among first 16 entries
-    let emb = db.open_tree("mem_embeddings");
-    if emb.is_err() {
-        return Vec::new();
-    }
-    let emb = emb.unwrap();
-    let mut entry_id: Option<String> = None;
-    let mut best_sim = -1.0f32;
-    for (idx, kv) in emb.iter().enumerate() {
-        if idx >= 16 {
-            break;
+            node.neighbors[l] = links.clone();
+            for n in links {
+                if let Some(mut other) = self.node(&n) {
+                    if let Some(list) = other.neighbors.get_mut(l) {
+                        if !list.iter().any(|x| x == id) {
+                            list.push(id.to_string());
+                        }
+                    }
+                    self.put_node(&n, other);
+                    self.prune(&n, l, self.params.max_links(l));
+                }
+            }
+            cur = found;
+        }
+        self.put_node(id, node);
+        if level > max_level {
+            self.entry = Some((id.to_string(), level));
         }
-        if let Ok((k, v)) = kv {
-            if v.len() == EMBED_DIM * 4 {
-                let id = String::from_utf8_lossy(&k).to_string();
-                let vec: &[f32] = bytemuck::cast_slice(&v);
-                let s = cosine_similarity(query, vec);
-                if s > best_sim {
-                    best_sim = s;
-                    entry_id = Some(id);
+    }
+
+    /// Unlink `id` from every layer, reconnecting its former neighbors through each other
+    /// so the layer stays navigable. Picks a new entry point if `id` was the entry.
+    fn remove(&mut self, id: &str) -> bool {
+        let node = match self.node(id) {
+            Some(n) => n,
+            None => return false,
+        };
+        self.nodes.insert(id.to_string(), None);
+        self.vecs.insert(id.to_string(), None);
+        self.dirty.insert(id.to_string());
+        for (layer, links) in node.neighbors.iter().enumerate() {
+            for n in links {
+                let mut other = match self.node(n) {
+                    Some(o) => o,
+                    None => continue,
+                };
+                if let Some(list) = other.neighbors.get_mut(layer) {
+                    list.retain(|x| x != id);
+                    for cand in links {
+                        if cand != n && !list.contains(cand) {
+                            list.push(cand.clone());
+                        }
+                    }
                 }
+                self.put_node(n, other);
+                self.prune(n, layer, self.params.max_links(layer));
             }
         }
+        if self.entry.as_ref().is_some_and(|(e, _)| e == id) {
+            self.entry = self.highest_node(id);
+        }
+        true
     }
-    let entry = match entry_id {
-        Some(e) => e,
-        None => return Vec::new(),
-    };
-    // greedy search
-    use std::collections::{BinaryHeap, HashSet};
-    #[derive(PartialEq)]
-    struct Scored {
-        score: f32,
-        id: String,
-    }
-    impl Eq for Scored {}
-    impl Ord for Scored {
-        fn cmp(&self, other: &Self) -> Ordering {
-            self.score
-                .partial_cmp(&other.score)
-                .unwrap_or(Ordering::Equal)
-        }
-    }
-    impl PartialOrd for Scored {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-    let mut visited: HashSet<String> = HashSet::new();
-    let mut best: BinaryHeap<Scored> = BinaryHeap::new();
-    let mut frontier: Vec<String> = vec![entry.clone()];
-    while let Some(cur) = frontier.pop() {
-        if !visited.insert(cur.clone()) {
-            continue;
+
+    /// Node with the highest level other than `exclude`; used to replace a removed entry point.
+    fn highest_node(&self, exclude: &str) -> Option<(String, usize)> {
+        let mut best: Option<(String, usize)> = None;
+        for (k, v) in self.tree.iter().flatten() {
+            if k.as_ref() == exclude.as_bytes() {
+                continue;
+            }
+            if let Ok(node) = serde_json::from_slice::<HnswNode>(&v) {
+                if best.as_ref().map(|(_, l)| node.level > *l).unwrap_or(true) {
+                    best = Some((String::from_utf8_lossy(&k).to_string(), node.level));
+                }
+            }
         }
-        if let Some(vec) = get_mem_embedding(db, &cur) {
-            let s = cosine_similarity(query, &vec);
-            best.push(Scored {
-                score: s,
-                id: cur.clone(),
-            });
-            if best.len() > top_k {
-                best.pop();
-            }
-        }
-        if let Ok(Some(nv)) = neigh.get(cur.as_bytes()) {
-            if let Ok(arr) = serde_json::from_slice::<Vec<serde_json::Value>>(&nv) {
-                for item in arr.into_iter().take(8) {
-                    // limit branching
-                    if let Some(nid) = item.get("id").and_then(|x| x.as_str()) {
-                        frontier.push(nid.to_string());
-                    }
+        best
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for id in self.dirty.drain() {
+            match self.nodes.get(&id) {
+                Some(Some(node)) => {
+                    self.tree.insert(id.as_bytes(), serde_json::to_vec(node)?)?;
+                }
+                _ => {
+                    self.tree.remove(id.as_bytes())?;
                 }
             }
         }
-        if visited.len() > 1024 {
+        let meta = self.db.open_tree("vec_meta")?;
+        match &self.entry {
+            Some((id, level)) => {
+                meta.insert(b"hnsw_entry", id.as_bytes())?;
+                meta.insert(b"hnsw_max_level", &(*level as u64).to_le_bytes())?;
+            }
+            None => {
+                meta.remove(b"hnsw_entry")?;
+                meta.remove(b"hnsw_max_level")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rebuild the multi-layer HNSW graph over all memory embeddings using the `HNSW_*` settings.
+pub fn build_mem_neighbor_graph(db: &sled::Db) -> Result<u64> {
+    let params = HnswParams::from_env();
+    let _guard = HNSW_WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let neigh = db.open_tree("hnsw_mem_neighbors")?;
+    neigh.clear()?;
+    let meta = db.open_tree("vec_meta")?;
+    meta.remove(b"hnsw_entry")?;
+    meta.remove(b"hnsw_max_level")?;
+    let mut graph = Hnsw::open(db, params)?;
+    let emb = db.open_tree("mem_embeddings")?;
+    let mut items: Vec<(String, Vec<f32>)> = Vec::new();
+    for kv in emb.iter() {
+        let (k, v) = kv?;
+        let Some(vec) = decode_embedding(&v) else {
+            continue;
+        };
+        let id = String::from_utf8_lossy(&k).to_string();
+        graph.vecs.insert(id.clone(), Some(Rc::new(vec.clone())));
+        items.push((id, vec));
+    }
+    for (id, vec) in &items {
+        graph.insert(id, vec);
+    }
+    graph.flush()?;
+    Ok(items.len() as u64)
+}
+
+/// Insert one memory into the HNSW graph. If no graph exists yet it is built from all
+/// stored embeddings (which already include this one).
+pub fn insert_into_neighbor_graph(db: &sled::Db, id: &str, vec: &[f32]) -> Result<()> {
+    {
+        let _guard = HNSW_WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut graph = Hnsw::open(db, HnswParams::from_env())?;
+        if graph.entry.is_some() {
+            graph.insert(id, vec);
+            return graph.flush();
+        }
+    }
+    build_mem_neighbor_graph(db)?;
+    Ok(())
+}
+
+/// Remove one memory from the HNSW graph. Returns false if it had no node.
+pub fn remove_from_neighbor_graph(db: &sled::Db, id: &str) -> Result<bool> {
+    let _guard = HNSW_WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut graph = Hnsw::open(db, HnswParams::from_env())?;
+    if !graph.remove(id) {
+        return Ok(false);
+    }
+    graph.flush()?;
+    Ok(true)
+}
+
+/// ANN search over the HNSW graph; falls back to brute force if the graph is missing.
+/// With a filter the beam is widened and non-matching hits dropped; if too few survive
+/// the filtered brute-force scan answers instead.
+pub fn ann_search_memories(
+    db: &sled::Db,
+    query: &[f32],
+    top_k: usize,
+    filter: Option<&VectorFilter>,
+) -> Vec<(String, f32)> {
+    let params = HnswParams::from_env();
+    let mut graph = match Hnsw::open(db, params) {
+        Ok(g) => g,
+        Err(_) => return search_memories_by_vector(db, query, top_k, filter),
+    };
+    let query = prepare_query(query, graph.normalized);
+    let mems = filter_tree(db, filter);
+    let ef = if mems.is_some() {
+        params.ef_search.max(top_k).saturating_mul(4)
+    } else {
+        params.ef_search.max(top_k)
+    };
+    let found = match graph.descend(&query, 0, ef) {
+        Some(found) => found,
+        None => return search_memories_by_vector(db, &query, top_k, filter),
+    };
+    let mut hits: Vec<(String, f32)> = Vec::new();
+    for (s, id) in found {
+        if hits.len() >= top_k {
             break;
         }
+        if let (Some(f), Some(m)) = (filter, mems.as_ref()) {
+            if !f.matches(m, &id) {
+                continue;
+            }
+        }
+        hits.push((id, s));
+    }
+    if mems.is_some() && hits.len() < top_k {
+        return search_memories_by_vector(db, &query, top_k, filter);
     }
-    let mut out: Vec<(String, f32)> = best
-        .into_sorted_vec()
-        .into_iter()
-        .rev()
-        .map(|s| (s.id, s.score))
-        .collect();
-    out.truncate(top_k);
-    out
+    hits
 }
 
-/// Re-embed all memories in batches using embed_batch.
+/// Memory pairs `(a, b, cosine)` with `a < b` whose embeddings are at least `threshold`
+/// similar. Candidates come from the ANN graph (`k` per memory) rather than all pairs.
+pub fn near_duplicate_pairs(db: &sled::Db, threshold: f32, k: usize) -> Vec<(String, String, f32)> {
+    let mut pairs: Vec<(String, String, f32)> = Vec::new();
+    let Ok(tree) = db.open_tree("mem_embeddings") else {
+        return pairs;
+    };
+    for (key, v) in tree.iter().flatten() {
+        let Some(emb) = decode_embedding(&v) else {
+            continue;
+        };
+        let id = String::from_utf8_lossy(&key).to_string();
+        for (other, _) in ann_search_memories(db, &emb, k + 1, None) {
+            if other <= id {
+                continue;
+            }
+            if let Some(o) = get_mem_embedding(db, &other) {
+                let sim = cosine_similarity(&emb, &o);
+                if sim >= threshold {
+                    pairs.push((id.clone(), other, sim));
+                }
+            }
+        }
+    }
+    pairs.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    pairs
+}
+
+/// Connected components over similarity pairs such as `near_duplicate_pairs` returns.
+/// Members and components are sorted by id.
+pub fn similarity_clusters(pairs: &[(String, String, f32)]) -> Vec<Vec<String>> {
+    let mut adj: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (a, b, _) in pairs {
+        adj.entry(a.as_str()).or_default().push(b.as_str());
+        adj.entry(b.as_str()).or_default().push(a.as_str());
+    }
+    let mut roots: Vec<&str> = adj.keys().copied().collect();
+    roots.sort();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut clusters = Vec::new();
+    for root in roots {
+        if !seen.insert(root) {
+            continue;
+        }
+        let mut members = vec![root];
+        let mut i = 0;
+        while i < members.len() {
+            for &n in &adj[members[i]] {
+                if seen.insert(n) {
+                    members.push(n);
+                }
+            }
+            i += 1;
+        }
+        let mut members: Vec<String> = members.into_iter().map(str::to_string).collect();
+        members.sort();
+        clusters.push(members);
+    }
+    clusters
+}
+
+/// Re-embed all memories in batches, reusing cached vectors for unchanged content.
 pub fn reembed_all_memories(db: &sled::Db, batch_size: usize) -> Result<u64> {
     let mems = db.open_tree("memories")?;
     let mut ids: Vec<String> = Vec::new();
@@ -287,19 +1260,226 @@ pub fn reembed_all_memories(db: &sled::Db, batch_size: usize) -> Result<u64> {
         }
     }
     let emb = db.open_tree("mem_embeddings")?;
+    let mode = quantization(db);
     let mut written: u64 = 0;
     let mut i = 0usize;
     while i < ids.len() {
         let end = (i + batch_size).min(ids.len());
         let slice = &texts[i..end];
         let refs: Vec<&str> = slice.iter().map(|s| s.as_str()).collect();
-        let vecs = crate::embeddings::embed_batch(&refs);
+        let vecs = crate::embeddings::embed_batch_cached(db, &refs);
         for (j, id) in ids[i..end].iter().enumerate() {
-            let bytes: &[u8] = bytemuck::cast_slice(&vecs[j]);
-            emb.insert(id.as_bytes(), bytes)?;
+            let mut v = vecs[j];
+            normalize(&mut v);
+            emb.insert(id.as_bytes(), encode_embedding(&v, mode))?;
             written += 1;
         }
         i = end;
     }
+    // Every memory vector was just rewritten normalized
+    set_normalized(db)?;
     Ok(written)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_embedding(db: &sled::Db, id: &str, seed: usize) -> Vec<f32> {
+        let mut v: Vec<f32> = (0..EMBED_DIM)
+            .map(|i| (((i * 31 + seed * 17) % 97) as f32) - 48.0)
+            .collect();
+        normalize(&mut v);
+        let emb = db.open_tree("mem_embeddings").unwrap();
+        emb.insert(id.as_bytes(), bytemuck::cast_slice(&v)).unwrap();
+        v
+    }
+
+    #[test]
+    fn test_neighbor_graph_insert_and_remove() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        set_normalized(&db).unwrap();
+        let mut vecs = Vec::new();
+        for i in 0..40 {
+            let id = format!("m{i}");
+            let v = put_embedding(&db, &id, i);
+            insert_into_neighbor_graph(&db, &id, &v).unwrap();
+            vecs.push((id, v));
+        }
+        // Remove the entry point and one ordinary node
+        let meta = db.open_tree("vec_meta").unwrap();
+        let entry = String::from_utf8(meta.get(b"hnsw_entry").unwrap().unwrap().to_vec()).unwrap();
+        let victims = [
+            entry.clone(),
+            if entry == "m7" { "m8" } else { "m7" }.to_string(),
+        ];
+        for (id, v) in &vecs {
+            if victims.contains(id) {
+                assert_eq!(ann_search_memories(&db, v, 1, None)[0].0, *id);
+            }
+        }
+        let emb = db.open_tree("mem_embeddings").unwrap();
+        for id in &victims {
+            emb.remove(id.as_bytes()).unwrap();
+            assert!(remove_from_neighbor_graph(&db, id).unwrap());
+        }
+        let new_entry = meta.get(b"hnsw_entry").unwrap().unwrap();
+        assert!(!victims.iter().any(|v| v.as_bytes() == new_entry.as_ref()));
+        for (id, v) in &vecs {
+            let hits = ann_search_memories(&db, v, 40, None);
+            assert!(hits.iter().all(|(h, _)| !victims.contains(h)));
+            if !victims.contains(id) {
+                assert_eq!(hits[0].0, *id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_search_skips_zero_vectors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let emb = db.open_tree("embeddings").unwrap();
+        let zero = vec![0.0f32; EMBED_DIM];
+        let mut one = vec![0.0f32; EMBED_DIM];
+        one[0] = 1.0;
+        emb.insert(b"doc-a:0", bytemuck::cast_slice(&zero)).unwrap();
+        emb.insert(b"doc-b:120", bytemuck::cast_slice(&one))
+            .unwrap();
+        let hits = search_chunks_by_vector(&db, &one, 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].0.as_str(), hits[0].1), ("doc-b", 120));
+        assert!((hits[0].2 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_filtered_vector_search() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        set_normalized(&db).unwrap();
+        let mems = db.open_tree("memories").unwrap();
+        let mut vecs = Vec::new();
+        for i in 0..20 {
+            let id = format!("m{i}");
+            let layer = if i % 2 == 0 { "LTM" } else { "STM" };
+            let episode = if i % 3 == 0 { "e1" } else { "e2" };
+            let rec = serde_json::json!({"id": id, "layer": layer, "episode_id": episode, "created_at": i * 1000});
+            mems.insert(id.as_bytes(), serde_json::to_vec(&rec).unwrap())
+                .unwrap();
+            let v = put_embedding(&db, &id, i);
+            insert_into_neighbor_graph(&db, &id, &v).unwrap();
+            vecs.push(v);
+        }
+        let filter = VectorFilter {
+            layer: Some("LTM".to_string()),
+            from: Some(4000),
+            to: Some(12000),
+            ..Default::default()
+        };
+        let allowed = ["m4", "m6", "m8", "m10", "m12"];
+        for hits in [
+            search_memories_by_vector(&db, &vecs[5], 10, Some(&filter)),
+            ann_search_memories(&db, &vecs[5], 10, Some(&filter)),
+        ] {
+            let mut ids: Vec<&str> = hits.iter().map(|(id, _)| id.as_str()).collect();
+            ids.sort();
+            let mut want = allowed.to_vec();
+            want.sort();
+            assert_eq!(ids, want);
+        }
+        let filter = VectorFilter {
+            layer: Some("LTM".to_string()),
+            episode: Some("e1".to_string()),
+            ..Default::default()
+        };
+        for hits in [
+            search_memories_by_vector(&db, &vecs[5], 10, Some(&filter)),
+            ann_search_memories(&db, &vecs[5], 10, Some(&filter)),
+        ] {
+            let mut ids: Vec<&str> = hits.iter().map(|(id, _)| id.as_str()).collect();
+            ids.sort();
+            assert_eq!(ids, ["m0", "m12", "m18", "m6"]);
+        }
+    }
+
+    #[test]
+    fn test_simd_cosine_matches_scalar() {
+        for len in [0usize, 3, 8, 13, 384] {
+            let a: Vec<f32> = (0..len).map(|i| (i as f32 * 0.7).sin()).collect();
+            let b: Vec<f32> = (0..len).map(|i| (i as f32 * 0.3).cos()).collect();
+            let fast = cosine_similarity(&a, &b);
+            let slow = cosine_similarity_scalar(&a, &b);
+            assert!((fast - slow).abs() < 1e-5, "len {len}: {fast} vs {slow}");
+            let d: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+            assert!((dot(&a, &b) - d).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_identical_query_is_top1_under_every_metric() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        set_normalized(&db).unwrap();
+        let mut vecs = Vec::new();
+        for i in 0..30 {
+            let id = format!("m{i}");
+            vecs.push((id.clone(), put_embedding(&db, &id, i)));
+        }
+        for metric in [
+            DistanceMetric::Cosine,
+            DistanceMetric::Dot,
+            DistanceMetric::Euclidean,
+        ] {
+            set_metric(&db, metric).unwrap();
+            build_mem_neighbor_graph(&db).unwrap();
+            for (id, v) in &vecs {
+                assert_eq!(search_memories_by_vector(&db, v, 1, None)[0].0, *id);
+                assert_eq!(ann_search_memories(&db, v, 1, None)[0].0, *id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_int8_search_keeps_the_full_precision_top1() {
+        let full = sled::Config::new().temporary(true).open().unwrap();
+        let quant = sled::Config::new().temporary(true).open().unwrap();
+        set_quantization(&quant, Quantization::Int8).unwrap();
+        let mut vecs = Vec::new();
+        for db in [&full, &quant] {
+            set_normalized(db).unwrap();
+            let mode = quantization(db);
+            let emb = db.open_tree("mem_embeddings").unwrap();
+            vecs.clear();
+            for i in 0..30 {
+                let id = format!("m{i}");
+                let v = put_embedding(db, &id, i);
+                emb.insert(id.as_bytes(), encode_embedding(&v, mode))
+                    .unwrap();
+                vecs.push((id, v));
+            }
+        }
+        let one = quant
+            .open_tree("mem_embeddings")
+            .unwrap()
+            .get(b"m0")
+            .unwrap()
+            .unwrap();
+        assert_eq!(one.len(), 4 + EMBED_DIM);
+        assert_eq!(validate_mem_embeddings(&quant), (30, 0));
+        build_mem_neighbor_graph(&quant).unwrap();
+        for (id, v) in &vecs {
+            let expected = &search_memories_by_vector(&full, v, 1, None)[0].0;
+            assert_eq!(expected, id);
+            assert_eq!(
+                &search_memories_by_vector(&quant, v, 1, None)[0].0,
+                expected
+            );
+            assert_eq!(&ann_search_memories(&quant, v, 1, None)[0].0, expected);
+        }
+
+        // A full-precision vector left behind is still read, but flagged
+        put_embedding(&quant, "legacy", 99);
+        assert_eq!(validate_mem_embeddings(&quant), (31, 1));
+        let legacy = get_mem_embedding(&quant, "legacy").unwrap();
+        assert_eq!(
+            search_memories_by_vector(&quant, &legacy, 1, None)[0].0,
+            "legacy"
+        );
+    }
+}